@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::net::TcpStream;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::http::{Method, ParseError, Request, Response, StatusCode};
+use crate::reader::{Limits, parse_response};
+
+// 服务器这一侧已经有了请求的序列化和响应的解析需要的全部零件，反过来用就是一个客户端：
+// 把 `Request` 序列化之后写进连接，再用 `parse_response` 从连接中读出 `Response`。
+// 和浏览器一样，客户端会把用完的连接放回连接池，下一个发往同一个主机的请求直接复用它，
+// 省去了 TCP 握手的时间。
+//
+// 目前只支持 http://，https 需要 TLS，而 rustls 只有异步多线程服务器依赖了。
+
+/// HTTP/1.1 客户端，克隆出来的副本共享同一个连接池。
+///
+/// ```ignore
+/// let client = Client::new();
+/// let res = client.get("http://127.0.0.1:7878/").await?;
+/// let req = Request::new(Method::Post, "http://127.0.0.1:7878/users").with_json(&user);
+/// let res = client.send(req).await?;
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    pool: Arc<Mutex<HashMap<String, Vec<IdleConnection>>>>,
+    timeout: Duration,
+    idle_timeout: Duration,
+    max_idle_per_host: usize,
+    limits: Limits,
+}
+
+struct IdleConnection {
+    stream: TcpStream,
+    since: Instant,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            pool: Arc::new(Mutex::new(HashMap::new())),
+            timeout: Duration::from_secs(30),
+            // 比服务器默认的空闲超时(5 秒)短，免得拿到一个服务器正要关闭的连接
+            idle_timeout: Duration::from_secs(4),
+            max_idle_per_host: 8,
+            limits: Limits {
+                max_head_size: 64 * 1024,
+                max_body_size: 16 * 1024 * 1024,
+            },
+        }
+    }
+
+    /// 一个请求(包括建立连接、发送请求和读取响应)最多可以花多久，默认 30 秒。
+    pub fn timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 连接在连接池中最多可以空闲多久，默认 4 秒。
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Client {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// 每个主机最多保留多少个空闲连接，默认 8 个，0 表示不复用连接。
+    pub fn max_idle_per_host(mut self, max: usize) -> Client {
+        self.max_idle_per_host = max;
+        self
+    }
+
+    /// 响应头和响应体的大小限制，默认分别是 64KB 和 16MB。
+    pub fn limits(mut self, limits: Limits) -> Client {
+        self.limits = limits;
+        self
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response, ClientError> {
+        self.send(Request::new(Method::Get, url)).await
+    }
+
+    pub async fn post(&self, url: &str, body: impl Into<Vec<u8>>) -> Result<Response, ClientError> {
+        self.send(Request::new(Method::Post, url).with_body(body))
+            .await
+    }
+
+    /// 发送一个请求，`request` 的目标必须是完整的 URL，例如 `http://127.0.0.1:7878/users?page=2`。
+    ///
+    /// 没有设置 `Host` 头时会根据 URL 自动设置。
+    pub async fn send(&self, request: Request) -> Result<Response, ClientError> {
+        let url = match request.query() {
+            Some(query) => format!("{}?{query}", request.path()),
+            None => request.path().to_string(),
+        };
+        let (authority, target) = split_url(&url)?;
+        let mut wire = Request::new(request.method(), &target).with_body(request.body());
+        for (name, value) in request.headers().iter() {
+            wire.headers_mut().append(name, value);
+        }
+        if !wire.headers().contains("Host") {
+            wire.headers_mut().insert("Host", authority.clone());
+        }
+
+        async_std::future::timeout(self.timeout, self.round_trip(&authority, &wire))
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    async fn round_trip(
+        &self,
+        authority: &str,
+        request: &Request,
+    ) -> Result<Response, ClientError> {
+        let bytes = request.to_bytes();
+        let method = request.method();
+        if let Some(mut stream) = self.checkout(authority) {
+            // 空闲的连接可能已经被服务器关掉了，而我们直到写入或者读取时才会发现。
+            // 写入失败时服务器没有收到完整的请求，换一个新的连接重新发送总是安全的；
+            // 写入成功之后才发现连接断开时，服务器可能已经处理了这个请求，
+            // 只有幂等的请求才能重新发送，否则 POST 可能被执行两次
+            let result = match write_request(&mut stream, &bytes).await {
+                Ok(()) => match self.read_response(stream, authority, method).await {
+                    Err(e) if !method.is_idempotent() => return Err(e),
+                    result => result,
+                },
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Err(e) if e.is_stale_connection() => {}
+                result => return result,
+            }
+        }
+        let mut stream = TcpStream::connect(host_and_port(authority)).await?;
+        // 请求和响应都很小时，Nagle 算法会让请求在内核中多等一会儿
+        stream.set_nodelay(true)?;
+        write_request(&mut stream, &bytes).await?;
+        self.read_response(stream, authority, method).await
+    }
+
+    /// 读取连接上的响应，连接还能继续使用时把它放回连接池。
+    async fn read_response(
+        &self,
+        mut stream: TcpStream,
+        authority: &str,
+        method: Method,
+    ) -> Result<Response, ClientError> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 8192];
+        loop {
+            if let Some((response, consumed)) = parse_response(&buf, &self.limits, method, false)? {
+                buf.drain(..consumed);
+                // 100 Continue 之类的中间响应之后才是真正的响应
+                let status = response.status();
+                if status.as_u16() < 200 && status != StatusCode::SWITCHING_PROTOCOLS {
+                    continue;
+                }
+                // 服务器多发送了数据，这个连接已经不可信了
+                if buf.is_empty() && keeps_alive(&response) {
+                    self.checkin(authority, stream);
+                }
+                return Ok(response);
+            }
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                if buf.is_empty() {
+                    return Err(ClientError::ConnectionClosed);
+                }
+                // 响应体一直持续到连接关闭，连接自然也不能复用了
+                return match parse_response(&buf, &self.limits, method, true)? {
+                    Some((response, _)) => Ok(response),
+                    None => Err(ClientError::ConnectionClosed),
+                };
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// 从连接池中取出一个还没有超过空闲时间的连接。
+    fn checkout(&self, authority: &str) -> Option<TcpStream> {
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.get_mut(authority)?;
+        // 后放回去的连接最新，从后往前取
+        while let Some(conn) = idle.pop() {
+            if conn.since.elapsed() < self.idle_timeout {
+                return Some(conn.stream);
+            }
+        }
+        None
+    }
+
+    fn checkin(&self, authority: &str, stream: TcpStream) {
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.entry(authority.to_string()).or_default();
+        idle.retain(|conn| conn.since.elapsed() < self.idle_timeout);
+        if idle.len() < self.max_idle_per_host {
+            idle.push(IdleConnection {
+                stream,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// 连接池中某个主机的空闲连接数。
+    pub fn idle_connections(&self, authority: &str) -> usize {
+        let pool = self.pool.lock().unwrap();
+        pool.get(authority).map_or(0, Vec::len)
+    }
+}
+
+/// 服务器在响应中要求关闭连接时，这个连接就不能再放回连接池了；
+/// 协议升级之后，连接上传输的也不再是 HTTP 了。
+fn keeps_alive(response: &Response) -> bool {
+    response.status() != StatusCode::SWITCHING_PROTOCOLS
+        && !response.header("Connection").is_some_and(|v| {
+            v.split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case("close"))
+        })
+}
+
+/// 把 `http://host:port/path?query` 拆分成 `host:port` 和 `/path?query`。
+fn split_url(url: &str) -> Result<(String, String), ClientError> {
+    let invalid = || ClientError::InvalidUrl(url.to_string());
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, target) = match rest.find(['/', '?', '#']) {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    // `#` 之后的片段只在客户端使用，不会发送给服务器
+    let target = target.split('#').next().unwrap_or("");
+    if authority.is_empty() || authority.contains('@') {
+        return Err(invalid());
+    }
+    let target = if target.starts_with('/') {
+        target.to_string()
+    } else {
+        format!("/{target}")
+    };
+    Ok((authority.to_string(), target))
+}
+
+/// 没有写端口时使用 HTTP 的默认端口 80。
+fn host_and_port(authority: &str) -> String {
+    // IPv6 地址本身就包含冒号，例如 `[::1]:8080`
+    let has_port = match authority.rsplit_once(':') {
+        Some((host, port)) => !port.is_empty() && (!host.contains(':') || host.ends_with(']')),
+        None => false,
+    };
+    if has_port {
+        authority.to_string()
+    } else {
+        format!("{authority}:80")
+    }
+}
+
+async fn write_request(stream: &mut TcpStream, request: &[u8]) -> io::Result<()> {
+    stream.write_all(request).await?;
+    stream.flush().await
+}
+
+/// 发送请求时可能出现的错误。
+#[derive(Debug)]
+pub enum ClientError {
+    /// 不是 `http://` 开头的完整 URL
+    InvalidUrl(String),
+    /// 服务器在发送完整的响应之前就关闭了连接
+    ConnectionClosed,
+    /// 服务器返回的响应格式错误
+    Parse(ParseError),
+    /// 超过了 `Client::timeout` 还没有收到响应
+    Timeout,
+    Io(io::Error),
+}
+
+impl ClientError {
+    /// 连接池中的连接是不是在我们不知道的时候被服务器关闭了。
+    fn is_stale_connection(&self) -> bool {
+        match self {
+            ClientError::ConnectionClosed => true,
+            ClientError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(url) => write!(f, "invalid url: {url:?}"),
+            ClientError::ConnectionClosed => {
+                write!(f, "connection closed before response was complete")
+            }
+            ClientError::Parse(e) => write!(f, "invalid response: {e}"),
+            ClientError::Timeout => write!(f, "request timed out"),
+            ClientError::Io(e) => write!(f, "io error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<ParseError> for ClientError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Io(e) => ClientError::Io(e),
+            ParseError::UnexpectedEof => ClientError::ConnectionClosed,
+            e => ClientError::Parse(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::server::{ServerConfig, serve_connection_async};
+    use async_std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    async fn echo(req: Request) -> Response {
+        let body = format!("{} {}", req.path(), String::from_utf8_lossy(req.body()));
+        Response::ok()
+            .with_header("X-Host", req.header("Host").unwrap_or(""))
+            .with_text(body)
+    }
+
+    /// 在随机端口上启动服务器，返回地址和已经接受的连接数。
+    async fn spawn_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        async_std::task::spawn(async move {
+            let router = Arc::new(Router::new().get("/*", echo).post("/*", echo));
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let router = Arc::clone(&router);
+                async_std::task::spawn(async move {
+                    let config = ServerConfig::default();
+                    let _ = serve_connection_async(stream, Some(peer), &*router, &config).await;
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    /// 对每个连接只回答一次固定的响应，然后关闭连接。
+    async fn spawn_raw_server(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        async_std::task::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response).await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn get_and_post_reuse_the_connection() {
+        async_std::task::block_on(async {
+            let (addr, accepted) = spawn_server().await;
+            let client = Client::new();
+
+            let res = client.get(&format!("http://{addr}/hello?x=1")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.body(), b"/hello ");
+            assert_eq!(res.header("X-Host"), Some(addr.as_str()));
+            assert_eq!(client.idle_connections(&addr), 1);
+
+            let res = client.post(&format!("http://{addr}/items"), "payload").await.unwrap();
+            assert_eq!(res.body(), b"/items payload");
+            assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+            // 不复用连接时每个请求都要建立新的连接
+            let client = Client::new().max_idle_per_host(0);
+            client.get(&format!("http://{addr}/a")).await.unwrap();
+            client.get(&format!("http://{addr}/b")).await.unwrap();
+            assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        });
+    }
+
+    #[test]
+    fn decodes_chunked_and_close_delimited_responses() {
+        async_std::task::block_on(async {
+            let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+            let addr = spawn_raw_server(chunked).await;
+            let res = Client::new().get(&format!("http://{addr}/")).await.unwrap();
+            assert_eq!(res.body(), b"hello world");
+
+            let until_close = b"HTTP/1.0 200 OK\r\n\r\nuntil close";
+            let addr = spawn_raw_server(until_close).await;
+            let client = Client::new();
+            let res = client.get(&format!("http://{addr}/")).await.unwrap();
+            assert_eq!(res.body(), b"until close");
+            assert_eq!(client.idle_connections(&addr), 0);
+        });
+    }
+
+    #[test]
+    fn requests_without_a_body_send_content_length_when_expected() {
+        async_std::task::block_on(async {
+            // 把收到的请求头交给测试检查
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let (sender, receiver) = async_std::channel::unbounded();
+            async_std::task::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = [0; 1024];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    let head = String::from_utf8_lossy(&buf[..n]).into_owned();
+                    sender.send(head).await.unwrap();
+                    let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+                }
+            });
+
+            let client = Client::new().max_idle_per_host(0);
+            let url = format!("http://{addr}/");
+            for method in [Method::Post, Method::Put, Method::Patch] {
+                client.send(Request::new(method, &url)).await.unwrap();
+                let head = receiver.recv().await.unwrap();
+                assert!(head.contains("\r\nContent-Length: 0\r\n"), "{head}");
+            }
+            client.get(&url).await.unwrap();
+            let head = receiver.recv().await.unwrap();
+            assert!(!head.contains("Content-Length"), "{head}");
+        });
+    }
+
+    #[test]
+    fn retries_when_pooled_connection_was_closed() {
+        async_std::task::block_on(async {
+            // 服务器声称保持连接，实际上回答完就关闭了，连接池中的连接已经失效
+            let addr = spawn_raw_server(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+            let client = Client::new();
+            for _ in 0..3 {
+                let res = client.get(&format!("http://{addr}/")).await.unwrap();
+                assert_eq!(res.body(), b"ok");
+            }
+        });
+    }
+
+    #[test]
+    fn post_is_not_resent_when_the_connection_drops_after_the_request() {
+        async_std::task::block_on(async {
+            // 服务器回答了第一个请求，读到第二个请求之后没有回答就关闭了连接
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let received = Arc::new(AtomicUsize::new(0));
+            let counter = Arc::clone(&received);
+            async_std::task::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = [0; 1024];
+                    if stream.read(&mut buf).await.unwrap() > 0 {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                        stream.write_all(ok).await.unwrap();
+                    }
+                    if stream.read(&mut buf).await.unwrap() > 0 {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+
+            let client = Client::new();
+            let url = format!("http://{addr}/orders");
+            assert_eq!(client.post(&url, "1").await.unwrap().body(), b"ok");
+            assert_eq!(client.idle_connections(&addr), 1);
+            // 服务器可能已经处理了第二个请求，不能再发一次
+            assert!(matches!(
+                client.post(&url, "2").await,
+                Err(ClientError::ConnectionClosed)
+            ));
+            assert_eq!(received.load(Ordering::SeqCst), 2);
+
+            // 幂等的请求依然会在新的连接上重新发送
+            assert_eq!(client.post(&url, "3").await.unwrap().body(), b"ok");
+            let res = client.send(Request::new(Method::Put, &url)).await.unwrap();
+            assert_eq!(res.body(), b"ok");
+            assert_eq!(received.load(Ordering::SeqCst), 5);
+        });
+    }
+
+    #[test]
+    fn reports_errors() {
+        async_std::task::block_on(async {
+            let client = Client::new().timeout(Duration::from_millis(200));
+            assert!(matches!(
+                client.get("https://example.com/").await,
+                Err(ClientError::InvalidUrl(_))
+            ));
+
+            // 服务器接受了连接，却一直不回答
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let _silent = async_std::task::spawn(async move {
+                let _conn = listener.accept().await;
+                async_std::task::sleep(Duration::from_secs(5)).await;
+            });
+            assert!(matches!(
+                client.get(&format!("http://{addr}/")).await,
+                Err(ClientError::Timeout)
+            ));
+        });
+    }
+
+    #[test]
+    fn splits_urls() {
+        let split = |url| split_url(url).unwrap();
+        let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+        assert_eq!(split("http://localhost:8080/a?b=1"), pair("localhost:8080", "/a?b=1"));
+        assert_eq!(split("http://localhost"), pair("localhost", "/"));
+        assert_eq!(split("http://localhost?q#frag"), pair("localhost", "/?q"));
+        assert!(split_url("ftp://localhost/").is_err());
+        assert!(split_url("http:///path").is_err());
+
+        assert_eq!(host_and_port("localhost"), "localhost:80");
+        assert_eq!(host_and_port("[::1]:8080"), "[::1]:8080");
+        assert_eq!(host_and_port("[::1]"), "[::1]:80");
+    }
+}
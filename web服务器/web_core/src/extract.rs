@@ -0,0 +1,257 @@
+use std::fmt;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+
+use crate::http::{IntoResponse, Request, Response, StatusCode};
+
+// 写一个 REST 接口时，处理函数的开头总是在重复同样的事情：检查 Content-Type、解析 JSON 请求体、
+// 解析查询字符串、把路径参数转换成数字，每一步出错时还要返回合适的状态码。
+// 提取器把这些步骤包装成类型，出错时返回 `Rejection`，处理函数用 `?` 直接把它返回给客户端：
+//
+//     async fn create_user(req: Request) -> Result<impl IntoResponse, Rejection> {
+//         let Json(user): Json<NewUser> = Json::from_request(&req)?;
+//         Ok((StatusCode::CREATED, Json(save(user))))
+//     }
+
+/// JSON 格式的请求体，也可以作为响应返回。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> Json<T> {
+    /// 解析请求体，请求的 `Content-Type` 必须是 `application/json`。
+    ///
+    /// `Content-Type` 不对时返回 415；不是合法的 JSON 时返回 400；
+    /// JSON 本身没有问题，但缺少字段或者字段类型不对时返回 422。
+    pub fn from_request(req: &Request) -> Result<Json<T>, Rejection> {
+        if !req.header("Content-Type").is_some_and(is_json) {
+            return Err(Rejection::new(
+                StatusCode(415),
+                "expected Content-Type: application/json",
+            ));
+        }
+        serde_json::from_slice(req.body()).map(Json).map_err(|e| {
+            let status = match e.classify() {
+                Category::Data => StatusCode(422),
+                Category::Io | Category::Syntax | Category::Eof => StatusCode::BAD_REQUEST,
+            };
+            Rejection::new(status, format!("invalid JSON body: {e}"))
+        })
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => Response::ok()
+                .with_header("Content-Type", "application/json")
+                .with_body(body),
+            // 例如 map 的键不是字符串，这是处理函数的 bug，而不是客户端的错误
+            Err(e) => Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+                .with_text(format!("failed to serialize response: {e}")),
+        }
+    }
+}
+
+/// `application/json`，也接受 `application/json; charset=utf-8` 以及 `application/problem+json` 这样的变体。
+fn is_json(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    let Some((kind, subtype)) = mime.split_once('/') else {
+        return false;
+    };
+    kind.eq_ignore_ascii_case("application")
+        && (subtype.eq_ignore_ascii_case("json")
+            || subtype.to_ascii_lowercase().ends_with("+json"))
+}
+
+/// 查询字符串，例如 `/users?page=2&per_page=20`。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> Query<T> {
+    /// 解析查询字符串，失败时返回 400。
+    ///
+    /// 没有查询字符串时当作空字符串处理，因此所有字段都是 `Option` 的结构体总能解析成功。
+    pub fn from_request(req: &Request) -> Result<Query<T>, Rejection> {
+        serde_urlencoded::from_str(req.query().unwrap_or(""))
+            .map(Query)
+            .map_err(|e| {
+                Rejection::new(StatusCode::BAD_REQUEST, format!("invalid query string: {e}"))
+            })
+    }
+}
+
+/// 路由匹配到的路径参数，例如 `/users/:id` 中的 `id`。
+///
+/// `T` 是一个字段名和参数名相同的结构体。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> Path<T> {
+    /// 解析路径参数，失败时(例如 `/users/abc` 中的 `abc` 不是数字)返回 400。
+    pub fn from_request(req: &Request) -> Result<Path<T>, Rejection> {
+        // 路径参数已经由路由解码过了，这里重新编码成查询字符串的格式再解析一次，
+        // 这样就能复用 serde_urlencoded 把字符串转换成数字、布尔值等类型的逻辑
+        let params: Vec<(&str, &str)> = req.params().iter().collect();
+        let encoded = serde_urlencoded::to_string(&params)
+            .map_err(|e| Rejection::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        serde_urlencoded::from_str(&encoded).map(Path).map_err(|e| {
+            Rejection::new(StatusCode::BAD_REQUEST, format!("invalid path parameter: {e}"))
+        })
+    }
+}
+
+/// 提取请求参数失败时的错误。
+///
+/// 作为响应返回时带上对应的状态码，响应体是 `{"error": "..."}` 格式的 JSON。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    status: StatusCode,
+    message: String,
+}
+
+impl Rejection {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Rejection {
+        Rejection {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.message });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::router::Router;
+    use crate::testing::TestClient;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct User {
+        id: u32,
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct NewUser {
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct UserId {
+        id: u32,
+    }
+
+    #[derive(Deserialize)]
+    struct Page {
+        page: Option<u32>,
+        q: Option<String>,
+    }
+
+    async fn create_user(req: Request) -> Result<impl IntoResponse, Rejection> {
+        let Json(new): Json<NewUser> = Json::from_request(&req)?;
+        Ok((StatusCode::CREATED, Json(User { id: 1, name: new.name })))
+    }
+
+    async fn show_user(req: Request) -> Result<Json<User>, Rejection> {
+        let Path(UserId { id }) = Path::from_request(&req)?;
+        let name = format!("user{id}");
+        Ok(Json(User { id, name }))
+    }
+
+    async fn list_users(req: Request) -> Result<Json<Vec<String>>, Rejection> {
+        let Query(page): Query<Page> = Query::from_request(&req)?;
+        let q = page.q.unwrap_or_default();
+        Ok(Json(vec![format!("page {} {q}", page.page.unwrap_or(1))]))
+    }
+
+    fn client() -> TestClient<Router> {
+        TestClient::new(
+            Router::new()
+                .post("/users", create_user)
+                .get("/users", list_users)
+                .get("/users/:id", show_user),
+        )
+    }
+
+    #[test]
+    fn json_body_in_and_out() {
+        let req = Request::new(Method::Post, "/users")
+            .with_json(&serde_json::json!({ "name": "ferris" }));
+        let res = client().send(req);
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.header("Content-Type"), Some("application/json"));
+        assert_eq!(
+            res.json::<User>(),
+            User {
+                id: 1,
+                name: "ferris".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn json_rejections() {
+        let client = client();
+        let res = client.post("/users", r#"{"name":"ferris"}"#);
+        assert_eq!(res.status().as_u16(), 415);
+
+        let post = |body: &str| {
+            let req = Request::new(Method::Post, "/users")
+                .with_header("Content-Type", "application/json; charset=utf-8")
+                .with_body(body);
+            client.send(req)
+        };
+        let res = post("{not json");
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.json::<serde_json::Value>()["error"].is_string());
+        assert_eq!(post(r#"{"nick":"ferris"}"#).status().as_u16(), 422);
+    }
+
+    #[test]
+    fn path_and_query_parameters() {
+        let client = client();
+        let res = client.get("/users/42");
+        assert_eq!(res.json::<User>().name, "user42");
+        assert_eq!(client.get("/users/abc").status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(client.get("/users").json::<Vec<String>>(), ["page 1 "]);
+        let res = client.get("/users?page=3&q=rust%20lang");
+        assert_eq!(res.json::<Vec<String>>(), ["page 3 rust lang"]);
+        assert_eq!(client.get("/users?page=x").status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn recognizes_json_content_types() {
+        assert!(is_json("application/json"));
+        assert!(is_json("Application/JSON; charset=utf-8"));
+        assert!(is_json("application/problem+json"));
+        assert!(!is_json("text/plain"));
+        assert!(!is_json("application/jsonp"));
+    }
+}
@@ -0,0 +1,769 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use futures::stream::{BoxStream, Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+
+use crate::reader::{Limits, parse_request};
+use crate::router::Params;
+use crate::websocket::Upgrade;
+
+/// HTTP 请求方法。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+        }
+    }
+
+    /// 同一个请求执行多次和执行一次的效果相同。POST 和 PATCH 不是幂等的。
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, Method::Post | Method::Patch)
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // 方法名是大小写敏感的，`get` 并不是合法的 GET 请求
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "PATCH" => Ok(Method::Patch),
+            "OPTIONS" => Ok(Method::Options),
+            _ => Err(ParseError::UnknownMethod(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 解析 HTTP 请求时可能出现的错误。
+#[derive(Debug)]
+pub enum ParseError {
+    /// 连接在读到完整的请求头之前就关闭了
+    UnexpectedEof,
+    BadRequestLine(String),
+    BadStatusLine(String),
+    UnknownMethod(String),
+    BadHeader(String),
+    BadContentLength(String),
+    /// chunked 编码的请求体格式错误
+    BadChunk,
+    /// 请求头超过了允许的最大长度
+    HeadTooLarge,
+    /// 请求体超过了允许的最大长度
+    BodyTooLarge,
+    /// 除了 chunked 之外的传输编码
+    UnsupportedTransferEncoding(String),
+    /// 请求读到一半时客户端长时间没有发送数据
+    Timeout,
+    Io(std::io::Error),
+}
+
+impl ParseError {
+    /// 返回给客户端的错误状态码。
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::HeadTooLarge => StatusCode(431),
+            ParseError::BodyTooLarge => StatusCode(413),
+            ParseError::UnsupportedTransferEncoding(_) => StatusCode(501),
+            ParseError::Timeout => StatusCode(408),
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedEof => write!(f, "connection closed before request was complete"),
+            ParseError::BadRequestLine(line) => write!(f, "bad request line: {line:?}"),
+            ParseError::BadStatusLine(line) => write!(f, "bad status line: {line:?}"),
+            ParseError::UnknownMethod(m) => write!(f, "unknown method: {m:?}"),
+            ParseError::BadHeader(line) => write!(f, "bad header: {line:?}"),
+            ParseError::BadContentLength(v) => write!(f, "bad content-length: {v:?}"),
+            ParseError::BadChunk => write!(f, "bad chunked body"),
+            ParseError::HeadTooLarge => write!(f, "request head too large"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+            ParseError::UnsupportedTransferEncoding(v) => {
+                write!(f, "unsupported transfer-encoding: {v:?}")
+            }
+            ParseError::Timeout => write!(f, "timed out reading request"),
+            ParseError::Io(e) => write!(f, "io error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<std::io::Error> for ParseError {
+    fn from(e: std::io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
+
+/// 请求头/响应头列表。
+///
+/// HTTP 头的名字不区分大小写，而且同名的头可以出现多次，
+/// 因此这里没有使用 HashMap，而是按顺序保存所有的 (名字, 值)。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers(Vec::new())
+    }
+
+    /// 返回第一个名为 `name` 的头的值。
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// 设置头的值，已存在的同名头会被全部替换。
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.0.push((name, value.into()));
+    }
+
+    /// 追加一个头，不影响已存在的同名头(例如多个 `Set-Cookie`)。
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// 一个完整的 HTTP 请求。
+#[derive(Debug, Clone)]
+pub struct Request {
+    method: Method,
+    path: String,
+    query: Option<String>,
+    version: String,
+    headers: Headers,
+    body: Vec<u8>,
+    params: Params,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl Request {
+    /// 构造一个没有请求头和请求体的请求，`target` 可以带查询字符串，例如 `/users?page=2`。
+    pub fn new(method: Method, target: &str) -> Request {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        Request {
+            method,
+            path,
+            query,
+            version: "HTTP/1.1".to_string(),
+            headers: Headers::new(),
+            body: Vec::new(),
+            params: Params::default(),
+            remote_addr: None,
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Request {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    /// 把 `value` 序列化为 JSON 作为请求体，并设置 `Content-Type`。
+    pub fn with_json<T: Serialize>(self, value: &T) -> Request {
+        let body = serde_json::to_vec(value).expect("failed to serialize request body");
+        self.with_header("Content-Type", "application/json")
+            .with_body(body)
+    }
+
+    pub fn with_remote_addr(mut self, addr: SocketAddr) -> Request {
+        self.remote_addr = Some(addr);
+        self
+    }
+
+    /// 从一段包含完整请求(请求行 + 请求头 + 请求体)的字节中解析出请求。
+    pub fn parse(bytes: &[u8]) -> Result<Request, ParseError> {
+        match parse_request(bytes, &Limits::default())? {
+            Some((request, _consumed)) => Ok(request),
+            None => Err(ParseError::UnexpectedEof),
+        }
+    }
+
+    /// 解析请求头部分(不含结尾的空行)。
+    pub fn parse_head(head: &str) -> Result<Request, ParseError> {
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().ok_or(ParseError::UnexpectedEof)?;
+
+        // 请求行的格式为：<method> <request-target> <version>，例如 `GET /users/1 HTTP/1.1`
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ParseError::BadRequestLine(request_line.to_string()));
+        };
+        if !target.starts_with('/') || !version.starts_with("HTTP/") {
+            return Err(ParseError::BadRequestLine(request_line.to_string()));
+        }
+
+        let mut request = Request::new(method.parse()?, target);
+        request.set_version(version);
+        for line in lines {
+            let (name, value) = parse_header(line)?;
+            request.headers.append(name, value);
+        }
+        Ok(request)
+    }
+
+    /// 序列化为可以直接写入连接的字节。
+    ///
+    /// 有请求体时自动设置 `Content-Length`，除非请求头中已经指定了 `Transfer-Encoding`，
+    /// 这时请求体需要是已经编码好的数据。POST、PUT 和 PATCH 请求即使没有请求体也会带上
+    /// `Content-Length: 0`，否则有些服务器会回复 411 Length Required。
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {}", self.method, self.path).into_bytes();
+        if let Some(query) = &self.query {
+            out.extend_from_slice(format!("?{query}").as_bytes());
+        }
+        out.extend_from_slice(format!(" {}\r\n", self.version).as_bytes());
+        for (name, value) in self.headers.iter() {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        let expects_body = matches!(self.method, Method::Post | Method::Put | Method::Patch);
+        if (expects_body || !self.body.is_empty()) && !self.headers.contains("Transfer-Encoding") {
+            out.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    /// 客户端是否希望在这个请求之后继续复用连接。
+    ///
+    /// HTTP/1.1 默认是持久连接，除非显式发送 `Connection: close`；
+    /// HTTP/1.0 则相反，只有发送了 `Connection: keep-alive` 才会复用连接。
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("");
+        let has = |token: &str| {
+            connection
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+        if self.version == "HTTP/1.0" {
+            has("keep-alive")
+        } else {
+            !has("close")
+        }
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// 中间件可以通过它给请求添加头，例如 `X-Request-Id`。
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// 客户端的地址，在不经过真实连接构造的请求(例如测试)中为 `None`。
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// 路由匹配到的路径参数，例如 `/users/:id` 中的 `id`。
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    pub(crate) fn set_params(&mut self, params: Params) {
+        self.params = params;
+    }
+
+    pub(crate) fn set_version(&mut self, version: &str) {
+        self.version = version.to_string();
+    }
+
+    pub(crate) fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+}
+
+/// 解析一行 `名字: 值` 形式的头，请求和响应共用。
+fn parse_header(line: &str) -> Result<(&str, &str), ParseError> {
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| ParseError::BadHeader(line.to_string()))?;
+    if name.is_empty() || name.contains(' ') {
+        return Err(ParseError::BadHeader(line.to_string()));
+    }
+    Ok((name, value.trim()))
+}
+
+/// 解码 URL 路径中的 `%XX` 转义，路由和静态文件共用。
+///
+/// 不完整或者不是十六进制的转义、解码之后不是合法的 UTF-8 都返回 `None`，
+/// 不会把 `%zz` 之类的原样留下来，也不会把非法的字节替换成 `\u{FFFD}`。
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// HTTP 状态码，例如 `StatusCode::NOT_FOUND`。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const TOO_MANY_REQUESTS: StatusCode = StatusCode(429);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const GATEWAY_TIMEOUT: StatusCode = StatusCode(504);
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// 状态码对应的原因短语，只覆盖了常用的状态码。
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            422 => "Unprocessable Entity",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason())
+    }
+}
+
+/// 一个 HTTP 响应。
+#[derive(Debug)]
+pub struct Response {
+    status: StatusCode,
+    headers: Headers,
+    body: Vec<u8>,
+    /// 逐块产生的响应体，设置之后 `body` 不再使用
+    stream: Option<BodyStream>,
+    /// 响应发送之后接管连接的协议，目前只有 WebSocket
+    upgrade: Option<Upgrade>,
+}
+
+/// 流式响应体，由 `serve_connection` 系列函数取出并逐块发送。
+pub(crate) struct BodyStream {
+    pub(crate) chunks: BoxStream<'static, io::Result<Vec<u8>>>,
+    /// 事先知道的响应体长度，知道时使用 `Content-Length` 而不是分块编码发送
+    pub(crate) len: Option<u64>,
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BodyStream(..)")
+    }
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Vec::new(),
+            stream: None,
+            upgrade: None,
+        }
+    }
+
+    pub fn ok() -> Response {
+        Response::new(StatusCode::OK)
+    }
+
+    /// 解析响应头部分(不含结尾的空行)，测试客户端用它来检查服务器返回的响应。
+    pub fn parse_head(head: &str) -> Result<Response, ParseError> {
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().ok_or(ParseError::UnexpectedEof)?;
+
+        // 状态行的格式为：<version> <status-code> <reason-phrase>，例如 `HTTP/1.1 404 Not Found`，
+        // 原因短语中可以有空格，也可以为空
+        let mut parts = status_line.splitn(3, ' ');
+        let (Some(version), Some(code)) = (parts.next(), parts.next()) else {
+            return Err(ParseError::BadStatusLine(status_line.to_string()));
+        };
+        let code = match code.parse() {
+            Ok(n @ 100..=999) if code.len() == 3 && version.starts_with("HTTP/") => n,
+            _ => return Err(ParseError::BadStatusLine(status_line.to_string())),
+        };
+
+        let mut response = Response::new(StatusCode(code));
+        for line in lines {
+            let (name, value) = parse_header(line)?;
+            response.headers.append(name, value);
+        }
+        Ok(response)
+    }
+
+    pub fn not_found() -> Response {
+        Response::new(StatusCode::NOT_FOUND).with_text("Not Found")
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self.stream = None;
+        self
+    }
+
+    /// 使用一个 Stream 作为响应体，它每产生一块数据就立刻发送给客户端，
+    /// 适合长时间运行的任务的进度、日志这类一开始并不知道全部内容的响应。
+    ///
+    /// 响应使用 `Transfer-Encoding: chunked` 发送，HTTP/1.0 的客户端不支持分块编码，
+    /// 这时直接发送数据，以关闭连接表示响应结束。
+    /// Stream 返回错误时连接会被立刻关闭，客户端能够发现响应不完整。
+    pub fn with_stream<S, B>(mut self, stream: S) -> Response
+    where
+        S: Stream<Item = io::Result<B>> + Send + 'static,
+        B: Into<Vec<u8>> + 'static,
+    {
+        self.body = Vec::new();
+        self.stream = Some(BodyStream {
+            chunks: stream.map_ok(Into::into).boxed(),
+            len: None,
+        });
+        self
+    }
+
+    /// 和 `with_stream` 一样逐块发送响应体，但是事先知道它一共有 `len` 个字节(例如文件)，
+    /// 因此使用 `Content-Length` 而不是分块编码，HTTP/1.0 的客户端也可以保持连接。
+    /// Stream 产生的数据和 `len` 对不上时连接会被关闭。
+    pub fn with_sized_stream<S, B>(mut self, len: u64, stream: S) -> Response
+    where
+        S: Stream<Item = io::Result<B>> + Send + 'static,
+        B: Into<Vec<u8>> + 'static,
+    {
+        self = self.with_stream(stream);
+        if let Some(stream) = self.stream.as_mut() {
+            stream.len = Some(len);
+        }
+        self
+    }
+
+    pub fn with_text(self, text: impl Into<String>) -> Response {
+        self.with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(text.into())
+    }
+
+    pub fn with_html(self, html: impl Into<String>) -> Response {
+        self.with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(html.into())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// 对于流式响应，`body` 总是空的。
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// 流式响应体的长度，长度未知或者不是流式响应时返回 `None`。
+    pub(crate) fn stream_len(&self) -> Option<u64> {
+        self.stream.as_ref().and_then(|stream| stream.len)
+    }
+
+    pub(crate) fn take_stream(&mut self) -> Option<BodyStream> {
+        self.stream.take()
+    }
+
+    pub(crate) fn set_upgrade(&mut self, upgrade: Upgrade) {
+        self.upgrade = Some(upgrade);
+    }
+
+    pub(crate) fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.upgrade.take()
+    }
+
+    /// 序列化为可以直接写入连接的字节，`Content-Length` 会根据响应体自动设置。
+    ///
+    /// 流式响应只序列化响应头，并带上 `Transfer-Encoding: chunked`(长度已知时是 `Content-Length`)，
+    /// 响应体需要另外发送。
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialize(true, false)
+    }
+
+    /// `chunked` 为 false 时，长度未知的流式响应既没有 `Content-Length` 也没有 `Transfer-Encoding`，
+    /// 只能以关闭连接表示响应结束。
+    ///
+    /// `head` 表示这是 HEAD 请求的响应：响应头和 GET 的一样(包括 `Content-Length`)，
+    /// 但是不发送响应体。
+    pub(crate) fn serialize(&self, chunked: bool, head: bool) -> Vec<u8> {
+        let mut out = format!("HTTP/1.1 {}\r\n", self.status).into_bytes();
+        for (name, value) in self.headers.iter() {
+            if name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                continue;
+            }
+            out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        if let Some(stream) = &self.stream {
+            if let Some(len) = stream.len {
+                out.extend_from_slice(format!("Content-Length: {len}\r\n").as_bytes());
+            } else if chunked {
+                out.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+            }
+        } else if !(100..200).contains(&self.status.0)
+            && self.status != StatusCode::NO_CONTENT
+            && self.status != StatusCode::NOT_MODIFIED
+        {
+            // 1xx 和 204 响应不能带 Content-Length；304 的 Content-Length 表示的是
+            // 完整响应的长度，而这里的响应体是空的，干脆不带
+            out.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        if !head {
+            out.extend_from_slice(&self.body);
+        }
+        out
+    }
+}
+
+/// 可以作为处理函数返回值的类型。
+///
+/// 处理函数除了直接返回 `Response`，还可以返回 `Json<T>`、`(StatusCode, Json<T>)`，
+/// 或者 `Result<_, Rejection>`，这样就能用 `?` 把提取请求参数时的错误直接返回给客户端。
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(ok) => ok.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+/// 替换响应的状态码，例如创建资源之后返回 `(StatusCode::CREATED, Json(user))`。
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Response {
+        let mut response = self.1.into_response();
+        response.status = self.0;
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_with_body() {
+        let raw = b"POST /users?admin=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 5\r\n\r\nhello";
+        let req = Request::parse(raw).unwrap();
+        assert_eq!(req.method(), Method::Post);
+        assert_eq!(req.path(), "/users");
+        assert_eq!(req.query(), Some("admin=1"));
+        assert_eq!(req.header("Content-Length"), Some("5"));
+        assert_eq!(req.body(), b"hello");
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        assert!(matches!(
+            Request::parse(b"HELLO\r\n\r\n"),
+            Err(ParseError::BadRequestLine(_))
+        ));
+        assert!(matches!(
+            Request::parse(b"FETCH / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::UnknownMethod(_))
+        ));
+        assert!(matches!(
+            Request::parse(b"GET / HTTP/1.1\r\nHost"),
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn keep_alive_defaults_depend_on_version() {
+        let req = Request::parse(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(req.wants_keep_alive());
+        let req = Request::parse(b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n").unwrap();
+        assert!(!req.wants_keep_alive());
+        let req = Request::parse(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert!(!req.wants_keep_alive());
+        let req = Request::parse(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        assert!(req.wants_keep_alive());
+    }
+
+    #[test]
+    fn response_sets_content_length() {
+        let bytes = Response::ok().with_text("hi").to_bytes();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(text.contains("Content-Length: 2\r\n"));
+        assert!(text.ends_with("\r\n\r\nhi"));
+    }
+}
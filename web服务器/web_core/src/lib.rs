@@ -0,0 +1,34 @@
+// web 服务器章节的几个版本(单线程、线程池、async-std 单线程、async-std 多线程)
+// 一开始都把 `/` 和 `/sleep` 硬编码在 `handle_connection` 中，
+// 这里把它们共同需要的部分抽取出来：HTTP 请求/响应的解析与序列化、路由表、JSON 等参数的提取、
+// 支持持久连接和流式响应的连接处理循环，访问日志、压缩等通用的中间件，WebSocket 和 SSE，
+// 以及不需要打开端口就能测试处理函数的测试客户端，和基于同一套请求/响应类型的 HTTP 客户端。
+
+pub mod client;
+pub mod extract;
+pub mod http;
+pub mod middleware;
+pub mod reader;
+pub mod router;
+pub mod server;
+pub mod shutdown;
+pub mod sse;
+pub mod static_files;
+pub mod testing;
+pub mod websocket;
+
+pub use client::{Client, ClientError};
+pub use extract::{Json, Path, Query, Rejection};
+pub use http::{Headers, IntoResponse, Method, ParseError, Request, Response, StatusCode};
+pub use middleware::{Middleware, Next, Pipeline};
+pub use reader::{Limits, RequestReader};
+pub use router::{Handler, Params, Router};
+pub use server::{
+    ConnectionLimit, ConnectionPermit, ServerConfig, busy_response, serve_connection,
+    serve_connection_async,
+};
+pub use shutdown::{InFlight, InFlightGuard, Shutdown};
+pub use sse::{Event, Sse};
+pub use static_files::ServeDir;
+pub use testing::{TestClient, TestResponse};
+pub use websocket::{Message, WebSocket};
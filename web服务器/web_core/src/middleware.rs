@@ -0,0 +1,161 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::http::{Request, Response};
+use crate::router::Handler;
+
+mod access_log;
+mod compression;
+mod cors;
+mod rate_limit;
+mod request_id;
+
+pub use access_log::AccessLog;
+pub use compression::Compression;
+pub use cors::Cors;
+pub use rate_limit::RateLimit;
+pub use request_id::RequestId;
+
+// 访问日志、请求 ID、压缩、跨域、限流这些功能和具体的业务无关，每个处理函数都需要，
+// 如果都写在 handle_connection 或者处理函数中，就只能到处复制粘贴。
+// 中间件把它们从处理函数中剥离出来：每个中间件拿到请求后可以先做一些事情，
+// 然后调用 `next.run(req)` 把请求交给后面的中间件(最终是路由表)，拿到响应后再做一些事情。
+// 中间件只依赖 `Handler` 特征，因此线程池版本和异步版本的服务器都可以使用。
+
+/// 中间件：包裹在处理函数外面，可以修改请求、修改响应，或者直接返回响应而不调用后面的处理函数。
+///
+/// 任何 `Fn(Request, Next) -> impl Future<Output = Response>` 的闭包都自动实现了该特征。
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Response>;
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Response> {
+        Box::pin(self(req, next))
+    }
+}
+
+/// 中间件链中剩下的部分。
+pub struct Next {
+    middlewares: Arc<[Arc<dyn Middleware>]>,
+    endpoint: Arc<dyn Handler>,
+    index: usize,
+}
+
+impl Next {
+    /// 把请求交给下一个中间件，没有中间件了就交给最终的处理函数。
+    pub fn run(self, req: Request) -> BoxFuture<'static, Response> {
+        match self.middlewares.get(self.index) {
+            Some(middleware) => {
+                let middleware = Arc::clone(middleware);
+                let next = Next {
+                    index: self.index + 1,
+                    ..self
+                };
+                middleware.handle(req, next)
+            }
+            None => self.endpoint.call(req),
+        }
+    }
+}
+
+/// 由若干中间件和一个最终处理函数组成的处理管道，它本身也是一个 `Handler`。
+///
+/// 请求按照 `with` 添加的顺序依次经过各个中间件，响应则按相反的顺序返回：
+///
+/// ```ignore
+/// let app = Pipeline::new(router)
+///     .with(AccessLog::stdout())   // 最外层，能看到最终的状态码和耗时
+///     .with(RequestId::new())
+///     .with(Compression::new());   // 最内层，直接压缩路由返回的响应
+/// ```
+pub struct Pipeline {
+    middlewares: Arc<[Arc<dyn Middleware>]>,
+    endpoint: Arc<dyn Handler>,
+}
+
+impl Pipeline {
+    pub fn new(endpoint: impl Handler) -> Pipeline {
+        Pipeline {
+            middlewares: Arc::new([]),
+            endpoint: Arc::new(endpoint),
+        }
+    }
+
+    pub fn with(self, middleware: impl Middleware) -> Pipeline {
+        let mut middlewares = self.middlewares.to_vec();
+        middlewares.push(Arc::new(middleware));
+        Pipeline {
+            middlewares: middlewares.into(),
+            endpoint: self.endpoint,
+        }
+    }
+}
+
+impl Handler for Pipeline {
+    fn call(&self, req: Request) -> BoxFuture<'static, Response> {
+        let next = Next {
+            middlewares: Arc::clone(&self.middlewares),
+            endpoint: Arc::clone(&self.endpoint),
+            index: 0,
+        };
+        next.run(req)
+    }
+}
+
+/// 在 `Vary` 头中追加一项，不覆盖其它中间件已经添加的内容。
+fn append_vary(res: &mut Response, name: &str) {
+    let vary = match res.header("Vary") {
+        Some(v) if v.split(',').any(|x| x.trim().eq_ignore_ascii_case(name)) => return,
+        Some(v) => format!("{v}, {name}"),
+        None => name.to_string(),
+    };
+    res.headers_mut().insert("Vary", vary);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, StatusCode};
+    use futures::executor::block_on;
+
+    #[test]
+    fn middlewares_run_in_order() {
+        let app = Pipeline::new(|req: Request| async move {
+            let trace = req.header("X-Trace").unwrap_or("").to_string();
+            Response::ok().with_text(format!("{trace}handler"))
+        })
+        .with(|mut req: Request, next: Next| async move {
+            req.headers_mut().insert("X-Trace", "a>");
+            let res = next.run(req).await;
+            res.with_header("X-Outer", "a")
+        })
+        .with(|mut req: Request, next: Next| async move {
+            let trace = format!("{}b>", req.header("X-Trace").unwrap());
+            req.headers_mut().insert("X-Trace", trace);
+            next.run(req).await
+        });
+
+        let res = block_on(app.call(Request::new(Method::Get, "/")));
+        assert_eq!(res.body(), b"a>b>handler");
+        assert_eq!(res.header("X-Outer"), Some("a"));
+    }
+
+    #[test]
+    fn middleware_can_short_circuit() {
+        let handler = |_req: Request| -> futures::future::Ready<Response> {
+            panic!("handler must not run")
+        };
+        let app = Pipeline::new(handler).with(|_req: Request, _next: Next| async {
+            Response::new(StatusCode(401)).with_text("no")
+        });
+        let res = block_on(app.call(Request::new(Method::Get, "/")));
+        assert_eq!(res.status(), StatusCode(401));
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+
+use super::{Middleware, Next};
+use crate::http::{Request, Response};
+
+/// 访问日志：每个请求处理完成后输出一行日志，格式为
+///
+/// ```text
+/// 127.0.0.1:53422 "GET /users/1 HTTP/1.1" 200 42B 3ms id=...
+/// ```
+///
+/// 放在 `RequestId` 之前(外层)时，日志中会带上请求 ID。
+#[derive(Clone)]
+pub struct AccessLog {
+    sink: Arc<dyn Fn(&str) + Send + Sync>,
+}
+
+impl AccessLog {
+    /// 把日志打印到标准输出。
+    pub fn stdout() -> AccessLog {
+        AccessLog::new(|line| println!("{line}"))
+    }
+
+    /// 自定义日志的输出位置，例如写入文件或者交给 log/tracing。
+    pub fn new(sink: impl Fn(&str) + Send + Sync + 'static) -> AccessLog {
+        AccessLog {
+            sink: Arc::new(sink),
+        }
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Response> {
+        let sink = Arc::clone(&self.sink);
+        Box::pin(async move {
+            let start = Instant::now();
+            let peer = req
+                .remote_addr()
+                .map_or_else(|| "-".to_string(), |addr| addr.to_string());
+            let request_line = format!("{} {} {}", req.method(), req.path(), req.version());
+
+            let res = next.run(req).await;
+
+            let mut line = format!(
+                "{peer} \"{request_line}\" {} {}B {}ms",
+                res.status().as_u16(),
+                res.body().len(),
+                start.elapsed().as_millis()
+            );
+            // 请求 ID 由内层的 RequestId 中间件写入响应头
+            if let Some(id) = res.header("X-Request-Id") {
+                line.push_str(&format!(" id={id}"));
+            }
+            sink(&line);
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::middleware::{Pipeline, RequestId};
+    use crate::router::Handler;
+    use std::sync::Mutex;
+
+    #[test]
+    fn logs_one_line_per_request() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&lines);
+        let app = Pipeline::new(|_req: Request| async { Response::ok().with_text("hi") })
+            .with(AccessLog::new(move |line| {
+                sink.lock().unwrap().push(line.to_string())
+            }))
+            .with(RequestId::new());
+
+        let req = Request::new(Method::Get, "/hello").with_header("X-Request-Id", "abc");
+        futures::executor::block_on(app.call(req));
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("- \"GET /hello HTTP/1.1\" 200 2B "));
+        assert!(lines[0].ends_with(" id=abc"));
+    }
+}
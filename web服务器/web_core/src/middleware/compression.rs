@@ -0,0 +1,153 @@
+use std::io::Write;
+
+use flate2::Compression as Level;
+use flate2::write::GzEncoder;
+use futures::future::BoxFuture;
+
+use super::{Middleware, Next, append_vary};
+use crate::http::{Request, Response};
+
+/// gzip 压缩：客户端在 `Accept-Encoding` 中声明支持 gzip 时，压缩文本类的响应体。
+///
+/// 图片、压缩包这类本身已经压缩过的内容再压缩一次几乎没有收益，因此只压缩文本类型；
+/// 太小的响应压缩后反而可能变大，也不压缩。
+pub struct Compression {
+    min_size: usize,
+    level: Level,
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 256,
+            level: Level::default(),
+        }
+    }
+
+    /// 小于该大小的响应体不压缩，默认 256 字节。
+    pub fn min_size(mut self, min_size: usize) -> Compression {
+        self.min_size = min_size;
+        self
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Response> {
+        let accepts_gzip = req.header("Accept-Encoding").is_some_and(accepts_gzip);
+        let (min_size, level) = (self.min_size, self.level);
+        Box::pin(async move {
+            let mut res = next.run(req).await;
+            // 无论这次是否压缩，响应内容都取决于 Accept-Encoding，需要告诉缓存服务器
+            append_vary(&mut res, "Accept-Encoding");
+            if !accepts_gzip || !should_compress(&res, min_size) {
+                return res;
+            }
+
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            let compressed = encoder
+                .write_all(res.body())
+                .and_then(|()| encoder.finish());
+            let Ok(compressed) = compressed else {
+                return res;
+            };
+            let mut res = res
+                .with_header("Content-Encoding", "gzip")
+                .with_body(compressed);
+            // 压缩后的内容和原来的不再逐字节相同，强 ETag 要变成弱 ETag
+            if let Some(etag) = res.header("ETag").filter(|e| !e.starts_with("W/")) {
+                let weak = format!("W/{etag}");
+                res.headers_mut().insert("ETag", weak);
+            }
+            res
+        })
+    }
+}
+
+/// 解析 `Accept-Encoding: gzip, deflate;q=0.5, br`，`q=0` 表示明确不接受。
+fn accepts_gzip(value: &str) -> bool {
+    value.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .filter_map(|q| q.parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+        (coding.eq_ignore_ascii_case("gzip") || coding == "*") && q > 0.0
+    })
+}
+
+fn should_compress(res: &Response, min_size: usize) -> bool {
+    let status = res.status().as_u16();
+    // 206 的响应体是文件的一部分，压缩后 Content-Range 就对不上了
+    if !(200..300).contains(&status) || status == 204 || status == 206 {
+        return false;
+    }
+    // 流式响应的数据是一块一块发出去的，整体压缩需要先把它们全部收集起来，就失去了流式的意义
+    if res.is_streaming() {
+        return false;
+    }
+    if res.body().len() < min_size || res.header("Content-Encoding").is_some() {
+        return false;
+    }
+    let content_type = res.header("Content-Type").unwrap_or("");
+    content_type.starts_with("text/")
+        || ["json", "javascript", "xml", "svg", "wasm"]
+            .iter()
+            .any(|t| content_type.contains(t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::middleware::Pipeline;
+    use crate::router::Handler;
+    use flate2::read::GzDecoder;
+    use futures::executor::block_on;
+    use std::io::Read;
+
+    fn app() -> Pipeline {
+        Pipeline::new(|req: Request| async move {
+            match req.path() {
+                "/png" => Response::ok()
+                    .with_header("Content-Type", "image/png")
+                    .with_body(vec![0; 1000]),
+                _ => Response::ok().with_text("hello ".repeat(100)),
+            }
+        })
+        .with(Compression::new())
+    }
+
+    #[test]
+    fn compresses_text_when_accepted() {
+        let req = Request::new(Method::Get, "/").with_header("Accept-Encoding", "br, gzip");
+        let res = block_on(app().call(req));
+        assert_eq!(res.header("Content-Encoding"), Some("gzip"));
+        assert!(res.body().len() < 600);
+
+        let mut text = String::new();
+        GzDecoder::new(res.body())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "hello ".repeat(100));
+    }
+
+    #[test]
+    fn skips_when_not_accepted_or_not_text() {
+        let req = Request::new(Method::Get, "/").with_header("Accept-Encoding", "gzip;q=0");
+        let res = block_on(app().call(req));
+        assert_eq!(res.header("Content-Encoding"), None);
+        assert_eq!(res.header("Vary"), Some("Accept-Encoding"));
+
+        let req = Request::new(Method::Get, "/png").with_header("Accept-Encoding", "gzip");
+        let res = block_on(app().call(req));
+        assert_eq!(res.header("Content-Encoding"), None);
+    }
+}
@@ -0,0 +1,215 @@
+use futures::future::BoxFuture;
+
+use super::{Middleware, Next, append_vary};
+use crate::http::{Method, Request, Response, StatusCode};
+
+/// 跨域资源共享(CORS)。
+///
+/// 浏览器在发送“非简单”跨域请求(例如带 JSON 请求体的 POST、PUT、DELETE)之前，
+/// 会先发送一个 `OPTIONS` 预检请求，询问服务器是否允许。预检请求由中间件直接回答，
+/// 不会进入路由表；普通请求则在响应上加上 `Access-Control-Allow-Origin`。
+#[derive(Debug, Clone)]
+pub struct Cors {
+    /// `None` 表示允许任意来源
+    allowed_origins: Option<Vec<String>>,
+    allowed_methods: Vec<Method>,
+    allowed_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl Cors {
+    /// 只允许指定的来源，例如 `https://dashboard.example.com`。
+    pub fn new<I, S>(origins: I) -> Cors
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Cors {
+            allowed_origins: Some(origins.into_iter().map(Into::into).collect()),
+            ..Cors::any()
+        }
+    }
+
+    /// 允许任意来源，适合公开的 API。
+    pub fn any() -> Cors {
+        Cors {
+            allowed_origins: None,
+            allowed_methods: vec![
+                Method::Get,
+                Method::Head,
+                Method::Post,
+                Method::Put,
+                Method::Patch,
+                Method::Delete,
+            ],
+            allowed_headers: vec!["Content-Type".to_string(), "Authorization".to_string()],
+            allow_credentials: false,
+            max_age: Some(600),
+        }
+    }
+
+    pub fn methods(mut self, methods: &[Method]) -> Cors {
+        self.allowed_methods = methods.to_vec();
+        self
+    }
+
+    pub fn headers(mut self, headers: &[&str]) -> Cors {
+        self.allowed_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// 允许浏览器携带 Cookie。此时响应中不能使用通配符 `*`，必须回显具体的来源。
+    pub fn allow_credentials(mut self, allow: bool) -> Cors {
+        self.allow_credentials = allow;
+        self
+    }
+
+    /// 预检结果可以被浏览器缓存多少秒。
+    pub fn max_age(mut self, seconds: Option<u64>) -> Cors {
+        self.max_age = seconds;
+        self
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        match &self.allowed_origins {
+            None => true,
+            Some(origins) => origins.iter().any(|o| o == origin),
+        }
+    }
+
+    fn allow_origin_value(&self, origin: &str) -> String {
+        if self.allowed_origins.is_none() && !self.allow_credentials {
+            "*".to_string()
+        } else {
+            origin.to_string()
+        }
+    }
+
+    fn add_headers(&self, res: &mut Response, origin: &str) {
+        let allow_origin = self.allow_origin_value(origin);
+        if allow_origin != "*" {
+            // 响应内容和请求的来源有关，缓存时需要区分
+            append_vary(res, "Origin");
+        }
+        let headers = res.headers_mut();
+        headers.insert("Access-Control-Allow-Origin", allow_origin);
+        if self.allow_credentials {
+            headers.insert("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    fn preflight(&self, req: &Request, origin: &str) -> Response {
+        let method_allowed = req
+            .header("Access-Control-Request-Method")
+            .and_then(|m| m.parse::<Method>().ok())
+            .is_some_and(|m| self.allowed_methods.contains(&m));
+        let headers_allowed = req
+            .header("Access-Control-Request-Headers")
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .all(|h| {
+                self.allowed_headers
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(h))
+            });
+        if !self.origin_allowed(origin) || !method_allowed || !headers_allowed {
+            // 不带 CORS 头的响应就表示拒绝，浏览器会拦截后续的真实请求
+            return Response::new(StatusCode::FORBIDDEN);
+        }
+
+        let methods: Vec<&str> = self.allowed_methods.iter().map(|m| m.as_str()).collect();
+        let mut res = Response::new(StatusCode::NO_CONTENT)
+            .with_header("Access-Control-Allow-Methods", methods.join(", "))
+            .with_header(
+                "Access-Control-Allow-Headers",
+                self.allowed_headers.join(", "),
+            );
+        if let Some(max_age) = self.max_age {
+            res = res.with_header("Access-Control-Max-Age", max_age.to_string());
+        }
+        self.add_headers(&mut res, origin);
+        res
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Response> {
+        // 没有 Origin 头的请求不是跨域请求，直接放行
+        let Some(origin) = req.header("Origin").map(str::to_string) else {
+            return next.run(req);
+        };
+        if req.method() == Method::Options && req.header("Access-Control-Request-Method").is_some()
+        {
+            let res = self.preflight(&req, &origin);
+            return Box::pin(async move { res });
+        }
+
+        let cors = self.clone();
+        Box::pin(async move {
+            let mut res = next.run(req).await;
+            if cors.origin_allowed(&origin) {
+                cors.add_headers(&mut res, &origin);
+            }
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Pipeline;
+    use crate::router::Handler;
+    use futures::executor::block_on;
+
+    fn app(cors: Cors) -> Pipeline {
+        Pipeline::new(|_req: Request| async { Response::ok().with_text("data") }).with(cors)
+    }
+
+    #[test]
+    fn preflight_is_answered_by_middleware() {
+        let req = Request::new(Method::Options, "/api")
+            .with_header("Origin", "https://app.example")
+            .with_header("Access-Control-Request-Method", "PUT")
+            .with_header("Access-Control-Request-Headers", "content-type");
+        let res = block_on(app(Cors::new(["https://app.example"])).call(req));
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.header("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        assert!(
+            res.header("Access-Control-Allow-Methods")
+                .unwrap()
+                .contains("PUT")
+        );
+        assert_eq!(res.header("Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn disallowed_origin_gets_no_cors_headers() {
+        let cors = Cors::new(["https://app.example"]);
+        let req = Request::new(Method::Get, "/api").with_header("Origin", "https://evil.example");
+        let res = block_on(app(cors.clone()).call(req));
+        assert_eq!(res.header("Access-Control-Allow-Origin"), None);
+
+        let req = Request::new(Method::Options, "/api")
+            .with_header("Origin", "https://evil.example")
+            .with_header("Access-Control-Request-Method", "GET");
+        assert_eq!(
+            block_on(app(cors).call(req)).status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn any_origin_uses_wildcard() {
+        let req = Request::new(Method::Get, "/api").with_header("Origin", "https://a.example");
+        let res = block_on(app(Cors::any()).call(req));
+        assert_eq!(res.header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(res.body(), b"data");
+    }
+}
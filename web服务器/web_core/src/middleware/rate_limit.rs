@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+
+use super::{Middleware, Next};
+use crate::http::{Request, Response, StatusCode};
+
+/// 按客户端 IP 限流，使用令牌桶算法。
+///
+/// 每个 IP 有一个容量为 `burst` 的桶，每秒补充 `per_second` 个令牌，
+/// 每个请求消耗一个令牌，桶空了就返回 429 并通过 `Retry-After` 告诉客户端多久之后再试。
+/// 令牌桶允许短时间的突发流量，但长期的平均速率不会超过 `per_second`。
+#[derive(Clone)]
+pub struct RateLimit {
+    burst: f64,
+    per_second: f64,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// 上一次清理的时间
+    last_sweep: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// 桶的数量超过这个值时，清理已经补满的桶，防止大量不同的 IP 让 HashMap 无限增长
+const CLEANUP_THRESHOLD: usize = 10_000;
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: u32) -> RateLimit {
+        assert!(per_second > 0.0 && burst > 0);
+        RateLimit {
+            burst: burst as f64,
+            per_second,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// 尝试为 `ip` 消耗一个令牌，失败时返回还需要等待多久。
+    fn acquire(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        // 一个桶至少要 `burst / per_second` 秒没有请求才会补满，清理得比这更频繁也删不掉
+        // 更多的桶，只是在每个请求上白白遍历一遍 HashMap，因此两次清理至少间隔这么久
+        let refill = Duration::from_secs_f64(self.burst / self.per_second);
+        let sweep_due = state
+            .last_sweep
+            .is_none_or(|last| now.saturating_duration_since(last) >= refill);
+        if state.buckets.len() >= CLEANUP_THRESHOLD && sweep_due {
+            let (burst, per_second) = (self.burst, self.per_second);
+            state
+                .buckets
+                .retain(|_, b| b.refilled(now, burst, per_second) < burst);
+            state.last_sweep = Some(now);
+        }
+
+        let bucket = state.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, self.burst, self.per_second);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.per_second;
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, burst: f64, per_second: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * per_second).min(burst)
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Response> {
+        // 没有客户端地址(例如测试中直接构造的请求)时不限流
+        let Some(addr) = req.remote_addr() else {
+            return next.run(req);
+        };
+        match self.acquire(addr.ip(), Instant::now()) {
+            Ok(()) => next.run(req),
+            Err(wait) => {
+                let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                let res = Response::new(StatusCode::TOO_MANY_REQUESTS)
+                    .with_header("Retry-After", retry_after.to_string())
+                    .with_text("Too Many Requests");
+                Box::pin(async move { res })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::middleware::Pipeline;
+    use crate::router::Handler;
+    use futures::executor::block_on;
+
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let limit = RateLimit::new(2.0, 3);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limit.acquire(ip, start).is_ok());
+        }
+        let wait = limit.acquire(ip, start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        // 不同的 IP 有各自独立的桶
+        assert!(limit.acquire(other, start).is_ok());
+        // 半秒之后补充了一个令牌
+        assert!(
+            limit
+                .acquire(ip, start + Duration::from_millis(500))
+                .is_ok()
+        );
+        assert!(
+            limit
+                .acquire(ip, start + Duration::from_millis(500))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn full_buckets_are_swept_at_most_once_per_refill() {
+        // 每个桶 1 个令牌、每秒补充 1 个，1 秒之后没有请求的桶就补满了
+        let limit = RateLimit::new(1.0, 1);
+        let start = Instant::now();
+        let ip = |i: usize| IpAddr::from((i as u32).to_be_bytes());
+        for i in 0..CLEANUP_THRESHOLD {
+            assert!(limit.acquire(ip(i), start).is_ok());
+        }
+        let buckets = |limit: &RateLimit| limit.state.lock().unwrap().buckets.len();
+
+        let first = start + Duration::from_secs(1);
+        assert!(limit.acquire(ip(0), first).is_ok());
+        assert_eq!(buckets(&limit), 1);
+        assert_eq!(limit.state.lock().unwrap().last_sweep, Some(first));
+
+        for i in 1..CLEANUP_THRESHOLD {
+            assert!(limit.acquire(ip(i), first).is_ok());
+        }
+        // 距离上一次清理还不到 1 秒，不会再遍历一遍
+        let early = first + Duration::from_millis(500);
+        assert!(limit.acquire(ip(CLEANUP_THRESHOLD), early).is_ok());
+        assert_eq!(buckets(&limit), CLEANUP_THRESHOLD + 1);
+        assert_eq!(limit.state.lock().unwrap().last_sweep, Some(first));
+
+        let second = first + Duration::from_secs(1);
+        assert!(limit.acquire(ip(0), second).is_ok());
+        // 在 `early` 时用掉令牌的桶还没有补满，其它的都被清理掉了
+        assert_eq!(buckets(&limit), 2);
+    }
+
+    #[test]
+    fn returns_429_with_retry_after() {
+        let app =
+            Pipeline::new(|_req: Request| async { Response::ok() }).with(RateLimit::new(0.5, 1));
+        let req =
+            || Request::new(Method::Get, "/").with_remote_addr("10.0.0.1:1234".parse().unwrap());
+
+        assert_eq!(block_on(app.call(req())).status(), StatusCode::OK);
+        let res = block_on(app.call(req()));
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.header("Retry-After"), Some("2"));
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+
+use super::{Middleware, Next};
+use crate::http::{Request, Response};
+
+/// 给每个请求分配一个 ID，写入请求头和响应头的 `X-Request-Id` 中，
+/// 方便把同一个请求在各个服务、各条日志中的记录串起来。
+///
+/// 如果客户端(或者前面的反向代理)已经带了 `X-Request-Id`，则沿用它。
+pub struct RequestId {
+    /// 进程启动时间，保证重启之后生成的 ID 不会和之前的重复
+    prefix: String,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new() -> RequestId {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        RequestId {
+            prefix: format!("{:x}", nanos & 0xffff_ffff_ffff),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    fn next_id(&self) -> String {
+        // 只需要保证唯一，不需要和其它内存操作同步，Relaxed 就足够了
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{:06x}", self.prefix, n)
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        RequestId::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<'static, Response> {
+        let id = match req.header("X-Request-Id") {
+            // 不信任过长或者带有奇怪字符的 ID，避免日志注入
+            Some(id) if id.len() <= 64 && id.bytes().all(|b| b.is_ascii_graphic()) => {
+                id.to_string()
+            }
+            _ => self.next_id(),
+        };
+        req.headers_mut().insert("X-Request-Id", id.clone());
+        Box::pin(async move { next.run(req).await.with_header("X-Request-Id", id) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::middleware::Pipeline;
+    use crate::router::Handler;
+    use futures::executor::block_on;
+
+    #[test]
+    fn generates_unique_ids_visible_to_handler() {
+        let app = Pipeline::new(|req: Request| async move {
+            Response::ok().with_text(req.header("X-Request-Id").unwrap().to_string())
+        })
+        .with(RequestId::new());
+
+        let a = block_on(app.call(Request::new(Method::Get, "/")));
+        let b = block_on(app.call(Request::new(Method::Get, "/")));
+        assert_eq!(a.header("X-Request-Id").unwrap().as_bytes(), a.body());
+        assert_ne!(a.header("X-Request-Id"), b.header("X-Request-Id"));
+
+        let req = Request::new(Method::Get, "/").with_header("X-Request-Id", "bad id\r\n");
+        let c = block_on(app.call(req));
+        assert_ne!(c.header("X-Request-Id"), Some("bad id\r\n"));
+    }
+}
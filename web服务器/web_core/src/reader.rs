@@ -0,0 +1,592 @@
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use futures::io::{AsyncRead, AsyncReadExt};
+
+use crate::http::{Headers, Method, ParseError, Request, Response};
+
+// 最早的异步服务器只调用一次 `stream.read` 读到一个 1024 字节的数组中，
+// 但 TCP 是字节流，一个请求可能被拆成好几段到达，也可能比 1024 字节大，
+// 流水线中的多个请求还可能在同一次读取中一起到达。
+// 正确的做法是维护一个缓冲区：不断把读到的数据追加进去，每次追加后尝试解析，
+// 数据不够就继续读，解析出一个请求后把它占用的字节从缓冲区中移除，剩下的留给下一个请求。
+
+/// 请求大小的限制，防止恶意的客户端发送无穷大的请求耗尽服务器内存。
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// 请求行 + 请求头的最大字节数，超出时返回 431
+    pub max_head_size: usize,
+    /// 请求体的最大字节数，超出时返回 413
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_head_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// 尝试从缓冲区的开头解析出一个完整的请求。
+///
+/// 成功时返回请求以及它占用的字节数；数据还不完整时返回 `Ok(None)`。
+pub fn parse_request(buf: &[u8], limits: &Limits) -> Result<Option<(Request, usize)>, ParseError> {
+    // 请求行之前的空行应当被忽略，有些客户端会在 POST 请求体后面多发一个 CRLF
+    let start = buf
+        .iter()
+        .position(|b| !matches!(b, b'\r' | b'\n'))
+        .unwrap_or(buf.len());
+    let rest = &buf[start..];
+
+    let Some(head_len) = find(rest, b"\r\n\r\n") else {
+        if rest.len() > limits.max_head_size {
+            return Err(ParseError::HeadTooLarge);
+        }
+        return Ok(None);
+    };
+    if head_len > limits.max_head_size {
+        return Err(ParseError::HeadTooLarge);
+    }
+    let head = std::str::from_utf8(&rest[..head_len])
+        .map_err(|_| ParseError::BadRequestLine(String::from_utf8_lossy(&rest[..head_len]).into()))?;
+    let mut request = Request::parse_head(head)?;
+
+    let body_start = start + head_len + 4;
+    // 请求既没有 Content-Length 也不是 chunked 编码时没有请求体
+    let framing = match framing(request.headers())? {
+        Framing::UntilEof => Framing::Length(0),
+        framing => framing,
+    };
+    let Some((body, body_len)) = take_body(&buf[body_start..], framing, limits, false)? else {
+        return Ok(None);
+    };
+    request.set_body(body);
+    Ok(Some((request, body_start + body_len)))
+}
+
+/// 尝试从缓冲区的开头解析出一个完整的响应，规则和 `parse_request` 相同。
+///
+/// 响应有没有响应体还取决于请求：HEAD 请求的响应只有响应头，因此需要传入请求的方法。
+/// 既没有 `Content-Length` 也不是 chunked 编码的响应体一直持续到连接关闭，
+/// 因此还需要通过 `eof` 说明缓冲区中是不是已经包含了连接上的全部数据。
+pub fn parse_response(
+    buf: &[u8],
+    limits: &Limits,
+    method: Method,
+    eof: bool,
+) -> Result<Option<(Response, usize)>, ParseError> {
+    let Some(head_len) = find(buf, b"\r\n\r\n") else {
+        if buf.len() > limits.max_head_size {
+            return Err(ParseError::HeadTooLarge);
+        }
+        return if eof && !buf.is_empty() {
+            Err(ParseError::UnexpectedEof)
+        } else {
+            Ok(None)
+        };
+    };
+    if head_len > limits.max_head_size {
+        return Err(ParseError::HeadTooLarge);
+    }
+    let head = std::str::from_utf8(&buf[..head_len])
+        .map_err(|_| ParseError::BadStatusLine(String::from_utf8_lossy(&buf[..head_len]).into()))?;
+    let response = Response::parse_head(head)?;
+
+    let body_start = head_len + 4;
+    // HEAD 请求的响应，以及 1xx、204 和 304 响应一定没有响应体，不管响应头里写了什么
+    let status = response.status().as_u16();
+    let no_body = method == Method::Head || (100..200).contains(&status);
+    let framing = if no_body || status == 204 || status == 304 {
+        Framing::Length(0)
+    } else {
+        framing(response.headers())?
+    };
+    let Some((body, body_len)) = take_body(&buf[body_start..], framing, limits, eof)? else {
+        return if eof {
+            Err(ParseError::UnexpectedEof)
+        } else {
+            Ok(None)
+        };
+    };
+    Ok(Some((response.with_body(body), body_start + body_len)))
+}
+
+/// 消息体的长度是怎么确定的，请求和响应的规则相同。
+enum Framing {
+    Chunked,
+    Length(usize),
+    /// 一直到连接关闭，只有响应可以这样
+    UntilEof,
+}
+
+fn framing(headers: &Headers) -> Result<Framing, ParseError> {
+    if let Some(encoding) = headers.get("Transfer-Encoding") {
+        // 同时带有 Content-Length 和 Transfer-Encoding 的请求是请求走私(request smuggling)的常见手法
+        if headers.contains("Content-Length") {
+            return Err(ParseError::BadHeader(
+                "both Content-Length and Transfer-Encoding".to_string(),
+            ));
+        }
+        if !encoding.trim().eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::UnsupportedTransferEncoding(encoding.to_string()));
+        }
+        return Ok(Framing::Chunked);
+    }
+    // 多个 Content-Length(或者逗号分隔的列表)只有在值都相同时才能接受，否则代理和服务器
+    // 可能按不同的长度切分消息，和上面一样可以用来走私请求
+    let mut length = None;
+    let values = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, value)| value);
+    for value in values {
+        for v in value.split(',').map(str::trim) {
+            let bad = || ParseError::BadContentLength(value.to_string());
+            // `usize::from_str` 接受 `+5`，而 Content-Length 只能是数字
+            if v.is_empty() || !v.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(bad());
+            }
+            let v = v.parse().map_err(|_| bad())?;
+            if length.is_some_and(|length| length != v) {
+                return Err(bad());
+            }
+            length = Some(v);
+        }
+    }
+    Ok(length.map_or(Framing::UntilEof, Framing::Length))
+}
+
+/// 从 `buf` 中取出消息体，返回消息体以及它占用的字节数；数据还不完整时返回 `Ok(None)`。
+fn take_body(
+    buf: &[u8],
+    framing: Framing,
+    limits: &Limits,
+    eof: bool,
+) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    let length = match framing {
+        Framing::Chunked => return decode_chunked(buf, limits.max_body_size),
+        Framing::Length(length) => length,
+        Framing::UntilEof if eof => buf.len(),
+        Framing::UntilEof => return Ok(None),
+    };
+    if length > limits.max_body_size {
+        return Err(ParseError::BodyTooLarge);
+    }
+    if buf.len() < length {
+        return Ok(None);
+    }
+    Ok(Some((buf[..length].to_vec(), length)))
+}
+
+/// 解码 `Transfer-Encoding: chunked` 的消息体。
+///
+/// 每个块的格式为 `<十六进制长度>\r\n<数据>\r\n`，长度为 0 的块表示结束，后面可以跟若干行 trailer。
+/// 成功时返回解码后的数据以及占用的字节数；数据还不完整时返回 `Ok(None)`。
+pub fn decode_chunked(
+    buf: &[u8],
+    max_size: usize,
+) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+    loop {
+        let Some(line_len) = find(&buf[pos..], b"\r\n") else {
+            return Ok(None);
+        };
+        let line = std::str::from_utf8(&buf[pos..pos + line_len]).map_err(|_| ParseError::BadChunk)?;
+        // 长度后面可以跟 `;name=value` 形式的扩展，直接忽略
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::BadChunk)?;
+        pos += line_len + 2;
+
+        if size == 0 {
+            loop {
+                let Some(trailer_len) = find(&buf[pos..], b"\r\n") else {
+                    return Ok(None);
+                };
+                pos += trailer_len + 2;
+                if trailer_len == 0 {
+                    return Ok(Some((body, pos)));
+                }
+            }
+        }
+
+        if body.len().saturating_add(size) > max_size {
+            return Err(ParseError::BodyTooLarge);
+        }
+        if buf.len() < pos + size + 2 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buf[pos..pos + size]);
+        if &buf[pos + size..pos + size + 2] != b"\r\n" {
+            return Err(ParseError::BadChunk);
+        }
+        pos += size + 2;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// 带缓冲区的请求读取器，可以从同一个连接上连续读取多个请求。
+pub struct RequestReader<S> {
+    stream: S,
+    buf: Vec<u8>,
+    limits: Limits,
+    /// 当前这个请求的第一个字节是什么时候到达的
+    started: Option<Instant>,
+}
+
+impl<S> RequestReader<S> {
+    pub fn new(stream: S, limits: Limits) -> RequestReader<S> {
+        RequestReader {
+            stream,
+            buf: Vec::new(),
+            limits,
+            started: None,
+        }
+    }
+
+    /// 返回内部的连接，用于写入响应。
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// 取回内部的连接，以及缓冲区中已经读到但还没有被解析的数据。
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    fn try_parse(&mut self) -> Result<Option<Request>, ParseError> {
+        match parse_request(&self.buf, &self.limits)? {
+            Some((request, consumed)) => {
+                self.buf.drain(..consumed);
+                self.started = None;
+                Ok(Some(request))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 缓冲区中还没有任何属于下一个请求的数据，说明连接正处于两个请求之间的空闲状态。
+    pub(crate) fn is_idle(&self) -> bool {
+        self.buf.iter().all(|b| matches!(b, b'\r' | b'\n'))
+    }
+
+    /// 这一次读取最多等待多久：空闲时等待 `idle_timeout`，
+    /// 否则等待 `read_timeout`，但不能超过整个请求的截止时间。
+    fn next_timeout(
+        &mut self,
+        idle_timeout: Duration,
+        read_timeout: Duration,
+        request_timeout: Duration,
+    ) -> Result<Duration, ParseError> {
+        if self.is_idle() {
+            self.started = None;
+            return Ok(idle_timeout);
+        }
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let remaining = request_timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Err(ParseError::Timeout);
+        }
+        Ok(read_timeout.min(remaining))
+    }
+
+    fn end_of_stream(&self) -> Result<Option<Request>, ParseError> {
+        if self.is_idle() {
+            Ok(None)
+        } else {
+            Err(ParseError::UnexpectedEof)
+        }
+    }
+}
+
+impl RequestReader<TcpStream> {
+    /// 读取下一个请求。
+    ///
+    /// 在两个请求之间等待超过 `idle_timeout`、或者客户端关闭了连接时返回 `Ok(None)`；
+    /// 请求读到一半时超过 `read_timeout` 没有收到任何数据，
+    /// 或者从收到请求的第一个字节开始超过 `request_timeout` 还没有收到完整的请求，则返回 `ParseError::Timeout`。
+    ///
+    /// 只有 `read_timeout` 是不够的：慢速攻击(slowloris)的客户端每隔几秒发送一个字节，
+    /// 每次读取都不会超时，一个请求却永远也发不完，连接就被一直占用着。
+    pub fn read_request(
+        &mut self,
+        idle_timeout: Duration,
+        read_timeout: Duration,
+        request_timeout: Duration,
+    ) -> Result<Option<Request>, ParseError> {
+        let mut chunk = [0; 8192];
+        loop {
+            if let Some(request) = self.try_parse()? {
+                return Ok(Some(request));
+            }
+            let idle = self.is_idle();
+            let timeout = self.next_timeout(idle_timeout, read_timeout, request_timeout)?;
+            self.stream.set_read_timeout(Some(timeout))?;
+
+            let n = match self.stream.read(&mut chunk) {
+                Ok(n) => n,
+                Err(e) if is_timeout(&e) && idle => return Ok(None),
+                Err(e) if is_timeout(&e) => return Err(ParseError::Timeout),
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                return self.end_of_stream();
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> RequestReader<S> {
+    /// `read_request` 的异步版本，超时的含义相同。
+    pub async fn read_request_async(
+        &mut self,
+        idle_timeout: Duration,
+        read_timeout: Duration,
+        request_timeout: Duration,
+    ) -> Result<Option<Request>, ParseError> {
+        let mut chunk = [0; 8192];
+        loop {
+            if let Some(request) = self.try_parse()? {
+                return Ok(Some(request));
+            }
+            let idle = self.is_idle();
+            let timeout = self.next_timeout(idle_timeout, read_timeout, request_timeout)?;
+
+            let n = match async_std::future::timeout(timeout, self.stream.read(&mut chunk)).await {
+                Ok(n) => n?,
+                Err(_elapsed) if idle => return Ok(None),
+                Err(_elapsed) => return Err(ParseError::Timeout),
+            };
+            if n == 0 {
+                return self.end_of_stream();
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    // 不同平台上读超时返回的错误类型不同
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use futures::executor::block_on;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// 每次 read 只返回一小段数据，模拟被拆成很多个 TCP 包的请求。
+    struct Fragmented {
+        data: Vec<u8>,
+        step: usize,
+    }
+
+    impl AsyncRead for Fragmented {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    fn reader(data: &[u8], step: usize) -> RequestReader<Fragmented> {
+        let stream = Fragmented {
+            data: data.to_vec(),
+            step,
+        };
+        RequestReader::new(stream, Limits::default())
+    }
+
+    fn read(reader: &mut RequestReader<Fragmented>) -> Result<Option<Request>, ParseError> {
+        let timeout = Duration::from_secs(1);
+        block_on(reader.read_request_async(timeout, timeout, timeout))
+    }
+
+    #[test]
+    fn reassembles_fragmented_requests() {
+        let body = "x".repeat(5000);
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}GET /next HTTP/1.1\r\n\r\n",
+            body.len()
+        );
+        let mut reader = reader(raw.as_bytes(), 7);
+
+        let first = read(&mut reader).unwrap().unwrap();
+        assert_eq!(first.method(), Method::Post);
+        assert_eq!(first.body(), body.as_bytes());
+        let second = read(&mut reader).unwrap().unwrap();
+        assert_eq!(second.path(), "/next");
+        assert!(read(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let mut reader = reader(raw, 3);
+        let req = read(&mut reader).unwrap().unwrap();
+        assert_eq!(req.body(), b"hello world");
+        assert!(read(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let raw = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(20_000));
+        let err = read(&mut reader(raw.as_bytes(), 4096)).unwrap_err();
+        assert!(matches!(err, ParseError::HeadTooLarge));
+        assert_eq!(err.status().as_u16(), 431);
+
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n";
+        let err = read(&mut reader(raw, 4096)).unwrap_err();
+        assert_eq!(err.status().as_u16(), 413);
+    }
+
+    #[test]
+    fn parses_responses() {
+        let limits = Limits::default();
+        let parse = |raw: &[u8], eof| parse_response(raw, &limits, Method::Get, eof);
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhiHTTP/1.1 204 No Content\r\n\r\n";
+        let (first, used) = parse(raw, false).unwrap().unwrap();
+        assert_eq!(first.status(), crate::http::StatusCode::OK);
+        assert_eq!(first.body(), b"hi");
+        let (second, rest) = parse(&raw[used..], false).unwrap().unwrap();
+        assert_eq!(second.status().as_u16(), 204);
+        assert_eq!(used + rest, raw.len());
+
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\n";
+        let (chunked, _) = parse(raw, false).unwrap().unwrap();
+        assert_eq!(chunked.body(), b"hi");
+
+        // 没有 Content-Length 的响应体一直持续到连接关闭
+        let raw = b"HTTP/1.0 200 OK\r\n\r\nuntil the end";
+        assert!(parse(raw, false).unwrap().is_none());
+        let (until_eof, _) = parse(raw, true).unwrap().unwrap();
+        assert_eq!(until_eof.body(), b"until the end");
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhi";
+        assert!(matches!(parse(raw, true), Err(ParseError::UnexpectedEof)));
+        assert!(parse(b"HTTP/1.1 OK\r\n\r\n", true).is_err());
+
+        // HEAD 请求的响应带有 Content-Length，但是没有响应体
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        let (head, used) = parse_response(raw, &limits, Method::Head, false).unwrap().unwrap();
+        assert_eq!(head.body(), b"");
+        assert_eq!(used, raw.len());
+    }
+
+    #[test]
+    fn truncated_request_is_an_error() {
+        let err = read(&mut reader(b"GET / HTTP/1.1\r\nHost: x", 100)).unwrap_err();
+        assert!(matches!(err, ParseError::UnexpectedEof));
+    }
+
+    #[test]
+    fn ambiguous_body_length_is_rejected() {
+        let parse = |headers: &str| {
+            let raw = format!("POST / HTTP/1.1\r\n{headers}\r\n\r\nabcd");
+            parse_request(raw.as_bytes(), &Limits::default()).map(|r| r.unwrap().0)
+        };
+        for headers in [
+            "Content-Length: 1\r\nTransfer-Encoding: chunked",
+            "Transfer-Encoding: chunked\r\nContent-Length: 1",
+            "Content-Length: 3\r\nContent-Length: 4",
+            "Content-Length: 3, 4",
+            "Content-Length: 3\r\nContent-Length: 3, 4",
+            "Content-Length: +3",
+            "Content-Length: 3,",
+        ] {
+            let err = parse(headers).unwrap_err();
+            assert_eq!(err.status(), crate::http::StatusCode::BAD_REQUEST, "{headers}");
+        }
+
+        // 重复但相同的值是允许的
+        for headers in ["Content-Length: 3\r\nContent-Length: 3", "Content-Length: 3, 3"] {
+            assert_eq!(parse(headers).unwrap().body(), b"abc", "{headers}");
+        }
+    }
+
+    #[test]
+    fn partial_request_times_out() {
+        // 一个永远不会结束的读取：模拟发了一半就不再发送数据的客户端
+        struct Stalled(bool);
+        impl AsyncRead for Stalled {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                if self.0 {
+                    return Poll::Pending;
+                }
+                self.0 = true;
+                buf[..4].copy_from_slice(b"GET ");
+                Poll::Ready(Ok(4))
+            }
+        }
+
+        let mut reader = RequestReader::new(Stalled(false), Limits::default());
+        let timeout = Duration::from_millis(50);
+        let idle = Duration::from_secs(5);
+        let err = block_on(reader.read_request_async(idle, timeout, idle)).unwrap_err();
+        assert!(matches!(err, ParseError::Timeout));
+
+        // 还没有收到任何数据时超时，只是一个空闲的连接
+        let mut reader = RequestReader::new(Stalled(true), Limits::default());
+        assert!(block_on(reader.read_request_async(timeout, timeout, timeout)).unwrap().is_none());
+    }
+
+    #[test]
+    fn slow_client_hits_request_timeout() {
+        // 每 10ms 发送一个字节，每次读取都不会超时，但请求永远也发不完
+        struct Trickle {
+            sent: usize,
+            delay: Option<futures::future::BoxFuture<'static, ()>>,
+        }
+        impl AsyncRead for Trickle {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                let delay = self
+                    .delay
+                    .get_or_insert_with(|| Box::pin(async_std::task::sleep(Duration::from_millis(10))));
+                futures::ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+                let head = b"GET / HTTP/1.1\r\nX-Slow: ";
+                buf[0] = head.get(self.sent).copied().unwrap_or(b'a');
+                self.sent += 1;
+                Poll::Ready(Ok(1))
+            }
+        }
+
+        let stream = Trickle {
+            sent: 0,
+            delay: None,
+        };
+        let mut reader = RequestReader::new(stream, Limits::default());
+        let start = Instant::now();
+        let read = reader.read_request_async(
+            Duration::from_secs(5),
+            Duration::from_millis(100),
+            Duration::from_millis(300),
+        );
+        assert!(matches!(block_on(read), Err(ParseError::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,344 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::http::{IntoResponse, Method, Request, Response, StatusCode, percent_decode};
+
+/// 请求处理函数。
+///
+/// 任何 `async fn(Request) -> Response` 都自动实现了该特征，
+/// 返回值也可以是其它实现了 `IntoResponse` 的类型，例如 `Json<T>` 或者 `Result<_, Rejection>`。
+/// 同步的线程池服务器同样使用异步的处理函数，只不过在工作线程中用 `block_on` 执行它们，
+/// 这样两类服务器就可以共用同一个路由表。
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, req: Request) -> BoxFuture<'static, Response>;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoResponse,
+{
+    fn call(&self, req: Request) -> BoxFuture<'static, Response> {
+        let response = self(req);
+        Box::pin(async move { response.await.into_response() })
+    }
+}
+
+/// 路由匹配到的路径参数。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    /// `:name`，匹配单个路径段
+    Param(String),
+    /// `*name` 或 `*`，匹配剩余的所有路径段，只能出现在最后
+    Wildcard(String),
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(path: &str) -> Pattern {
+        assert!(path.starts_with('/'), "route must start with '/': {path}");
+        let segments: Vec<Segment> = split_path(path)
+            .map(|s| {
+                if let Some(name) = s.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = s.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Static(s.to_string())
+                }
+            })
+            .collect();
+        if let Some(pos) = segments
+            .iter()
+            .position(|s| matches!(s, Segment::Wildcard(_)))
+        {
+            assert!(
+                pos == segments.len() - 1,
+                "wildcard must be the last segment: {path}"
+            );
+        }
+        Pattern { segments }
+    }
+
+    /// 匹配成功时返回路径参数和匹配的“精确程度”，越精确的路由优先级越高：
+    /// 静态段 > 参数段 > 通配符。
+    ///
+    /// 参数和通配符捕获的路径段会被解码，含有非法转义的路径不匹配这个路由。
+    fn matches(&self, path: &str) -> Option<(Params, Vec<u8>)> {
+        let parts: Vec<&str> = split_path(path).collect();
+        let mut params = Vec::new();
+        let mut rank = Vec::with_capacity(self.segments.len());
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    if !name.is_empty() {
+                        let rest: Vec<String> = parts[i.min(parts.len())..]
+                            .iter()
+                            .map(|p| percent_decode(p))
+                            .collect::<Option<_>>()?;
+                        params.push((name.clone(), rest.join("/")));
+                    }
+                    rank.push(0);
+                    return Some((Params(params), rank));
+                }
+                Segment::Static(s) => {
+                    if parts.get(i) != Some(&s.as_str()) {
+                        return None;
+                    }
+                    rank.push(2);
+                }
+                Segment::Param(name) => {
+                    let part = parts.get(i)?;
+                    params.push((name.clone(), percent_decode(part)?));
+                    rank.push(1);
+                }
+            }
+        }
+
+        (parts.len() == self.segments.len()).then_some((Params(params), rank))
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    // 忽略空的路径段，因此 `/users/` 和 `/users` 是同一个路径
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+/// 匹配的精确程度：路径的匹配程度(见 `Pattern::matches`)，以及请求方法是否完全相同。
+type Rank = (Vec<u8>, bool);
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Arc<dyn Handler>,
+}
+
+/// 路由表：根据请求方法和路径把请求分发给对应的处理函数。
+///
+/// ```ignore
+/// let router = Router::new()
+///     .get("/", index)
+///     .get("/users/:id", show_user)
+///     .get("/static/*path", static_file);
+/// ```
+///
+/// 路径匹配但方法不匹配时返回 405 并带上 `Allow` 头，路径都不匹配时返回 404。
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Arc<dyn Handler>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route(mut self, method: Method, path: &str, handler: impl Handler) -> Router {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::parse(path),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    pub fn get(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Get, path, handler)
+    }
+
+    pub fn post(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Post, path, handler)
+    }
+
+    pub fn put(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Put, path, handler)
+    }
+
+    pub fn patch(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Patch, path, handler)
+    }
+
+    pub fn delete(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Delete, path, handler)
+    }
+
+    /// 设置没有任何路由匹配时使用的处理函数，默认返回一个简单的 404 响应。
+    pub fn fallback(mut self, handler: impl Handler) -> Router {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    /// 查找匹配的路由并调用处理函数。
+    ///
+    /// 路由查找是同步完成的，返回的 future 不再借用路由表。
+    ///
+    /// 和 HTTP/1.1 要求的一样，支持 GET 的路径也支持 HEAD：没有单独注册 HEAD 路由时，
+    /// HEAD 请求交给 GET 的处理函数，由服务器在发送时去掉响应体。
+    pub fn handle(&self, mut req: Request) -> BoxFuture<'static, Response> {
+        let mut best: Option<(&Route, Params, Rank)> = None;
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            let Some((params, rank)) = route.pattern.matches(req.path()) else {
+                continue;
+            };
+            let exact = route.method == req.method();
+            let head_to_get = req.method() == Method::Head && route.method == Method::Get;
+            if !exact && !head_to_get {
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method);
+                }
+                if route.method == Method::Get && !allowed.contains(&Method::Head) {
+                    allowed.push(Method::Head);
+                }
+                continue;
+            }
+            // 同样精确的路由中，专门注册的 HEAD 路由优先于 GET 路由
+            let rank = (rank, exact);
+            if best.as_ref().is_none_or(|(_, _, best_rank)| rank > *best_rank) {
+                best = Some((route, params, rank));
+            }
+        }
+
+        if let Some((route, params, _)) = best {
+            req.set_params(params);
+            return route.handler.call(req);
+        }
+        if !allowed.is_empty() {
+            let allow: Vec<&str> = allowed.iter().map(|m| m.as_str()).collect();
+            let response = Response::new(StatusCode::METHOD_NOT_ALLOWED)
+                .with_header("Allow", allow.join(", "))
+                .with_text("Method Not Allowed");
+            return Box::pin(async move { response });
+        }
+        match &self.fallback {
+            Some(handler) => handler.call(req),
+            None => Box::pin(async { Response::not_found() }),
+        }
+    }
+}
+
+impl Handler for Router {
+    fn call(&self, req: Request) -> BoxFuture<'static, Response> {
+        self.handle(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    async fn show_user(req: Request) -> Response {
+        Response::ok().with_text(format!("user {}", req.param("id").unwrap()))
+    }
+
+    async fn me(_req: Request) -> Response {
+        Response::ok().with_text("me")
+    }
+
+    async fn files(req: Request) -> Response {
+        Response::ok().with_text(req.param("path").unwrap().to_string())
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/users/:id", show_user)
+            .get("/users/me", me)
+            .delete("/users/:id", show_user)
+            .get("/static/*path", files)
+    }
+
+    fn call(router: &Router, method: Method, target: &str) -> Response {
+        block_on(router.handle(Request::new(method, target)))
+    }
+
+    #[test]
+    fn path_params_are_extracted() {
+        let res = call(&router(), Method::Get, "/users/42");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), b"user 42");
+
+        let res = call(&router(), Method::Get, "/users/a%20b?x=1");
+        assert_eq!(res.body(), b"user a b");
+
+        // 非法的转义不会被原样当作参数的值
+        let res = call(&router(), Method::Get, "/users/a%zz");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn static_segments_win_over_params() {
+        let res = call(&router(), Method::Get, "/users/me");
+        assert_eq!(res.body(), b"me");
+    }
+
+    #[test]
+    fn wildcard_captures_rest() {
+        let res = call(&router(), Method::Get, "/static/css/site.css");
+        assert_eq!(res.body(), b"css/site.css");
+
+        let res = call(&router(), Method::Get, "/static/css/100%25.css");
+        assert_eq!(res.body(), b"css/100%.css");
+        let res = call(&router(), Method::Get, "/static/css/%ff.css");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn not_found_and_method_not_allowed() {
+        let res = call(&router(), Method::Get, "/nope");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = call(&router(), Method::Post, "/users/1");
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.header("Allow"), Some("GET, HEAD, DELETE"));
+    }
+
+    #[test]
+    fn head_uses_the_get_route() {
+        let res = call(&router(), Method::Head, "/users/42");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), b"user 42");
+
+        let router = router().route(Method::Head, "/users/:id", me);
+        assert_eq!(call(&router, Method::Head, "/users/42").body(), b"me");
+        assert_eq!(call(&router, Method::Get, "/users/42").body(), b"user 42");
+    }
+
+    #[test]
+    fn fallback_replaces_default_404() {
+        let router = router().fallback(|_req| async { Response::ok().with_text("fallback") });
+        let res = call(&router, Method::Get, "/nope");
+        assert_eq!(res.body(), b"fallback");
+    }
+}
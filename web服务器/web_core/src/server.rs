@@ -0,0 +1,679 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use futures::stream::StreamExt;
+
+use crate::http::{BodyStream, Method, ParseError, Request, Response, StatusCode};
+use crate::reader::{Limits, RequestReader};
+use crate::router::Handler;
+use crate::shutdown::Shutdown;
+
+/// 连接相关的配置。
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// 持久连接在两个请求之间最多可以空闲多久，超时后服务器主动关闭连接
+    pub idle_timeout: Duration,
+    /// 读取一个请求的过程中，两次收到数据之间最多可以间隔多久，超时后返回 408
+    pub read_timeout: Duration,
+    /// 从收到请求的第一个字节开始，最多等待多久收到完整的请求，超时后返回 408
+    pub request_timeout: Duration,
+    /// 处理函数最多可以执行多久，超时后返回 504，`None` 表示不限制
+    pub handler_timeout: Option<Duration>,
+    /// 最多同时处理多少个连接，超出的连接会收到 503，见 `ConnectionLimit`
+    pub max_connections: usize,
+    /// 请求头和请求体的大小限制
+    pub limits: Limits,
+    /// 一个连接最多处理多少个请求，达到上限后服务器会在响应中带上 `Connection: close`
+    pub max_requests_per_connection: usize,
+    /// 触发关闭之后，正在处理的请求处理完就关闭连接，空闲的连接不再等待下一个请求
+    pub shutdown: Option<Shutdown>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            idle_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            handler_timeout: Some(Duration::from_secs(30)),
+            max_connections: 1024,
+            limits: Limits::default(),
+            max_requests_per_connection: 100,
+            shutdown: None,
+        }
+    }
+}
+
+// 之前每个版本的服务器都是一个连接只处理一个请求：读一次、写一次、然后关闭，
+// 这样每个请求都要重新进行一次 TCP 握手。HTTP/1.1 默认使用持久连接(keep-alive)，
+// 同一个连接上可以顺序发送多个请求，客户端甚至可以不等响应回来就连续发送多个请求(pipelining)，
+// 服务器只需要按请求的顺序依次返回响应即可。
+
+/// 在一个同步的 TCP 连接上循环处理请求，直到客户端关闭连接、空闲超时或者请求了 `Connection: close`。
+///
+/// 处理函数是异步的，这里用 `block_on` 在当前线程上执行它们。
+pub fn serve_connection(
+    stream: TcpStream,
+    handler: &dyn Handler,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    let peer_addr = stream.peer_addr().ok();
+    // 读写分别使用一个句柄，读取器缓冲区中没消费完的数据就是流水线中的下一个请求
+    let mut reader = RequestReader::new(stream.try_clone()?, config.limits.clone());
+    let mut writer = stream;
+
+    let mut served = 0;
+    loop {
+        // 同步的读取无法被关闭信号打断，空闲的连接最晚在 idle_timeout 之后关闭
+        if shutting_down(config) && reader.is_idle() {
+            return Ok(());
+        }
+        let read = reader.read_request(
+            config.idle_timeout,
+            config.read_timeout,
+            config.request_timeout,
+        );
+        let request = match read {
+            Ok(Some(request)) => with_peer(request, peer_addr),
+            Ok(None) => return Ok(()),
+            Err(ParseError::Io(e)) => return Err(e),
+            Err(e) => {
+                writer.write_all(&error_response(e).to_bytes())?;
+                return Ok(());
+            }
+        };
+        served += 1;
+
+        let keep_alive = request.wants_keep_alive() && served < config.max_requests_per_connection;
+        let chunked = supports_chunked(&request);
+        let head = request.method() == Method::Head;
+        let mut response = futures::executor::block_on(call_handler(handler, request, config));
+        if let Some(upgrade) = response.take_upgrade() {
+            writer.write_all(&response.to_bytes())?;
+            // 升级之后的连接可能长时间没有数据，不再受读超时的限制
+            writer.set_read_timeout(None)?;
+            let (_, leftover) = reader.into_parts();
+            let mut stream = futures::io::AllowStdIo::new(writer);
+            futures::executor::block_on(upgrade.run(&mut stream, leftover));
+            return Ok(());
+        }
+        // 处理请求的过程中触发了关闭，这个响应就是连接上的最后一个响应
+        let keep_alive = keep_alive && !shutting_down(config);
+        let (mut response, keep_alive) = finish_response(response, keep_alive, chunked);
+        writer.write_all(&response.serialize(chunked, head))?;
+        let complete = match response.take_stream() {
+            // HEAD 请求的响应没有响应体，流式的响应体直接丢掉
+            Some(_) if head => true,
+            Some(body) => {
+                let mut stream = futures::io::AllowStdIo::new(&mut writer);
+                futures::executor::block_on(write_stream(&mut stream, body, chunked, config))?
+            }
+            None => true,
+        };
+        if !keep_alive || !complete {
+            return Ok(());
+        }
+    }
+}
+
+/// `serve_connection` 的异步版本，可以用于任何实现了 `AsyncRead + AsyncWrite` 的连接。
+///
+/// 连接不一定是 TCP 连接，因此客户端地址需要由调用者传入。
+/// 处理函数返回了 WebSocket 握手响应时，连接会在响应发送之后交给 WebSocket 处理，直到它结束。
+pub async fn serve_connection_async<S>(
+    stream: S,
+    peer_addr: Option<SocketAddr>,
+    handler: &dyn Handler,
+    config: &ServerConfig,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut reader = RequestReader::new(stream, config.limits.clone());
+
+    let mut served = 0;
+    loop {
+        if shutting_down(config) && reader.is_idle() {
+            return Ok(());
+        }
+        let read = reader.read_request_async(
+            config.idle_timeout,
+            config.read_timeout,
+            config.request_timeout,
+        );
+        let read = match config.shutdown.as_ref().filter(|s| !s.is_triggered()) {
+            // 等待请求的同时等待关闭信号，收到信号时读取被取消(读到一半的数据保存在缓冲区中)，
+            // 回到循环开头：空闲的连接直接关闭，读到一半的请求继续读完
+            Some(shutdown) => {
+                let wait = shutdown.wait();
+                futures::pin_mut!(read, wait);
+                match future::select(read, wait).await {
+                    Either::Left((read, _)) => read,
+                    Either::Right(_) => continue,
+                }
+            }
+            None => read.await,
+        };
+        let request = match read {
+            Ok(Some(request)) => with_peer(request, peer_addr),
+            Ok(None) => return Ok(()),
+            Err(ParseError::Io(e)) => return Err(e),
+            Err(e) => {
+                let stream = reader.get_mut();
+                stream.write_all(&error_response(e).to_bytes()).await?;
+                stream.flush().await?;
+                return Ok(());
+            }
+        };
+        served += 1;
+
+        let keep_alive = request.wants_keep_alive() && served < config.max_requests_per_connection;
+        let chunked = supports_chunked(&request);
+        let head = request.method() == Method::Head;
+        let mut response = call_handler(handler, request, config).await;
+        if let Some(upgrade) = response.take_upgrade() {
+            let stream = reader.get_mut();
+            stream.write_all(&response.to_bytes()).await?;
+            stream.flush().await?;
+            // 握手请求之后的数据可能已经被读进了缓冲区，它们属于升级之后的协议
+            let (mut stream, leftover) = reader.into_parts();
+            upgrade.run(&mut stream, leftover).await;
+            return Ok(());
+        }
+        // 处理请求的过程中触发了关闭，这个响应就是连接上的最后一个响应
+        let keep_alive = keep_alive && !shutting_down(config);
+        let (mut response, keep_alive) = finish_response(response, keep_alive, chunked);
+        let stream = reader.get_mut();
+        stream.write_all(&response.serialize(chunked, head)).await?;
+        stream.flush().await?;
+        let complete = match response.take_stream() {
+            Some(_) if head => true,
+            Some(body) => write_stream(stream, body, chunked, config).await?,
+            None => true,
+        };
+        if !keep_alive || !complete {
+            return Ok(());
+        }
+    }
+}
+
+/// 执行处理函数，超过 `handler_timeout` 时放弃它并返回 504。
+///
+/// 超时只能在处理函数 `.await` 的时候生效：像线程池版本中那样用 `std::thread::sleep`
+/// 阻塞住线程的处理函数是无法被打断的。
+async fn call_handler(handler: &dyn Handler, request: Request, config: &ServerConfig) -> Response {
+    let Some(timeout) = config.handler_timeout else {
+        return handler.call(request).await;
+    };
+    match async_std::future::timeout(timeout, handler.call(request)).await {
+        Ok(response) => response,
+        Err(_elapsed) => Response::new(StatusCode::GATEWAY_TIMEOUT).with_text("Gateway Timeout"),
+    }
+}
+
+// 之前的响应都是先在内存中拼好整个响应体，再连同 Content-Length 一起发送出去。
+// 分块编码(chunked)不需要事先知道响应体的长度：每块数据前面写上它的长度(十六进制)，
+// 最后用一个长度为 0 的块表示结束，这样处理函数就可以边产生数据边发送，例如 SSE。
+
+/// 逐块发送流式响应体，每块数据都立刻 flush，不在缓冲区中等待。
+///
+/// 返回 false 表示响应没有完整地发送(Stream 返回了错误、数据和声明的长度对不上，
+/// 或者长度未知又不使用分块编码)，
+/// 连接必须关闭：客户端只能通过连接关闭发现响应结束了。
+/// 触发关闭时不再等待 Stream 的下一块数据，正常结束响应，像 SSE 这样永远不会结束的响应也能及时关闭。
+async fn write_stream<W>(
+    stream: &mut W,
+    body: BodyStream,
+    chunked: bool,
+    config: &ServerConfig,
+) -> io::Result<bool>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let len = body.len;
+    // 长度已知时用 Content-Length 表示响应在哪里结束，直接发送数据
+    let chunked = chunked && len.is_none();
+    let mut written = 0;
+    let mut body = body.chunks;
+    loop {
+        let next = match config.shutdown.as_ref() {
+            Some(shutdown) => {
+                let wait = shutdown.wait();
+                futures::pin_mut!(wait);
+                match future::select(body.next(), wait).await {
+                    Either::Left((next, _)) => next,
+                    Either::Right(_) => None,
+                }
+            }
+            None => body.next().await,
+        };
+        let chunk = match next {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(_)) => return Ok(false),
+            None => break,
+        };
+        // 长度为 0 的块表示响应结束，空的数据块直接跳过
+        if chunk.is_empty() {
+            continue;
+        }
+        // 多出来的数据会被客户端当成下一个响应的开头
+        written += chunk.len() as u64;
+        if len.is_some_and(|len| written > len) {
+            return Ok(false);
+        }
+        if chunked {
+            stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+            stream.write_all(&chunk).await?;
+            stream.write_all(b"\r\n").await?;
+        } else {
+            stream.write_all(&chunk).await?;
+        }
+        stream.flush().await?;
+    }
+    if let Some(len) = len {
+        // 数据比声明的少(例如文件在发送的过程中被截断了)，客户端只能通过连接关闭发现
+        return Ok(written == len);
+    }
+    if !chunked {
+        return Ok(false);
+    }
+    stream.write_all(b"0\r\n\r\n").await?;
+    stream.flush().await?;
+    Ok(true)
+}
+
+/// 分块编码是 HTTP/1.1 才有的。
+fn supports_chunked(request: &Request) -> bool {
+    request.version() != "HTTP/1.0"
+}
+
+fn shutting_down(config: &ServerConfig) -> bool {
+    config.shutdown.as_ref().is_some_and(Shutdown::is_triggered)
+}
+
+fn with_peer(request: Request, peer_addr: Option<SocketAddr>) -> Request {
+    match peer_addr {
+        Some(addr) => request.with_remote_addr(addr),
+        None => request,
+    }
+}
+
+/// 处理函数也可以通过返回 `Connection: close` 要求关闭连接。
+///
+/// 不能使用分块编码、长度又未知的流式响应以关闭连接表示结束，也不能保持连接。
+fn finish_response(mut response: Response, keep_alive: bool, chunked: bool) -> (Response, bool) {
+    let keep_alive = keep_alive
+        && (chunked || !response.is_streaming() || response.stream_len().is_some())
+        && !response
+            .header("Connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    response.headers_mut().insert("Connection", connection);
+    (response, keep_alive)
+}
+
+/// 读取请求失败时返回给客户端的响应，之后连接会被关闭：
+/// 出错之后已经无法确定下一个请求从哪里开始了。
+fn error_response(e: ParseError) -> Response {
+    Response::new(e.status())
+        .with_header("Connection", "close")
+        .with_text(e.to_string())
+}
+
+// 之前 `for_each_concurrent(None, ...)` 对同时处理的连接数没有任何限制，
+// 每个连接都要占用内存和文件描述符，大量的连接(不管是不是恶意的)最终会拖垮整个服务器。
+// 连接数达到上限之后，与其让新的连接一直排队等待，不如立刻返回 503，让客户端稍后重试。
+
+/// 限制同时处理的连接数。
+///
+/// 每个被接受的连接持有一个 `ConnectionPermit`，连接处理完毕、permit 被 drop 时名额自动归还。
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// 获取一个名额，连接数已经达到上限时返回 `None`。
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ConnectionPermit(Arc::clone(&self.active)))
+    }
+
+    /// 当前正在处理的连接数。
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+/// 一个连接名额，drop 时归还。
+#[derive(Debug)]
+pub struct ConnectionPermit(Arc<AtomicUsize>);
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// 连接数达到上限时返回给新连接的响应。
+pub fn busy_response() -> Response {
+    Response::new(StatusCode::SERVICE_UNAVAILABLE)
+        .with_header("Retry-After", "1")
+        .with_header("Connection", "close")
+        .with_text("Service Unavailable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::testing::TestClient;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    async fn echo(req: Request) -> Response {
+        Response::ok().with_text(req.path().to_string())
+    }
+
+    async fn chunks(_req: Request) -> Response {
+        let chunks = ["a", "", "bc"].map(Ok::<_, io::Error>);
+        Response::ok().with_stream(futures::stream::iter(chunks))
+    }
+
+    async fn sized(req: Request) -> Response {
+        let chunks = ["a", "bc"].map(Ok::<_, io::Error>);
+        // `/sized/short` 声明的长度比实际的数据多
+        let len = if req.path().ends_with("short") { 5 } else { 3 };
+        Response::ok().with_sized_stream(len, futures::stream::iter(chunks))
+    }
+
+    async fn broken(_req: Request) -> Response {
+        let chunks = [Ok("a"), Err(io::Error::other("disk error")), Ok("b")];
+        Response::ok().with_stream(futures::stream::iter(chunks))
+    }
+
+    fn spawn_server(config: ServerConfig) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let router = Router::new()
+                .get("/chunks", chunks)
+                .get("/broken", broken)
+                .get("/sized/*", sized)
+                .get("/*", echo);
+            for stream in listener.incoming() {
+                let _ = serve_connection(stream.unwrap(), &router, &config);
+            }
+        });
+        addr
+    }
+
+    fn read_all(stream: &mut TcpStream) -> String {
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn pipelined_requests_get_responses_in_order() {
+        let addr = spawn_server(ServerConfig::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        // 最后一个请求带了 Connection: close，因此服务器会主动关闭连接，read_to_string 才能返回
+        let out = read_all(&mut stream);
+        let bodies: Vec<&str> = out
+            .split("HTTP/1.1 200 OK")
+            .skip(1)
+            .map(|r| r.rsplit("\r\n\r\n").next().unwrap())
+            .collect();
+        assert_eq!(bodies, ["/a", "/b", "/c"]);
+        assert_eq!(out.matches("Connection: keep-alive").count(), 2);
+        assert!(out.contains("Connection: close"));
+    }
+
+    #[test]
+    fn idle_connection_is_closed() {
+        let addr = spawn_server(ServerConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        let out = read_all(&mut stream);
+        assert!(out.ends_with("/a"));
+    }
+
+    #[test]
+    fn max_requests_per_connection() {
+        let addr = spawn_server(ServerConfig {
+            max_requests_per_connection: 1,
+            ..ServerConfig::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let out = read_all(&mut stream);
+        assert!(out.contains("Connection: close"));
+        assert!(!out.contains("/b"));
+    }
+
+    #[test]
+    fn bad_requests_get_error_responses() {
+        let addr = spawn_server(ServerConfig {
+            read_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"NOT A REQUEST\r\n\r\n").unwrap();
+        assert!(read_all(&mut stream).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        // 只发送了一半的请求，服务器等待 read_timeout 之后返回 408
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\nHost: loc").unwrap();
+        assert!(read_all(&mut stream).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn slow_client_is_disconnected() {
+        let addr = spawn_server(ServerConfig {
+            read_timeout: Duration::from_millis(200),
+            request_timeout: Duration::from_millis(300),
+            ..ServerConfig::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\n").unwrap();
+        // 每次只发送一行请求头，间隔比 read_timeout 短，但整个请求超过了 request_timeout
+        let start = std::time::Instant::now();
+        while stream.write_all(b"X-Slow: a\r\n").is_ok() && start.elapsed() < Duration::from_secs(2) {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut buf = [0; 64];
+            stream.set_nonblocking(true).unwrap();
+            let n = stream.read(&mut buf);
+            stream.set_nonblocking(false).unwrap();
+            if let Ok(n) = n {
+                assert!(buf[..n].starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+                return;
+            }
+        }
+        panic!("server did not time out the slow request");
+    }
+
+    #[test]
+    fn slow_handler_gets_504() {
+        let router = Router::new()
+            .get("/slow", |_req: Request| async {
+                async_std::task::sleep(Duration::from_secs(10)).await;
+                Response::ok()
+            })
+            .get("/fast", echo);
+        let config = ServerConfig {
+            handler_timeout: Some(Duration::from_millis(50)),
+            ..ServerConfig::default()
+        };
+        let client = TestClient::new(router).with_config(config);
+        let raw = b"GET /slow HTTP/1.1\r\n\r\nGET /fast HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let responses = client.send_raw(raw);
+
+        // 超时的请求返回 504，连接上的下一个请求不受影响
+        assert_eq!(responses[0].status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(responses[1].text(), "/fast");
+    }
+
+    #[test]
+    fn connection_limit_releases_permits_on_drop() {
+        let limit = ConnectionLimit::new(2);
+        let a = limit.try_acquire().unwrap();
+        let _b = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.active(), 2);
+        drop(a);
+        assert!(limit.try_acquire().is_some());
+        assert_eq!(limit.active(), 1);
+        assert!(busy_response().to_bytes().starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+
+    #[test]
+    fn shutdown_finishes_current_request_then_closes() {
+        let shutdown = Shutdown::new();
+        let trigger = shutdown.clone();
+        let router = Router::new().get("/stop", move |_req: Request| {
+            trigger.trigger();
+            async { Response::ok().with_text("stopping") }
+        });
+        let config = ServerConfig {
+            shutdown: Some(shutdown),
+            ..ServerConfig::default()
+        };
+
+        // 正在处理的请求正常返回，但带上 Connection: close，流水线中后面的请求不再处理
+        let client = TestClient::new(router).with_config(config);
+        let responses = client.send_raw(b"GET /stop HTTP/1.1\r\n\r\nGET /next HTTP/1.1\r\n\r\n");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].header("Connection"), Some("close"));
+        assert_eq!(responses[0].text(), "stopping");
+    }
+
+    #[test]
+    fn shutdown_closes_idle_connections_immediately() {
+        async_std::task::block_on(async {
+            use futures::io::AsyncReadExt;
+
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let shutdown = Shutdown::new();
+            let config = ServerConfig {
+                shutdown: Some(shutdown.clone()),
+                ..ServerConfig::default()
+            };
+            let server = async_std::task::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let router = Router::new().get("/*", echo);
+                serve_connection_async(stream, None, &router, &config).await
+            });
+
+            let mut client = async_std::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"GET /a HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut buf = [0; 1024];
+            let n = client.read(&mut buf).await.unwrap();
+            assert!(String::from_utf8_lossy(&buf[..n]).contains("Connection: keep-alive"));
+
+            // 连接正在空闲等待下一个请求，不需要等到 idle_timeout(5 秒)就会关闭
+            let start = std::time::Instant::now();
+            shutdown.trigger();
+            server.await.unwrap();
+            assert!(start.elapsed() < Duration::from_secs(1));
+            assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn async_connection_handles_pipelining() {
+        let raw = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.0\r\n\r\nGET /c HTTP/1.1\r\n\r\n";
+        let client = TestClient::new(Router::new().get("/*", echo));
+        let bodies: Vec<_> = client.send_raw(raw).iter().map(|r| r.text().to_string()).collect();
+        // HTTP/1.0 的请求默认不复用连接，因此 /c 不会被处理
+        assert_eq!(bodies, ["/a", "/b"]);
+    }
+
+    #[test]
+    fn streaming_responses_use_chunked_encoding() {
+        let addr = spawn_server(ServerConfig::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /chunks HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        // 空的数据块被跳过，结束块之后连接上还可以继续处理下一个请求
+        let out = read_all(&mut stream);
+        assert!(!out.contains("Content-Length: 0"));
+        assert!(out.contains("Transfer-Encoding: chunked\r\n\r\n1\r\na\r\n2\r\nbc\r\n0\r\n\r\nHTTP/1.1"));
+        assert!(out.ends_with("/a"));
+
+        // HTTP/1.0 不支持分块编码，直接发送数据，然后关闭连接
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /chunks HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let out = read_all(&mut stream);
+        assert!(out.contains("Connection: close\r\n"));
+        assert!(!out.contains("Transfer-Encoding"));
+        assert!(out.ends_with("\r\n\r\nabc"));
+    }
+
+    #[test]
+    fn sized_streams_use_content_length() {
+        let addr = spawn_server(ServerConfig::default());
+        // 长度已知时 HTTP/1.0 的连接也可以保持
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /sized/ok HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /a HTTP/1.0\r\n\r\n")
+            .unwrap();
+        let out = read_all(&mut stream);
+        assert!(out.contains("Connection: keep-alive\r\n"));
+        assert!(!out.contains("Transfer-Encoding"));
+        assert!(out.contains("Content-Length: 3\r\n\r\nabcHTTP/1.1 200 OK"));
+        assert!(out.ends_with("/a"));
+
+        // 数据不够声明的长度，连接被关闭，后面的请求不再处理
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /sized/short HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let out = read_all(&mut stream);
+        assert!(out.contains("Content-Length: 5\r\n"));
+        assert!(out.ends_with("\r\n\r\nabc"));
+    }
+
+    #[test]
+    fn stream_error_closes_connection() {
+        let addr = spawn_server(ServerConfig::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /broken HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\n")
+            .unwrap();
+        // 没有结束块，客户端能发现响应不完整；流水线中后面的请求也不再处理
+        let out = read_all(&mut stream);
+        assert!(out.ends_with("\r\n\r\n1\r\na\r\n"));
+    }
+}
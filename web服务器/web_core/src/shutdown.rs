@@ -0,0 +1,174 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use async_std::channel::{self, Receiver, Sender};
+
+// 优雅关闭分为三步(和 `tokio_优雅的关闭` 中的例子一样)：
+// 1. 检测关闭信号：收到 SIGINT(ctrl + c) 或者 SIGTERM(例如 `kill`、`docker stop`)；
+// 2. 通知程序的每个部分开始关闭：停止接受新连接，正在处理的请求处理完之后关闭连接，空闲的连接直接关闭；
+// 3. 等待所有部分关闭完毕：每个连接持有一个通道的发送端，全部被 drop 时接收端就会返回错误。
+//    等待有一个期限(grace period)，超过期限还没有处理完的连接就不再等了。
+
+/// 关闭信号，克隆出来的所有副本共享同一个状态。
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+    // 通道中从来不会发送任何消息，触发关闭时直接关闭通道，所有等待 recv 的任务都会被唤醒
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, receiver) = channel::bounded(1);
+        Shutdown {
+            triggered: Arc::new(AtomicBool::new(false)),
+            sender,
+            receiver,
+        }
+    }
+
+    /// 触发关闭，可以重复调用。
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::Release);
+        self.sender.close();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::Acquire)
+    }
+
+    /// 等待关闭被触发。
+    pub async fn wait(&self) {
+        // 通道被关闭之后 recv 返回错误
+        let _ = self.receiver.recv().await;
+    }
+
+    /// `wait` 的阻塞版本，用于同步的服务器。
+    pub fn wait_blocking(&self) {
+        futures::executor::block_on(self.wait())
+    }
+
+    /// 在后台线程中监听 SIGINT 和 SIGTERM，收到第一个信号时触发关闭。
+    ///
+    /// 关闭过程中再次收到信号会立即退出进程，免得在等待卡住的连接时无法结束程序。
+    pub fn trigger_on_signals(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+
+        let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.is_triggered() {
+                    println!("Received signal {signal} again, exiting immediately.");
+                    std::process::exit(128 + signal);
+                }
+                println!("Received signal {signal}, shutting down gracefully.");
+                shutdown.trigger();
+            }
+        });
+        Ok(())
+    }
+}
+
+/// 记录还在处理中的连接，关闭时等待它们全部结束。
+pub struct InFlight {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+/// 一个正在处理的连接，drop 时表示连接处理完毕。
+#[derive(Debug)]
+pub struct InFlightGuard {
+    _sender: Sender<()>,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight::new()
+    }
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        let (sender, receiver) = channel::bounded(1);
+        InFlight { sender, receiver }
+    }
+
+    /// 开始处理一个连接，返回的 guard 需要一直持有到连接处理完毕。
+    pub fn guard(&self) -> InFlightGuard {
+        InFlightGuard {
+            _sender: self.sender.clone(),
+        }
+    }
+
+    /// 当前正在处理的连接数。
+    pub fn count(&self) -> usize {
+        self.sender.sender_count() - 1
+    }
+
+    /// 等待所有连接处理完毕，最多等待 `grace`。全部处理完毕时返回 `true`。
+    pub async fn drain(self, grace: Duration) -> bool {
+        let InFlight { sender, receiver } = self;
+        // 必须先 drop 自己的发送端，否则 recv 永远也等不到所有发送端都被 drop
+        drop(sender);
+        async_std::future::timeout(grace, receiver.recv())
+            .await
+            .is_ok()
+    }
+
+    /// `drain` 的阻塞版本，用于同步的服务器。
+    pub fn drain_blocking(self, grace: Duration) -> bool {
+        futures::executor::block_on(self.drain(grace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn trigger_wakes_all_waiters() {
+        let shutdown = Shutdown::new();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let shutdown = shutdown.clone();
+                std::thread::spawn(move || shutdown.wait_blocking())
+            })
+            .collect();
+        assert!(!shutdown.is_triggered());
+        shutdown.trigger();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert!(shutdown.clone().is_triggered());
+        // 已经触发之后再等待会立即返回
+        block_on(shutdown.wait());
+    }
+
+    #[test]
+    fn drain_waits_for_guards_within_grace_period() {
+        let in_flight = InFlight::new();
+        let guard = in_flight.guard();
+        assert_eq!(in_flight.count(), 1);
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+        assert!(in_flight.drain_blocking(Duration::from_secs(5)));
+
+        // 超过期限还没有结束的连接不再等待
+        let in_flight = InFlight::new();
+        let _stuck = in_flight.guard();
+        assert!(!in_flight.drain_blocking(Duration::from_millis(50)));
+    }
+}
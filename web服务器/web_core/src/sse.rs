@@ -0,0 +1,177 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use futures::stream::{Stream, StreamExt};
+use serde::Serialize;
+
+use crate::http::{IntoResponse, Response};
+
+// 服务器推送事件(Server-Sent Events)比 WebSocket 简单得多：它就是一个普通的 HTTP 响应，
+// `Content-Type` 是 `text/event-stream`，响应体一直不结束，服务器每有一个新事件就发送一段文本：
+//
+//     event: progress
+//     id: 3
+//     data: {"done":30,"total":100}
+//
+// 事件之间用空行分隔。浏览器端用 `new EventSource("/jobs/1/progress")` 接收，
+// 断线之后浏览器会自动重连，并在 `Last-Event-ID` 请求头中带上最后收到的事件 id。
+// 只需要服务器单向推送数据(例如任务进度)时，SSE 比 WebSocket 更合适。
+
+/// 一个 SSE 事件。
+///
+/// ```ignore
+/// let event = Event::new().event("progress").id("3").json_data(&progress)?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    pub fn new() -> Event {
+        Event::default()
+    }
+
+    /// 事件的内容，多行的内容会被拆成多个 `data:` 字段，浏览器收到后再用换行符拼起来。
+    pub fn data(mut self, data: impl Into<String>) -> Event {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// 把一个值序列化为 JSON 作为事件的内容。
+    pub fn json_data<T: Serialize>(self, value: &T) -> Result<Event, serde_json::Error> {
+        Ok(self.data(serde_json::to_string(value)?))
+    }
+
+    /// 事件类型，浏览器端用 `addEventListener(type, ...)` 接收，不设置时为 `message`。
+    pub fn event(mut self, event: impl Into<String>) -> Event {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// 事件 id，浏览器重连时会通过 `Last-Event-ID` 请求头告诉服务器。
+    pub fn id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// 连接断开之后，浏览器等待多久再重连。
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// 注释会被浏览器忽略，通常用来定时发送一点数据，防止空闲的连接被代理服务器断开。
+    pub fn comment(mut self, comment: impl Into<String>) -> Event {
+        self.comment = Some(comment.into());
+        self
+    }
+}
+
+/// 按照 `text/event-stream` 的格式序列化，以一个空行结尾。
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(comment) = &self.comment {
+            for line in lines(comment) {
+                writeln!(f, ": {line}")?;
+            }
+        }
+        // 事件类型和 id 只能占一行，其中的换行符会破坏整个事件流，直接去掉
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", single_line(event))?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", single_line(id))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        if let Some(data) = &self.data {
+            for line in lines(data) {
+                writeln!(f, "data: {line}")?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+/// `\r\n`、`\r` 和 `\n` 都是合法的换行符。
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split("\r\n").flat_map(|line| line.split(['\r', '\n']))
+}
+
+fn single_line(text: &str) -> String {
+    text.chars().filter(|c| !matches!(c, '\r' | '\n')).collect()
+}
+
+/// 把一个事件的 Stream 作为 SSE 响应返回，Stream 结束时响应也随之结束。
+///
+/// ```ignore
+/// async fn progress(_req: Request) -> Sse<impl Stream<Item = Event>> {
+///     Sse(stream::iter(0..=100).map(|n| Event::new().data(n.to_string())))
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sse<S>(pub S);
+
+impl<S> IntoResponse for Sse<S>
+where
+    S: Stream<Item = Event> + Send + 'static,
+{
+    fn into_response(self) -> Response {
+        let body = self.0.map(|event| Ok::<_, io::Error>(event.to_string()));
+        Response::ok()
+            .with_header("Content-Type", "text/event-stream")
+            // 事件流是实时的，不能被浏览器或者代理服务器缓存
+            .with_header("Cache-Control", "no-cache")
+            .with_stream(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Request, StatusCode};
+    use crate::router::Router;
+    use crate::testing::TestClient;
+    use futures::stream;
+
+    #[test]
+    fn formats_events() {
+        assert_eq!(Event::new().data("hello").to_string(), "data: hello\n\n");
+        let event = Event::new()
+            .event("progress\nforged: 1")
+            .id("7")
+            .retry(Duration::from_secs(3))
+            .data("line 1\r\nline 2\nline 3");
+        assert_eq!(
+            event.to_string(),
+            "event: progressforged: 1\nid: 7\nretry: 3000\n\
+             data: line 1\ndata: line 2\ndata: line 3\n\n"
+        );
+        assert_eq!(Event::new().comment("ping").to_string(), ": ping\n\n");
+        let event = Event::new().json_data(&serde_json::json!({ "done": 1 })).unwrap();
+        assert_eq!(event.to_string(), "data: {\"done\":1}\n\n");
+    }
+
+    #[test]
+    fn streams_events_with_chunked_encoding() {
+        let router = Router::new().get("/events", |_req: Request| async {
+            Sse(stream::iter(1..=3).map(|n| Event::new().id(n.to_string()).data("tick")))
+        });
+        let res = TestClient::new(router).get("/events");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.header("Content-Type"), Some("text/event-stream"));
+        assert_eq!(res.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(res.header("Content-Length"), None);
+        assert_eq!(
+            res.text(),
+            "id: 1\ndata: tick\n\nid: 2\ndata: tick\n\nid: 3\ndata: tick\n\n"
+        );
+    }
+}
@@ -0,0 +1,430 @@
+use std::fs::{File, Metadata};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncReadExt};
+use futures::stream::{self, Stream};
+
+use crate::http::{Request, Response, StatusCode, percent_decode};
+use crate::router::Handler;
+
+/// 以某个目录为根提供静态文件服务。
+///
+/// 挂载到带通配符的路由上，通配符参数需要命名为 `path`：
+///
+/// ```ignore
+/// Router::new().get("/static/*path", ServeDir::new("static"))
+/// ```
+///
+/// 没有 `path` 参数时(例如作为 fallback)，使用整个请求路径。
+/// 支持根据扩展名设置 `Content-Type`、单个区间的 `Range` 请求，
+/// 以及基于 `ETag`/`Last-Modified` 的 304 响应。
+///
+/// 文件内容按块读取、边读边发送，不会把整个文件读进内存。
+#[derive(Debug, Clone)]
+pub struct ServeDir {
+    root: PathBuf,
+    index: Option<String>,
+}
+
+impl ServeDir {
+    pub fn new(root: impl Into<PathBuf>) -> ServeDir {
+        ServeDir {
+            root: root.into(),
+            index: Some("index.html".to_string()),
+        }
+    }
+
+    /// 请求目录时返回的文件名，默认为 `index.html`，`None` 表示目录一律返回 404。
+    pub fn index_file(mut self, index: Option<&str>) -> ServeDir {
+        self.index = index.map(str::to_string);
+        self
+    }
+
+    pub fn serve(&self, req: &Request) -> Response {
+        // 路由已经解码过路径参数，只有直接使用请求路径时才需要解码，
+        // 否则 `100%25.txt` 会被解码两次
+        let relative = match req.param("path") {
+            Some(path) => Some(path.to_string()),
+            None => percent_decode(req.path()),
+        };
+        let Some(path) = relative.and_then(|relative| self.resolve(&relative)) else {
+            return Response::not_found();
+        };
+        let (file, metadata) = match open_file(&path) {
+            Ok(opened) => opened,
+            Err(_) => return Response::not_found(),
+        };
+
+        let modified = metadata.modified().ok();
+        let etag = etag(&metadata);
+        let mut headers = vec![
+            ("Accept-Ranges", "bytes".to_string()),
+            ("ETag", etag.clone()),
+        ];
+        if let Some(modified) = modified {
+            headers.push(("Last-Modified", httpdate::fmt_http_date(modified)));
+        }
+
+        if is_not_modified(req, &etag, modified) {
+            // 304 响应不带响应体，但要带上缓存相关的头，让客户端更新自己的缓存
+            return with_headers(Response::new(StatusCode::NOT_MODIFIED), headers);
+        }
+
+        let len = metadata.len();
+        let range = match req.header("Range") {
+            Some(range) if if_range_matches(req, &etag, modified) => parse_range(range, len),
+            _ => None,
+        };
+        let (response, start, count) = match range {
+            None => (Response::ok(), 0, len),
+            Some(Err(())) => {
+                return Response::new(StatusCode(416))
+                    .with_header("Content-Range", format!("bytes */{len}"));
+            }
+            Some(Ok((start, end))) => {
+                let response = Response::new(StatusCode(206))
+                    .with_header("Content-Range", format!("bytes {start}-{end}/{len}"));
+                (response, start, end - start + 1)
+            }
+        };
+
+        match read_range(file, start, count) {
+            Ok(body) => with_headers(response, headers)
+                .with_header("Content-Type", mime_type(&path))
+                .with_sized_stream(count, body),
+            Err(e) => Response::new(StatusCode::INTERNAL_SERVER_ERROR).with_text(e.to_string()),
+        }
+    }
+
+    /// 把(已经解码的)请求路径映射到根目录下的文件，拒绝任何可能逃出根目录的路径。
+    fn resolve(&self, relative: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for component in Path::new(relative.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => {
+                    // Windows 下 `\` 也是路径分隔符，`C:` 之类的前缀同样不能出现在段中
+                    let part = part.to_str()?;
+                    if part.contains(['\\', ':', '\0']) {
+                        return None;
+                    }
+                    path.push(part);
+                }
+                Component::CurDir => {}
+                // `..`、绝对路径和盘符都直接拒绝
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        if path.is_dir() {
+            path.push(self.index.as_ref()?);
+        }
+        // 即使路径本身是干净的，根目录下的符号链接依然可能指向外面，
+        // 因此最后再比较一次规范化之后的路径。
+        let root = self.root.canonicalize().ok()?;
+        let resolved = path.canonicalize().ok()?;
+        resolved.starts_with(&root).then_some(resolved)
+    }
+}
+
+impl Handler for ServeDir {
+    /// 打开和读取文件都是阻塞操作，放到专门的线程池中执行，不占用执行异步任务的线程。
+    fn call(&self, req: Request) -> BoxFuture<'static, Response> {
+        let dir = self.clone();
+        Box::pin(async_std::task::spawn_blocking(move || dir.serve(&req)))
+    }
+}
+
+fn with_headers(mut response: Response, headers: Vec<(&str, String)>) -> Response {
+    for (name, value) in headers {
+        response.headers_mut().insert(name, value);
+    }
+    response
+}
+
+fn open_file(path: &Path) -> io::Result<(File, Metadata)> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    Ok((file, metadata))
+}
+
+/// 每次从文件中读取、发送的数据量。
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// 从 `start` 开始读取 `len` 个字节，返回逐块产生数据的 Stream。
+///
+/// 读取使用 async-std 的文件，实际的读操作在它的线程池中执行，不会阻塞发送响应的任务。
+fn read_range(
+    mut file: File,
+    start: u64,
+    len: u64,
+) -> io::Result<impl Stream<Item = io::Result<Vec<u8>>> + Send + 'static> {
+    file.seek(SeekFrom::Start(start))?;
+    let reader = async_std::fs::File::from(file).take(len);
+    Ok(stream::try_unfold(reader, read_chunk))
+}
+
+async fn read_chunk<R>(mut reader: R) -> io::Result<Option<(Vec<u8>, R)>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let n = reader.read(&mut chunk).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    chunk.truncate(n);
+    Ok(Some((chunk, reader)))
+}
+
+/// 用文件大小和修改时间生成 ETag，文件内容变化时两者至少有一个会变。
+fn etag(metadata: &Metadata) -> String {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), mtime)
+}
+
+fn is_not_modified(req: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    // 同时存在时 If-None-Match 优先，If-Modified-Since 会被忽略
+    if let Some(tags) = req.header("If-None-Match") {
+        return tags
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+    }
+    match (req.header("If-Modified-Since"), modified) {
+        (Some(since), Some(modified)) => match httpdate::parse_http_date(since) {
+            // HTTP 日期只精确到秒
+            Ok(since) => truncate_to_secs(modified) <= since,
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// `If-Range` 不匹配时说明客户端手里的部分内容已经过期，此时应该忽略 `Range` 返回整个文件。
+fn if_range_matches(req: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(value) = req.header("If-Range") else {
+        return true;
+    };
+    if value.starts_with('"') {
+        return value == etag;
+    }
+    match (httpdate::parse_http_date(value), modified) {
+        (Ok(date), Some(modified)) => truncate_to_secs(modified) == date,
+        _ => false,
+    }
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + std::time::Duration::from_secs(secs)
+}
+
+/// 解析 `Range: bytes=...`，返回闭区间 `[start, end]`。
+///
+/// - `None`：不是我们支持的格式(例如多个区间)，按普通请求处理
+/// - `Some(Err(()))`：区间无法满足，应该返回 416
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // `bytes=-500` 表示最后 500 个字节
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            let end: u64 = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            end.min(len.saturating_sub(1))
+        };
+        if start >= len {
+            return Some(Err(()));
+        }
+        (start, end)
+    };
+    Some(Ok(range))
+}
+
+/// 根据扩展名推断 `Content-Type`，未知类型一律当作二进制数据。
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("webp") => "image/webp",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::router::Router;
+    use crate::testing::TestClient;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("web_core_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        std::fs::write(root.join("index.html"), "<h1>index</h1>").unwrap();
+        std::fs::write(root.join("css/site.css"), "0123456789").unwrap();
+        root
+    }
+
+    fn get(target: &str) -> Request {
+        Request::new(Method::Get, target)
+    }
+
+    #[test]
+    fn serves_files_with_content_type() {
+        let client = TestClient::new(ServeDir::new(temp_root("mime")));
+        let res = client.get("/css/site.css");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.header("Content-Type"), Some("text/css; charset=utf-8"));
+        assert_eq!(res.body(), b"0123456789");
+
+        let res = client.get("/");
+        assert_eq!(res.body(), b"<h1>index</h1>");
+    }
+
+    #[test]
+    fn large_files_are_streamed_with_content_length() {
+        let root = temp_root("large");
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 3).map(|i| i as u8).collect();
+        std::fs::write(root.join("large.bin"), &content).unwrap();
+        let dir = ServeDir::new(root);
+
+        let res = dir.serve(&get("/large.bin"));
+        assert!(res.is_streaming());
+        assert!(res.body().is_empty());
+
+        let client = TestClient::new(dir);
+        let res = client.get("/large.bin");
+        assert_eq!(res.header("Content-Length"), Some("131075"));
+        assert_eq!(res.header("Transfer-Encoding"), None);
+        assert_eq!(res.body(), content);
+
+        let res = client.head("/large.bin");
+        assert_eq!(res.header("Content-Length"), Some("131075"));
+        assert!(res.body().is_empty());
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        let root = temp_root("traversal");
+        std::fs::write(root.parent().unwrap().join("secret.txt"), "secret").unwrap();
+        let dir = ServeDir::new(root.join("css"));
+        for target in ["/../index.html", "/%2e%2e/index.html", "/..%2fsecret.txt", "/a\\..\\b"] {
+            assert_eq!(
+                dir.serve(&get(target)).status(),
+                StatusCode::NOT_FOUND,
+                "{target}"
+            );
+        }
+    }
+
+    #[test]
+    fn percent_signs_in_file_names() {
+        let root = temp_root("percent");
+        std::fs::write(root.join("100%.txt"), "full").unwrap();
+        let dir = ServeDir::new(root);
+        assert_eq!(TestClient::new(dir.clone()).get("/100%25.txt").body(), b"full");
+
+        let router = Router::new().get("/static/*path", dir);
+        let res = TestClient::new(router).get("/static/100%25.txt");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), b"full");
+    }
+
+    #[test]
+    fn range_requests() {
+        let client = TestClient::new(ServeDir::new(temp_root("range")));
+        let req = get("/css/site.css").with_header("Range", "bytes=2-4");
+        let res = client.send(req);
+        assert_eq!(res.status(), StatusCode(206));
+        assert_eq!(res.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(res.header("Content-Length"), Some("3"));
+        assert_eq!(res.body(), b"234");
+
+        let req = get("/css/site.css").with_header("Range", "bytes=-3");
+        assert_eq!(client.send(req).body(), b"789");
+
+        let req = get("/css/site.css").with_header("Range", "bytes=20-");
+        let res = client.send(req);
+        assert_eq!(res.status(), StatusCode(416));
+        assert_eq!(res.header("Content-Range"), Some("bytes */10"));
+    }
+
+    #[test]
+    fn conditional_requests_get_304() {
+        let dir = ServeDir::new(temp_root("cache"));
+        let res = dir.serve(&get("/css/site.css"));
+        let etag = res.header("ETag").unwrap().to_string();
+        let last_modified = res.header("Last-Modified").unwrap().to_string();
+
+        let req = get("/css/site.css").with_header("If-None-Match", etag.clone());
+        let res = dir.serve(&req);
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(res.body().is_empty());
+        assert_eq!(res.header("ETag"), Some(etag.as_str()));
+        let head = String::from_utf8(res.to_bytes()).unwrap();
+        assert!(head.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(!head.contains("Content-Length"), "{head}");
+
+        let req = get("/css/site.css").with_header("If-Modified-Since", last_modified);
+        assert_eq!(dir.serve(&req).status(), StatusCode::NOT_MODIFIED);
+
+        let req = get("/css/site.css").with_header("If-None-Match", "\"other\"");
+        assert_eq!(dir.serve(&req).status(), StatusCode::OK);
+    }
+}
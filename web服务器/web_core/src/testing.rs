@@ -0,0 +1,274 @@
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use serde::de::DeserializeOwned;
+
+use crate::http::{Headers, Method, Request, Response, StatusCode};
+use crate::reader::{Limits, parse_request, parse_response};
+use crate::router::Handler;
+use crate::server::{ServerConfig, serve_connection_async};
+
+// 之前的测试要么真的监听一个端口、用 TcpStream 发请求，要么像异步多线程服务器那样
+// 手写一个 MockTcpStream，然后对写出来的字节做 `starts_with` 之类的字符串匹配。
+// 测试客户端把这两件事都包了起来：请求被序列化之后写进一个内存中的“连接”，
+// 交给和真实服务器完全相同的连接处理循环(`serve_connection_async`)，
+// 再把服务器写出来的字节解析成状态码、响应头和响应体。
+// 不需要打开任何端口，四个版本的服务器都可以用它测试自己的路由和中间件。
+
+/// 不经过网络、直接驱动处理函数的测试客户端。
+///
+/// ```ignore
+/// let client = TestClient::new(app());
+/// let res = client.get("/");
+/// assert_eq!(res.status(), StatusCode::OK);
+/// assert!(res.text().contains("Hello"));
+/// ```
+pub struct TestClient<H> {
+    handler: H,
+    config: ServerConfig,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl<H: Handler> TestClient<H> {
+    pub fn new(handler: H) -> TestClient<H> {
+        TestClient {
+            handler,
+            config: ServerConfig::default(),
+            // 限流等中间件需要客户端地址，默认假装请求来自本机
+            remote_addr: Some(SocketAddr::from(([127, 0, 0, 1], 50000))),
+        }
+    }
+
+    /// 使用指定的连接配置，例如测试超时或者请求大小的限制。
+    pub fn with_config(mut self, config: ServerConfig) -> TestClient<H> {
+        self.config = config;
+        self
+    }
+
+    /// 请求来自哪个地址，`None` 表示地址未知。
+    pub fn with_remote_addr(mut self, addr: Option<SocketAddr>) -> TestClient<H> {
+        self.remote_addr = addr;
+        self
+    }
+
+    pub fn get(&self, target: &str) -> TestResponse {
+        self.send(Request::new(Method::Get, target))
+    }
+
+    pub fn post(&self, target: &str, body: impl Into<Vec<u8>>) -> TestResponse {
+        self.send(Request::new(Method::Post, target).with_body(body))
+    }
+
+    pub fn head(&self, target: &str) -> TestResponse {
+        self.send(Request::new(Method::Head, target))
+    }
+
+    /// 发送一个请求，返回服务器的响应。
+    ///
+    /// 服务器没有返回响应(例如连接处理出错)时 panic。
+    pub fn send(&self, request: Request) -> TestResponse {
+        let mut responses = self.send_raw(&request.to_bytes());
+        assert!(
+            !responses.is_empty(),
+            "no response for {} {}",
+            request.method(),
+            request.path()
+        );
+        responses.remove(0)
+    }
+
+    /// 把原始的字节作为一个连接上收到的全部数据交给服务器，返回服务器在这个连接上发送的所有响应。
+    ///
+    /// 用于测试流水线、格式错误的请求等无法用 `Request` 构造出来的情况。
+    /// 客户端发送完这些数据之后就关闭了连接(读到 EOF)，因此不会等到任何超时。
+    pub fn send_raw(&self, raw: &[u8]) -> Vec<TestResponse> {
+        let mut stream = MemoryStream {
+            input: io::Cursor::new(raw.to_vec()),
+            output: Vec::new(),
+        };
+        let (addr, handler, config) = (self.remote_addr, &self.handler, &self.config);
+        futures::executor::block_on(serve_connection_async(&mut stream, addr, handler, config))
+            .expect("in-memory connection failed");
+        parse_responses(&stream.output, &request_methods(raw))
+    }
+}
+
+// 请求和响应的大小可能很大(例如静态文件)，不受默认大小限制的约束
+const UNLIMITED: Limits = Limits {
+    max_head_size: usize::MAX,
+    max_body_size: usize::MAX,
+};
+
+/// 连接上每个请求的方法。HEAD 请求的响应带有 `Content-Length` 却没有响应体，
+/// 解析响应时需要知道它对应的请求是不是 HEAD。
+fn request_methods(mut raw: &[u8]) -> Vec<Method> {
+    let mut methods = Vec::new();
+    while let Ok(Some((request, consumed))) = parse_request(raw, &UNLIMITED) {
+        methods.push(request.method());
+        raw = &raw[consumed..];
+    }
+    methods
+}
+
+fn parse_responses(mut out: &[u8], methods: &[Method]) -> Vec<TestResponse> {
+    let mut responses = Vec::new();
+    while !out.is_empty() {
+        // 格式错误的请求没有对应的方法，它的响应(400 之类)按 GET 解析
+        let method = methods.get(responses.len()).copied().unwrap_or(Method::Get);
+        match parse_response(out, &UNLIMITED, method, true) {
+            Ok(Some((response, consumed))) => {
+                out = &out[consumed..];
+                // WebSocket 握手之后的数据不再是 HTTP 响应
+                let upgraded = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+                responses.push(TestResponse { response });
+                if upgraded {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => panic!(
+                "server sent an invalid response ({e}): {:?}",
+                String::from_utf8_lossy(out)
+            ),
+        }
+    }
+    responses
+}
+
+/// 测试客户端收到的响应。
+#[derive(Debug)]
+pub struct TestResponse {
+    response: Response,
+}
+
+impl TestResponse {
+    pub fn status(&self) -> StatusCode {
+        self.response.status()
+    }
+
+    pub fn headers(&self) -> &Headers {
+        self.response.headers()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.response.header(name)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        self.response.body()
+    }
+
+    /// 以 UTF-8 文本的形式返回响应体，不是合法的 UTF-8 时 panic。
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(self.body()).expect("response body is not valid UTF-8")
+    }
+
+    /// 把响应体解析为 JSON，解析失败时 panic。
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(self.body()).unwrap_or_else(|e| {
+            panic!("response body is not valid JSON ({e}): {:?}", self.text())
+        })
+    }
+
+    pub fn into_response(self) -> Response {
+        self.response
+    }
+}
+
+/// 内存中的连接：读取预先准备好的数据，读完之后返回 EOF；写入的数据全部保存下来。
+struct MemoryStream {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.input.read(buf))
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.output.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::{Pipeline, RequestId};
+    use crate::router::Router;
+
+    async fn echo(req: Request) -> Response {
+        let body = format!("{} {}", req.method(), String::from_utf8_lossy(req.body()));
+        Response::ok().with_header("X-Path", req.path()).with_text(body)
+    }
+
+    fn app() -> Pipeline {
+        Pipeline::new(Router::new().get("/*", echo).post("/*", echo)).with(RequestId::new())
+    }
+
+    #[test]
+    fn sends_typed_requests_through_middleware() {
+        let client = TestClient::new(app());
+
+        let res = client.get("/hello?x=1");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.header("X-Path"), Some("/hello"));
+        assert!(res.header("X-Request-Id").is_some());
+        assert_eq!(res.text(), "GET ");
+
+        let res = client.post("/items", "payload");
+        assert_eq!(res.text(), "POST payload");
+        assert_eq!(res.header("Content-Length"), Some("12"));
+
+        // HEAD 请求由 GET 的处理函数处理，响应头相同，但是没有响应体
+        let res = client.head("/hello");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.header("X-Path"), Some("/hello"));
+        assert_eq!(res.header("Content-Length"), Some("5"));
+        assert!(res.body().is_empty());
+    }
+
+    #[test]
+    fn raw_bytes_go_through_the_connection_loop() {
+        let client = TestClient::new(app());
+        let raw = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let responses = client.send_raw(raw);
+        let paths: Vec<_> = responses.iter().map(|r| r.header("X-Path").unwrap()).collect();
+        assert_eq!(paths, ["/a", "/b"]);
+        assert_eq!(responses[1].header("Connection"), Some("close"));
+
+        // HEAD 的响应没有响应体，后面紧接着下一个响应
+        let raw = b"HEAD /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let responses = client.send_raw(raw);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].header("Content-Length"), Some("5"));
+        assert_eq!(responses[0].body(), b"");
+        assert_eq!(responses[1].text(), "GET ");
+
+        let responses = client.send_raw(b"NOT A REQUEST\r\n\r\n");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,648 @@
+use std::fmt;
+use std::io;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use sha1::{Digest, Sha1};
+
+use crate::http::{Method, Request, Response, StatusCode};
+
+// HTTP 是一问一答的：服务器只能等客户端来问，想要把实时的数据推给浏览器只能让浏览器不停地轮询。
+// WebSocket 借用一次 HTTP 请求完成握手(`Upgrade: websocket`)，服务器返回 101 之后，
+// 这个 TCP 连接就不再说 HTTP 了，双方可以随时向对方发送消息。
+//
+// 握手之后的数据由一个个帧(frame)组成，每个帧的格式为(RFC 6455 第 5.2 节)：
+//
+//   FIN(1) RSV(3) opcode(4) | MASK(1) 长度(7) | [扩展长度 16/64] | [掩码 32] | 数据
+//
+// 客户端发送的帧必须使用掩码，服务器发送的帧不能使用掩码；
+// 一条消息可以拆成多个帧(分片)，控制帧(close/ping/pong)可以插在分片之间。
+
+/// 握手时拼接在客户端 key 后面的固定 GUID。
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// 单条消息(合并所有分片之后)的默认大小上限。
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// 一条 WebSocket 消息。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// 收到 ping 时会自动回复 pong，不需要手动处理
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// 关闭连接，可以带上状态码和原因
+    Close(Option<(u16, String)>),
+}
+
+/// 关闭连接时使用的状态码(RFC 6455 第 7.4.1 节)。
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const TOO_BIG: u16 = 1009;
+}
+
+/// 读写 WebSocket 连接所需的特征，任何 `AsyncRead + AsyncWrite + Unpin + Send` 的类型都实现了它。
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+type Callback = Box<dyn for<'a> FnOnce(WebSocket<'a>) -> BoxFuture<'a, ()> + Send>;
+
+/// 握手响应发送之后要执行的回调，由 `serve_connection` 系列函数取出并执行。
+pub(crate) struct Upgrade(Callback);
+
+impl Upgrade {
+    pub(crate) async fn run(self, stream: &mut dyn Connection, leftover: Vec<u8>) {
+        let ws = WebSocket {
+            stream,
+            buf: leftover,
+            out: Vec::new(),
+            fragments: None,
+            ready: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_sent: false,
+            close_received: false,
+        };
+        (self.0)(ws).await
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade(..)")
+    }
+}
+
+/// 检查 WebSocket 握手请求，成功时返回 101 响应，响应发送之后连接交给 `on_upgrade` 处理。
+///
+/// 请求不是合法的 WebSocket 握手时返回 400，版本不支持时返回 426。
+///
+/// ```ignore
+/// async fn ws(req: Request) -> Response {
+///     websocket::upgrade(&req, |mut ws| Box::pin(async move {
+///         while let Ok(Some(msg)) = ws.recv().await {
+///             if let Message::Text(text) = msg {
+///                 let _ = ws.send(Message::Text(text)).await;
+///             }
+///         }
+///     }))
+/// }
+/// ```
+pub fn upgrade<F>(req: &Request, on_upgrade: F) -> Response
+where
+    F: for<'a> FnOnce(WebSocket<'a>) -> BoxFuture<'a, ()> + Send + 'static,
+{
+    let key = match check_handshake(req) {
+        Ok(key) => key,
+        Err(res) => return res,
+    };
+    let mut res = Response::new(StatusCode::SWITCHING_PROTOCOLS)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key));
+    res.set_upgrade(Upgrade(Box::new(on_upgrade)));
+    res
+}
+
+fn check_handshake(req: &Request) -> Result<&str, Response> {
+    let has_token = |name: &str, token: &str| {
+        req.header(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    let bad_request = |msg: &str| Response::new(StatusCode::BAD_REQUEST).with_text(msg.to_string());
+
+    if req.method() != Method::Get || req.version() != "HTTP/1.1" {
+        return Err(bad_request("WebSocket handshake must be a GET request over HTTP/1.1"));
+    }
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(bad_request("expected Upgrade: websocket"));
+    }
+    if req.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(Response::new(StatusCode(426))
+            .with_header("Sec-WebSocket-Version", "13")
+            .with_text("unsupported WebSocket version"));
+    }
+    // key 是客户端随机生成的 16 字节，经过 base64 编码
+    match req.header("Sec-WebSocket-Key").map(str::trim) {
+        Some(key) if BASE64.decode(key).is_ok_and(|k| k.len() == 16) => Ok(key),
+        _ => Err(bad_request("missing or invalid Sec-WebSocket-Key")),
+    }
+}
+
+/// `Sec-WebSocket-Accept` = base64(sha1(key + GUID))，用来证明服务器确实理解 WebSocket 协议。
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// 协议错误：关闭连接时使用的状态码和原因。
+struct ProtocolError(u16, &'static str);
+
+/// 握手完成之后的 WebSocket 连接。
+///
+/// `recv` 和 `send` 都可以安全地放在 `futures::select!` 中：
+/// 读到一半的帧保存在内部缓冲区中，没写完的数据会在下一次读写之前继续发送，被取消时不会丢数据。
+pub struct WebSocket<'a> {
+    stream: &'a mut dyn Connection,
+    /// 已经读到但还没有解析的数据
+    buf: Vec<u8>,
+    /// 还没有写出去的数据
+    out: Vec<u8>,
+    /// 正在接收的分片消息：第一个帧的 opcode 和已经收到的数据
+    fragments: Option<(u8, Vec<u8>)>,
+    /// 已经解析出来、但还没有返回给调用者的消息
+    ready: Option<Message>,
+    max_message_size: usize,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl WebSocket<'_> {
+    /// 设置单条消息的大小上限，超过上限时以 1009 关闭连接。
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max;
+    }
+
+    /// 接收下一条消息，连接关闭之后返回 `Ok(None)`。
+    ///
+    /// 收到 ping 会自动回复 pong；收到 close 会回复 close，返回 `Message::Close` 之后再调用返回 `None`。
+    /// 客户端违反协议时，会以对应的状态码关闭连接并返回错误。
+    pub async fn recv(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            // 先把自动回复的 pong 或者 close 发出去，再返回收到的消息
+            self.flush_out().await?;
+            if let Some(msg) = self.ready.take() {
+                return Ok(Some(msg));
+            }
+            if self.close_received {
+                return Ok(None);
+            }
+            let frame = match parse_frame(&self.buf, self.max_message_size) {
+                Ok(Some((frame, used))) => {
+                    self.buf.drain(..used);
+                    frame
+                }
+                Ok(None) => {
+                    let mut chunk = [0u8; 4096];
+                    let n = self.stream.read(&mut chunk).await?;
+                    if n == 0 {
+                        // 对方没有发送 close 帧就断开了连接
+                        self.close_received = true;
+                        return Ok(None);
+                    }
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    continue;
+                }
+                Err(e) => return Err(self.fail(e).await),
+            };
+            match self.handle_frame(frame) {
+                Ok(msg) => self.ready = msg,
+                Err(e) => return Err(self.fail(e).await),
+            }
+        }
+    }
+
+    /// 发送一条消息。发送了 `Message::Close` 之后就不能再发送其它消息了。
+    pub async fn send(&mut self, msg: Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "WebSocket is closing",
+            ));
+        }
+        self.queue(msg);
+        self.flush_out().await
+    }
+
+    /// 发送 close 帧，并等待对方回复 close(期间收到的其它消息会被丢弃)。
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if !self.close_sent {
+            self.send(Message::Close(Some((code, reason.to_string()))))
+                .await?;
+        }
+        while self.recv().await?.is_some() {}
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<Option<Message>, ProtocolError> {
+        match frame.opcode {
+            OP_TEXT | OP_BINARY => {
+                if self.fragments.is_some() {
+                    return Err(ProtocolError(
+                        close_code::PROTOCOL_ERROR,
+                        "expected a continuation frame",
+                    ));
+                }
+                if frame.fin {
+                    return data_message(frame.opcode, frame.payload).map(Some);
+                }
+                self.fragments = Some((frame.opcode, frame.payload));
+                Ok(None)
+            }
+            OP_CONTINUATION => {
+                let Some((_, data)) = self.fragments.as_mut() else {
+                    return Err(ProtocolError(
+                        close_code::PROTOCOL_ERROR,
+                        "unexpected continuation frame",
+                    ));
+                };
+                if data.len() + frame.payload.len() > self.max_message_size {
+                    return Err(ProtocolError(close_code::TOO_BIG, "message too big"));
+                }
+                data.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    return Ok(None);
+                }
+                let (opcode, data) = self.fragments.take().unwrap();
+                data_message(opcode, data).map(Some)
+            }
+            OP_PING => {
+                if !self.close_sent {
+                    self.queue(Message::Pong(frame.payload.clone()));
+                }
+                Ok(Some(Message::Ping(frame.payload)))
+            }
+            OP_PONG => Ok(Some(Message::Pong(frame.payload))),
+            OP_CLOSE => {
+                let close = parse_close(&frame.payload)?;
+                self.close_received = true;
+                // 对方先发起关闭时，原样回复它的状态码
+                if !self.close_sent {
+                    let code = close.as_ref().map(|(code, _)| *code);
+                    self.queue(Message::Close(code.map(|c| (c, String::new()))));
+                }
+                Ok(Some(Message::Close(close)))
+            }
+            _ => Err(ProtocolError(close_code::PROTOCOL_ERROR, "unknown opcode")),
+        }
+    }
+
+    /// 对方违反协议：发送 close 帧然后放弃这个连接。
+    async fn fail(&mut self, ProtocolError(code, reason): ProtocolError) -> io::Error {
+        if !self.close_sent {
+            self.queue(Message::Close(Some((code, reason.to_string()))));
+            let _ = self.flush_out().await;
+        }
+        self.close_received = true;
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    fn queue(&mut self, msg: Message) {
+        let (opcode, payload) = match msg {
+            Message::Text(text) => (OP_TEXT, text.into_bytes()),
+            Message::Binary(data) => (OP_BINARY, data),
+            Message::Ping(data) => (OP_PING, data),
+            Message::Pong(data) => (OP_PONG, data),
+            Message::Close(close) => {
+                self.close_sent = true;
+                let payload = match close {
+                    Some((code, reason)) => {
+                        let mut payload = code.to_be_bytes().to_vec();
+                        payload.extend_from_slice(reason.as_bytes());
+                        payload
+                    }
+                    None => Vec::new(),
+                };
+                (OP_CLOSE, payload)
+            }
+        };
+        encode_frame(&mut self.out, opcode, &payload);
+    }
+
+    async fn flush_out(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            let n = self.stream.write(&self.out).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.out.drain(..n);
+        }
+        self.stream.flush().await
+    }
+}
+
+fn data_message(opcode: u8, payload: Vec<u8>) -> Result<Message, ProtocolError> {
+    if opcode == OP_BINARY {
+        return Ok(Message::Binary(payload));
+    }
+    String::from_utf8(payload)
+        .map(Message::Text)
+        .map_err(|_| ProtocolError(close_code::INVALID_DATA, "text message is not valid UTF-8"))
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<(u16, String)>, ProtocolError> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(ProtocolError(close_code::PROTOCOL_ERROR, "invalid close frame")),
+        [hi, lo, reason @ ..] => {
+            let code = u16::from_be_bytes([*hi, *lo]);
+            // 1004~1006 和 1015 是保留的，不能出现在 close 帧中
+            let valid = matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999);
+            if !valid {
+                return Err(ProtocolError(close_code::PROTOCOL_ERROR, "invalid close code"));
+            }
+            let reason = String::from_utf8(reason.to_vec()).map_err(|_| {
+                ProtocolError(close_code::INVALID_DATA, "close reason is not valid UTF-8")
+            })?;
+            Ok(Some((code, reason)))
+        }
+    }
+}
+
+/// 从缓冲区中解析一个客户端发送的帧，数据不完整时返回 `Ok(None)`。
+fn parse_frame(buf: &[u8], max_size: usize) -> Result<Option<(Frame, usize)>, ProtocolError> {
+    let [b0, b1, ..] = *buf else {
+        return Ok(None);
+    };
+    let fin = b0 & 0x80 != 0;
+    let opcode = b0 & 0x0F;
+    if b0 & 0x70 != 0 {
+        // 没有协商任何扩展，RSV 位必须为 0
+        return Err(ProtocolError(close_code::PROTOCOL_ERROR, "reserved bits set"));
+    }
+    if b1 & 0x80 == 0 {
+        return Err(ProtocolError(close_code::PROTOCOL_ERROR, "client frames must be masked"));
+    }
+
+    let (len, mut pos) = match b1 & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err(ProtocolError(close_code::PROTOCOL_ERROR, "invalid control frame"));
+    }
+    if len > max_size as u64 {
+        return Err(ProtocolError(close_code::TOO_BIG, "message too big"));
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+    let payload = buf[pos..pos + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        pos + len,
+    )))
+}
+
+/// 编码一个服务器发送的帧：不分片，不使用掩码。
+fn encode_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => out.push(len as u8),
+        len @ 126..=0xFFFF => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::server::{ServerConfig, serve_connection_async};
+    use futures::executor::block_on;
+    use std::io::Read;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    struct MockStream {
+        read_data: std::io::Cursor<Vec<u8>>,
+        write_data: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(input: Vec<u8>) -> MockStream {
+            MockStream {
+                read_data: std::io::Cursor::new(input),
+                write_data: Vec::new(),
+            }
+        }
+    }
+
+    impl AsyncRead for MockStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.read_data.read(buf))
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.write_data.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// 按客户端的方式编码一个帧(使用掩码)
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut out = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => out.push(0x80 | len as u8),
+            len => {
+                out.push(0x80 | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    fn server_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_frame(&mut out, opcode, payload);
+        out
+    }
+
+    /// 在 `input` 上运行 `f`，返回服务器写出的数据
+    fn run<F>(input: Vec<u8>, f: F) -> Vec<u8>
+    where
+        F: for<'a> FnOnce(WebSocket<'a>) -> BoxFuture<'a, ()> + Send + 'static,
+    {
+        let mut stream = MockStream::new(input);
+        block_on(Upgrade(Box::new(f)).run(&mut stream, Vec::new()));
+        stream.write_data
+    }
+
+    fn handshake(key: &str) -> Request {
+        Request::new(Method::Get, "/ws")
+            .with_header("Host", "localhost")
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "keep-alive, Upgrade")
+            .with_header("Sec-WebSocket-Version", "13")
+            .with_header("Sec-WebSocket-Key", key)
+    }
+
+    #[test]
+    fn handshake_computes_accept_key() {
+        // RFC 6455 第 1.3 节中的例子
+        let res = upgrade(&handshake("dGhlIHNhbXBsZSBub25jZQ=="), |_| Box::pin(async {}));
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            res.header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert!(!String::from_utf8(res.to_bytes()).unwrap().contains("Content-Length"));
+
+        let plain = Request::new(Method::Get, "/ws");
+        assert_eq!(upgrade(&plain, |_| Box::pin(async {})).status(), StatusCode::BAD_REQUEST);
+        let bad_key = handshake("too-short");
+        assert_eq!(upgrade(&bad_key, |_| Box::pin(async {})).status(), StatusCode::BAD_REQUEST);
+        let mut old = handshake("dGhlIHNhbXBsZSBub25jZQ==");
+        old.headers_mut().insert("Sec-WebSocket-Version", "8");
+        let res = upgrade(&old, |_| Box::pin(async {}));
+        assert_eq!(res.status(), StatusCode(426));
+    }
+
+    #[test]
+    fn reassembles_fragments_and_answers_control_frames() {
+        let mut input = client_frame(false, OP_TEXT, b"hel");
+        input.extend(client_frame(true, OP_PING, b"p"));
+        input.extend(client_frame(true, OP_CONTINUATION, "lo 世界".as_bytes()));
+        input.extend(client_frame(true, OP_BINARY, &[0u8; 300]));
+        input.extend(client_frame(true, OP_CLOSE, &1000u16.to_be_bytes()));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let output = run(input, move |mut ws| {
+            Box::pin(async move {
+                while let Some(msg) = ws.recv().await.unwrap() {
+                    tx.send(msg).unwrap();
+                }
+            })
+        });
+
+        let received: Vec<Message> = rx.iter().collect();
+        assert_eq!(
+            received,
+            [
+                Message::Ping(b"p".to_vec()),
+                Message::Text("hello 世界".to_string()),
+                Message::Binary(vec![0; 300]),
+                Message::Close(Some((1000, String::new()))),
+            ]
+        );
+        let mut expected = server_frame(OP_PONG, b"p");
+        expected.extend(server_frame(OP_CLOSE, &1000u16.to_be_bytes()));
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn protocol_errors_close_the_connection() {
+        let cases: [(Vec<u8>, u16); 4] = [
+            // 没有使用掩码
+            (vec![0x81, 0x01, b'a'], close_code::PROTOCOL_ERROR),
+            // 不是合法的 UTF-8
+            (client_frame(true, OP_TEXT, &[0xFF, 0xFE]), close_code::INVALID_DATA),
+            // 没有开始分片就收到了后续分片
+            (client_frame(true, OP_CONTINUATION, b"x"), close_code::PROTOCOL_ERROR),
+            // 超过大小上限
+            (client_frame(true, OP_BINARY, &[0; 200]), close_code::TOO_BIG),
+        ];
+        for (input, code) in cases {
+            let output = run(input, |mut ws| {
+                Box::pin(async move {
+                    ws.set_max_message_size(100);
+                    assert!(ws.recv().await.is_err());
+                    assert!(ws.recv().await.unwrap().is_none());
+                })
+            });
+            assert_eq!(&output[..4], &[0x88, output[1], (code >> 8) as u8, code as u8]);
+        }
+    }
+
+    #[test]
+    fn echo_over_http_upgrade() {
+        let router = Router::new().get("/ws", |req: Request| async move {
+            upgrade(&req, |mut ws| {
+                Box::pin(async move {
+                    while let Ok(Some(msg)) = ws.recv().await {
+                        if let Message::Text(text) = msg {
+                            ws.send(Message::Text(text.to_uppercase())).await.unwrap();
+                        }
+                    }
+                })
+            })
+        });
+
+        // 握手请求后面紧跟着的帧已经被读进了 HTTP 读取器的缓冲区，升级之后不能丢掉
+        let mut input = b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+            .to_vec();
+        input.extend(client_frame(true, OP_TEXT, b"hi"));
+        input.extend(client_frame(true, OP_CLOSE, &[]));
+        let mut stream = MockStream::new(input);
+        block_on(serve_connection_async(
+            &mut stream,
+            None,
+            &router,
+            &ServerConfig::default(),
+        ))
+        .unwrap();
+
+        let out = stream.write_data;
+        let head_end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&out[..head_end]);
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Connection: Upgrade\r\n"));
+        let mut frames = server_frame(OP_TEXT, b"HI");
+        frames.extend(server_frame(OP_CLOSE, &[]));
+        assert_eq!(&out[head_end..], &frames[..]);
+    }
+}
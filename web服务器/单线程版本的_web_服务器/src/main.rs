@@ -1,13 +1,14 @@
 use std::fs;
-use std::io::prelude::*;
 use std::net::TcpListener;
-use std::net::TcpStream;
+use web_core::middleware::AccessLog;
+use web_core::{Pipeline, Request, Response, Router, ServerConfig, StatusCode};
 
 // 单线程版本可以修改为多线程甚至于线程池来实现并发处理，
 // 但是线程还是太重了，使用 async 实现 Web 服务器才是最适合的。
 fn main() {
     // 监听本地端口 7878 ，等待 TCP 连接的建立
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    let app = app();
 
     // 阻塞等待请求的进入
     // incoming 会返回一个迭代器，它每一次迭代都会返回一个新的连接 stream(客户端发起，web服务器监听接收)，
@@ -15,34 +16,60 @@ fn main() {
     for stream in listener.incoming() {
         let stream = stream.unwrap();
 
-        let res = handle_connection(stream);
+        // 单线程版本一次只能服务一个连接，如果保持持久连接，
+        // 一个空闲的浏览器连接就会让其它所有客户端等到空闲超时为止，
+        // 因此这里每个连接只处理一个请求，响应中会带上 `Connection: close`。
+        let config = ServerConfig {
+            max_requests_per_connection: 1,
+            ..ServerConfig::default()
+        };
+        let res = web_core::serve_connection(stream, &app, &config);
         if res.is_err() {
             println!("Error: {:?}", res);
         }
     }
 }
 
-fn handle_connection(mut stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
-    // 从连接中顺序读取 1024 字节数据
-    let mut buffer = [0; 1024];
-    let n = stream.read(&mut buffer)?;
-
-    println!("Request:\n {}", String::from_utf8_lossy(&buffer[..n]));
-
-    let get = b"GET / HTTP/1.1\r\n";
-
-    // 处理HTTP协议头，若不符合则返回404和对应的 `html` 文件
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
-    };
-    let contents = fs::read_to_string(filename)?;
-
-    // 将回复内容写入连接缓存中
-    let response = format!("{status_line}{contents}");
-    stream.write_all(response.as_bytes())?;
-    // 使用 flush 将缓存中的内容发送到客户端
-    stream.flush()?;
-    Ok(())
+fn app() -> Pipeline {
+    let router = Router::new().get("/", hello).fallback(not_found);
+    // 每个请求在控制台打印一行访问日志
+    Pipeline::new(router).with(AccessLog::stdout())
+}
+
+// 处理函数是 async fn，这样它们就能和异步版本的服务器共用同一个 Router，
+// 在单线程版本中直接用 block_on 执行即可。
+async fn hello(_req: Request) -> Response {
+    html_file(StatusCode::OK, "hello.html")
+}
+
+// 处理HTTP协议头，若不符合则返回404和对应的 `html` 文件
+async fn not_found(_req: Request) -> Response {
+    html_file(StatusCode::NOT_FOUND, "404.html")
+}
+
+fn html_file(status: StatusCode, filename: &str) -> Response {
+    match fs::read_to_string(filename) {
+        Ok(contents) => Response::new(status).with_html(contents),
+        Err(e) => Response::new(StatusCode::INTERNAL_SERVER_ERROR).with_text(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web_core::TestClient;
+
+    #[test]
+    fn serves_hello_page() {
+        let res = TestClient::new(app()).get("/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text(), fs::read_to_string("hello.html").unwrap());
+    }
+
+    #[test]
+    fn unknown_path_gets_404_page() {
+        let res = TestClient::new(app()).get("/missing");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.text(), fs::read_to_string("404.html").unwrap());
+    }
 }
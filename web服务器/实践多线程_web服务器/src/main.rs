@@ -1,9 +1,15 @@
-use std::{
-    io::{BufReader, prelude::*},
-    net::{TcpListener, TcpStream},
-};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use practice_thread_web_server::ThreadPool;
+use web_core::middleware::{AccessLog, RequestId};
+use web_core::{
+    ConnectionLimit, InFlight, Pipeline, Request, Response, Router, ServeDir, ServerConfig, Shutdown,
+    StatusCode,
+};
 
 // 线程池包含一组已生成的线程，它们时刻等待着接收并处理新的任务。
 // 当程序接收到新任务时，它会将线程池中的一个线程指派给该任务，在该线程忙着处理时，
@@ -25,39 +31,134 @@ use practice_thread_web_server::ThreadPool;
 fn main() {
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
     let pool = ThreadPool::new(4);
+    // 处理管道在所有工作线程之间共享，因此放到 Arc 中
+    let app = Arc::new(app());
+    // 收到 SIGINT/SIGTERM 时停止接受新连接，正在处理的请求处理完之后关闭连接
+    let shutdown = Shutdown::new();
+    shutdown
+        .trigger_on_signals()
+        .expect("failed to install signal handlers");
+    // 阻塞的 accept 无法被信号打断，收到信号之后自己连接一下自己，让 incoming() 返回
+    {
+        let shutdown = shutdown.clone();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            shutdown.wait_blocking();
+            let _ = TcpStream::connect(addr);
+        });
+    }
+    let config = Arc::new(ServerConfig {
+        shutdown: Some(shutdown.clone()),
+        ..ServerConfig::default()
+    });
+    let in_flight = InFlight::new();
+    // 线程池的任务队列没有上限，连接数达到上限之后新的连接直接收到 503，而不是排在队列中等待
+    let limit = ConnectionLimit::new(config.max_connections);
 
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+    for stream in listener.incoming() {
+        if shutdown.is_triggered() {
+            break;
+        }
+        let mut stream = stream.unwrap();
+        let Some(permit) = limit.try_acquire() else {
+            let _ = stream.write_all(&web_core::busy_response().to_bytes());
+            continue;
+        };
+        let app = Arc::clone(&app);
+        let config = Arc::clone(&config);
+        let guard = in_flight.guard();
 
-        pool.execute(|| {
-            let err = handle_connection(stream);
+        pool.execute(move || {
+            // 连接处理完毕之后 permit 和 guard 被 drop，名额自动归还
+            let _permit = permit;
+            let _guard = guard;
+            // 持久连接会一直占用这个工作线程，直到客户端关闭连接或者空闲超时，
+            // 因此空闲超时不宜太长，否则少量空闲的客户端就能占满整个线程池。
+            let err = web_core::serve_connection(stream, &*app, &config);
             if let Err(e) = err {
                 println!("handle_connection error: {}", e);
             }
         });
     }
-    println!("Shutting down.");
+    println!("Shutting down, waiting for {} connections.", in_flight.count());
+    if !in_flight.drain_blocking(GRACE_PERIOD) {
+        // 线程池在 drop 时会等待所有工作线程结束，已经等不了了，直接退出进程
+        println!("Grace period elapsed, shutting down anyway.");
+        std::process::exit(1);
+    }
     // 即便主线程退出，只要子线程还在运行，程序就不会终止。
+    // 线程池被 drop 时会通知所有工作线程退出，并等待它们结束。
 }
 
-fn handle_connection(mut stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
-    let buf_reader = BufReader::new(&mut stream);
-    let request_line = buf_reader.lines().next().ok_or("bad request")??;
+/// 收到关闭信号之后，最多等待正在处理的连接多久
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
 
-    let (status_line, filename) = match &request_line[..] {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "hello.html"),
-        "GET /sleep HTTP/1.1" => {
-            std::thread::sleep(std::time::Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "hello.html")
-        }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
-    };
+// 访问日志和请求 ID 以中间件的形式包裹在路由外面
+fn app() -> Pipeline {
+    Pipeline::new(router())
+        .with(AccessLog::stdout())
+        .with(RequestId::new())
+}
+
+// 路由与异步版本的服务器共用 web_core 中的 Router，处理函数也是 async fn，
+// 线程池中的工作线程通过 block_on 来执行它们。
+fn router() -> Router {
+    Router::new()
+        .get("/", hello)
+        .get("/sleep", sleep)
+        .get("/static/*path", ServeDir::new(static_dir()))
+        .fallback(not_found)
+}
 
-    let contents = std::fs::read_to_string(filename)?;
-    let length = contents.len();
+// 静态文件的根目录可以通过环境变量 STATIC_DIR 配置，默认是当前目录下的 static 目录
+fn static_dir() -> String {
+    std::env::var("STATIC_DIR").unwrap_or_else(|_| "static".to_string())
+}
 
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+async fn hello(_req: Request) -> Response {
+    html_file(StatusCode::OK, "hello.html")
+}
 
-    stream.write_all(response.as_bytes())?;
-    Ok(())
+async fn sleep(_req: Request) -> Response {
+    // 工作线程本来就是专门用来处理这个连接的，阻塞它并不会影响其它连接
+    std::thread::sleep(std::time::Duration::from_secs(5));
+    html_file(StatusCode::OK, "hello.html")
+}
+
+async fn not_found(_req: Request) -> Response {
+    html_file(StatusCode::NOT_FOUND, "404.html")
+}
+
+fn html_file(status: StatusCode, filename: &str) -> Response {
+    match std::fs::read_to_string(filename) {
+        Ok(contents) => Response::new(status).with_html(contents),
+        Err(e) => Response::new(StatusCode::INTERNAL_SERVER_ERROR).with_text(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web_core::TestClient;
+
+    #[test]
+    fn serves_hello_page() {
+        let res = TestClient::new(app()).get("/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text(), std::fs::read_to_string("hello.html").unwrap());
+    }
+
+    #[test]
+    fn unknown_path_gets_404_page() {
+        let res = TestClient::new(app()).get("/missing");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.text(), std::fs::read_to_string("404.html").unwrap());
+    }
+
+    #[test]
+    fn static_files_cannot_escape_root() {
+        let res = TestClient::new(app()).get("/static/../Cargo.toml");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.header("X-Request-Id").is_some());
+    }
 }
@@ -1,7 +1,15 @@
 use async_std::net::TcpListener;
 use async_std::net::TcpStream;
-use async_std::prelude::*;
-use futures::stream::StreamExt;
+use futures::AsyncWriteExt;
+use futures::future::{self, Either};
+use futures::stream::{self, Stream, StreamExt};
+use std::pin::pin;
+use std::time::{Duration, Instant};
+use web_core::middleware::{AccessLog, Compression, RequestId};
+use web_core::{
+    ConnectionLimit, Event, Handler, Message, Pipeline, Request, Response, Router, ServerConfig,
+    Shutdown, Sse, StatusCode, websocket,
+};
 
 #[async_std::main]
 async fn main() {
@@ -20,47 +28,197 @@ async fn main() {
     // }
 
     // 在将数据读写改造成异步后，现在该函数也彻底变成了异步的版本，因此一次慢请求不再会阻止其它请求的运行。
-    listener
+    let app = &app();
+    // 收到 SIGINT/SIGTERM 时停止接受新连接，正在处理的请求处理完之后关闭连接
+    let shutdown = Shutdown::new();
+    shutdown
+        .trigger_on_signals()
+        .expect("failed to install signal handlers");
+    let config = &ServerConfig {
+        shutdown: Some(shutdown.clone()),
+        ..ServerConfig::default()
+    };
+    // 这里不给 for_each_concurrent 设置上限：达到上限之后它会停止接受新连接，
+    // 客户端只能一直等着。用 ConnectionLimit 限制连接数，超出的连接立刻收到 503。
+    let limit = &ConnectionLimit::new(config.max_connections);
+    let serve = listener
         .incoming()
+        // 关闭信号到来时 Stream 结束，for_each_concurrent 会等待已经接受的连接全部处理完毕
+        .take_until(shutdown.wait())
         .for_each_concurrent(/* limit */ None, |tcpstream| async move {
-            let tcpstream = tcpstream.unwrap();
-            handle_connection(tcpstream).await;
+            // accept 失败(例如文件描述符耗尽)只影响这一个连接，不能让整个服务器 panic
+            match tcpstream {
+                Ok(tcpstream) => match limit.try_acquire() {
+                    Some(_permit) => handle_connection(tcpstream, app, config).await,
+                    None => reject(tcpstream).await,
+                },
+                Err(e) => println!("accept error: {}", e),
+            }
+        });
+    // 但最多只等待 GRACE_PERIOD，有的连接(例如 WebSocket)可能永远也不会自己结束
+    let grace = async {
+        shutdown.wait().await;
+        async_std::task::sleep(GRACE_PERIOD).await;
+    };
+    match future::select(pin!(serve), pin!(grace)).await {
+        Either::Left(_) => println!("All connections closed, shutting down."),
+        Either::Right(_) => println!("Grace period elapsed, shutting down anyway."),
+    }
+}
+
+/// 收到关闭信号之后，最多等待正在处理的连接多久
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+fn app() -> Pipeline {
+    Pipeline::new(router())
+        .with(AccessLog::stdout())
+        .with(RequestId::new())
+        .with(Compression::new())
+}
+
+fn router() -> Router {
+    Router::new()
+        .get("/", hello)
+        .get("/sleep", sleep)
+        .get("/ws", ws)
+        .get("/job", job)
+        .fallback(not_found)
+}
+
+async fn hello(_req: Request) -> Response {
+    html_file(StatusCode::OK, "hello.html")
+}
+
+async fn sleep(_req: Request) -> Response {
+    // 在内部睡眠 5 秒，模拟一次用户慢请求，需要注意的是，
+    // 我们并没有使用 std::thread::sleep 进行睡眠，原因是该函数是阻塞的，
+    // 它会让当前线程陷入睡眠中，导致其它任务无法继续运行！
+    // 因此我们需要一个睡眠函数 async_std::task::sleep，
+    // 它仅会让当前的任务陷入睡眠，然后该任务会让出线程的控制权，这样线程就可以继续运行其它任务。
+    // 光把函数变成 async 往往是不够的，还需要将它内部的代码也都变成异步兼容的，阻塞线程绝对是不可行的。
+    async_std::task::sleep(std::time::Duration::from_secs(5)).await;
+    html_file(StatusCode::OK, "hello.html")
+}
+
+// WebSocket 连接建立之后，服务器每秒主动推送一次运行时间，同时把客户端发来的文本原样发回去，
+// 浏览器不再需要轮询就能拿到实时的数据
+async fn ws(req: Request) -> Response {
+    websocket::upgrade(&req, |mut ws| {
+        Box::pin(async move {
+            let start = Instant::now();
+            let mut next_tick = start + Duration::from_secs(1);
+            loop {
+                // recv 可以被安全地取消，因此可以给它加上超时：到了推送的时间还没有收到消息，就先推送一次
+                let wait = next_tick.saturating_duration_since(Instant::now());
+                let reply = match async_std::future::timeout(wait, ws.recv()).await {
+                    Ok(Ok(Some(Message::Text(text)))) => Message::Text(text),
+                    Ok(Ok(Some(_))) => continue,
+                    // 连接关闭或者客户端违反了协议
+                    Ok(Ok(None)) | Ok(Err(_)) => return,
+                    Err(_) => {
+                        next_tick += Duration::from_secs(1);
+                        Message::Text(format!("uptime {}s", start.elapsed().as_secs()))
+                    }
+                };
+                if ws.send(reply).await.is_err() {
+                    return;
+                }
+            }
         })
-        .await;
+    })
+}
+
+// 长时间运行的任务通过 SSE 把进度推送给浏览器：
+//     const events = new EventSource("/job");
+//     events.addEventListener("progress", e => console.log(JSON.parse(e.data)));
+//     events.addEventListener("done", () => events.close());
+async fn job(_req: Request) -> Sse<impl Stream<Item = Event>> {
+    Sse(job_progress(10, Duration::from_millis(500)))
+}
+
+// 模拟一个分成 steps 步的任务，每完成一步推送一次进度。最后一个事件的类型是 done，
+// 浏览器收到后应该主动关闭 EventSource，否则它会在连接断开之后自动重连，任务又从头开始
+fn job_progress(steps: u32, step_time: Duration) -> impl Stream<Item = Event> {
+    stream::unfold(0, move |done| async move {
+        if done > steps {
+            return None;
+        }
+        if done > 0 {
+            async_std::task::sleep(step_time).await;
+        }
+        let kind = if done == steps { "done" } else { "progress" };
+        let event = Event::new()
+            .event(kind)
+            .id(done.to_string())
+            .data(format!(r#"{{"done":{done},"total":{steps}}}"#));
+        Some((event, done + 1))
+    })
+}
+
+async fn not_found(_req: Request) -> Response {
+    html_file(StatusCode::NOT_FOUND, "404.html")
+}
+
+fn html_file(status: StatusCode, filename: &str) -> Response {
+    match std::fs::read_to_string(filename) {
+        Ok(contents) => Response::new(status).with_html(contents),
+        Err(e) => Response::new(StatusCode::INTERNAL_SERVER_ERROR).with_text(e.to_string()),
+    }
 }
 
 // 该修改会将函数的返回值从 () 变成 Future<Output=()> ，因此直接运行将不再有任何效果，
 // 只用通过 .await 或执行器的 poll 调用后才能获取 Future 的结果。
-async fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await.unwrap();
-
-    let get = b"GET / HTTP/1.1\r\n";
+async fn handle_connection(stream: TcpStream, handler: &dyn Handler, config: &ServerConfig) {
     // 现在运行服务器，并访问 127.0.0.1:7878/sleep， 你会发现只有在完成第一个用户请求(5 秒后)，
     //  才能开始处理第二个用户请求 127.0.0.1:7878。现在再来看看该如何解决这个问题，让请求并发起来。
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        // 在内部睡眠 5 秒，模拟一次用户慢请求，需要注意的是，
-        // 我们并没有使用 std::thread::sleep 进行睡眠，原因是该函数是阻塞的，
-        // 它会让当前线程陷入睡眠中，导致其它任务无法继续运行！
-        // 因此我们需要一个睡眠函数 async_std::task::sleep，
-        // 它仅会让当前的任务陷入睡眠，然后该任务会让出线程的控制权，这样线程就可以继续运行其它任务。
-        // 光把函数变成 async 往往是不够的，还需要将它内部的代码也都变成异步兼容的，阻塞线程绝对是不可行的。
-        async_std::task::sleep(std::time::Duration::from_secs(5)).await;
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
-    };
-    let contents = std::fs::read_to_string(filename).unwrap();
 
-    let response = format!("{status_line}{contents}");
-    stream.write(response.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
+    // 在同一个连接上循环处理请求(keep-alive)，直到客户端关闭连接、空闲超时或者请求关闭连接
+    let peer_addr = stream.peer_addr().ok();
+    if let Err(e) = web_core::serve_connection_async(stream, peer_addr, handler, config).await {
+        println!("handle_connection error: {}", e);
+    }
+}
+
+// 连接数已经达到上限：返回 503 然后关闭连接
+async fn reject(mut stream: TcpStream) {
+    let _ = stream.write_all(&web_core::busy_response().to_bytes()).await;
 }
 
 // 在之前的代码中，我们使用了自己实现的简单的执行器来进行 .await 或 poll ，
 // 实际上这只是为了学习原理，在实际项目中，需要选择一个三方的 async 运行时来实现相关的功能。
 // 现在先选择 async-std ，该包的最大优点就是跟标准库的 API 类似，相对来说更简单易用。
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web_core::TestClient;
+
+    #[test]
+    fn serves_hello_page() {
+        let res = TestClient::new(app()).get("/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text(), std::fs::read_to_string("hello.html").unwrap());
+    }
+
+    #[test]
+    fn unknown_path_gets_404_page() {
+        let res = TestClient::new(app()).get("/missing");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.text(), std::fs::read_to_string("404.html").unwrap());
+    }
+
+    #[test]
+    fn job_progress_ends_with_done_event() {
+        let router = Router::new().get("/job", |_req: Request| async {
+            Sse(job_progress(2, Duration::ZERO))
+        });
+        let res = TestClient::new(router).get("/job");
+        assert_eq!(res.header("Content-Type"), Some("text/event-stream"));
+        assert_eq!(
+            res.text(),
+            "event: progress\nid: 0\ndata: {\"done\":0,\"total\":2}\n\n\
+             event: progress\nid: 1\ndata: {\"done\":1,\"total\":2}\n\n\
+             event: done\nid: 2\ndata: {\"done\":2,\"total\":2}\n\n"
+        );
+    }
+}
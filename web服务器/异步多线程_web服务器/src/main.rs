@@ -1,7 +1,17 @@
 use async_std::net::TcpListener;
 // use async_std::net::TcpStream;
-use async_std::prelude::*;
 use futures::stream::StreamExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tls::TlsConfig;
+use web_core::middleware::{AccessLog, Compression, Cors, RateLimit, RequestId};
+use web_core::{
+    ConnectionLimit, ConnectionPermit, Handler, InFlight, Pipeline, Request, Response, Router,
+    ServeDir, ServerConfig, Shutdown, StatusCode,
+};
+
+mod tls;
 
 // 之前的例子有一个致命的缺陷：只能使用一个线程并发的处理用户请求。
 // 是的，这样也可以实现并发，一秒处理几千次请求问题不大，
@@ -14,99 +24,186 @@ use futures::stream::StreamExt;
 #[async_std::main]
 async fn main() {
     let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
+    // 任务可能运行在任意线程上，且生命周期必须是 'static，因此处理管道要放到 Arc 中共享
+    let app = Arc::new(app());
+    // 设置了 TLS_CERT 和 TLS_KEY 时使用 HTTPS，证书有问题就直接退出，而不是悄悄地退回到明文的 HTTP
+    let tls = TlsConfig::from_env()
+        .map(|config| config.acceptor().expect("failed to load TLS certificate or key"));
+    // 收到 SIGINT/SIGTERM 时停止接受新连接，正在处理的请求处理完之后关闭连接
+    let shutdown = Shutdown::new();
+    shutdown
+        .trigger_on_signals()
+        .expect("failed to install signal handlers");
+    let config = Arc::new(ServerConfig {
+        shutdown: Some(shutdown.clone()),
+        ..ServerConfig::default()
+    });
+    // 每个连接任务持有一个 InFlightGuard，关闭时等待它们全部结束
+    let in_flight = InFlight::new();
+    // 限制同时处理的连接数，超出的连接立刻收到 503，而不是在队列中无限地等待
+    let limit = ConnectionLimit::new(config.max_connections);
     listener
         .incoming()
-        .for_each_concurrent(/* limit */ None, |stream| async move {
-            let stream = stream.unwrap();
-            // 至此，我们实现了同时使用并行(多线程)和并发( `async` )来同时处理多个请求！
-            async_std::task::spawn(handle_connection(stream));
+        // 关闭信号到来时 Stream 结束，不再接受新的连接
+        .take_until(shutdown.wait())
+        .for_each_concurrent(/* limit */ None, |stream| {
+            let app = Arc::clone(&app);
+            let config = Arc::clone(&config);
+            let tls = tls.clone();
+            let limit = limit.clone();
+            let guard = in_flight.guard();
+            async move {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("accept error: {}", e);
+                        return;
+                    }
+                };
+                let peer_addr = stream.peer_addr().ok();
+                let permit = limit.try_acquire();
+                // 至此，我们实现了同时使用并行(多线程)和并发( `async` )来同时处理多个请求！
+                async_std::task::spawn(async move {
+                    let _guard = guard;
+                    let Some(tls) = tls else {
+                        return serve(stream, peer_addr, permit, &*app, &config).await;
+                    };
+                    // 握手同样受读超时的限制
+                    match tls.accept(stream, config.read_timeout).await {
+                        Ok(stream) => serve(stream, peer_addr, permit, &*app, &config).await,
+                        Err(e) => println!("TLS handshake error: {}", e),
+                    }
+                });
+            }
         })
         .await;
-}
 
-// async fn handle_connection(mut stream: TcpStream)
-// 为了方便测试， 我们修改 handle_connection 的函数签名让测试更简单。
-// 之所以可以修改签名，原因在于 async_std::net::TcpStream 实际上并不是必须的，
-// 只要任何结构体实现了 async_std::io::Read, async_std::io::Write 和 marker::Unpin 就可以替代它。
-use async_std::io::{Read, Write};
-async fn handle_connection(mut stream: impl Read + Write + Unpin) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await.unwrap();
-
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        async_std::task::sleep(std::time::Duration::from_secs(5)).await;
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
+    // 任务已经被 spawn 出去了，for_each_concurrent 结束并不代表连接都处理完了
+    println!("Shutting down, waiting for {} connections.", in_flight.count());
+    if in_flight.drain(GRACE_PERIOD).await {
+        println!("All connections closed.");
     } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
-    };
-    let contents = std::fs::read_to_string(filename).unwrap();
+        println!("Grace period elapsed, shutting down anyway.");
+    }
+}
 
-    let response = format!("{status_line}{contents}");
-    stream.write(response.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
+/// 收到关闭信号之后，最多等待正在处理的连接多久
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// 中间件按添加的顺序执行：访问日志在最外层，能记录到被限流的请求和最终的状态码；
+// 压缩在最内层，直接处理路由返回的响应
+fn app() -> Pipeline {
+    Pipeline::new(router())
+        .with(AccessLog::stdout())
+        .with(RequestId::new())
+        .with(RateLimit::new(50.0, 100))
+        .with(Cors::any())
+        .with(Compression::new())
 }
 
-use futures::io::Error;
-use futures::task::{Context, Poll};
-use std::cmp::min;
-use std::pin::Pin;
-// 下面，来构建一个 mock 的 `TcpStream` 并实现了上面这些特征，
-// 它包含一些数据，这些数据将被拷贝到 `read` 缓存中, 然后返回 `Poll::Ready` 说明 `read` 已经结束：
-struct MockTcpStream {
-    read_data: Vec<u8>,
-    write_data: Vec<u8>,
+fn router() -> Router {
+    Router::new()
+        .get("/", hello)
+        .get("/sleep", sleep)
+        .get("/static/*path", ServeDir::new(static_dir()))
+        .fallback(not_found)
 }
 
-impl Read for MockTcpStream {
-    fn poll_read(
-        self: Pin<&mut Self>,
-        _: &mut Context,
-        buf: &mut [u8],
-    ) -> Poll<Result<usize, Error>> {
-        let size: usize = min(self.read_data.len(), buf.len());
-        buf[..size].copy_from_slice(&self.read_data[..size]);
-        Poll::Ready(Ok(size))
-    }
+// 静态文件的根目录可以通过环境变量 STATIC_DIR 配置，默认是当前目录下的 static 目录
+fn static_dir() -> String {
+    std::env::var("STATIC_DIR").unwrap_or_else(|_| "static".to_string())
+}
+
+async fn hello(_req: Request) -> Response {
+    html_file(StatusCode::OK, "hello.html")
+}
+
+async fn sleep(_req: Request) -> Response {
+    async_std::task::sleep(std::time::Duration::from_secs(5)).await;
+    html_file(StatusCode::OK, "hello.html")
 }
-impl Write for MockTcpStream {
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        _: &mut Context,
-        buf: &[u8],
-    ) -> Poll<Result<usize, Error>> {
-        self.write_data = Vec::from(buf);
-
-        Poll::Ready(Ok(buf.len()))
-    }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
-        Poll::Ready(Ok(()))
+async fn not_found(_req: Request) -> Response {
+    html_file(StatusCode::NOT_FOUND, "404.html")
+}
+
+fn html_file(status: StatusCode, filename: &str) -> Response {
+    match std::fs::read_to_string(filename) {
+        Ok(contents) => Response::new(status).with_html(contents),
+        Err(e) => Response::new(StatusCode::INTERNAL_SERVER_ERROR).with_text(e.to_string()),
     }
+}
 
-    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
-        Poll::Ready(Ok(()))
+// 拿到了名额就正常处理连接，否则返回 503。
+// 对于 HTTPS 连接，503 也要在 TLS 握手之后发送，否则客户端看到的只是一堆无法解密的数据。
+async fn serve(
+    mut stream: impl Read + Write + Unpin + Send,
+    peer_addr: Option<SocketAddr>,
+    permit: Option<ConnectionPermit>,
+    handler: &dyn Handler,
+    config: &ServerConfig,
+) {
+    match permit {
+        Some(_permit) => handle_connection(stream, peer_addr, handler, config).await,
+        None => {
+            let _ = stream.write_all(&web_core::busy_response().to_bytes()).await;
+            let _ = stream.close().await;
+        }
     }
 }
 
-#[async_std::test]
-async fn test_handle_connection() {
-    let input_bytes = b"GET / HTTP/1.1\r\n";
-    let mut contents = vec![0u8; 1024];
-    contents[..input_bytes.len()].clone_from_slice(input_bytes);
+// async fn handle_connection(mut stream: TcpStream)
+// 为了同时支持 TCP 和 TLS 连接，我们修改了 handle_connection 的函数签名。
+// 之所以可以修改签名，原因在于 async_std::net::TcpStream 实际上并不是必须的，
+// 只要任何结构体实现了 async_std::io::Read, async_std::io::Write 和 marker::Unpin 就可以替代它。
+use async_std::io::{Read, Write};
+use futures::AsyncWriteExt;
+async fn handle_connection(
+    mut stream: impl Read + Write + Unpin + Send,
+    peer_addr: Option<SocketAddr>,
+    handler: &dyn Handler,
+    config: &ServerConfig,
+) {
+    // 在同一个连接上循环处理请求(keep-alive)，直到客户端关闭连接、空闲超时或者请求关闭连接
+    if let Err(e) = web_core::serve_connection_async(&mut stream, peer_addr, handler, config).await
+    {
+        println!("handle_connection error: {}", e);
+    }
+    // 对于 TLS 连接，关闭时需要先发送 close_notify，否则客户端无法区分连接是正常结束还是被截断了
+    let _ = stream.close().await;
+}
 
-    let mut stream = MockTcpStream {
-        read_data: contents,
-        write_data: Vec::new(),
-    };
+// 之前这里手写了一个 MockTcpStream，把预先准备好的请求交给 handle_connection，
+// 再检查写出来的字节是不是以 `HTTP/1.1 200 OK` 开头。web_core 的测试客户端做的是同一件事，
+// 但会把响应解析成状态码、响应头和响应体，测试可以直接断言它们。
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web_core::{Method, TestClient};
+
+    #[test]
+    fn serves_hello_page() {
+        let res = TestClient::new(app()).get("/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert!(res.header("X-Request-Id").is_some());
+        assert_eq!(res.text(), std::fs::read_to_string("hello.html").unwrap());
+    }
 
-    handle_connection(&mut stream).await;
+    #[test]
+    fn unknown_path_gets_404_page() {
+        let res = TestClient::new(app()).get("/missing");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.text(), std::fs::read_to_string("404.html").unwrap());
+    }
 
-    let expected_contents = std::fs::read_to_string("hello.html").unwrap();
-    let expected_response = format!("HTTP/1.1 200 OK\r\n\r\n{}", expected_contents);
-    assert!(stream.write_data.starts_with(expected_response.as_bytes()));
+    #[test]
+    fn answers_cors_preflight() {
+        let preflight = Request::new(Method::Options, "/")
+            .with_header("Origin", "https://example.com")
+            .with_header("Access-Control-Request-Method", "POST");
+        let res = TestClient::new(app()).send(preflight);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.header("Access-Control-Allow-Origin"), Some("*"));
+    }
 }
@@ -0,0 +1,197 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::io::{Read, Write};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+// 明文的 HTTP 只适合在本机上调试，一旦服务器暴露到 localhost 之外，
+// 请求和响应(包括 Cookie、密码)都会在网络上被看得一清二楚，因此需要 TLS(也就是 HTTPS)。
+// TLS 位于 TCP 和 HTTP 之间：握手完成之后得到的加密流同样实现了 Read + Write + Unpin，
+// 因此 handle_connection 以及 web_core 中的连接处理循环都不需要做任何修改。
+//
+// rustls 有一个 tokio 版本的异步封装 tokio-rustls，而我们使用的是 async-std，
+// 两者的 AsyncRead/AsyncWrite 特征并不相同，tokio-util 的 compat 模块负责在两者之间转换。
+
+/// TLS 证书和私钥的路径，两者都是 PEM 格式。
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    /// 同时设置了环境变量 TLS_CERT 和 TLS_KEY 时启用 HTTPS，否则返回 `None`，使用明文的 HTTP。
+    pub fn from_env() -> Option<TlsConfig> {
+        let cert_path = std::env::var_os("TLS_CERT")?;
+        let key_path = std::env::var_os("TLS_KEY")?;
+        Some(TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        })
+    }
+
+    /// 读取证书和私钥，创建 `TlsAcceptor`。
+    pub fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let cert = std::fs::read(&self.cert_path)?;
+        let key = std::fs::read(&self.key_path)?;
+        TlsAcceptor::from_pem(&cert, &key)
+    }
+}
+
+/// 在已经建立的 TCP 连接上完成 TLS 握手。
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// `cert` 可以包含完整的证书链，第一个是服务器自己的证书。
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> io::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_data)?;
+        if certs.is_empty() {
+            return Err(invalid_data("no certificate found"));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(invalid_data)?;
+
+        // 显式指定使用 ring 作为加密库，而不是依赖进程级别的默认设置
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(invalid_data)?;
+        // 通过 ALPN 告诉客户端我们只支持 HTTP/1.1
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor {
+            inner: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// 完成 TLS 握手，返回加密之后的流。
+    ///
+    /// 握手最多等待 `timeout`，防止客户端建立连接之后什么都不发送，一直占用着连接。
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+        timeout: Duration,
+    ) -> io::Result<impl Read + Write + Unpin + use<S>>
+    where
+        S: Read + Write + Unpin,
+    {
+        let handshake = self.inner.accept(stream.compat());
+        let stream = async_std::future::timeout(timeout, handshake)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        Ok(stream.compat())
+    }
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::prelude::*;
+    use rustls::pki_types::ServerName;
+
+    // 每次测试都生成一个新的自签名证书，而不是把证书和私钥提交到仓库中
+    fn self_signed() -> rcgen::CertifiedKey {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap()
+    }
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn connector(cert: &rcgen::CertifiedKey) -> tokio_rustls::TlsConnector {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+    }
+
+    #[async_std::test]
+    async fn serves_https_with_certificate_from_config() {
+        let cert = self_signed();
+        let config = TlsConfig {
+            cert_path: write_temp("cert.pem", &cert.cert.pem()),
+            key_path: write_temp("key.pem", &cert.key_pair.serialize_pem()),
+        };
+        let acceptor = config.acceptor().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            let stream = acceptor
+                .accept(stream, Duration::from_secs(5))
+                .await
+                .unwrap();
+            let config = web_core::ServerConfig::default();
+            crate::handle_connection(stream, Some(peer_addr), &crate::app(), &config).await;
+        });
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector(&cert)
+            .connect(server_name, tcp.compat())
+            .await
+            .unwrap()
+            .compat();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let expected_contents = std::fs::read_to_string("hello.html").unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&expected_contents));
+
+        std::fs::remove_file(config.cert_path).unwrap();
+        std::fs::remove_file(config.key_path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn rejects_untrusted_certificate() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cert = self_signed();
+        let key = cert.key_pair.serialize_pem();
+        let acceptor = TlsAcceptor::from_pem(cert.cert.pem().as_bytes(), key.as_bytes()).unwrap();
+        async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(acceptor.accept(stream, Duration::from_secs(5)).await.is_err());
+        });
+
+        // 客户端只信任另一个证书，握手失败
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let result = connector(&self_signed()).connect(server_name, tcp.compat()).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn invalid_pem_is_an_error() {
+        assert!(TlsAcceptor::from_pem(b"not a certificate", b"not a key").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use mini_redis::{Connection, Frame};
+use my_redis2::{Command, Connection, Frame};
 use tokio::net::{TcpListener, TcpStream};
 
 #[tokio::main]
@@ -23,7 +23,8 @@ async fn main() {
 async fn process(socket: TcpStream) {
     // Connection 对于 redis 的读写进行了抽象封装，
     // 因此我们读到的是一个一个数据帧frame(数据帧 = redis命令 + 数据)，而不是字节流
-    // Connection 是在 mini-redis 中定义
+    // Connection 最早使用的是 mini-redis 中定义的，现在换成了共享状态章节中自己实现的版本，
+    // 帧的解析直接在读缓冲区上进行，不需要额外复制数据(见 my-redis-共享状态/src/frame.rs)
     let mut connection = Connection::new(socket);
 
     // 使用 hashmap 来存储 redis 的数据
@@ -37,16 +38,16 @@ async fn process(socket: TcpStream) {
     //     connection.write_frame(&response).await.unwrap();
     // }
     // 使用 `read_frame` 方法从连接获取一个数据帧：一条redis命令 + 相应的数据
-    use mini_redis::Command::{Get, Set};
+    use Command::{Get, Set};
     while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match mini_redis::Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
+        let response = match Command::from_frame(frame).unwrap() {
+            Set { key, value, .. } => {
                 // 值被存储为 `Vec<u8>` 的形式
-                db.insert(cmd.key().to_string(), cmd.value().to_vec());
+                db.insert(key, value.to_vec());
                 Frame::Simple("OK".to_string())
             }
-            Get(cmd) => {
-                if let Some(value) = db.get(cmd.key()) {
+            Get { key } => {
+                if let Some(value) = db.get(&key) {
                     // `Frame::Bulk` 期待数据的类型是 `Bytes`， 该类型会在后面章节讲解，
                     // 此时，你只要知道 `&Vec<u8>` 可以使用 `into()` 方法转换成 `Bytes` 类型
                     Frame::Bulk(value.clone().into())
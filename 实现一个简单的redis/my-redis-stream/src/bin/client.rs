@@ -34,6 +34,8 @@ async fn subscribe() -> mini_redis::Result<()> {
 
 // 先运行mini-redis服务器
 // $mini-redis-server
+// 或者运行共享状态章节中我们自己的服务器，它同样支持 PUBLISH/SUBSCRIBE
+// $cd ../my-redis-共享状态 && cargo run --bin server
 #[tokio::main]
 async fn main() -> mini_redis::Result<()> {
     tokio::spawn(async { publish().await });
@@ -0,0 +1,57 @@
+use bytes::Bytes;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use my_redis2::{Command, Db, DbDropGuard};
+
+// 比较不同分片数下，大量客户端同时访问数据库时的吞吐量：
+// 只有一个分片时所有命令都在竞争同一把锁，分片越多竞争越少。
+// 锁竞争只有在多个线程同时运行时才会出现，机器的 CPU 核数越多，差别越明显。
+//
+// cargo bench --bench contention
+
+/// 同时访问数据库的客户端个数，每个客户端是一个 tokio 任务
+const CLIENTS: usize = 64;
+/// 每个客户端执行的命令个数，一半 SET 一半 GET
+const COMMANDS_PER_CLIENT: usize = 1000;
+
+async fn run_clients(db: &Db) {
+    let tasks: Vec<_> = (0..CLIENTS)
+        .map(|client| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..COMMANDS_PER_CLIENT / 2 {
+                    // 每个客户端访问自己的 100 个键，不同客户端之间只有锁的竞争，没有数据的竞争
+                    let key = format!("client:{}:{}", client, i % 100);
+                    let value = Bytes::from_static(b"value");
+                    Command::Set {
+                        key: key.clone(),
+                        value,
+                        expire: None,
+                    }
+                    .apply(&db);
+                    Command::Get { key }.apply(&db);
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn contention(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("contention");
+    group.throughput(Throughput::Elements((CLIENTS * COMMANDS_PER_CLIENT) as u64));
+    for shards in [1, 4, 16, 64] {
+        // DbDropGuard 会启动后台任务，需要在运行时中创建
+        let guard = runtime.block_on(async { DbDropGuard::with_shards(shards) });
+        let db = guard.db();
+        group.bench_with_input(BenchmarkId::new("shards", shards), &db, |b, db| {
+            b.iter(|| runtime.block_on(run_clients(db)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, contention);
+criterion_main!(benches);
@@ -0,0 +1,381 @@
+use crate::frame::{DEFAULT_MAX_FRAME_SIZE, Frame, Protocol};
+use crate::rdb::Record;
+use crate::value::Value;
+
+use bytes::{Bytes, BytesMut};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+// AOF(append-only file)：每执行一个写命令，就把它追加到文件的末尾，
+// 启动时按顺序重新执行一遍文件中的命令，就恢复出了原来的数据。
+// 和快照相比，两次保存之间不会丢失数据，丢多少取决于多久 fsync 一次(见 `Fsync`)。
+//
+// 文件的内容就是 RESP 格式的命令，和客户端发送的一样，可以直接用文本编辑器查看。
+// 带相对过期时间的命令(SET EX、EXPIRE 等)会被改写成绝对时间(SET PXAT、PEXPIREAT)，
+// 否则重启时重新执行，过期时间就从重启的时刻重新开始计算了，见 `Command::propagate`。
+//
+// 文件只会越来越长：一个键被 INCR 一万次，文件中就有一万条命令。BGREWRITEAOF 根据
+// 数据库当前的内容重新生成一个最短的 AOF，每个键只需要一两条命令。重写在后台进行，
+// 这期间新的写命令除了照常追加到旧文件中，还会保存在一个缓冲区里，
+// 新文件写完之后把缓冲区追加到它的末尾，再原子地替换掉旧文件。
+
+/// 什么时候调用 fsync 把 AOF 刷到磁盘上。
+///
+/// 没有 fsync 时数据只是写进了操作系统的页缓存，进程崩溃不会丢失，但是机器断电会。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// 每个写命令都 fsync 之后才回复客户端，最安全也最慢
+    Always,
+    /// 后台任务每秒 fsync 一次，断电最多丢失一秒的数据
+    EverySec,
+    /// 从不主动 fsync，由操作系统决定什么时候写到磁盘上
+    No,
+}
+
+impl FromStr for Fsync {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> crate::Result<Fsync> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "no" => Ok(Fsync::No),
+            _ => Err(format!("invalid fsync policy '{}'", s).into()),
+        }
+    }
+}
+
+/// 读取 AOF 中的所有命令，文件不存在时返回 `None`。
+///
+/// 服务器在写命令的过程中崩溃时，文件的末尾可能只有半条命令，或者只有半个事务
+/// (MULTI 之后没有 EXEC)。这种情况下丢弃这半条命令或者整个事务，并把文件截断到
+/// 最后一条完整的命令，之后追加的命令才不会接在后面。文件中间的数据损坏时返回错误。
+///
+/// 返回的命令中不包括 MULTI 和 EXEC：启动时按顺序执行，事务的原子性只需要保证全有或者全无。
+pub fn read_file(path: &Path) -> crate::Result<Option<Vec<Frame>>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (commands, len) = decode(&data)?;
+    if len < data.len() {
+        eprintln!(
+            "aof: {} is truncated, discarding the last {} bytes",
+            path.display(),
+            data.len() - len
+        );
+        OpenOptions::new().write(true).open(path)?.set_len(len as u64)?;
+    }
+    Ok(Some(commands))
+}
+
+/// 解析 AOF 的内容，返回所有完整的命令，以及这些命令占用的字节数。
+fn decode(data: &[u8]) -> crate::Result<(Vec<Frame>, usize)> {
+    let mut buf = BytesMut::from(data);
+    let mut commands = vec![];
+    // 还没有看到 EXEC 的 MULTI：它之前有多少条命令，以及它在文件中的位置
+    let mut multi = None;
+    loop {
+        let start = data.len() - buf.len();
+        // 文件中只有 `Aof::append` 写入的命令数组，不接受内联命令
+        if !buf.is_empty() && buf[0] != b'*' {
+            return Err(format!("aof: invalid data at offset {}: expected a command", start).into());
+        }
+        match Frame::parse(&mut buf, DEFAULT_MAX_FRAME_SIZE) {
+            Ok(Some(Frame::Array(items))) => match items.first() {
+                Some(Frame::Bulk(name)) if name.eq_ignore_ascii_case(b"multi") => {
+                    multi = Some((commands.len(), start));
+                }
+                Some(Frame::Bulk(name)) if name.eq_ignore_ascii_case(b"exec") => multi = None,
+                _ => commands.push(Frame::Array(items)),
+            },
+            Ok(Some(_)) => return Err(format!("aof: invalid command at offset {}", start).into()),
+            Ok(None) => match multi {
+                Some((len, start)) => {
+                    commands.truncate(len);
+                    return Ok((commands, start));
+                }
+                None => return Ok((commands, start)),
+            },
+            Err(e) => {
+                return Err(format!("aof: invalid data at offset {}: {}", start, e).into());
+            }
+        }
+    }
+}
+
+/// 生成能够重建出这些键的最短的命令序列，用于重写 AOF。
+pub fn encode(records: &[Record]) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    let mut push = |frame: Frame| frame.encode(Protocol::Resp2, &mut buf);
+    for record in records {
+        let key = Bytes::from(record.key.clone());
+        let with_key = |args: Vec<Bytes>| iter::once(key.clone()).chain(args);
+        match &record.value {
+            Value::String(s) => push(command("SET", [key.clone(), s.clone()])),
+            // 元素很多时分成多条命令，避免产生一个特别大的帧
+            Value::List(list) => {
+                let items: Vec<Bytes> = list.iter().cloned().collect();
+                for chunk in items.chunks(ITEMS_PER_COMMAND) {
+                    push(command("RPUSH", with_key(chunk.to_vec())));
+                }
+            }
+            Value::Hash(hash) => {
+                let pairs: Vec<_> = hash.iter().collect();
+                for chunk in pairs.chunks(ITEMS_PER_COMMAND) {
+                    let args = chunk.iter().flat_map(|(f, v)| [(*f).clone(), (*v).clone()]);
+                    push(command("HSET", with_key(args.collect())));
+                }
+            }
+            Value::Set(set) => {
+                let members: Vec<Bytes> = set.iter().cloned().collect();
+                for chunk in members.chunks(ITEMS_PER_COMMAND) {
+                    push(command("SADD", with_key(chunk.to_vec())));
+                }
+            }
+            Value::ZSet(zset) => {
+                let members: Vec<_> = zset.iter().collect();
+                for chunk in members.chunks(ITEMS_PER_COMMAND) {
+                    let args = chunk
+                        .iter()
+                        .flat_map(|(m, score)| [Bytes::from(score.to_string()), (*m).clone()]);
+                    push(command("ZADD", with_key(args.collect())));
+                }
+            }
+        }
+        if let Some(when) = record.expires_at {
+            let millis = when.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+            push(command("PEXPIREAT", [key, Bytes::from(millis.to_string())]));
+        }
+    }
+    buf.into()
+}
+
+/// 重写时一条命令最多包含的元素个数
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// 由命令名和参数组成一个命令帧，和客户端发送的命令一样是一个 bulk 数组。
+pub(crate) fn command(name: &'static str, args: impl IntoIterator<Item = Bytes>) -> Frame {
+    let mut frames = vec![Frame::Bulk(Bytes::from_static(name.as_bytes()))];
+    frames.extend(args.into_iter().map(Frame::Bulk));
+    Frame::Array(frames)
+}
+
+/// 正在写入的 AOF。
+#[derive(Debug)]
+pub(crate) struct Aof {
+    path: PathBuf,
+    fsync: Fsync,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    /// 上一次 fsync 之后有没有写入新的命令
+    unsynced: bool,
+    /// 正在重写时，新的写命令同时保存在这里，重写完成后追加到新文件的末尾
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl Aof {
+    /// 打开 AOF 准备追加，文件不存在时创建一个空文件。
+    pub(crate) fn open(path: PathBuf, fsync: Fsync) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Aof {
+            path,
+            fsync,
+            state: Mutex::new(State {
+                file,
+                unsynced: false,
+                rewrite_buffer: None,
+            }),
+        })
+    }
+
+    /// 追加编码好的写命令(一条命令或者一个事务)。`Fsync::Always` 时写到磁盘上之后才返回。
+    ///
+    /// 调用者需要持有命令访问的键所在分片的锁：同一个键上的命令在文件中的顺序
+    /// 必须和它们执行的顺序一样，否则重新执行时会得到不同的结果。
+    pub(crate) fn append(&self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.file.write_all(data)?;
+        if let Some(rewrite_buffer) = &mut state.rewrite_buffer {
+            rewrite_buffer.extend_from_slice(data);
+        }
+        match self.fsync {
+            Fsync::Always => state.file.sync_data(),
+            Fsync::EverySec | Fsync::No => {
+                state.unsynced = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// 有新的写入时 fsync 一次，`Fsync::EverySec` 的后台任务每秒调用一次。
+    ///
+    /// fsync 可能要花很长时间，期间不能一直持有锁，否则所有的写命令都要等它。
+    /// 这里复制一个文件描述符，在锁外面 fsync。
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        let file = {
+            let mut state = self.state.lock().unwrap();
+            if !state.unsynced {
+                return Ok(());
+            }
+            state.unsynced = false;
+            state.file.try_clone()?
+        };
+        file.sync_data()
+    }
+
+    /// 开始重写。调用者需要锁住数据库的所有分片，保证从这一刻开始的写命令
+    /// 都会进入缓冲区，而在这之前的写命令都已经反映在数据库的内容中。
+    pub(crate) fn start_rewrite(&self) -> crate::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.rewrite_buffer.is_some() {
+            return Err("Background append only file rewriting already in progress".into());
+        }
+        state.rewrite_buffer = Some(vec![]);
+        Ok(())
+    }
+
+    /// 把 `records` 写成一个新的 AOF，替换掉原来的文件。
+    pub(crate) fn finish_rewrite(&self, records: &[Record]) -> crate::Result<()> {
+        let tmp = self.path.with_extension(format!("rewrite-{}", std::process::id()));
+        let result = self.rewrite_into(&tmp, records);
+        if result.is_err() {
+            self.state.lock().unwrap().rewrite_buffer = None;
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    fn rewrite_into(&self, tmp: &Path, records: &[Record]) -> crate::Result<()> {
+        // 大部分的内容在锁外面写，这期间的写命令进入缓冲区
+        let mut file = BufWriter::new(File::create(tmp)?);
+        file.write_all(&encode(records))?;
+        file.flush()?;
+
+        let mut state = self.state.lock().unwrap();
+        let buffer = state.rewrite_buffer.take().unwrap_or_default();
+        let mut file = file.into_inner().map_err(|e| e.into_error())?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)?;
+        // 之后的命令追加到新文件中。旧文件已经被替换掉了，原来的文件描述符不再使用
+        state.file = OpenOptions::new().append(true).open(&self.path)?;
+        state.unsynced = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::SortedSet;
+    use std::time::Duration;
+
+    fn frames(data: &[u8]) -> Vec<String> {
+        decode(data).unwrap().0.iter().map(Frame::to_string).collect()
+    }
+
+    #[test]
+    fn truncated_tail_is_ignored() {
+        let mut data = BytesMut::new();
+        command("SET", [Bytes::from("k"), Bytes::from("v")]).encode(Protocol::Resp2, &mut data);
+        let complete = data.len();
+        data.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nk");
+        assert_eq!(decode(&data).unwrap().1, complete);
+        assert_eq!(frames(&data), ["SET k v"]);
+
+        let err = decode(b"*1\r\n$3\r\nDEL\r\n?garbage\r\n").unwrap_err().to_string();
+        assert!(err.starts_with("aof: invalid data at offset 13"), "{}", err);
+    }
+
+    #[test]
+    fn unfinished_transactions_are_discarded() {
+        let encode = |args: &[&str]| {
+            let args = args.iter().map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())));
+            let mut buf = BytesMut::new();
+            Frame::Array(args.collect()).encode(Protocol::Resp2, &mut buf);
+            buf
+        };
+        let mut data = BytesMut::new();
+        let committed = [&["MULTI"][..], &["SET", "a", "1"], &["INCR", "n"], &["EXEC"]];
+        for args in committed.into_iter().chain([&["SET", "b", "2"][..]]) {
+            data.extend_from_slice(&encode(args));
+        }
+        let complete = data.len();
+        data.extend_from_slice(&encode(&["MULTI"]));
+        data.extend_from_slice(&encode(&["SET", "c", "3"]));
+        // 崩溃发生在事务的第二条命令写到一半时
+        data.extend_from_slice(&encode(&["INCR", "n"])[..10]);
+        assert_eq!(decode(&data).unwrap().1, complete);
+        assert_eq!(frames(&data), ["SET a 1", "INCR n", "SET b 2"]);
+    }
+
+    #[test]
+    fn rewrite_generates_commands_for_every_type() {
+        let b = |s: &str| Bytes::copy_from_slice(s.as_bytes());
+        let mut zset = SortedSet::new();
+        zset.insert(b("m"), 2.5);
+        let records = [
+            Record {
+                key: "s".into(),
+                value: Value::String(b("v")),
+                expires_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_000)),
+            },
+            Record {
+                key: "l".into(),
+                value: Value::List((0..100).map(|i| Bytes::from(i.to_string())).collect()),
+                expires_at: None,
+            },
+            Record {
+                key: "z".into(),
+                value: Value::ZSet(zset),
+                expires_at: None,
+            },
+        ];
+        let commands = frames(&encode(&records));
+        assert_eq!(commands.len(), 5);
+        assert_eq!(commands[0], "SET s v");
+        assert_eq!(commands[1], "PEXPIREAT s 1700000000000");
+        // 100 个元素分成了两条 RPUSH
+        assert!(commands[2].starts_with("RPUSH l 0 1 "));
+        assert!(commands[3].starts_with("RPUSH l 64 65 "));
+        assert_eq!(commands[4], "ZADD z 2.5 m");
+    }
+
+    #[test]
+    fn writes_during_a_rewrite_are_kept() {
+        let path = std::env::temp_dir().join(format!("my-redis2-{}-unit.aof", std::process::id()));
+        let aof = Aof::open(path.clone(), Fsync::No).unwrap();
+        let set = |value: &'static str| {
+            let mut buf = BytesMut::new();
+            let frame = command("SET", [Bytes::from("k"), Bytes::from(value)]);
+            frame.encode(Protocol::Resp2, &mut buf);
+            buf
+        };
+        aof.append(&set("1")).unwrap();
+        aof.start_rewrite().unwrap();
+        assert!(aof.start_rewrite().is_err());
+        aof.append(&set("2")).unwrap();
+
+        let records = [Record {
+            key: "k".into(),
+            value: Value::String(Bytes::from("1")),
+            expires_at: None,
+        }];
+        aof.finish_rewrite(&records).unwrap();
+        aof.append(&set("3")).unwrap();
+        let commands = frames(&fs::read(&path).unwrap());
+        assert_eq!(commands, ["SET k 1", "SET k 2", "SET k 3"]);
+        fs::remove_file(path).unwrap();
+    }
+}
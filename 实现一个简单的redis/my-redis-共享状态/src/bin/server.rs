@@ -1,12 +1,23 @@
-use bytes::Bytes;
-use mini_redis::{Connection, Frame};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use tokio::net::{TcpListener, TcpStream};
+use my_redis2::replication::serve_replica;
+use my_redis2::{Command, Config, Connection, Db, DbDropGuard, Frame, Subscriber, Transaction};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
 
-// cargo run --bin server
+// cargo run --bin server [-- --shards 16 --dbfilename dump.rdb --save 60 --appendonly yes]
+// 从服务器：cargo run --bin server -- --port 6380 --replicaof 127.0.0.1:6379
+// 用作缓存：cargo run --bin server -- --maxmemory 100mb --maxmemory-policy allkeys-lru
+// 集群的一个节点(每个节点使用同样的 --cluster 和各自的 --bind、--port)：
+//   cargo run --bin server -- --port 7000 --cluster 127.0.0.1:7000,127.0.0.1:7001,127.0.0.1:7002
 #[tokio::main]
 async fn main() {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     // 上一章节中，咱们搭建了一个异步的 redis 服务器，并成功的提供了服务，但是其隐藏了一个
     // 巨大的问题：状态(数据)无法在多个连接之间共享，下面一起来看看该如何解决。
     // 好在 Tokio 十分强大，上面问题对应的解决方法也不止一种：
@@ -17,55 +28,123 @@ async fn main() {
     // 由于我们使用的数据存储类型是 HashMap，使用到的 相关操作是 insert 和 get ，
     // 又因为这两个操作都不是异步的，因此只要使用 Mutex 即可解决问题。
 
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
+    let listener = TcpListener::bind((config.bind.as_str(), config.port)).await.unwrap();
     println!("Listening");
 
-    let db = Arc::new(Mutex::new(HashMap::new()));
+    // Db 内部是若干个分片，每个分片是一个 Mutex<HashMap>，
+    // 同时还会启动一个后台任务删除过期的键，db_guard 被 drop 时这个任务随之退出
+    let db_guard = DbDropGuard::with_config(&config);
+    let db = db_guard.db();
+    // 读取上一次保存的数据(开启了 AOF 时读取 AOF，否则读取快照)。文件损坏时拒绝启动，
+    // 而不是带着一个空的数据库运行、然后在下一次保存时把原来的文件覆盖掉
+    let file = match config.appendonly {
+        true => &config.appendfilename,
+        false => &config.dbfilename,
+    };
+    match db.load() {
+        Ok(loaded) => println!("Loaded {} keys from {}", loaded, file.display()),
+        Err(e) => {
+            eprintln!("failed to load {}: {}", file.display(), e);
+            std::process::exit(1);
+        }
+    }
+    // 从服务器在后台连接主服务器，同步完成之前先使用上面读到的数据
+    if config.replicaof.is_some() {
+        db.replicaof(config.replicaof.clone());
+    }
     // 我们使用了 std::sync::Mutex 来保护 HashMap，而不是使用 tokio::sync::Mutex。
     // 在使用 Tokio 编写异步代码时，一个常见的错误无条件地使用 tokio::sync::Mutex ，
     // 而真相是：Tokio 提供的异步锁只应该在跨多个 .await调用时使用，
     // 而且 Tokio 的 Mutex 实际上内部使用的也是 std::sync::Mutex。
 
+    tokio::select! {
+        _ = accept(listener, db.clone(), config.max_frame_size) => {}
+        _ = tokio::signal::ctrl_c() => {
+            // 配置了定期保存时，和 Redis 一样在退出之前再保存一次，不丢失最后一个周期的修改
+            if config.save.is_some()
+                && let Err(e) = db.save()
+            {
+                eprintln!("failed to save before exiting: {}", e);
+            }
+            if let Err(e) = db.sync_aof() {
+                eprintln!("failed to fsync the AOF before exiting: {}", e);
+            }
+        }
+    }
+}
+
+async fn accept(listener: TcpListener, db: Db, max_frame_size: usize) {
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+        let connection = Connection::with_max_frame_size(socket, max_frame_size);
 
         // 将 handle 克隆一份
         let db = db.clone();
         tokio::spawn(async move {
-            process(socket, db).await;
+            // 连接出错(例如客户端发送了格式错误的数据)只影响这一个连接
+            if let Err(e) = process(connection, db, addr).await {
+                println!("connection error: {}", e);
+            }
         });
     }
 }
 
-// 类型别名，简化类型定义
-type Db = Arc<Mutex<HashMap<String, Bytes>>>;
-// 在上一节中，我们使用 Vec<u8> 来保存目标数据，
-// 但是它有一个问题，对它进行克隆时会将底层数据也整个复制一份，效率很低
-// Bytes 是一个引用计数类型，跟 Arc 非常类似，或者准确的说，
-// Bytes 就是基于 Arc 实现的，但相比后者Bytes 提供了一些额外的能力。
-async fn process(socket: TcpStream, db: Db) {
-    use mini_redis::Command::{self, Get, Set};
-
-    let mut connection = Connection::new(socket);
+// Db 的定义(以及为什么使用 Bytes 而不是 Vec<u8>)见 db.rs
+async fn process(mut connection: Connection, db: Db, addr: SocketAddr) -> my_redis2::Result<()> {
+    // MULTI 之后排队的命令和 WATCH 的键，连接断开时随之丢弃
+    let mut transaction = Transaction::new(db.clone());
+    // 对端是从服务器时，它通过 REPLCONF 告诉我们的监听端口
+    let mut replica_port = None;
+    // 集群模式下上一个命令是 ASKING，只对紧接着的一个命令有效
+    let mut asking = false;
 
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                let mut db = db.lock().unwrap();
-                db.insert(cmd.key().to_string(), cmd.value().clone());
-                Frame::Simple("OK".to_string())
+    loop {
+        let frame = match connection.read_frame().await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            // 格式错误或者太大的帧：和 Redis 一样先回复错误再断开连接，让客户端知道出了什么问题
+            Err(e) => {
+                let _ = connection.write_frame(&Frame::Error(format!("ERR {}", e))).await;
+                return Err(e);
+            }
+        };
+        let was_asking = std::mem::take(&mut asking);
+        // 不认识的命令、参数不对的命令都回复一个错误，而不是 panic 让整个连接任务崩溃
+        let response = match Command::from_frame(frame) {
+            // 订阅之后连接进入订阅状态，直到取消了所有订阅。事务中的订阅命令由 `Transaction` 拒绝
+            Ok(cmd) if cmd.is_subscription() && !transaction.is_queuing() => {
+                Subscriber::new(db.clone()).run(&mut connection, cmd).await?;
+                continue;
+            }
+            // HELLO 切换的是这个连接的协议版本，之后的回复都按新的版本编码
+            Ok(Command::Hello { protover }) if !transaction.is_queuing() => {
+                connection.hello(protover, &db)
+            }
+            Ok(Command::ReplConf {
+                listening_port: Some(port),
+                ..
+            }) if !transaction.is_queuing() => {
+                replica_port = Some(port);
+                Frame::ok()
+            }
+            // PSYNC 之后这个连接就只用来向从服务器转发写命令了
+            Ok(Command::PSync { replid, offset }) if !transaction.is_queuing() => {
+                let addr = SocketAddr::new(addr.ip(), replica_port.unwrap_or(addr.port()));
+                return serve_replica(&mut connection, &db, addr, &replid, offset).await;
             }
-            Get(cmd) => {
-                let db = db.lock().unwrap();
-                if let Some(value) = db.get(cmd.key()) {
-                    Frame::Bulk(value.clone())
-                } else {
-                    Frame::Null
-                }
+            Ok(Command::Asking) if !transaction.is_queuing() => {
+                let response = Command::Asking.apply(&db);
+                asking = response == Frame::ok();
+                response
             }
-            cmd => panic!("unimplemented {:?}", cmd),
+            // 集群模式下键不在这个节点上时回复 MOVED/ASK，事务中的命令在排队时检查
+            Ok(cmd) => match db.redirect(&cmd, was_asking) {
+                Some(redirect) => transaction.reject(redirect),
+                None => transaction.apply(cmd),
+            },
+            Err(e) => transaction.reject(e.to_string()),
         };
 
-        connection.write_frame(&response).await.unwrap();
+        connection.write_frame(&response).await?;
     }
 }
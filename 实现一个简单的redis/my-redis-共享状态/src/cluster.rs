@@ -0,0 +1,609 @@
+use crate::parse::{Parse, ParseError};
+use crate::{Command, Config, Db, Frame};
+
+use bytes::Bytes;
+use crc::{CRC_16_XMODEM, CRC_64_REDIS, Crc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// 集群模式：一个服务器装不下、处理不过来的时候，把键分散到多个服务器(节点)上。
+//
+// 和 tokio_hello 中 `_split_lock` 的 `hash(key) % 分片数` 不同，键不直接映射到节点，
+// 而是和 Redis Cluster 一样先映射到固定的 16384 个哈希槽(`CRC16(key) % 16384`)，
+// 每个槽再分配给某一个节点。增加或者减少节点时只需要把一部分槽连同其中的键迁移到别的节点，
+// 其它的键不受影响。
+//
+// 客户端可以连接任意一个节点，键不在这个节点上时回复重定向，由客户端重新发给正确的节点：
+// - `MOVED 槽 地址`：槽属于另一个节点，客户端应该更新自己的槽表，之后直接发给那个节点
+// - `ASK 槽 地址`：槽正在迁移，要访问的键已经不在这里了，客户端先发 ASKING、
+//   再把这一个命令发给迁移的目标节点，但是不修改槽表，之后的命令还是先发到这里
+// 一个命令的所有键必须在同一个槽中，否则回复 CROSSSLOT。键名中 `{...}` 的部分(hash tag)
+// 才参与计算，`{user1000}.following` 和 `{user1000}.followers` 总是在同一个槽中。
+//
+// 这里的集群比 Redis 简单得多：所有的节点在启动时通过 `--cluster` 得到同一份节点列表，
+// 槽按顺序平均分给每个节点。节点之间没有 gossip，也没有故障转移，迁移槽时需要对每个节点
+// 执行 CLUSTER SETSLOT，和 redis-cli --cluster reshard 做的事情一样：
+//
+//   目标节点：CLUSTER SETSLOT 槽 IMPORTING 源节点的 id
+//   源节点：  CLUSTER SETSLOT 槽 MIGRATING 目标节点的 id
+//   源节点：  CLUSTER GETKEYSINSLOT 槽 数量，对每个键 DUMP，
+//             在目标节点上 ASKING + RESTORE 键 0 内容，再回到源节点 DEL
+//   所有节点：CLUSTER SETSLOT 槽 NODE 目标节点的 id
+//
+// Redis 的 MIGRATE 在一个命令中完成一个键的 DUMP/RESTORE/DEL，迁移期间服务器是阻塞的。
+// 这里分成了三个命令，DUMP 之后、DEL 之前对这个键的写命令会丢失，迁移时应该暂停写入。
+
+/// 哈希槽的个数，和 Redis Cluster 一样。
+pub const SLOTS: u16 = 16384;
+
+/// Redis Cluster 使用的 CRC16(XMODEM)。
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+
+const CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+/// 键所在的哈希槽。
+///
+/// 键名中第一个 `{` 和它之后第一个 `}` 之间的内容不为空时，只有这部分参与计算。
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        let close = rest.iter().position(|&b| b == b'}')?;
+        (close > 0).then(|| &rest[..close])
+    });
+    CRC16.checksum(tag.unwrap_or(key)) % SLOTS
+}
+
+/// 集群中的一个节点。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// 40 个十六进制字符，CLUSTER SETSLOT 等命令用它来指定节点
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Node {
+    /// Redis 的节点 id 是随机生成的，再通过 gossip 告诉其它节点。这里没有 gossip，
+    /// id 由地址计算出来，所有节点从同一份节点列表中得到同样的 id。
+    fn new(host: String, port: u16) -> Node {
+        let addr = format!("{}:{}", host, port);
+        let id: String = (0..3)
+            .map(|i| format!("{:016x}", CRC64.checksum(format!("{}#{}", addr, i).as_bytes())))
+            .collect();
+        Node {
+            id: id[..40].to_string(),
+            host,
+            port,
+        }
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// CLUSTER 的子命令。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Subcommand {
+    /// 每一段连续的槽属于哪个节点
+    Slots,
+    /// 每个节点的信息，和 Redis 的 nodes.conf 格式相同
+    Nodes,
+    Info,
+    MyId,
+    KeySlot { key: Bytes },
+    CountKeysInSlot { slot: u16 },
+    GetKeysInSlot { slot: u16, count: usize },
+    SetSlot { slot: u16, state: SlotState },
+}
+
+/// CLUSTER SETSLOT 的参数。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlotState {
+    /// 槽正在迁移到这个节点，源节点上执行
+    Migrating(String),
+    /// 槽正在从这个节点迁移过来，目标节点上执行
+    Importing(String),
+    /// 取消迁移
+    Stable,
+    /// 槽属于这个节点，迁移完成之后在所有节点上执行
+    Node(String),
+}
+
+impl Subcommand {
+    pub(crate) fn parse(parse: &mut Parse) -> Result<Subcommand, ParseError> {
+        let subcommand = match parse.next_string()?.to_lowercase().as_str() {
+            "slots" => Subcommand::Slots,
+            "nodes" => Subcommand::Nodes,
+            "info" => Subcommand::Info,
+            "myid" => Subcommand::MyId,
+            "keyslot" => Subcommand::KeySlot {
+                key: parse.next_bytes()?,
+            },
+            "countkeysinslot" => Subcommand::CountKeysInSlot { slot: slot(parse)? },
+            "getkeysinslot" => Subcommand::GetKeysInSlot {
+                slot: slot(parse)?,
+                count: usize::try_from(parse.next_int()?)
+                    .map_err(|_| "ERR Invalid number of keys")?,
+            },
+            "setslot" => {
+                let slot = slot(parse)?;
+                let state = match parse.next_string()?.to_lowercase().as_str() {
+                    "migrating" => SlotState::Migrating(parse.next_string()?),
+                    "importing" => SlotState::Importing(parse.next_string()?),
+                    "stable" => SlotState::Stable,
+                    "node" => SlotState::Node(parse.next_string()?),
+                    _ => {
+                        let msg = "ERR Invalid CLUSTER SETSLOT action or number of arguments";
+                        return Err(msg.into());
+                    }
+                };
+                Subcommand::SetSlot { slot, state }
+            }
+            sub => return Err(format!("ERR unknown subcommand '{}'", sub).into()),
+        };
+        Ok(subcommand)
+    }
+}
+
+fn slot(parse: &mut Parse) -> Result<u16, ParseError> {
+    match parse.next_int()? {
+        slot @ 0..16384 => Ok(slot as u16),
+        _ => Err("ERR Invalid or out of range slot".into()),
+    }
+}
+
+/// 集群的节点列表和每个槽的归属，`--cluster` 开启集群模式时由 `Db` 持有。
+#[derive(Debug)]
+pub(crate) struct Cluster {
+    nodes: Vec<Node>,
+    /// 这个服务器在 `nodes` 中的下标
+    myself: usize,
+    slots: Mutex<Slots>,
+}
+
+#[derive(Debug)]
+struct Slots {
+    /// 每个槽属于哪个节点(`nodes` 中的下标)
+    owners: Vec<usize>,
+    /// 正在从这里迁移出去的槽和目标节点
+    migrating: HashMap<u16, usize>,
+    /// 正在迁移到这里的槽和源节点
+    importing: HashMap<u16, usize>,
+}
+
+impl Cluster {
+    /// 没有配置 `--cluster` 时返回 `None`。
+    pub(crate) fn new(config: &Config) -> Option<Cluster> {
+        let addrs = config.cluster.as_ref()?;
+        let nodes: Vec<Node> = addrs
+            .iter()
+            .map(|(host, port)| Node::new(host.clone(), *port))
+            .collect();
+        // `Config::from_args` 已经检查过节点列表中恰好有一个是这个服务器
+        let myself = nodes
+            .iter()
+            .position(|node| node.host == config.bind && node.port == config.port)
+            .expect("the cluster nodes should include this server");
+        // 和 redis-cli --cluster create 一样，每个节点分到的槽数四舍五入：
+        // 三个节点时是 0-5460、5461-10922 和 10923-16383
+        let n = nodes.len();
+        let start = |i: usize| (2 * i * SLOTS as usize + n) / (2 * n);
+        let owners = (0..n)
+            .flat_map(|i| std::iter::repeat_n(i, start(i + 1) - start(i)))
+            .collect();
+        Some(Cluster {
+            nodes,
+            myself,
+            slots: Mutex::new(Slots {
+                owners,
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+            }),
+        })
+    }
+
+    /// 命令的键不在这个节点上时返回重定向的错误，`asking` 表示这个连接刚刚执行了 ASKING。
+    ///
+    /// 不访问键的命令总是在这个节点上执行。
+    pub(crate) fn redirect(&self, db: &Db, keys: &[&str], asking: bool) -> Option<String> {
+        let (first, rest) = keys.split_first()?;
+        let slot = key_slot(first.as_bytes());
+        if rest.iter().any(|key| key_slot(key.as_bytes()) != slot) {
+            return Some("CROSSSLOT Keys in request don't hash to the same slot".to_string());
+        }
+        let slots = self.slots.lock().unwrap();
+        let owner = slots.owners[slot as usize];
+        if owner != self.myself {
+            // 迁移过程中，ASK 重定向过来的命令在目标节点上执行
+            if asking && slots.importing.contains_key(&slot) {
+                return None;
+            }
+            return Some(format!("MOVED {} {}", slot, self.nodes[owner].addr()));
+        }
+        let target = *slots.migrating.get(&slot)?;
+        drop(slots);
+        // 槽正在迁移出去：键还在这里就在这里执行，已经迁移走的键让客户端去目标节点
+        let mut guard = db.lock(keys.iter().copied());
+        let missing = keys.iter().filter(|key| !guard.contains_key(key)).count();
+        match missing {
+            0 => None,
+            n if n == keys.len() => Some(format!("ASK {} {}", slot, self.nodes[target].addr())),
+            // 一部分键在这里、一部分在目标节点，哪边都执行不了，等迁移完了再试
+            _ => Some("TRYAGAIN Multiple keys request during rehashing of slot".to_string()),
+        }
+    }
+
+    pub(crate) fn execute(&self, db: &Db, subcommand: Subcommand) -> Frame {
+        match subcommand {
+            Subcommand::Slots => self.slots(),
+            Subcommand::Nodes => Frame::Bulk(Bytes::from(self.nodes())),
+            Subcommand::Info => Frame::Bulk(Bytes::from(self.info())),
+            Subcommand::MyId => Frame::Bulk(Bytes::from(self.nodes[self.myself].id.clone())),
+            Subcommand::KeySlot { key } => Frame::Integer(key_slot(&key) as i64),
+            Subcommand::CountKeysInSlot { slot } => {
+                Frame::Integer(keys_in_slot(db, slot, usize::MAX).len() as i64)
+            }
+            Subcommand::GetKeysInSlot { slot, count } => Frame::Array(
+                keys_in_slot(db, slot, count)
+                    .into_iter()
+                    .map(|key| Frame::Bulk(Bytes::from(key)))
+                    .collect(),
+            ),
+            Subcommand::SetSlot { slot, state } => match self.set_slot(db, slot, state) {
+                Ok(()) => Frame::ok(),
+                Err(msg) => Frame::Error(msg),
+            },
+        }
+    }
+
+    fn set_slot(&self, db: &Db, slot: u16, state: SlotState) -> Result<(), String> {
+        let node = |id: &str| {
+            self.nodes
+                .iter()
+                .position(|node| node.id == id)
+                .ok_or_else(|| format!("ERR I don't know about node {}", id))
+        };
+        let mut slots = self.slots.lock().unwrap();
+        let owner = slots.owners[slot as usize];
+        match state {
+            SlotState::Migrating(id) => {
+                let target = node(&id)?;
+                if owner != self.myself {
+                    return Err(format!("ERR I'm not the owner of hash slot {}", slot));
+                }
+                slots.migrating.insert(slot, target);
+            }
+            SlotState::Importing(id) => {
+                let source = node(&id)?;
+                if owner == self.myself {
+                    return Err(format!("ERR I'm already the owner of hash slot {}", slot));
+                }
+                slots.importing.insert(slot, source);
+            }
+            SlotState::Stable => {
+                slots.migrating.remove(&slot);
+                slots.importing.remove(&slot);
+            }
+            SlotState::Node(id) => {
+                let new_owner = node(&id)?;
+                // 槽交给别的节点之后，这里剩下的键再也访问不到了
+                if owner == self.myself
+                    && new_owner != self.myself
+                    && !keys_in_slot(db, slot, 1).is_empty()
+                {
+                    return Err(format!(
+                        "ERR Can't assign hashslot {} to a different node \
+                         while I still hold keys for this hash slot.",
+                        slot
+                    ));
+                }
+                slots.owners[slot as usize] = new_owner;
+                slots.migrating.remove(&slot);
+                slots.importing.remove(&slot);
+            }
+        }
+        Ok(())
+    }
+
+    /// CLUSTER SLOTS：`[[起始槽, 结束槽, [host, port, id]], ...]`。
+    fn slots(&self) -> Frame {
+        let slots = self.slots.lock().unwrap();
+        let ranges = ranges(&slots.owners).map(|(start, end, owner)| {
+            let node = &self.nodes[owner];
+            Frame::Array(vec![
+                Frame::Integer(start as i64),
+                Frame::Integer(end as i64),
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(node.host.clone())),
+                    Frame::Integer(node.port as i64),
+                    Frame::Bulk(Bytes::from(node.id.clone())),
+                ]),
+            ])
+        });
+        Frame::Array(ranges.collect())
+    }
+
+    /// CLUSTER NODES：每个节点一行，
+    /// `id host:port@集群总线端口 flags 主节点 ping发送时间 pong接收时间 epoch 连接状态 槽...`。
+    ///
+    /// 这里没有集群总线和从节点，这些字段都是固定的值。正在迁移的槽只出现在自己的那一行，
+    /// 和 Redis 一样写成 `[槽->-目标id]` 和 `[槽-<-源id]`。
+    fn nodes(&self) -> String {
+        let slots = self.slots.lock().unwrap();
+        let mut out = String::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let flags = if index == self.myself { "myself,master" } else { "master" };
+            out.push_str(&format!(
+                "{} {}@{} {} - 0 0 {} connected",
+                node.id,
+                node.addr(),
+                node.port as u32 + 10000,
+                flags,
+                index + 1
+            ));
+            for (start, end, owner) in ranges(&slots.owners) {
+                match owner == index {
+                    true if start == end => out.push_str(&format!(" {}", start)),
+                    true => out.push_str(&format!(" {}-{}", start, end)),
+                    false => {}
+                }
+            }
+            if index == self.myself {
+                let mut migrating: Vec<_> = slots.migrating.iter().collect();
+                migrating.sort_unstable();
+                for (slot, target) in migrating {
+                    out.push_str(&format!(" [{}->-{}]", slot, self.nodes[*target].id));
+                }
+                let mut importing: Vec<_> = slots.importing.iter().collect();
+                importing.sort_unstable();
+                for (slot, source) in importing {
+                    out.push_str(&format!(" [{}-<-{}]", slot, self.nodes[*source].id));
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn info(&self) -> String {
+        let slots = self.slots.lock().unwrap();
+        let size = (0..self.nodes.len())
+            .filter(|index| slots.owners.contains(index))
+            .count();
+        format!(
+            "cluster_enabled:1\r\ncluster_state:ok\r\ncluster_slots_assigned:{0}\r\n\
+             cluster_slots_ok:{0}\r\ncluster_known_nodes:{1}\r\ncluster_size:{2}\r\n\
+             cluster_my_epoch:{3}\r\n",
+            SLOTS,
+            self.nodes.len(),
+            size,
+            self.myself + 1
+        )
+    }
+}
+
+/// 连续的属于同一个节点的槽：`(起始槽, 结束槽, 节点)`。
+fn ranges(owners: &[usize]) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+    owners.chunk_by(|a, b| a == b).scan(0, |start, chunk| {
+        let range = (*start, *start + chunk.len() - 1, chunk[0]);
+        *start += chunk.len();
+        Some(range)
+    })
+}
+
+/// 槽中最多 `count` 个键。需要遍历所有的键，只应该在迁移槽的时候使用。
+fn keys_in_slot(db: &Db, slot: u16, count: usize) -> Vec<String> {
+    db.scan_keys(count, |key| key_slot(key.as_bytes()) == slot)
+}
+
+impl Db {
+    /// 集群模式下，命令的键不在这个节点上时返回 MOVED/ASK 等重定向的错误。
+    ///
+    /// 由服务器在执行每个命令之前调用：ASKING 只对同一个连接上的下一个命令有效，
+    /// `Command::apply` 不知道这个状态。没有开启集群模式时总是返回 `None`。
+    pub fn redirect(&self, cmd: &Command, asking: bool) -> Option<String> {
+        self.cluster()?.redirect(self, &cmd.keys(), asking)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DbDropGuard;
+    use crate::cmd::tests::{array, bulk, run};
+
+    /// 三个节点的集群，每个节点一个数据库，不需要真正监听端口。
+    fn cluster() -> Vec<DbDropGuard> {
+        let nodes: Vec<_> = (7000..7003).map(|port| ("127.0.0.1".to_string(), port)).collect();
+        (7000..7003)
+            .map(|port| {
+                DbDropGuard::with_config(&Config {
+                    port,
+                    cluster: Some(nodes.clone()),
+                    ..Config::default()
+                })
+            })
+            .collect()
+    }
+
+    fn redirect(db: &Db, args: &[&str], asking: bool) -> Option<String> {
+        db.redirect(&Command::from_frame(array(args)).unwrap(), asking)
+    }
+
+    /// DUMP 的内容是二进制的，不能通过 `run` 的 `&str` 参数传递。
+    fn dump(db: &Db, key: &str) -> Bytes {
+        match run(db, &["DUMP", key]) {
+            Frame::Bulk(payload) => payload,
+            frame => panic!("unexpected reply {:?}", frame),
+        }
+    }
+
+    fn restore(db: &Db, key: &str, ttl: &str, payload: Bytes) -> Frame {
+        let frame = Frame::Array(vec![bulk("RESTORE"), bulk(key), bulk(ttl), Frame::Bulk(payload)]);
+        Command::from_frame(frame).unwrap().apply(db)
+    }
+
+    fn myid(db: &Db) -> String {
+        match run(db, &["CLUSTER", "MYID"]) {
+            Frame::Bulk(id) => String::from_utf8(id.to_vec()).unwrap(),
+            frame => panic!("unexpected reply {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn slots_are_computed_like_redis() {
+        // CRC16/XMODEM 的标准测试向量
+        assert_eq!(CRC16.checksum(b"123456789"), 0x31C3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"user1000"));
+        // 空的 `{}` 不是 hash tag，只看第一对括号
+        assert_eq!(key_slot(b"foo{}{bar}"), CRC16.checksum(b"foo{}{bar}") % SLOTS);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    #[tokio::test]
+    async fn keys_are_redirected_to_their_node() {
+        let guards = cluster();
+        let dbs: Vec<Db> = guards.iter().map(DbDropGuard::db).collect();
+        // foo 在 12182 号槽，属于第三个节点
+        assert_eq!(
+            redirect(&dbs[0], &["GET", "foo"], false),
+            Some("MOVED 12182 127.0.0.1:7002".to_string())
+        );
+        assert_eq!(redirect(&dbs[2], &["GET", "foo"], false), None);
+        assert_eq!(run(&dbs[2], &["SET", "foo", "1"]), Frame::ok());
+        // bar 在 5061 号槽，属于第一个节点(0-5460)
+        assert_eq!(redirect(&dbs[0], &["SET", "bar", "1"], false), None);
+        assert_eq!(
+            redirect(&dbs[0], &["MSET", "foo", "1", "bar", "2"], false),
+            Some("CROSSSLOT Keys in request don't hash to the same slot".to_string())
+        );
+        assert_eq!(redirect(&dbs[2], &["MSET", "{foo}a", "1", "{foo}b", "2"], false), None);
+        // 不访问键的命令在哪个节点上都可以执行
+        assert_eq!(redirect(&dbs[0], &["PING"], false), None);
+        assert_eq!(run(&dbs[0], &["CLUSTER", "KEYSLOT", "foo"]), Frame::Integer(12182));
+    }
+
+    #[tokio::test]
+    async fn nodes_are_identified_by_host_and_port() {
+        // 两台机器上的节点使用同一个端口
+        let nodes = vec![("10.0.0.1".to_string(), 7000), ("10.0.0.2".to_string(), 7000)];
+        let guard = DbDropGuard::with_config(&Config {
+            bind: "10.0.0.2".to_string(),
+            port: 7000,
+            cluster: Some(nodes),
+            ..Config::default()
+        });
+        let db = guard.db();
+        assert_eq!(db.cluster().unwrap().myself, 1);
+        // bar 在 5061 号槽，属于第一个节点(0-8191)
+        assert_eq!(
+            redirect(&db, &["GET", "bar"], false),
+            Some("MOVED 5061 10.0.0.1:7000".to_string())
+        );
+        assert_eq!(redirect(&db, &["GET", "foo"], false), None);
+    }
+
+    #[tokio::test]
+    async fn cluster_slots_and_nodes() {
+        let guards = cluster();
+        let db = guards[1].db();
+        let ids: Vec<String> = guards.iter().map(|guard| myid(&guard.db())).collect();
+        assert!(ids.iter().all(|id| id.len() == 40));
+        assert!(ids[0] != ids[1] && ids[1] != ids[2]);
+
+        let range = |start, end, port, id: &str| {
+            Frame::Array(vec![
+                Frame::Integer(start),
+                Frame::Integer(end),
+                Frame::Array(vec![bulk("127.0.0.1"), Frame::Integer(port), bulk(id)]),
+            ])
+        };
+        assert_eq!(
+            run(&db, &["CLUSTER", "SLOTS"]),
+            Frame::Array(vec![
+                range(0, 5460, 7000, &ids[0]),
+                range(5461, 10922, 7001, &ids[1]),
+                range(10923, 16383, 7002, &ids[2]),
+            ])
+        );
+        let Frame::Bulk(nodes) = run(&db, &["CLUSTER", "NODES"]) else {
+            panic!("CLUSTER NODES should reply a bulk string");
+        };
+        let nodes = String::from_utf8(nodes.to_vec()).unwrap();
+        let lines: Vec<&str> = nodes.lines().collect();
+        assert_eq!(
+            lines[1],
+            format!("{} 127.0.0.1:7001@17001 myself,master - 0 0 2 connected 5461-10922", ids[1])
+        );
+        assert!(lines[0].ends_with(" master - 0 0 1 connected 0-5460"));
+
+        let not_enabled = DbDropGuard::new();
+        assert_eq!(
+            run(&not_enabled.db(), &["CLUSTER", "SLOTS"]),
+            Frame::Error("ERR This instance has cluster support disabled".to_string())
+        );
+        assert_eq!(not_enabled.db().redirect(&Command::Role, false), None);
+    }
+
+    #[tokio::test]
+    async fn slots_migrate_with_ask_redirections() {
+        let guards = cluster();
+        let (source, target) = (guards[2].db(), guards[0].db());
+        let (source_id, target_id) = (myid(&source), myid(&target));
+        run(&source, &["SET", "foo", "v", "EX", "100"]);
+        run(&source, &["RPUSH", "{foo}list", "a", "b"]);
+        assert_eq!(run(&source, &["CLUSTER", "COUNTKEYSINSLOT", "12182"]), Frame::Integer(2));
+
+        let ok = Frame::ok();
+        assert_eq!(run(&target, &["CLUSTER", "SETSLOT", "12182", "IMPORTING", &source_id]), ok);
+        assert_eq!(run(&source, &["CLUSTER", "SETSLOT", "12182", "MIGRATING", &target_id]), ok);
+        let Frame::Bulk(nodes) = run(&source, &["CLUSTER", "NODES"]) else {
+            panic!("CLUSTER NODES should reply a bulk string");
+        };
+        assert!(String::from_utf8_lossy(&nodes).contains(&format!("[12182->-{}]", target_id)));
+
+        // 只迁移 foo：还在源节点上的键照常执行，已经迁移走的键回复 ASK
+        let restore_foo = ["RESTORE", "foo", "0", "payload"];
+        assert_eq!(
+            redirect(&target, &restore_foo, false),
+            Some("MOVED 12182 127.0.0.1:7002".to_string())
+        );
+        assert_eq!(redirect(&target, &restore_foo, true), None);
+        // DUMP 的内容不包括过期时间，迁移时用 PTTL 读出来
+        assert_eq!(restore(&target, "foo", "100000", dump(&source, "foo")), ok);
+        assert_eq!(run(&source, &["DEL", "foo"]), Frame::Integer(1));
+
+        let ask = Some("ASK 12182 127.0.0.1:7000".to_string());
+        assert_eq!(redirect(&source, &["GET", "foo"], false), ask);
+        assert_eq!(redirect(&source, &["SET", "{foo}new", "1"], false), ask);
+        assert_eq!(redirect(&source, &["LRANGE", "{foo}list", "0", "-1"], false), None);
+        assert_eq!(
+            redirect(&source, &["MGET", "foo", "{foo}list"], false).unwrap(),
+            "TRYAGAIN Multiple keys request during rehashing of slot"
+        );
+        assert_eq!(run(&target, &["GET", "foo"]), bulk("v"));
+        assert!(matches!(run(&target, &["TTL", "foo"]), Frame::Integer(99 | 100)));
+
+        // 还有键没有迁移走，不能把槽交出去
+        let busy = run(&source, &["CLUSTER", "SETSLOT", "12182", "NODE", &target_id]);
+        assert!(matches!(busy, Frame::Error(msg) if msg.starts_with("ERR Can't assign")));
+        let keys = run(&source, &["CLUSTER", "GETKEYSINSLOT", "12182", "10"]);
+        assert_eq!(keys, array(&["{foo}list"]));
+        assert_eq!(restore(&target, "{foo}list", "0", dump(&source, "{foo}list")), ok);
+        run(&source, &["DEL", "{foo}list"]);
+
+        for guard in &guards {
+            assert_eq!(run(&guard.db(), &["CLUSTER", "SETSLOT", "12182", "NODE", &target_id]), ok);
+        }
+        assert_eq!(
+            redirect(&source, &["GET", "foo"], false),
+            Some("MOVED 12182 127.0.0.1:7000".to_string())
+        );
+        assert_eq!(redirect(&target, &["GET", "foo"], false), None);
+        assert_eq!(run(&target, &["LRANGE", "{foo}list", "0", "-1"]), array(&["a", "b"]));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::db::DbGuard;
+use crate::value::{Value, WrongType};
+use crate::Frame;
+
+use bytes::Bytes;
+use std::collections::HashMap;
+
+// 哈希表命令，每个键保存一个从字段到值的映射。
+
+/// HSET，返回新添加的字段个数，已经存在的字段只会更新它的值。
+pub(super) fn set(
+    db: &mut DbGuard,
+    key: &str,
+    pairs: Vec<(Bytes, Bytes)>,
+) -> Result<Frame, WrongType> {
+    let hash = db
+        .get_or_insert_with(key, || Value::Hash(HashMap::new()))
+        .as_hash_mut()?;
+    let mut added = 0;
+    for (field, value) in pairs {
+        if hash.insert(field, value).is_none() {
+            added += 1;
+        }
+    }
+    Ok(Frame::Integer(added))
+}
+
+pub(super) fn get(db: &mut DbGuard, key: &str, field: &[u8]) -> Result<Frame, WrongType> {
+    let value = match db.get(key) {
+        Some(value) => value.as_hash()?.get(field).cloned(),
+        None => None,
+    };
+    Ok(value.map_or(Frame::Null, Frame::Bulk))
+}
+
+/// HGETALL，回复一个从字段到值的 map。
+pub(super) fn get_all(db: &mut DbGuard, key: &str) -> Result<Frame, WrongType> {
+    let pairs = match db.get(key) {
+        Some(value) => value
+            .as_hash()?
+            .iter()
+            .map(|(field, value)| (Frame::Bulk(field.clone()), Frame::Bulk(value.clone())))
+            .collect(),
+        None => vec![],
+    };
+    // RESP2 的客户端收到的是字段和值交替的数组
+    Ok(Frame::Map(pairs))
+}
+
+/// HDEL，返回删除的字段个数，删除了所有字段之后键也会被删除。
+pub(super) fn del(db: &mut DbGuard, key: &str, fields: &[Bytes]) -> Result<Frame, WrongType> {
+    let Some(value) = db.get_mut(key) else {
+        return Ok(Frame::Integer(0));
+    };
+    let hash = value.as_hash_mut()?;
+    let removed = fields.iter().filter(|field| hash.remove(*field).is_some()).count();
+    db.remove_if_empty(key);
+    Ok(Frame::Integer(removed as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd::tests::{bulk, run};
+    use crate::{DbDropGuard, Frame};
+
+    #[tokio::test]
+    async fn set_get_and_delete_fields() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["HSET", "h", "a", "1", "b", "2"]), Frame::Integer(2));
+        assert_eq!(run(db, &["HSET", "h", "a", "3", "c", "4"]), Frame::Integer(1));
+        assert_eq!(run(db, &["HGET", "h", "a"]), bulk("3"));
+        assert_eq!(run(db, &["HGET", "h", "x"]), Frame::Null);
+        assert_eq!(run(db, &["HGET", "missing", "a"]), Frame::Null);
+
+        // 哈希表是无序的，排序之后再比较
+        let Frame::Map(mut pairs) = run(db, &["HGETALL", "h"]) else {
+            panic!("HGETALL should reply a map");
+        };
+        pairs.sort_by_key(|(field, _)| field.to_string());
+        let expected = [("a", "3"), ("b", "2"), ("c", "4")].map(|(f, v)| (bulk(f), bulk(v)));
+        assert_eq!(pairs, expected);
+
+        assert_eq!(run(db, &["HDEL", "h", "a", "x", "b"]), Frame::Integer(2));
+        assert_eq!(run(db, &["HDEL", "h", "c"]), Frame::Integer(1));
+        assert_eq!(run(db, &["EXISTS", "h"]), Frame::Integer(0));
+        assert_eq!(run(db, &["HGETALL", "h"]), Frame::Map(vec![]));
+        assert_eq!(
+            run(db, &["HSET", "h", "a"]),
+            Frame::Error("ERR wrong number of arguments for 'hset' command".into())
+        );
+    }
+}
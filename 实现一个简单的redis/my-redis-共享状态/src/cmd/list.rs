@@ -0,0 +1,103 @@
+use super::index_range;
+use crate::db::DbGuard;
+use crate::value::{Value, WrongType};
+use crate::Frame;
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+// 列表命令。列表用 VecDeque 保存，两端的插入和弹出都是 O(1)。
+
+/// LPUSH 和 RPUSH，键不存在时创建一个新的列表，返回插入之后列表的长度。
+///
+/// 和 Redis 一样，LPUSH 的多个元素依次插入到头部，`LPUSH k a b c` 之后列表是 c b a。
+pub(super) fn push(
+    db: &mut DbGuard,
+    key: &str,
+    values: Vec<Bytes>,
+    front: bool,
+) -> Result<Frame, WrongType> {
+    let list = db
+        .get_or_insert_with(key, || Value::List(VecDeque::new()))
+        .as_list_mut()?;
+    for value in values {
+        if front {
+            list.push_front(value);
+        } else {
+            list.push_back(value);
+        }
+    }
+    Ok(Frame::Integer(list.len() as i64))
+}
+
+/// LPOP 和 RPOP，弹出最后一个元素之后键会被删除。
+pub(super) fn pop(
+    db: &mut DbGuard,
+    key: &str,
+    count: Option<usize>,
+    front: bool,
+) -> Result<Frame, WrongType> {
+    let Some(value) = db.get_mut(key) else {
+        return Ok(Frame::Null);
+    };
+    let list = value.as_list_mut()?;
+    let n = count.unwrap_or(1).min(list.len());
+    let popped: Vec<Bytes> = if front {
+        list.drain(..n).collect()
+    } else {
+        let len = list.len();
+        list.drain(len - n..).rev().collect()
+    };
+    db.remove_if_empty(key);
+
+    Ok(match count {
+        // 存在的列表不会是空的，一定能弹出一个元素
+        None => popped.into_iter().next().map_or(Frame::Null, Frame::Bulk),
+        Some(_) => Frame::Array(popped.into_iter().map(Frame::Bulk).collect()),
+    })
+}
+
+/// LRANGE，`start` 和 `stop` 都包括在内，负数表示从末尾往前数。
+pub(super) fn range(
+    db: &mut DbGuard,
+    key: &str,
+    start: i64,
+    stop: i64,
+) -> Result<Frame, WrongType> {
+    let Some(value) = db.get(key) else {
+        return Ok(Frame::Array(vec![]));
+    };
+    let list = value.as_list()?;
+    let items = list.range(index_range(start, stop, list.len()));
+    Ok(Frame::Array(items.cloned().map(Frame::Bulk).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd::tests::{array, bulk, run};
+    use crate::{DbDropGuard, Frame};
+
+    #[tokio::test]
+    async fn push_pop_and_range() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["RPUSH", "l", "b", "c"]), Frame::Integer(2));
+        assert_eq!(run(db, &["LPUSH", "l", "a", "z"]), Frame::Integer(4));
+        assert_eq!(run(db, &["LRANGE", "l", "0", "-1"]), array(&["z", "a", "b", "c"]));
+        assert_eq!(run(db, &["LRANGE", "l", "-3", "1"]), array(&["a"]));
+        assert_eq!(run(db, &["LRANGE", "l", "2", "100"]), array(&["b", "c"]));
+        assert_eq!(run(db, &["LRANGE", "l", "3", "2"]), array(&[]));
+        assert_eq!(run(db, &["LRANGE", "missing", "0", "-1"]), array(&[]));
+
+        assert_eq!(run(db, &["LPOP", "l"]), bulk("z"));
+        assert_eq!(run(db, &["RPOP", "l", "2"]), array(&["c", "b"]));
+        assert_eq!(run(db, &["RPOP", "missing"]), Frame::Null);
+        // 弹出最后一个元素之后键被删除
+        assert_eq!(run(db, &["LPOP", "l", "5"]), array(&["a"]));
+        assert_eq!(run(db, &["EXISTS", "l"]), Frame::Integer(0));
+        assert_eq!(
+            run(db, &["LPOP", "l", "-1"]),
+            Frame::Error("ERR value is out of range, must be positive".into())
+        );
+    }
+}
@@ -0,0 +1,1405 @@
+mod hash;
+mod list;
+mod propagate;
+mod set;
+mod zset;
+
+use crate::cluster;
+use crate::db::DbGuard;
+use crate::parse::{Parse, ParseError};
+use crate::rdb;
+use crate::value::{ScoreBound, Value, WrongType};
+use crate::{Db, Frame};
+
+use bytes::{Bytes, BytesMut};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// 服务器支持的命令。
+///
+/// 一开始 `process` 直接使用 mini-redis 的 `Command`，它只认识 GET/SET 等几个命令，
+/// 遇到其它命令只能 `panic!`，整个连接任务随之崩溃。现在命令由我们自己解析，
+/// 不认识的命令、参数不对的命令都会回复一个 RESP 错误，连接可以继续使用。
+///
+/// 字符串命令在这个模块中执行，列表、哈希表、集合和有序集合的命令分别在各自的子模块中。
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Get { key: String },
+    /// `SET key value [EX seconds | PX milliseconds | EXAT timestamp | PXAT timestamp]`，
+    /// 没有指定过期时间时会清除原来的过期时间
+    Set {
+        key: String,
+        value: Bytes,
+        expire: Option<Expiry>,
+    },
+    /// 键不存在时才设置
+    SetNx { key: String, value: Bytes },
+    /// 设置新值，返回旧值
+    GetSet { key: String, value: Bytes },
+    Del { keys: Vec<String> },
+    Exists { keys: Vec<String> },
+    /// INCR、DECR、INCRBY 和 DECRBY 都是给整数加上一个增量
+    IncrBy { key: String, delta: i64 },
+    Append { key: String, value: Bytes },
+    StrLen { key: String },
+    MGet { keys: Vec<String> },
+    MSet { pairs: Vec<(String, Bytes)> },
+    /// EXPIRE 和 PEXPIRE，过期时间不是正数时键会被立刻删除
+    Expire { key: String, millis: i64 },
+    /// EXPIREAT 和 PEXPIREAT，过期时间是 Unix 时间戳(毫秒)，已经过去时键会被立刻删除
+    ExpireAt { key: String, unix_millis: i64 },
+    /// TTL 和 PTTL，`millis` 表示以毫秒为单位返回
+    Ttl { key: String, millis: bool },
+    /// 清除过期时间
+    Persist { key: String },
+    /// 键保存的值的类型，键不存在时返回 none
+    Type { key: String },
+    /// LPUSH 和 RPUSH，`front` 表示插入到列表的头部
+    Push {
+        key: String,
+        values: Vec<Bytes>,
+        front: bool,
+    },
+    /// LPOP 和 RPOP，指定了 `count` 时回复一个数组
+    Pop {
+        key: String,
+        count: Option<usize>,
+        front: bool,
+    },
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    HSet {
+        key: String,
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    HGet { key: String, field: Bytes },
+    HGetAll { key: String },
+    HDel { key: String, fields: Vec<Bytes> },
+    SAdd { key: String, members: Vec<Bytes> },
+    SRem { key: String, members: Vec<Bytes> },
+    SMembers { key: String },
+    SIsMember { key: String, member: Bytes },
+    /// `ZADD key score member [score member ...]`，不支持 NX、XX、INCR 等选项
+    ZAdd {
+        key: String,
+        members: Vec<(f64, Bytes)>,
+    },
+    /// 按排名返回成员，`ZRANGE key start stop [WITHSCORES]`
+    ZRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+    /// `ZRANGEBYSCORE key min max [WITHSCORES]`
+    ZRangeByScore {
+        key: String,
+        min: ScoreBound,
+        max: ScoreBound,
+        with_scores: bool,
+    },
+    ZRem { key: String, members: Vec<Bytes> },
+    /// 向频道发布消息，返回收到消息的订阅者个数
+    Publish {
+        channel: String,
+        message: Bytes,
+    },
+    /// 订阅相关的四个命令由 `Subscriber` 处理，连接会进入订阅状态
+    Subscribe { channels: Vec<String> },
+    PSubscribe { patterns: Vec<String> },
+    /// 没有指定频道时取消所有的订阅
+    Unsubscribe { channels: Vec<String> },
+    PUnsubscribe { patterns: Vec<String> },
+    Ping { message: Option<Bytes> },
+    /// 切换协议版本，由 `Connection::hello` 处理
+    Hello { protover: Option<i64> },
+    /// 保存快照，SAVE 写完文件才回复，BGSAVE 在后台写
+    Save { background: bool },
+    /// 上一次成功保存快照的时间
+    LastSave,
+    /// 在后台重写 AOF
+    BgRewriteAof,
+    /// REPLICAOF host port(以及旧的名字 SLAVEOF)，`None` 表示 REPLICAOF NO ONE
+    ReplicaOf { primary: Option<(String, u16)> },
+    /// 从服务器请求同步，由 `replication::serve_replica` 处理
+    PSync { replid: String, offset: i64 },
+    /// 从服务器在握手时告诉主服务器自己监听的端口，同步之后每秒报告一次自己的偏移量
+    ReplConf {
+        listening_port: Option<u16>,
+        ack: Option<u64>,
+    },
+    /// 当前的角色和复制的进度
+    Role,
+    /// MEMORY USAGE key：键大约占用多少字节的内存
+    MemoryUsage { key: String },
+    /// 把键的值序列化，RESTORE 可以在另一个服务器上还原它，迁移哈希槽时使用
+    Dump { key: String },
+    /// `RESTORE key ttl payload [REPLACE] [ABSTTL]`，ttl 为 0 表示不过期
+    Restore {
+        key: String,
+        payload: Bytes,
+        expire: Option<Expiry>,
+        replace: bool,
+    },
+    /// CLUSTER SLOTS、CLUSTER NODES 等集群相关的命令
+    Cluster { subcommand: cluster::Subcommand },
+    /// 下一个命令访问的是正在迁移到这里的槽，由服务器记在连接上(见 `Db::redirect`)
+    Asking,
+    /// 事务相关的五个命令由 `Transaction` 处理，MULTI 之后的命令会被放进队列，
+    /// 直到 EXEC 时一起执行
+    Multi,
+    Exec,
+    Discard,
+    Watch { keys: Vec<String> },
+    Unwatch,
+    Unknown { name: String },
+}
+
+/// SET 命令的过期时间。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// EX 和 PX：从执行命令的时刻开始计算
+    After(Duration),
+    /// EXAT 和 PXAT：Unix 时间戳(毫秒)
+    At(u64),
+}
+
+impl Expiry {
+    /// 过期时间对应的 Unix 时间戳(毫秒)。
+    fn unix_millis(self) -> u64 {
+        match self {
+            Expiry::After(ttl) => (unix_now_millis() as u64).saturating_add(ttl.as_millis() as u64),
+            Expiry::At(unix_millis) => unix_millis,
+        }
+    }
+
+    /// 过期的时刻，超出了 `Instant` 的表示范围时返回 `None`。
+    fn instant(self) -> Option<Instant> {
+        let ttl = match self {
+            Expiry::After(ttl) => ttl,
+            // 已经过去的时间戳相当于立刻过期
+            Expiry::At(unix_millis) => {
+                Duration::from_millis(unix_millis.saturating_sub(unix_now_millis() as u64))
+            }
+        };
+        Instant::now().checked_add(ttl)
+    }
+}
+
+impl Command {
+    /// 从客户端发送的帧中解析出命令。
+    ///
+    /// 返回的错误信息可以直接作为错误回复发给客户端。
+    pub fn from_frame(frame: Frame) -> crate::Result<Command> {
+        let mut parse = Parse::new(frame)?;
+        let name = parse.next_string()?.to_lowercase();
+        match Command::parse_args(&name, &mut parse) {
+            Ok(cmd) => Ok(cmd),
+            Err(ParseError::EndOfStream) => {
+                Err(format!("ERR wrong number of arguments for '{}' command", name).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn parse_args(name: &str, parse: &mut Parse) -> Result<Command, ParseError> {
+        let cmd = match name {
+            "get" => Command::Get {
+                key: parse.next_string()?,
+            },
+            "set" => Command::Set {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+                expire: set_options(parse)?,
+            },
+            "setnx" => Command::SetNx {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+            },
+            "getset" => Command::GetSet {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+            },
+            "del" => Command::Del {
+                keys: keys(parse)?,
+            },
+            "exists" => Command::Exists {
+                keys: keys(parse)?,
+            },
+            "incr" => Command::IncrBy {
+                key: parse.next_string()?,
+                delta: 1,
+            },
+            "decr" => Command::IncrBy {
+                key: parse.next_string()?,
+                delta: -1,
+            },
+            "incrby" => Command::IncrBy {
+                key: parse.next_string()?,
+                delta: parse.next_int()?,
+            },
+            "decrby" => Command::IncrBy {
+                key: parse.next_string()?,
+                delta: parse
+                    .next_int()?
+                    .checked_neg()
+                    .ok_or("ERR decrement would overflow")?,
+            },
+            "append" => Command::Append {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+            },
+            "strlen" => Command::StrLen {
+                key: parse.next_string()?,
+            },
+            "mget" => Command::MGet {
+                keys: keys(parse)?,
+            },
+            "mset" => {
+                if parse.remaining() == 0 || !parse.remaining().is_multiple_of(2) {
+                    return Err(ParseError::EndOfStream);
+                }
+                let mut pairs = Vec::with_capacity(parse.remaining() / 2);
+                while parse.remaining() > 0 {
+                    pairs.push((parse.next_string()?, parse.next_bytes()?));
+                }
+                Command::MSet { pairs }
+            }
+            "expire" | "pexpire" => {
+                let key = parse.next_string()?;
+                let n = parse.next_int()?;
+                let millis = if name == "expire" { n.checked_mul(1000) } else { Some(n) };
+                let millis = millis
+                    .ok_or_else(|| format!("ERR invalid expire time in '{}' command", name))?;
+                Command::Expire { key, millis }
+            }
+            "expireat" | "pexpireat" => {
+                let key = parse.next_string()?;
+                let n = parse.next_int()?;
+                let unix_millis = if name == "expireat" { n.checked_mul(1000) } else { Some(n) };
+                let unix_millis = unix_millis
+                    .ok_or_else(|| format!("ERR invalid expire time in '{}' command", name))?;
+                Command::ExpireAt { key, unix_millis }
+            }
+            "ttl" | "pttl" => Command::Ttl {
+                key: parse.next_string()?,
+                millis: name == "pttl",
+            },
+            "persist" => Command::Persist {
+                key: parse.next_string()?,
+            },
+            "type" => Command::Type {
+                key: parse.next_string()?,
+            },
+            "lpush" | "rpush" => Command::Push {
+                key: parse.next_string()?,
+                values: values(parse)?,
+                front: name == "lpush",
+            },
+            "lpop" | "rpop" => Command::Pop {
+                key: parse.next_string()?,
+                count: match parse.remaining() {
+                    0 => None,
+                    _ => Some(
+                        usize::try_from(parse.next_int()?)
+                            .map_err(|_| "ERR value is out of range, must be positive")?,
+                    ),
+                },
+                front: name == "lpop",
+            },
+            "lrange" => Command::LRange {
+                key: parse.next_string()?,
+                start: parse.next_int()?,
+                stop: parse.next_int()?,
+            },
+            "hset" => {
+                let key = parse.next_string()?;
+                if parse.remaining() == 0 || !parse.remaining().is_multiple_of(2) {
+                    return Err(ParseError::EndOfStream);
+                }
+                let mut pairs = Vec::with_capacity(parse.remaining() / 2);
+                while parse.remaining() > 0 {
+                    pairs.push((parse.next_bytes()?, parse.next_bytes()?));
+                }
+                Command::HSet { key, pairs }
+            }
+            "hget" => Command::HGet {
+                key: parse.next_string()?,
+                field: parse.next_bytes()?,
+            },
+            "hgetall" => Command::HGetAll {
+                key: parse.next_string()?,
+            },
+            "hdel" => Command::HDel {
+                key: parse.next_string()?,
+                fields: values(parse)?,
+            },
+            "sadd" => Command::SAdd {
+                key: parse.next_string()?,
+                members: values(parse)?,
+            },
+            "srem" => Command::SRem {
+                key: parse.next_string()?,
+                members: values(parse)?,
+            },
+            "smembers" => Command::SMembers {
+                key: parse.next_string()?,
+            },
+            "sismember" => Command::SIsMember {
+                key: parse.next_string()?,
+                member: parse.next_bytes()?,
+            },
+            "zadd" => {
+                let key = parse.next_string()?;
+                if parse.remaining() == 0 || !parse.remaining().is_multiple_of(2) {
+                    return Err(ParseError::EndOfStream);
+                }
+                let mut members = Vec::with_capacity(parse.remaining() / 2);
+                while parse.remaining() > 0 {
+                    let score = zset::parse_score(&parse.next_bytes()?)
+                        .ok_or("ERR value is not a valid float")?;
+                    members.push((score, parse.next_bytes()?));
+                }
+                Command::ZAdd { key, members }
+            }
+            "zrange" => Command::ZRange {
+                key: parse.next_string()?,
+                start: parse.next_int()?,
+                stop: parse.next_int()?,
+                with_scores: with_scores(parse)?,
+            },
+            "zrangebyscore" => {
+                let key = parse.next_string()?;
+                let mut bound = || -> Result<ScoreBound, ParseError> {
+                    zset::parse_bound(&parse.next_bytes()?)
+                        .ok_or_else(|| "ERR min or max is not a float".into())
+                };
+                let (min, max) = (bound()?, bound()?);
+                Command::ZRangeByScore {
+                    key,
+                    min,
+                    max,
+                    with_scores: with_scores(parse)?,
+                }
+            }
+            "zrem" => Command::ZRem {
+                key: parse.next_string()?,
+                members: values(parse)?,
+            },
+            "publish" => Command::Publish {
+                channel: parse.next_string()?,
+                message: parse.next_bytes()?,
+            },
+            "subscribe" => Command::Subscribe {
+                channels: keys(parse)?,
+            },
+            "psubscribe" => Command::PSubscribe {
+                patterns: keys(parse)?,
+            },
+            "unsubscribe" => Command::Unsubscribe {
+                channels: remaining_strings(parse)?,
+            },
+            "punsubscribe" => Command::PUnsubscribe {
+                patterns: remaining_strings(parse)?,
+            },
+            "ping" => Command::Ping {
+                message: match parse.remaining() {
+                    0 => None,
+                    _ => Some(parse.next_bytes()?),
+                },
+            },
+            "hello" => Command::Hello {
+                protover: match parse.remaining() {
+                    0 => None,
+                    _ => Some(parse.next_int()?),
+                },
+            },
+            "save" => Command::Save { background: false },
+            "bgsave" => Command::Save { background: true },
+            "lastsave" => Command::LastSave,
+            "bgrewriteaof" => Command::BgRewriteAof,
+            "replicaof" | "slaveof" => Command::ReplicaOf {
+                primary: primary(parse)?,
+            },
+            "psync" => Command::PSync {
+                replid: parse.next_string()?,
+                offset: parse.next_int()?,
+            },
+            "replconf" => replconf(parse)?,
+            "role" => Command::Role,
+            "memory" => match parse.next_string()?.to_lowercase().as_str() {
+                "usage" => Command::MemoryUsage {
+                    key: parse.next_string()?,
+                },
+                sub => return Err(format!("ERR unknown subcommand '{}'", sub).into()),
+            },
+            "dump" => Command::Dump {
+                key: parse.next_string()?,
+            },
+            "restore" => restore(parse)?,
+            "cluster" => Command::Cluster {
+                subcommand: cluster::Subcommand::parse(parse)?,
+            },
+            "asking" => Command::Asking,
+            "multi" => Command::Multi,
+            "exec" => Command::Exec,
+            "discard" => Command::Discard,
+            "watch" => Command::Watch {
+                keys: keys(parse)?,
+            },
+            "unwatch" => Command::Unwatch,
+            _ => {
+                // 不认识的命令不检查参数
+                return Ok(Command::Unknown {
+                    name: name.to_string(),
+                });
+            }
+        };
+        parse.finish()?;
+        Ok(cmd)
+    }
+
+    /// SUBSCRIBE、PSUBSCRIBE、UNSUBSCRIBE 和 PUNSUBSCRIBE 需要由 `Subscriber` 处理。
+    pub fn is_subscription(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe { .. }
+                | Command::PSubscribe { .. }
+                | Command::Unsubscribe { .. }
+                | Command::PUnsubscribe { .. }
+        )
+    }
+
+    /// MULTI、EXEC、DISCARD、WATCH 和 UNWATCH 需要由 `Transaction` 处理。
+    pub fn is_transaction(&self) -> bool {
+        matches!(
+            self,
+            Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch { .. }
+                | Command::Unwatch
+        )
+    }
+
+    /// 命令要访问的键，执行命令之前会锁住这些键所在的分片。
+    pub fn keys(&self) -> Vec<&str> {
+        // 这里故意不使用 `_`，新增的命令必须在这里声明它访问的键
+        match self {
+            Command::Get { key }
+            | Command::Set { key, .. }
+            | Command::SetNx { key, .. }
+            | Command::GetSet { key, .. }
+            | Command::IncrBy { key, .. }
+            | Command::Append { key, .. }
+            | Command::StrLen { key }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::Ttl { key, .. }
+            | Command::Persist { key }
+            | Command::Type { key }
+            | Command::Push { key, .. }
+            | Command::Pop { key, .. }
+            | Command::LRange { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HGet { key, .. }
+            | Command::HGetAll { key }
+            | Command::HDel { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::SRem { key, .. }
+            | Command::SMembers { key }
+            | Command::SIsMember { key, .. }
+            | Command::ZAdd { key, .. }
+            | Command::ZRange { key, .. }
+            | Command::ZRangeByScore { key, .. }
+            | Command::ZRem { key, .. }
+            | Command::MemoryUsage { key }
+            | Command::Dump { key }
+            | Command::Restore { key, .. } => vec![key],
+            Command::Del { keys } | Command::Exists { keys } | Command::MGet { keys } => {
+                keys.iter().map(String::as_str).collect()
+            }
+            Command::MSet { pairs } => pairs.iter().map(|(key, _)| key.as_str()).collect(),
+            Command::Publish { .. }
+            | Command::Subscribe { .. }
+            | Command::PSubscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::PUnsubscribe { .. }
+            | Command::Ping { .. }
+            | Command::Hello { .. }
+            | Command::Save { .. }
+            | Command::LastSave
+            | Command::BgRewriteAof
+            | Command::ReplicaOf { .. }
+            | Command::PSync { .. }
+            | Command::ReplConf { .. }
+            | Command::Role
+            | Command::Cluster { .. }
+            | Command::Asking
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Unknown { .. } => vec![],
+        }
+    }
+
+    /// 从服务器是只读的，它上面的数据只能来自主服务器。写命令返回 READONLY 错误。
+    pub(crate) fn readonly_error(&self, db: &Db) -> Option<Frame> {
+        (db.is_replica() && self.propagate().is_some())
+            .then(|| error("READONLY You can't write against a read only replica."))
+    }
+
+    /// 会让内存增长的写命令。使用的内存超过 maxmemory、又淘汰不掉键时，
+    /// 这些命令回复 OOM 错误，DEL、LPOP 这样释放内存的命令仍然可以执行。
+    pub(crate) fn denyoom(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::SetNx { .. }
+                | Command::GetSet { .. }
+                | Command::IncrBy { .. }
+                | Command::Append { .. }
+                | Command::MSet { .. }
+                | Command::Push { .. }
+                | Command::HSet { .. }
+                | Command::SAdd { .. }
+                | Command::ZAdd { .. }
+                | Command::Restore { .. }
+        )
+    }
+
+    /// 执行 `denyoom` 的命令之前先腾出内存(见 `Db::free_memory`)，腾不出时返回 OOM 错误。
+    pub(crate) fn oom_error(&self, db: &Db) -> Option<Frame> {
+        (self.denyoom() && !db.free_memory())
+            .then(|| error("OOM command not allowed when used memory > 'maxmemory'."))
+    }
+
+    /// 执行命令，返回要回复给客户端的帧。
+    pub fn apply(self, db: &Db) -> Frame {
+        if let Some(frame) = self.readonly_error(db).or_else(|| self.oom_error(db)) {
+            return frame;
+        }
+        match self {
+            // 发布消息不需要锁住数据库
+            Command::Publish { channel, message } => {
+                Frame::Integer(db.publish(&channel, message) as i64)
+            }
+            cmd if cmd.is_subscription() => {
+                error("ERR subscription commands must be handled by a Subscriber")
+            }
+            cmd if cmd.is_transaction() => {
+                error("ERR transaction commands must be handled by a Transaction")
+            }
+            Command::Hello { .. } => error("ERR HELLO must be handled by a Connection"),
+            // 快照需要锁住所有的分片，由 `Db` 自己加锁。SAVE 会阻塞当前的工作线程直到写完，
+            // 和 Redis 一样，它主要用于调试，平时应该使用 BGSAVE
+            Command::Save { background: false } => match db.save() {
+                Ok(()) => Frame::ok(),
+                Err(e) => error(&format!("ERR {}", e)),
+            },
+            Command::Save { background: true } => match db.bgsave() {
+                Ok(_) => Frame::Simple("Background saving started".to_string()),
+                Err(e) => error(&format!("ERR {}", e)),
+            },
+            Command::LastSave => Frame::Integer(db.last_save() as i64),
+            Command::BgRewriteAof => match db.bgrewriteaof() {
+                Ok(_) => Frame::Simple("Background append only file rewriting started".into()),
+                Err(e) => error(&format!("ERR {}", e)),
+            },
+            Command::ReplicaOf { primary } => {
+                db.replicaof(primary);
+                Frame::ok()
+            }
+            Command::PSync { .. } => error("ERR PSYNC must be handled by the server"),
+            // 监听的端口由 `process` 记下来，ACK 只在同步之后由 `serve_replica` 处理
+            Command::ReplConf { .. } => Frame::ok(),
+            Command::Role => db.replication().role(),
+            Command::Cluster { .. } | Command::Asking if db.cluster().is_none() => {
+                error("ERR This instance has cluster support disabled")
+            }
+            Command::Cluster { subcommand } => db.cluster().unwrap().execute(db, subcommand),
+            Command::Asking => Frame::ok(),
+            cmd => {
+                // 所有命令都只在持有锁的这一小段时间内访问数据，不会跨越 .await
+                let mut db = db.lock(cmd.keys());
+                cmd.apply_locked(&mut db)
+            }
+        }
+    }
+
+    /// 在已经锁住了 `keys()` 所在分片的状态下执行命令，并把成功的写命令写到 AOF 中。
+    pub(crate) fn apply_locked(self, db: &mut DbGuard) -> Frame {
+        let mut propagated = vec![];
+        let frame = self.apply_locked_into(db, &mut propagated);
+        match db.propagate(&propagated) {
+            Ok(()) => frame,
+            Err(e) => error(&format!("ERR error writing to the AOF: {}", e)),
+        }
+    }
+
+    /// 和 `apply_locked` 一样，但是要写到 AOF 中的命令只放进 `propagated`，由调用者写入。
+    ///
+    /// EXEC 在同一次加锁中依次调用事务中每个命令的 `apply_locked_into`，
+    /// 最后把所有的写命令作为一个事务写到 AOF 中。
+    pub(crate) fn apply_locked_into(self, db: &mut DbGuard, propagated: &mut Vec<Frame>) -> Frame {
+        // 要写到 AOF 中的命令在执行之前确定，相对的过期时间在这里换算成绝对时间
+        let propagate = self.propagate();
+        let frame = self.execute(db).unwrap_or_else(|e| error(&e.to_string()));
+        // 执行失败的命令没有修改数据，不需要写到 AOF 中
+        if let Some(command) = propagate
+            && !matches!(frame, Frame::Error(_))
+        {
+            propagated.push(command);
+        }
+        frame
+    }
+
+    /// 在持有锁的状态下执行命令，操作的键保存的值类型不对时返回 `WrongType`。
+    fn execute(self, db: &mut DbGuard) -> Result<Frame, WrongType> {
+        let frame = match self {
+            Command::Get { key } => db.get_string(&key)?.cloned().map_or(Frame::Null, Frame::Bulk),
+            Command::Set { key, value, expire } => {
+                let expires_at = match expire.map(Expiry::instant) {
+                    Some(None) => return Ok(error("ERR invalid expire time in 'set' command")),
+                    Some(when) => when,
+                    None => None,
+                };
+                db.set(key, value, expires_at);
+                Frame::ok()
+            }
+            Command::SetNx { key, value } => {
+                if db.contains_key(&key) {
+                    return Ok(Frame::Integer(0));
+                }
+                db.set(key, value, None);
+                Frame::Integer(1)
+            }
+            Command::GetSet { key, value } => {
+                let prev = db.get_string(&key)?.cloned();
+                db.set(key, value, None);
+                prev.map_or(Frame::Null, Frame::Bulk)
+            }
+            Command::Del { keys } => {
+                let removed = keys.iter().filter(|key| db.remove(key).is_some()).count();
+                Frame::Integer(removed as i64)
+            }
+            // 和 Redis 一样，同一个键出现多次就计算多次
+            Command::Exists { keys } => {
+                Frame::Integer(keys.iter().filter(|key| db.contains_key(key)).count() as i64)
+            }
+            Command::IncrBy { key, delta } => {
+                // 不存在的键当作 0
+                let current = match db.get_string(&key)? {
+                    Some(value) => match parse_integer(value) {
+                        Some(n) => n,
+                        None => return Ok(error("ERR value is not an integer or out of range")),
+                    },
+                    None => 0,
+                };
+                let Some(n) = current.checked_add(delta) else {
+                    return Ok(error("ERR increment or decrement would overflow"));
+                };
+                // 修改值不会影响键的过期时间
+                db.update(key, Bytes::from(n.to_string()));
+                Frame::Integer(n)
+            }
+            Command::Append { key, value } => {
+                // Bytes 是不可变的，追加时需要复制一份
+                let mut buf = BytesMut::new();
+                if let Some(old) = db.get_string(&key)? {
+                    buf.extend_from_slice(old);
+                }
+                buf.extend_from_slice(&value);
+                let len = buf.len();
+                db.update(key, buf.freeze());
+                Frame::Integer(len as i64)
+            }
+            Command::StrLen { key } => {
+                Frame::Integer(db.get_string(&key)?.map_or(0, |v| v.len()) as i64)
+            }
+            // 和 Redis 一样，不是字符串的键当作不存在，不回复 WRONGTYPE 错误
+            Command::MGet { keys } => Frame::Array(
+                keys.iter()
+                    .map(|key| match db.get(key).map(Value::as_string) {
+                        Some(Ok(value)) => Frame::Bulk(value.clone()),
+                        _ => Frame::Null,
+                    })
+                    .collect(),
+            ),
+            // 所有的键在同一次加锁中设置，其它客户端不会看到只设置了一部分的状态
+            Command::MSet { pairs } => {
+                for (key, value) in pairs {
+                    db.set(key, value, None);
+                }
+                Frame::ok()
+            }
+            Command::Expire { key, millis } => expire(db, &key, millis),
+            Command::ExpireAt { key, unix_millis } => {
+                expire(db, &key, unix_millis.saturating_sub(unix_now_millis()))
+            }
+            Command::Ttl { key, millis } => match db.expires_at(&key) {
+                None => Frame::Integer(-2),
+                Some(None) => Frame::Integer(-1),
+                Some(Some(when)) => {
+                    let ttl = when.saturating_duration_since(Instant::now()).as_millis() as i64;
+                    // 和 Redis 一样，TTL 四舍五入到秒
+                    Frame::Integer(if millis { ttl } else { (ttl + 500) / 1000 })
+                }
+            },
+            Command::Persist { key } => {
+                let had_expiry = matches!(db.expires_at(&key), Some(Some(_)));
+                if had_expiry {
+                    db.set_expires_at(&key, None);
+                }
+                Frame::Integer(had_expiry as i64)
+            }
+            Command::Type { key } => {
+                Frame::Simple(db.get(&key).map_or("none", Value::type_name).to_string())
+            }
+            Command::Push { key, values, front } => list::push(db, &key, values, front)?,
+            Command::Pop { key, count, front } => list::pop(db, &key, count, front)?,
+            Command::LRange { key, start, stop } => list::range(db, &key, start, stop)?,
+            Command::HSet { key, pairs } => hash::set(db, &key, pairs)?,
+            Command::HGet { key, field } => hash::get(db, &key, &field)?,
+            Command::HGetAll { key } => hash::get_all(db, &key)?,
+            Command::HDel { key, fields } => hash::del(db, &key, &fields)?,
+            Command::SAdd { key, members } => set::add(db, &key, members)?,
+            Command::SRem { key, members } => set::rem(db, &key, &members)?,
+            Command::SMembers { key } => set::members(db, &key)?,
+            Command::SIsMember { key, member } => set::is_member(db, &key, &member)?,
+            Command::ZAdd { key, members } => zset::add(db, &key, members)?,
+            Command::ZRange {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => zset::range(db, &key, start, stop, with_scores)?,
+            Command::ZRangeByScore {
+                key,
+                min,
+                max,
+                with_scores,
+            } => zset::range_by_score(db, &key, min, max, with_scores)?,
+            Command::ZRem { key, members } => zset::rem(db, &key, &members)?,
+            Command::MemoryUsage { key } => db
+                .memory_usage(&key)
+                .map_or(Frame::Null, |size| Frame::Integer(size as i64)),
+            Command::Dump { key } => db
+                .get(&key)
+                .map_or(Frame::Null, |value| Frame::Bulk(rdb::dump(value).into())),
+            Command::Restore {
+                key,
+                payload,
+                expire,
+                replace,
+            } => {
+                let Ok(value) = rdb::restore(payload) else {
+                    return Ok(error("ERR DUMP payload version or checksum are wrong"));
+                };
+                if !replace && db.contains_key(&key) {
+                    return Ok(error("BUSYKEY Target key name already exists."));
+                }
+                let expires_at = match expire.map(Expiry::instant) {
+                    Some(None) => return Ok(error("ERR invalid expire time in 'restore' command")),
+                    Some(when) => when,
+                    None => None,
+                };
+                db.set(key, value, expires_at);
+                Frame::ok()
+            }
+            Command::Publish { .. }
+            | Command::Subscribe { .. }
+            | Command::PSubscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::PUnsubscribe { .. }
+            | Command::Hello { .. }
+            | Command::Save { .. }
+            | Command::LastSave
+            | Command::BgRewriteAof
+            | Command::ReplicaOf { .. }
+            | Command::PSync { .. }
+            | Command::ReplConf { .. }
+            | Command::Role
+            | Command::Cluster { .. }
+            | Command::Asking
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch => unreachable!("handled by `Command::apply`"),
+            Command::Ping { message: None } => Frame::Simple("PONG".to_string()),
+            Command::Ping {
+                message: Some(message),
+            } => Frame::Bulk(message),
+            Command::Unknown { name } => error(&format!("ERR unknown command '{}'", name)),
+        };
+        Ok(frame)
+    }
+}
+
+/// SET 命令的选项：`EX seconds`、`PX milliseconds`、`EXAT timestamp` 或者 `PXAT timestamp`。
+fn set_options(parse: &mut Parse) -> Result<Option<Expiry>, ParseError> {
+    let mut expire = None;
+    while parse.remaining() > 0 {
+        let option = parse.next_string()?.to_uppercase();
+        // 这几个选项只能出现一个，后面必须跟着过期时间
+        let known = matches!(option.as_str(), "EX" | "PX" | "EXAT" | "PXAT");
+        if !known || expire.is_some() || parse.remaining() == 0 {
+            return Err("ERR syntax error".into());
+        }
+        let n = parse.next_int()?;
+        let millis = if option.starts_with("EX") { n.checked_mul(1000) } else { Some(n) };
+        let millis = match millis {
+            Some(millis) if millis > 0 => millis as u64,
+            _ => return Err("ERR invalid expire time in 'set' command".into()),
+        };
+        expire = Some(if option.ends_with("AT") {
+            Expiry::At(millis)
+        } else {
+            Expiry::After(Duration::from_millis(millis))
+        });
+    }
+    Ok(expire)
+}
+
+/// REPLICAOF 的参数：`host port` 或者 `NO ONE`。
+fn primary(parse: &mut Parse) -> Result<Option<(String, u16)>, ParseError> {
+    let host = parse.next_string()?;
+    let port = parse.next_string()?;
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        return Ok(None);
+    }
+    let port = port.parse().map_err(|_| "ERR Invalid master port")?;
+    Ok(Some((host, port)))
+}
+
+/// REPLCONF 的参数是若干个 `option value`，只关心 listening-port 和 ACK，
+/// 其它的选项(例如 capa)忽略。
+fn replconf(parse: &mut Parse) -> Result<Command, ParseError> {
+    const MSG: &str = "ERR value is out of range";
+
+    let (mut listening_port, mut ack) = (None, None);
+    while parse.remaining() > 0 {
+        match parse.next_string()?.to_lowercase().as_str() {
+            "listening-port" => {
+                listening_port = Some(parse.next_int()?.try_into().map_err(|_| MSG)?)
+            }
+            "ack" => ack = Some(parse.next_int()?.try_into().map_err(|_| MSG)?),
+            _ => {
+                parse.next_bytes()?;
+            }
+        }
+    }
+    Ok(Command::ReplConf {
+        listening_port,
+        ack,
+    })
+}
+
+/// RESTORE 的参数：`key ttl payload [REPLACE] [ABSTTL]`，ABSTTL 表示 ttl 是 Unix 时间戳(毫秒)。
+fn restore(parse: &mut Parse) -> Result<Command, ParseError> {
+    let key = parse.next_string()?;
+    let ttl = parse.next_int()?;
+    let payload = parse.next_bytes()?;
+    let (mut replace, mut absttl) = (false, false);
+    while parse.remaining() > 0 {
+        match parse.next_string()?.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absttl = true,
+            _ => return Err("ERR syntax error".into()),
+        }
+    }
+    let expire = match ttl {
+        ..0 => return Err("ERR Invalid TTL value, must be >= 0".into()),
+        0 => None,
+        ttl if absttl => Some(Expiry::At(ttl as u64)),
+        ttl => Some(Expiry::After(Duration::from_millis(ttl as u64))),
+    };
+    Ok(Command::Restore {
+        key,
+        payload,
+        expire,
+        replace,
+    })
+}
+
+/// EXPIRE 系列命令，过期时间不是正数时键会被立刻删除。
+fn expire(db: &mut DbGuard, key: &str, millis: i64) -> Frame {
+    if millis <= 0 {
+        return Frame::Integer(db.remove(key).is_some() as i64);
+    }
+    let Some(when) = Instant::now().checked_add(Duration::from_millis(millis as u64)) else {
+        return error("ERR invalid expire time in 'expire' command");
+    };
+    Frame::Integer(db.set_expires_at(key, Some(when)) as i64)
+}
+
+/// 当前的 Unix 时间戳(毫秒)。
+fn unix_now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64)
+}
+
+fn keys(parse: &mut Parse) -> Result<Vec<String>, ParseError> {
+    // 至少要有一个键
+    let mut keys = vec![parse.next_string()?];
+    while parse.remaining() > 0 {
+        keys.push(parse.next_string()?);
+    }
+    Ok(keys)
+}
+
+/// 剩下的所有参数，可以没有。
+fn remaining_strings(parse: &mut Parse) -> Result<Vec<String>, ParseError> {
+    let mut strings = Vec::with_capacity(parse.remaining());
+    while parse.remaining() > 0 {
+        strings.push(parse.next_string()?);
+    }
+    Ok(strings)
+}
+
+/// 至少一个值，例如 LPUSH 的元素、SADD 的成员。
+fn values(parse: &mut Parse) -> Result<Vec<Bytes>, ParseError> {
+    let mut values = vec![parse.next_bytes()?];
+    while parse.remaining() > 0 {
+        values.push(parse.next_bytes()?);
+    }
+    Ok(values)
+}
+
+/// ZRANGE 和 ZRANGEBYSCORE 最后可选的 WITHSCORES。
+fn with_scores(parse: &mut Parse) -> Result<bool, ParseError> {
+    match parse.remaining() {
+        0 => Ok(false),
+        1 if parse.next_string()?.eq_ignore_ascii_case("withscores") => Ok(true),
+        _ => Err("ERR syntax error".into()),
+    }
+}
+
+/// 把 LRANGE、ZRANGE 的 start 和 stop 转换成下标范围，负数表示从末尾往前数，
+/// 超出 `0..len` 的部分会被截掉。
+fn index_range(start: i64, stop: i64, len: usize) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { (start + len).max(0) } else { start };
+    let stop = if stop < 0 { stop + len } else { stop.min(len - 1) };
+    if start > stop {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
+
+/// 和 Redis 一样，只接受规范的十进制整数：不能有前导的 `+`、空格或者多余的 0。
+fn parse_integer(value: &[u8]) -> Option<i64> {
+    let s = std::str::from_utf8(value).ok()?;
+    let n: i64 = s.parse().ok()?;
+    (n.to_string() == s).then_some(n)
+}
+
+fn error(msg: &str) -> Frame {
+    Frame::Error(msg.to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::DbDropGuard;
+
+    pub(crate) fn run(db: &Db, args: &[&str]) -> Frame {
+        let frame = Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        match Command::from_frame(frame) {
+            Ok(cmd) => cmd.apply(db),
+            Err(e) => Frame::Error(e.to_string()),
+        }
+    }
+
+    pub(crate) fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    pub(crate) fn array(items: &[&str]) -> Frame {
+        Frame::Array(items.iter().map(|s| bulk(s)).collect())
+    }
+
+    #[tokio::test]
+    async fn set_get_and_friends() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["SET", "k", "v"]), Frame::ok());
+        assert_eq!(run(db, &["get", "k"]), bulk("v"));
+        assert_eq!(run(db, &["GET", "missing"]), Frame::Null);
+        assert_eq!(run(db, &["SETNX", "k", "other"]), Frame::Integer(0));
+        assert_eq!(run(db, &["SETNX", "n", "1"]), Frame::Integer(1));
+        assert_eq!(run(db, &["GETSET", "k", "w"]), bulk("v"));
+        assert_eq!(run(db, &["GETSET", "new", "x"]), Frame::Null);
+        assert_eq!(run(db, &["APPEND", "k", "xyz"]), Frame::Integer(4));
+        assert_eq!(run(db, &["APPEND", "fresh", "ab"]), Frame::Integer(2));
+        assert_eq!(run(db, &["STRLEN", "k"]), Frame::Integer(4));
+        assert_eq!(run(db, &["STRLEN", "missing"]), Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn multi_key_commands() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["MSET", "a", "1", "b", "2"]), Frame::ok());
+        assert_eq!(
+            run(db, &["MGET", "a", "x", "b"]),
+            Frame::Array(vec![bulk("1"), Frame::Null, bulk("2")])
+        );
+        assert_eq!(run(db, &["EXISTS", "a", "a", "x"]), Frame::Integer(2));
+        assert_eq!(run(db, &["DEL", "a", "x", "b"]), Frame::Integer(2));
+        assert_eq!(run(db, &["EXISTS", "a", "b"]), Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn counters() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["INCR", "n"]), Frame::Integer(1));
+        assert_eq!(run(db, &["INCRBY", "n", "41"]), Frame::Integer(42));
+        assert_eq!(run(db, &["DECRBY", "n", "50"]), Frame::Integer(-8));
+        assert_eq!(run(db, &["DECR", "n"]), Frame::Integer(-9));
+        assert_eq!(run(db, &["GET", "n"]), bulk("-9"));
+
+        let not_integer = error("ERR value is not an integer or out of range");
+        run(db, &["SET", "s", "12abc"]);
+        assert_eq!(run(db, &["INCR", "s"]), not_integer);
+        run(db, &["SET", "s", " 1"]);
+        assert_eq!(run(db, &["INCR", "s"]), not_integer);
+        assert_eq!(run(db, &["INCRBY", "n", "x"]), not_integer);
+
+        run(db, &["SET", "max", &i64::MAX.to_string()]);
+        let overflow = error("ERR increment or decrement would overflow");
+        assert_eq!(run(db, &["INCR", "max"]), overflow);
+        assert_eq!(run(db, &["GET", "max"]), bulk(&i64::MAX.to_string()));
+    }
+
+    #[tokio::test]
+    async fn errors_do_not_panic() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["FLY", "away"]), error("ERR unknown command 'fly'"));
+        assert_eq!(
+            run(db, &["GET"]),
+            error("ERR wrong number of arguments for 'get' command")
+        );
+        assert_eq!(
+            run(db, &["GET", "a", "b"]),
+            error("ERR wrong number of arguments for 'get' command")
+        );
+        assert_eq!(
+            run(db, &["MSET", "a", "1", "b"]),
+            error("ERR wrong number of arguments for 'mset' command")
+        );
+        assert_eq!(run(db, &["DEL"]), error("ERR wrong number of arguments for 'del' command"));
+        assert!(Command::from_frame(Frame::Simple("GET".into())).is_err());
+        assert_eq!(run(db, &["PING"]), Frame::Simple("PONG".into()));
+    }
+
+    #[tokio::test]
+    async fn wrong_type() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        let wrong_type = error("WRONGTYPE Operation against a key holding the wrong kind of value");
+        run(db, &["SET", "s", "1"]);
+        run(db, &["RPUSH", "l", "a"]);
+        assert_eq!(run(db, &["TYPE", "s"]), Frame::Simple("string".into()));
+        assert_eq!(run(db, &["TYPE", "l"]), Frame::Simple("list".into()));
+        assert_eq!(run(db, &["TYPE", "missing"]), Frame::Simple("none".into()));
+
+        for args in [
+            &["GET", "l"][..],
+            &["INCR", "l"],
+            &["APPEND", "l", "x"],
+            &["GETSET", "l", "x"],
+            &["LPUSH", "s", "x"],
+            &["HGET", "s", "f"],
+            &["SADD", "l", "m"],
+            &["ZRANGE", "s", "0", "-1"],
+        ] {
+            assert_eq!(run(db, args), wrong_type, "{:?}", args);
+        }
+        // 出错的命令不会修改数据
+        assert_eq!(run(db, &["LRANGE", "l", "0", "-1"]), array(&["a"]));
+        assert_eq!(run(db, &["MGET", "s", "l"]), Frame::Array(vec![bulk("1"), Frame::Null]));
+
+        // SET 可以覆盖任何类型的值
+        assert_eq!(run(db, &["SET", "l", "v"]), Frame::ok());
+        assert_eq!(run(db, &["GET", "l"]), bulk("v"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_expire() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["SET", "k", "v", "EX", "10"]), Frame::ok());
+        assert_eq!(run(db, &["TTL", "k"]), Frame::Integer(10));
+        tokio::time::sleep(Duration::from_millis(2600)).await;
+        assert_eq!(run(db, &["PTTL", "k"]), Frame::Integer(7400));
+        assert_eq!(run(db, &["TTL", "k"]), Frame::Integer(7));
+        tokio::time::sleep(Duration::from_millis(7400)).await;
+        assert_eq!(run(db, &["GET", "k"]), Frame::Null);
+        assert_eq!(run(db, &["TTL", "k"]), Frame::Integer(-2));
+
+        run(db, &["SET", "k", "v", "px", "100"]);
+        assert_eq!(run(db, &["SET", "k", "v2"]), Frame::ok());
+        assert_eq!(run(db, &["TTL", "k"]), Frame::Integer(-1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expire_and_persist() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["EXPIRE", "missing", "10"]), Frame::Integer(0));
+        run(db, &["SET", "n", "1"]);
+        assert_eq!(run(db, &["PEXPIRE", "n", "1500"]), Frame::Integer(1));
+        // 修改值不会清除过期时间
+        assert_eq!(run(db, &["INCR", "n"]), Frame::Integer(2));
+        assert_eq!(run(db, &["PTTL", "n"]), Frame::Integer(1500));
+        assert_eq!(run(db, &["PERSIST", "n"]), Frame::Integer(1));
+        assert_eq!(run(db, &["PERSIST", "n"]), Frame::Integer(0));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(run(db, &["GET", "n"]), bulk("2"));
+
+        // 过期时间不是正数时立刻删除
+        assert_eq!(run(db, &["EXPIRE", "n", "-1"]), Frame::Integer(1));
+        assert_eq!(run(db, &["EXISTS", "n"]), Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn invalid_expire_options() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        let syntax = error("ERR syntax error");
+        assert_eq!(run(db, &["SET", "k", "v", "EX"]), syntax);
+        assert_eq!(run(db, &["SET", "k", "v", "XX", "1"]), syntax);
+        assert_eq!(run(db, &["SET", "k", "v", "EX", "1", "PX", "1"]), syntax);
+        assert_eq!(
+            run(db, &["SET", "k", "v", "EX", "0"]),
+            error("ERR invalid expire time in 'set' command")
+        );
+        assert_eq!(
+            run(db, &["EXPIRE", "k", &i64::MAX.to_string()]),
+            error("ERR invalid expire time in 'expire' command")
+        );
+        assert_eq!(run(db, &["EXISTS", "k"]), Frame::Integer(0));
+    }
+
+    /// 快照和 AOF 都放在临时目录中的配置。
+    pub(crate) fn temp_config(name: &str) -> crate::Config {
+        let path = |ext| {
+            let file = format!("my-redis2-{}-{}.{}", std::process::id(), name, ext);
+            std::env::temp_dir().join(file)
+        };
+        crate::Config {
+            dbfilename: path("rdb"),
+            appendfilename: path("aof"),
+            ..crate::Config::default()
+        }
+    }
+
+    /// 模拟服务器重启：用同样的配置创建一个新的数据库，读取之前保存的数据。
+    pub(crate) fn restart(config: &crate::Config) -> DbDropGuard {
+        let guard = DbDropGuard::with_config(config);
+        guard.db().load().unwrap();
+        guard
+    }
+
+    #[tokio::test]
+    async fn save_and_load_a_snapshot() {
+        let config = temp_config("snapshot");
+        let guard = DbDropGuard::with_config(&config);
+        let db = &guard.db();
+        run(db, &["SET", "s", "v", "EX", "100"]);
+        run(db, &["SET", "gone", "v", "PX", "1"]);
+        run(db, &["RPUSH", "l", "a", "b"]);
+        run(db, &["ZADD", "z", "1.5", "m"]);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(run(db, &["SAVE"]), Frame::ok());
+        let Frame::Integer(last_save) = run(db, &["LASTSAVE"]) else {
+            panic!("LASTSAVE should reply an integer");
+        };
+        assert!(last_save > 0);
+
+        // 已经过期的键不会被读回来
+        let restarted = DbDropGuard::with_config(&config);
+        let db = &restarted.db();
+        assert_eq!(db.load().unwrap(), 3);
+        assert_eq!(run(db, &["GET", "s"]), bulk("v"));
+        assert!(matches!(run(db, &["TTL", "s"]), Frame::Integer(99 | 100)));
+        assert_eq!(run(db, &["EXISTS", "gone"]), Frame::Integer(0));
+        assert_eq!(run(db, &["LRANGE", "l", "0", "-1"]), array(&["a", "b"]));
+        assert_eq!(run(db, &["ZRANGE", "z", "0", "-1", "WITHSCORES"]), array(&["m", "1.5"]));
+
+        run(db, &["DEL", "s"]);
+        assert_eq!(run(db, &["BGSAVE"]), Frame::Simple("Background saving started".into()));
+        std::fs::remove_file(config.dbfilename).unwrap();
+    }
+
+    #[tokio::test]
+    async fn aof_replays_writes_after_a_restart() {
+        let config = crate::Config {
+            appendonly: true,
+            appendfsync: crate::aof::Fsync::Always,
+            ..temp_config("replay")
+        };
+        let guard = restart(&config);
+        let db = &guard.db();
+        run(db, &["SET", "s", "v", "EX", "100"]);
+        run(db, &["SET", "gone", "v", "PX", "1"]);
+        run(db, &["INCR", "n"]);
+        run(db, &["INCRBY", "n", "41"]);
+        run(db, &["RPUSH", "l", "a", "b", "c"]);
+        run(db, &["LPOP", "l"]);
+        run(db, &["HSET", "h", "f", "v"]);
+        // 执行失败的命令不会写到 AOF 中，重新执行时也不会出错
+        assert!(matches!(run(db, &["INCR", "l"]), Frame::Error(_)));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        // 服务器在写一条命令的过程中崩溃了，AOF 的末尾只有半条命令
+        let mut file = std::fs::OpenOptions::new().append(true).open(&config.appendfilename);
+        std::io::Write::write_all(file.as_mut().unwrap(), b"*3\r\n$3\r\nSET\r\n$1\r\nx").unwrap();
+
+        let restarted = restart(&config);
+        let db = &restarted.db();
+        assert_eq!(run(db, &["GET", "s"]), bulk("v"));
+        assert!(matches!(run(db, &["TTL", "s"]), Frame::Integer(99 | 100)));
+        assert_eq!(run(db, &["EXISTS", "gone", "x"]), Frame::Integer(0));
+        assert_eq!(run(db, &["GET", "n"]), bulk("42"));
+        assert_eq!(run(db, &["LRANGE", "l", "0", "-1"]), array(&["b", "c"]));
+        assert_eq!(run(db, &["HGET", "h", "f"]), bulk("v"));
+
+        // 半条命令被截掉了，之后追加的命令可以正常读回来
+        run(db, &["SET", "after", "1"]);
+        let len = std::fs::metadata(&config.appendfilename).unwrap().len();
+        let restarted = restart(&config);
+        assert_eq!(run(&restarted.db(), &["GET", "after"]), bulk("1"));
+
+        // 重写之后文件变短了，内容不变
+        let db = restarted.db();
+        db.bgrewriteaof().unwrap().await.unwrap().unwrap();
+        assert!(std::fs::metadata(&config.appendfilename).unwrap().len() < len);
+        let restarted = restart(&config);
+        let db = &restarted.db();
+        assert_eq!(run(db, &["GET", "n"]), bulk("42"));
+        assert!(matches!(run(db, &["TTL", "s"]), Frame::Integer(99 | 100)));
+        assert_eq!(run(db, &["LRANGE", "l", "0", "-1"]), array(&["b", "c"]));
+        std::fs::remove_file(config.appendfilename).unwrap();
+    }
+
+    #[tokio::test]
+    async fn enabling_aof_keeps_the_data_in_the_snapshot() {
+        let config = temp_config("seed");
+        let guard = restart(&config);
+        run(&guard.db(), &["SET", "k", "v"]);
+        assert_eq!(run(&guard.db(), &["BGREWRITEAOF"]), error("ERR append only file is disabled"));
+        run(&guard.db(), &["SAVE"]);
+
+        let config = crate::Config {
+            appendonly: true,
+            ..config
+        };
+        restart(&config);
+        std::fs::remove_file(&config.dbfilename).unwrap();
+        // 第一次开启 AOF 时用快照中的数据生成了 AOF，之后不再需要快照
+        let restarted = restart(&config);
+        assert_eq!(run(&restarted.db(), &["GET", "k"]), bulk("v"));
+        std::fs::remove_file(config.appendfilename).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_are_refused_over_maxmemory() {
+        let guard = DbDropGuard::with_config(&crate::Config {
+            maxmemory: 1000,
+            ..crate::Config::default()
+        });
+        let db = &guard.db();
+        let large = "x".repeat(2000);
+        assert_eq!(run(db, &["SET", "k", &large]), Frame::ok());
+        let Frame::Integer(usage) = run(db, &["MEMORY", "USAGE", "k"]) else {
+            panic!("MEMORY USAGE should reply an integer");
+        };
+        assert!(usage > 2000, "{}", usage);
+        assert_eq!(run(db, &["MEMORY", "USAGE", "missing"]), Frame::Null);
+        assert_eq!(run(db, &["MEMORY", "DOCTOR"]), error("ERR unknown subcommand 'doctor'"));
+
+        let oom = error("OOM command not allowed when used memory > 'maxmemory'.");
+        assert_eq!(run(db, &["RPUSH", "l", "a"]), oom);
+        // 读命令和释放内存的命令不受影响
+        assert_eq!(run(db, &["STRLEN", "k"]), Frame::Integer(2000));
+        assert_eq!(run(db, &["DEL", "k"]), Frame::Integer(1));
+        assert_eq!(run(db, &["RPUSH", "l", "a"]), Frame::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn evictions_are_written_to_the_aof() {
+        let config = crate::Config {
+            appendonly: true,
+            appendfsync: crate::aof::Fsync::Always,
+            maxmemory: 10_000,
+            maxmemory_policy: crate::eviction::Policy::AllKeysLru,
+            ..temp_config("evict")
+        };
+        let guard = restart(&config);
+        let db = &guard.db();
+        let value = "x".repeat(1000);
+        for i in 0..20 {
+            assert_eq!(run(db, &["SET", &format!("k{}", i), &value]), Frame::ok());
+        }
+        assert!(db.used_memory() <= 10_000 + 2000);
+        let evicted = db.evicted_keys() as usize;
+        assert!(evicted > 0);
+        assert_eq!(db.len(), 20 - evicted);
+
+        // 淘汰写成了 DEL，重启之后被淘汰的键仍然不存在
+        let restarted = restart(&config);
+        assert_eq!(restarted.db().len(), 20 - evicted);
+        assert_eq!(restarted.db().evicted_keys(), 0);
+        std::fs::remove_file(config.appendfilename).unwrap();
+    }
+
+    #[tokio::test]
+    async fn loading_the_aof_ignores_a_smaller_maxmemory() {
+        let config = crate::Config {
+            appendonly: true,
+            appendfsync: crate::aof::Fsync::Always,
+            ..temp_config("shrink")
+        };
+        let guard = restart(&config);
+        let value = "x".repeat(1000);
+        for i in 0..20 {
+            run(&guard.db(), &["SET", &format!("k{}", i), &value]);
+        }
+        drop(guard);
+
+        // 数据有 20KB，重启时 maxmemory 只有 5KB：无论哪种策略都要读回所有的键
+        use crate::eviction::Policy;
+        for policy in [Policy::NoEviction, Policy::AllKeysLru] {
+            let restarted = restart(&crate::Config {
+                maxmemory: 5000,
+                maxmemory_policy: policy,
+                ..config.clone()
+            });
+            let db = &restarted.db();
+            assert_eq!(db.len(), 20, "{:?}", policy);
+            assert_eq!(db.evicted_keys(), 0, "{:?}", policy);
+        }
+        let restarted = restart(&crate::Config {
+            maxmemory: 5000,
+            ..config.clone()
+        });
+        // 读完之后，新的写命令照常受 maxmemory 的限制
+        let oom = error("OOM command not allowed when used memory > 'maxmemory'.");
+        assert_eq!(run(&restarted.db(), &["SET", "new", "v"]), oom);
+        std::fs::remove_file(config.appendfilename).unwrap();
+    }
+}
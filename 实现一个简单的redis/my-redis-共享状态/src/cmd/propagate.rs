@@ -0,0 +1,168 @@
+use super::{Command, unix_now_millis};
+use crate::Frame;
+use crate::aof::command;
+
+use bytes::Bytes;
+
+// 写命令在 AOF 中的形式。大部分命令原样写入，只有带相对过期时间的命令需要改写：
+// `SET k v EX 10` 写成 `SET k v PXAT <时间戳>`，`EXPIRE k 10` 写成 `PEXPIREAT k <时间戳>`，
+// 重新执行时键还是在原来的时刻过期。
+
+impl Command {
+    /// 写命令需要写到 AOF 中的帧，只读的命令返回 `None`。
+    ///
+    /// 需要在执行命令之前调用：相对的过期时间是从这一刻开始计算的。
+    pub fn propagate(&self) -> Option<Frame> {
+        let frame = match self {
+            Command::Set { key, value, expire } => {
+                let mut args = vec![arg(key), value.clone()];
+                if let Some(expire) = expire {
+                    args.extend([arg("PXAT"), arg(&expire.unix_millis().to_string())]);
+                }
+                command("SET", args)
+            }
+            Command::SetNx { key, value } => command("SETNX", [arg(key), value.clone()]),
+            Command::GetSet { key, value } => command("GETSET", [arg(key), value.clone()]),
+            Command::Del { keys } => command("DEL", keys.iter().map(|key| arg(key))),
+            Command::IncrBy { key, delta } => {
+                command("INCRBY", [arg(key), arg(&delta.to_string())])
+            }
+            Command::Append { key, value } => command("APPEND", [arg(key), value.clone()]),
+            Command::MSet { pairs } => command(
+                "MSET",
+                pairs.iter().flat_map(|(key, value)| [arg(key), value.clone()]),
+            ),
+            Command::Expire { key, millis } => {
+                let unix_millis = unix_now_millis().saturating_add(*millis);
+                command("PEXPIREAT", [arg(key), arg(&unix_millis.to_string())])
+            }
+            Command::ExpireAt { key, unix_millis } => {
+                command("PEXPIREAT", [arg(key), arg(&unix_millis.to_string())])
+            }
+            Command::Persist { key } => command("PERSIST", [arg(key)]),
+            Command::Push { key, values, front } => {
+                let name = if *front { "LPUSH" } else { "RPUSH" };
+                command(name, with_key(key, values.iter().cloned()))
+            }
+            Command::Pop { key, count, front } => {
+                let name = if *front { "LPOP" } else { "RPOP" };
+                let count = count.map(|count| arg(&count.to_string()));
+                command(name, with_key(key, count))
+            }
+            Command::HSet { key, pairs } => {
+                let args = pairs.iter().flat_map(|(field, value)| [field.clone(), value.clone()]);
+                command("HSET", with_key(key, args))
+            }
+            Command::HDel { key, fields } => command("HDEL", with_key(key, fields.iter().cloned())),
+            Command::SAdd { key, members } => {
+                command("SADD", with_key(key, members.iter().cloned()))
+            }
+            Command::SRem { key, members } => {
+                command("SREM", with_key(key, members.iter().cloned()))
+            }
+            Command::ZAdd { key, members } => {
+                let args = members
+                    .iter()
+                    .flat_map(|(score, member)| [arg(&score.to_string()), member.clone()]);
+                command("ZADD", with_key(key, args))
+            }
+            Command::ZRem { key, members } => {
+                command("ZREM", with_key(key, members.iter().cloned()))
+            }
+            // DUMP 的内容里没有过期时间，换算成绝对时间写在参数中。REPLACE 总是可以加上：
+            // 没有 REPLACE 时键已经存在的命令执行失败了，不会写到 AOF 中
+            Command::Restore {
+                key,
+                payload,
+                expire,
+                ..
+            } => {
+                let ttl = expire.map_or(0, |expire| expire.unix_millis());
+                let args = [arg(&ttl.to_string()), payload.clone(), arg("REPLACE"), arg("ABSTTL")];
+                command("RESTORE", with_key(key, args))
+            }
+            // 这里故意不使用 `_`，新增的写命令不能忘了写到 AOF 中
+            Command::Get { .. }
+            | Command::Exists { .. }
+            | Command::StrLen { .. }
+            | Command::MGet { .. }
+            | Command::Ttl { .. }
+            | Command::Type { .. }
+            | Command::LRange { .. }
+            | Command::HGet { .. }
+            | Command::HGetAll { .. }
+            | Command::SMembers { .. }
+            | Command::SIsMember { .. }
+            | Command::ZRange { .. }
+            | Command::ZRangeByScore { .. }
+            | Command::MemoryUsage { .. }
+            | Command::Dump { .. }
+            | Command::Publish { .. }
+            | Command::Subscribe { .. }
+            | Command::PSubscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::PUnsubscribe { .. }
+            | Command::Ping { .. }
+            | Command::Hello { .. }
+            | Command::Save { .. }
+            | Command::LastSave
+            | Command::BgRewriteAof
+            | Command::ReplicaOf { .. }
+            | Command::PSync { .. }
+            | Command::ReplConf { .. }
+            | Command::Role
+            | Command::Cluster { .. }
+            | Command::Asking
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Unknown { .. } => return None,
+        };
+        Some(frame)
+    }
+}
+
+fn arg(s: &str) -> Bytes {
+    Bytes::copy_from_slice(s.as_bytes())
+}
+
+fn with_key(key: &str, args: impl IntoIterator<Item = Bytes>) -> impl Iterator<Item = Bytes> {
+    std::iter::once(arg(key)).chain(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Command;
+    use crate::cmd::tests::array;
+
+    fn propagate(args: &[&str]) -> Option<String> {
+        let cmd = Command::from_frame(array(args)).unwrap();
+        cmd.propagate().map(|frame| frame.to_string())
+    }
+
+    #[test]
+    fn relative_expirations_become_absolute() {
+        let now = super::unix_now_millis();
+        let set = propagate(&["SET", "k", "v", "EX", "10"]).unwrap();
+        let at: i64 = set.strip_prefix("SET k v PXAT ").unwrap().parse().unwrap();
+        assert!((now + 10_000..now + 11_000).contains(&at), "{}", set);
+
+        let expire = propagate(&["EXPIRE", "k", "10"]).unwrap();
+        let at: i64 = expire.strip_prefix("PEXPIREAT k ").unwrap().parse().unwrap();
+        assert!((now + 10_000..now + 11_000).contains(&at), "{}", expire);
+
+        let expire_at = propagate(&["EXPIREAT", "k", "1700000000"]);
+        assert_eq!(expire_at.unwrap(), "PEXPIREAT k 1700000000000");
+    }
+
+    #[test]
+    fn only_write_commands_are_propagated() {
+        assert_eq!(propagate(&["rpush", "l", "a", "b"]).unwrap(), "RPUSH l a b");
+        assert_eq!(propagate(&["DECR", "n"]).unwrap(), "INCRBY n -1");
+        assert_eq!(propagate(&["ZADD", "z", "1.5", "m"]).unwrap(), "ZADD z 1.5 m");
+        assert_eq!(propagate(&["GET", "k"]), None);
+        assert_eq!(propagate(&["PUBLISH", "c", "m"]), None);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::db::DbGuard;
+use crate::value::{Value, WrongType};
+use crate::Frame;
+
+use bytes::Bytes;
+use std::collections::HashSet;
+
+// 集合命令，集合中的成员各不相同，没有顺序。
+
+/// SADD，返回新添加的成员个数。
+pub(super) fn add(db: &mut DbGuard, key: &str, members: Vec<Bytes>) -> Result<Frame, WrongType> {
+    let set = db
+        .get_or_insert_with(key, || Value::Set(HashSet::new()))
+        .as_set_mut()?;
+    let added = members.into_iter().filter(|member| set.insert(member.clone())).count();
+    Ok(Frame::Integer(added as i64))
+}
+
+/// SREM，返回删除的成员个数，删除了所有成员之后键也会被删除。
+pub(super) fn rem(db: &mut DbGuard, key: &str, members: &[Bytes]) -> Result<Frame, WrongType> {
+    let Some(value) = db.get_mut(key) else {
+        return Ok(Frame::Integer(0));
+    };
+    let set = value.as_set_mut()?;
+    let removed = members.iter().filter(|member| set.remove(*member)).count();
+    db.remove_if_empty(key);
+    Ok(Frame::Integer(removed as i64))
+}
+
+pub(super) fn members(db: &mut DbGuard, key: &str) -> Result<Frame, WrongType> {
+    let members = match db.get(key) {
+        Some(value) => value.as_set()?.iter().cloned().map(Frame::Bulk).collect(),
+        None => vec![],
+    };
+    Ok(Frame::Set(members))
+}
+
+pub(super) fn is_member(db: &mut DbGuard, key: &str, member: &[u8]) -> Result<Frame, WrongType> {
+    let found = match db.get(key) {
+        Some(value) => value.as_set()?.contains(member),
+        None => false,
+    };
+    Ok(Frame::Integer(found as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd::tests::{array, run};
+    use crate::{DbDropGuard, Frame};
+
+    #[tokio::test]
+    async fn add_remove_and_query_members() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["SADD", "s", "a", "b", "a"]), Frame::Integer(2));
+        assert_eq!(run(db, &["SADD", "s", "b", "c"]), Frame::Integer(1));
+        assert_eq!(run(db, &["SISMEMBER", "s", "c"]), Frame::Integer(1));
+        assert_eq!(run(db, &["SISMEMBER", "s", "x"]), Frame::Integer(0));
+        assert_eq!(run(db, &["SISMEMBER", "missing", "x"]), Frame::Integer(0));
+
+        let Frame::Set(mut members) = run(db, &["SMEMBERS", "s"]) else {
+            panic!("SMEMBERS should reply a set");
+        };
+        members.sort_by_key(|member| member.to_string());
+        assert_eq!(Frame::Array(members), array(&["a", "b", "c"]));
+
+        assert_eq!(run(db, &["SREM", "s", "a", "x"]), Frame::Integer(1));
+        assert_eq!(run(db, &["SREM", "s", "b", "c"]), Frame::Integer(2));
+        assert_eq!(run(db, &["EXISTS", "s"]), Frame::Integer(0));
+        assert_eq!(run(db, &["SMEMBERS", "s"]), Frame::Set(vec![]));
+    }
+}
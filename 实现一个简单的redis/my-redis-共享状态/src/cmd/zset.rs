@@ -0,0 +1,146 @@
+use super::index_range;
+use crate::db::DbGuard;
+use crate::value::{ScoreBound, SortedSet, Value, WrongType};
+use crate::Frame;
+
+use bytes::Bytes;
+
+// 有序集合命令，排序和范围查找由 `SortedSet` 实现。
+
+/// 解析分数，和 Redis 一样接受 inf、-inf，但不接受 NaN。
+pub(super) fn parse_score(s: &[u8]) -> Option<f64> {
+    let score: f64 = std::str::from_utf8(s).ok()?.parse().ok()?;
+    (!score.is_nan()).then_some(score)
+}
+
+/// ZRANGEBYSCORE 的 min 和 max，以 `(` 开头表示不包括这个分数。
+pub(super) fn parse_bound(s: &[u8]) -> Option<ScoreBound> {
+    let (s, exclusive) = match s.strip_prefix(b"(") {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+    Some(ScoreBound {
+        value: parse_score(s)?,
+        exclusive,
+    })
+}
+
+/// ZADD，返回新添加的成员个数，已经存在的成员只会更新分数。
+pub(super) fn add(
+    db: &mut DbGuard,
+    key: &str,
+    members: Vec<(f64, Bytes)>,
+) -> Result<Frame, WrongType> {
+    let zset = db
+        .get_or_insert_with(key, || Value::ZSet(SortedSet::new()))
+        .as_zset_mut()?;
+    let added = members
+        .into_iter()
+        .filter(|(score, member)| zset.insert(member.clone(), *score))
+        .count();
+    Ok(Frame::Integer(added as i64))
+}
+
+/// ZRANGE，按排名返回成员，`start` 和 `stop` 的含义和 LRANGE 一样。
+pub(super) fn range(
+    db: &mut DbGuard,
+    key: &str,
+    start: i64,
+    stop: i64,
+    with_scores: bool,
+) -> Result<Frame, WrongType> {
+    let Some(value) = db.get(key) else {
+        return Ok(Frame::Array(vec![]));
+    };
+    let zset = value.as_zset()?;
+    let range = index_range(start, stop, zset.len());
+    let members = zset.iter().skip(range.start).take(range.len());
+    Ok(reply(members, with_scores))
+}
+
+pub(super) fn range_by_score(
+    db: &mut DbGuard,
+    key: &str,
+    min: ScoreBound,
+    max: ScoreBound,
+    with_scores: bool,
+) -> Result<Frame, WrongType> {
+    let Some(value) = db.get(key) else {
+        return Ok(Frame::Array(vec![]));
+    };
+    Ok(reply(value.as_zset()?.range_by_score(min, max), with_scores))
+}
+
+/// ZREM，返回删除的成员个数，删除了所有成员之后键也会被删除。
+pub(super) fn rem(db: &mut DbGuard, key: &str, members: &[Bytes]) -> Result<Frame, WrongType> {
+    let Some(value) = db.get_mut(key) else {
+        return Ok(Frame::Integer(0));
+    };
+    let zset = value.as_zset_mut()?;
+    let removed = members.iter().filter(|member| zset.remove(member)).count();
+    db.remove_if_empty(key);
+    Ok(Frame::Integer(removed as i64))
+}
+
+/// 指定了 WITHSCORES 时，每个成员后面跟着它的分数。
+fn reply<'a>(members: impl Iterator<Item = (&'a Bytes, f64)>, with_scores: bool) -> Frame {
+    let mut frames = vec![];
+    for (member, score) in members {
+        frames.push(Frame::Bulk(member.clone()));
+        if with_scores {
+            frames.push(Frame::Bulk(Bytes::from(score.to_string())));
+        }
+    }
+    Frame::Array(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd::tests::{array, run};
+    use crate::{DbDropGuard, Frame};
+
+    #[tokio::test]
+    async fn add_range_and_remove() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        assert_eq!(run(db, &["ZADD", "z", "2", "b", "1", "a", "3", "c"]), Frame::Integer(3));
+        // 更新分数不算新成员
+        assert_eq!(run(db, &["ZADD", "z", "0.5", "c", "-inf", "d"]), Frame::Integer(1));
+        assert_eq!(run(db, &["ZRANGE", "z", "0", "-1"]), array(&["d", "c", "a", "b"]));
+        assert_eq!(
+            run(db, &["ZRANGE", "z", "-2", "-1", "withscores"]),
+            array(&["a", "1", "b", "2"])
+        );
+        assert_eq!(run(db, &["ZRANGE", "z", "5", "10"]), array(&[]));
+
+        assert_eq!(run(db, &["ZRANGEBYSCORE", "z", "0.5", "2"]), array(&["c", "a", "b"]));
+        assert_eq!(
+            run(db, &["ZRANGEBYSCORE", "z", "-inf", "(1", "WITHSCORES"]),
+            array(&["d", "-inf", "c", "0.5"])
+        );
+        assert_eq!(run(db, &["ZRANGEBYSCORE", "z", "(2", "+inf"]), array(&[]));
+
+        assert_eq!(run(db, &["ZREM", "z", "a", "x", "d"]), Frame::Integer(2));
+        assert_eq!(run(db, &["ZRANGE", "z", "0", "-1"]), array(&["c", "b"]));
+        assert_eq!(run(db, &["ZREM", "z", "b", "c"]), Frame::Integer(2));
+        assert_eq!(run(db, &["EXISTS", "z"]), Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn invalid_scores() {
+        let guard = DbDropGuard::new();
+        let db = &guard.db();
+        let not_float = Frame::Error("ERR value is not a valid float".into());
+        assert_eq!(run(db, &["ZADD", "z", "nan", "a"]), not_float);
+        assert_eq!(run(db, &["ZADD", "z", "1x", "a"]), not_float);
+        assert_eq!(
+            run(db, &["ZRANGEBYSCORE", "z", "[1", "2"]),
+            Frame::Error("ERR min or max is not a float".into())
+        );
+        assert_eq!(
+            run(db, &["ZRANGE", "z", "0", "1", "LIMIT"]),
+            Frame::Error("ERR syntax error".into())
+        );
+        assert_eq!(run(db, &["EXISTS", "z"]), Frame::Integer(0));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::aof::Fsync;
+use crate::db::DEFAULT_SHARDS;
+use crate::eviction::{self, Policy};
+use crate::frame::DEFAULT_MAX_FRAME_SIZE;
+use crate::replication::DEFAULT_BACKLOG_SIZE;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// 服务器的配置，从命令行参数中读取，例如 `cargo run --bin server -- --shards 64 --save 60`。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// 监听的地址
+    pub bind: String,
+    /// 监听的端口
+    pub port: u16,
+    /// 数据库的分片数。分片越多，访问不同键的连接之间的锁竞争越少，
+    /// 但是 MGET、MSET 这样访问多个键的命令需要锁住的分片也越多
+    pub shards: usize,
+    /// RDB 快照文件的路径，启动时从这里读取数据，SAVE 和 BGSAVE 写到这里
+    pub dbfilename: PathBuf,
+    /// 每隔多长时间自动保存一次快照(只在有修改时保存)，`None` 表示只在执行 SAVE/BGSAVE 时保存
+    pub save: Option<Duration>,
+    /// 是否开启 AOF。开启之后启动时从 AOF 而不是快照中恢复数据
+    pub appendonly: bool,
+    pub appendfilename: PathBuf,
+    /// 什么时候把 AOF 刷到磁盘上
+    pub appendfsync: Fsync,
+    /// 客户端发送的一个帧最多多少字节，超过时断开连接
+    pub max_frame_size: usize,
+    /// 启动时成为这个主服务器的从服务器
+    pub replicaof: Option<(String, u16)>,
+    /// 主服务器为断开的从服务器保留多少字节最近的写命令，断开期间的写命令没有超过
+    /// 这个大小时，从服务器重新连接之后只需要补上缺少的部分，不需要重新同步整个数据库
+    pub repl_backlog_size: usize,
+    /// 数据库最多使用多少字节的内存，超过时按照 `maxmemory_policy` 淘汰键，0 表示不限制。
+    /// 使用的内存是估算的，只包括键和值本身(见 eviction.rs)
+    pub maxmemory: usize,
+    pub maxmemory_policy: Policy,
+    /// 每次淘汰时抽样的键的个数
+    pub maxmemory_samples: usize,
+    /// 开启集群模式：集群中所有节点的地址(包括这个服务器)，每个节点的列表必须相同，
+    /// 哈希槽按照列表的顺序分给每个节点(见 cluster.rs)。服务器用 `bind:port` 在列表中
+    /// 认出自己，因此地址的写法要和 `--bind` 完全一致
+    pub cluster: Option<Vec<(String, u16)>>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 6379,
+            shards: DEFAULT_SHARDS,
+            dbfilename: PathBuf::from("dump.rdb"),
+            save: None,
+            appendonly: false,
+            appendfilename: PathBuf::from("appendonly.aof"),
+            appendfsync: Fsync::EverySec,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            replicaof: None,
+            repl_backlog_size: DEFAULT_BACKLOG_SIZE,
+            maxmemory: 0,
+            maxmemory_policy: Policy::NoEviction,
+            maxmemory_samples: eviction::DEFAULT_SAMPLES,
+            cluster: None,
+        }
+    }
+}
+
+impl Config {
+    /// 解析命令行参数(不包括程序名)，没有出现的选项使用默认值。
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> crate::Result<Config> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(option) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for option '{}'", option))?;
+            match option.as_str() {
+                "--bind" => config.bind = value,
+                "--port" => config.port = value.parse().map_err(|_| invalid(&option, &value))?,
+                "--shards" => config.shards = parse_positive(&option, &value)?,
+                "--dbfilename" => config.dbfilename = PathBuf::from(value),
+                "--save" => {
+                    let secs = parse_positive(&option, &value)?;
+                    config.save = Some(Duration::from_secs(secs as u64));
+                }
+                "--appendonly" => {
+                    config.appendonly = match value.as_str() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(invalid(&option, &value)),
+                    }
+                }
+                "--appendfilename" => config.appendfilename = PathBuf::from(value),
+                "--appendfsync" => {
+                    config.appendfsync = value.parse().map_err(|_| invalid(&option, &value))?
+                }
+                "--max-frame-size" => config.max_frame_size = parse_positive(&option, &value)?,
+                "--replicaof" => {
+                    let primary = parse_addr(&value).ok_or_else(|| invalid(&option, &value))?;
+                    config.replicaof = Some(primary);
+                }
+                "--repl-backlog-size" => {
+                    config.repl_backlog_size = parse_positive(&option, &value)?
+                }
+                "--maxmemory" => {
+                    config.maxmemory = parse_memory(&value).ok_or_else(|| invalid(&option, &value))?
+                }
+                "--maxmemory-policy" => {
+                    config.maxmemory_policy = value.parse().map_err(|_| invalid(&option, &value))?
+                }
+                "--maxmemory-samples" => {
+                    config.maxmemory_samples = parse_positive(&option, &value)?
+                }
+                "--cluster" => {
+                    let nodes: Option<Vec<_>> = value.split(',').map(parse_addr).collect();
+                    config.cluster = Some(nodes.ok_or_else(|| invalid(&option, &value))?);
+                }
+                _ => return Err(format!("unknown option '{}'", option).into()),
+            }
+        }
+        // 集群中的节点通过地址认出自己，选项的顺序无关紧要，所以在最后检查
+        if let Some(nodes) = &config.cluster {
+            for (i, (host, port)) in nodes.iter().enumerate() {
+                if nodes[..i].contains(&(host.clone(), *port)) {
+                    return Err(format!("--cluster lists {}:{} more than once", host, port).into());
+                }
+            }
+            if !nodes.contains(&(config.bind.clone(), config.port)) {
+                let addr = format!("{}:{}", config.bind, config.port);
+                return Err(format!("--cluster does not include {}", addr).into());
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn parse_positive(option: &str, value: &str) -> crate::Result<usize> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(invalid(option, value)),
+    }
+}
+
+/// `host:port` 形式的地址。
+fn parse_addr(value: &str) -> Option<(String, u16)> {
+    let (host, port) = value.rsplit_once(':')?;
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// 内存大小，可以带上单位，和 Redis 的配置文件一样：`100mb`、`1gb`、`1048576`。
+fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_lowercase();
+    let units = [("gb", 1 << 30), ("mb", 1 << 20), ("kb", 1 << 10), ("b", 1)];
+    let (digits, unit) = units
+        .into_iter()
+        .find_map(|(suffix, unit)| Some((value.strip_suffix(suffix)?, unit)))
+        .unwrap_or((&value, 1));
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+fn invalid(option: &str, value: &str) -> crate::Error {
+    format!("invalid value '{}' for option '{}'", value, option).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> crate::Result<Config> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(parse(&[]).unwrap(), Config::default());
+        assert_eq!(parse(&["--shards", "64"]).unwrap().shards, 64);
+        let config = parse(&["--dbfilename", "/tmp/a.rdb", "--save", "60"]).unwrap();
+        assert_eq!(config.dbfilename, PathBuf::from("/tmp/a.rdb"));
+        assert_eq!(config.save, Some(Duration::from_secs(60)));
+        let config = parse(&["--appendonly", "yes", "--appendfsync", "always"]).unwrap();
+        assert!(config.appendonly);
+        assert_eq!(config.appendfsync, Fsync::Always);
+        assert_eq!(parse(&["--max-frame-size", "1024"]).unwrap().max_frame_size, 1024);
+        let config = parse(&["--port", "6380", "--replicaof", "127.0.0.1:6379"]).unwrap();
+        assert_eq!(config.port, 6380);
+        assert_eq!(config.replicaof, Some(("127.0.0.1".to_string(), 6379)));
+        let config = parse(&["--maxmemory", "100MB", "--maxmemory-policy", "allkeys-lfu"]).unwrap();
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+        assert_eq!(config.maxmemory_policy, Policy::AllKeysLfu);
+        assert_eq!(parse(&["--maxmemory", "4096"]).unwrap().maxmemory, 4096);
+        let config = parse(&["--cluster", "127.0.0.1:7000,127.0.0.1:7001", "--port", "7001"]);
+        let nodes = config.unwrap().cluster.unwrap();
+        assert_eq!(nodes, [("127.0.0.1".to_string(), 7000), ("127.0.0.1".to_string(), 7001)]);
+        let config = parse(&["--bind", "10.0.0.2", "--cluster", "10.0.0.1:6379,10.0.0.2:6379"]);
+        assert_eq!(config.unwrap().bind, "10.0.0.2");
+
+        let err = |args| parse(args).unwrap_err().to_string();
+        assert_eq!(err(&["--shards"]), "missing value for option '--shards'");
+        assert_eq!(err(&["--shards", "0"]), "invalid value '0' for option '--shards'");
+        assert_eq!(err(&["--save", "soon"]), "invalid value 'soon' for option '--save'");
+        assert_eq!(err(&["--appendfsync", "x"]), "invalid value 'x' for option '--appendfsync'");
+        assert_eq!(err(&["--replicaof", "6379"]), "invalid value '6379' for option '--replicaof'");
+        assert_eq!(err(&["--maxmemory", "1tb"]), "invalid value '1tb' for option '--maxmemory'");
+        assert_eq!(err(&["--daemonize", "yes"]), "unknown option '--daemonize'");
+        assert_eq!(err(&["--cluster", "a:1,b"]), "invalid value 'a:1,b' for option '--cluster'");
+        assert_eq!(err(&["--cluster", "a:7000"]), "--cluster does not include 127.0.0.1:6379");
+        // 端口相同、主机不同的节点不是这个服务器
+        let other_host = err(&["--cluster", "10.0.0.1:6379"]);
+        assert_eq!(other_host, "--cluster does not include 127.0.0.1:6379");
+        let twice = err(&["--cluster", "127.0.0.1:6379,a:1,127.0.0.1:6379"]);
+        assert_eq!(twice, "--cluster lists 127.0.0.1:6379 more than once");
+    }
+}
@@ -0,0 +1,189 @@
+use crate::Db;
+use crate::frame::{DEFAULT_MAX_FRAME_SIZE, Frame, Protocol};
+
+use bytes::{Bytes, BytesMut};
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+
+/// 在 TCP 连接上收发帧。
+///
+/// TCP 是字节流，一次 read 读到的数据可能不到一个帧，也可能包含好几个帧，
+/// 因此读到的数据先放进缓冲区，攒够一个完整的帧之后再解析。
+#[derive(Debug)]
+pub struct Connection {
+    stream: BufWriter<TcpStream>,
+    buffer: BytesMut,
+    /// 回复使用的协议版本，客户端通过 HELLO 切换
+    protocol: Protocol,
+    /// 一个帧最多多少字节，超过时当作协议错误断开连接
+    max_frame_size: usize,
+}
+
+impl Connection {
+    pub fn new(socket: TcpStream) -> Connection {
+        Connection::with_max_frame_size(socket, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(socket: TcpStream, max_frame_size: usize) -> Connection {
+        Connection {
+            stream: BufWriter::new(socket),
+            buffer: BytesMut::with_capacity(4 * 1024),
+            protocol: Protocol::default(),
+            max_frame_size,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// 执行 HELLO 命令：切换协议版本(没有指定版本时保持不变)，回复服务器的信息。
+    ///
+    /// 协议版本是连接的状态，所以 HELLO 由 `Connection` 处理，而不是 `Command::apply`。
+    /// 回复中的 mode 和 role 来自 `db`：是否开启了集群模式，是否是从服务器。
+    pub fn hello(&mut self, protover: Option<i64>, db: &Db) -> Frame {
+        self.protocol = match protover {
+            None => self.protocol,
+            Some(2) => Protocol::Resp2,
+            Some(3) => Protocol::Resp3,
+            Some(_) => return Frame::Error("NOPROTO unsupported protocol version".to_string()),
+        };
+        let proto = match self.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let bulk = |value: &'static str| Frame::Bulk(Bytes::from_static(value.as_bytes()));
+        let field = |name, value| (bulk(name), value);
+        // 和 Redis 一样是一个 map，RESP2 的客户端收到的是 key、value 交替的数组
+        Frame::Map(vec![
+            field("server", bulk("redis")),
+            field("version", bulk(env!("CARGO_PKG_VERSION"))),
+            field("proto", Frame::Integer(proto)),
+            field("mode", bulk(if db.cluster().is_some() { "cluster" } else { "standalone" })),
+            field("role", bulk(if db.is_replica() { "replica" } else { "master" })),
+            field("modules", Frame::Array(vec![])),
+        ])
+    }
+
+    /// 读取一个帧，对端正常关闭连接时返回 `None`。
+    pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(Some(frame));
+            }
+
+            // 缓冲区中的数据不够一个帧，从连接中读取更多的数据
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                // 缓冲区中还有数据说明对端在发送一个帧的过程中关闭了连接
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+
+    fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
+        Frame::parse(&mut self.buffer, self.max_frame_size)
+    }
+
+    /// 按照当前的协议版本写入一个帧。
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        frame.encode(self.protocol, &mut buf);
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await
+    }
+
+    /// 写入已经编码好的数据，主服务器用它转发命令流。
+    pub(crate) async fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data).await?;
+        self.stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DbDropGuard;
+    use tokio::net::TcpListener;
+
+    async fn pair() -> (Connection, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (Connection::new(server), Connection::new(client))
+    }
+
+    #[tokio::test]
+    async fn hello_switches_the_protocol() {
+        let (mut server, mut client) = pair().await;
+        let set = Frame::Set(vec![Frame::Integer(1)]);
+
+        server.write_frame(&set).await.unwrap();
+        assert_eq!(client.read_frame().await.unwrap(), Some(Frame::Array(vec![Frame::Integer(1)])));
+
+        let guard = DbDropGuard::new();
+        let Frame::Map(info) = server.hello(Some(3), &guard.db()) else {
+            panic!("HELLO should reply a map");
+        };
+        assert!(info.contains(&(Frame::Bulk("proto".into()), Frame::Integer(3))));
+        assert_eq!(server.protocol(), Protocol::Resp3);
+        server.write_frame(&set).await.unwrap();
+        assert_eq!(client.read_frame().await.unwrap(), Some(set));
+
+        let error = Frame::Error("NOPROTO unsupported protocol version".into());
+        assert_eq!(server.hello(Some(4), &guard.db()), error);
+        assert_eq!(server.protocol(), Protocol::Resp3);
+        server.hello(Some(2), &guard.db());
+        assert_eq!(server.protocol(), Protocol::Resp2);
+    }
+
+    #[tokio::test]
+    async fn hello_reports_the_mode_and_role() {
+        let (mut server, _client) = pair().await;
+        let mut info = |db: &Db| {
+            let Frame::Map(info) = server.hello(None, db) else {
+                panic!("HELLO should reply a map");
+            };
+            let field = |name: &'static str| {
+                let name = Frame::Bulk(name.into());
+                info.iter().find(|(key, _)| *key == name).unwrap().1.clone()
+            };
+            (field("mode"), field("role"))
+        };
+        let bulk = |value: &'static str| Frame::Bulk(value.into());
+
+        let standalone = DbDropGuard::new();
+        assert_eq!(info(&standalone.db()), (bulk("standalone"), bulk("master")));
+
+        let cluster = DbDropGuard::with_config(&crate::Config {
+            cluster: Some(vec![("127.0.0.1".to_string(), 6379)]),
+            ..crate::Config::default()
+        });
+        assert_eq!(info(&cluster.db()), (bulk("cluster"), bulk("master")));
+
+        // 主服务器不存在也没关系，REPLICAOF 之后就是从服务器了
+        let replica = DbDropGuard::new();
+        replica.db().replicaof(Some(("127.0.0.1".to_string(), 1)));
+        assert_eq!(info(&replica.db()), (bulk("standalone"), bulk("replica")));
+        replica.db().replicaof(None);
+        assert_eq!(info(&replica.db()), (bulk("standalone"), bulk("master")));
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_close_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut server = Connection::with_max_frame_size(server, 16);
+
+        client.write_all(b"PING\r\n$100\r\n").await.unwrap();
+        let ping = Frame::Array(vec![Frame::Bulk("PING".into())]);
+        assert_eq!(server.read_frame().await.unwrap(), Some(ping));
+        let err = server.read_frame().await.unwrap_err().to_string();
+        assert_eq!(err, "Protocol error: invalid length");
+    }
+}
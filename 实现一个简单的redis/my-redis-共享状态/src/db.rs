@@ -0,0 +1,1429 @@
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+use crate::aof::{self, Aof, Fsync};
+use crate::cluster::Cluster;
+use crate::eviction::{Access, Policy};
+use crate::frame::Protocol;
+use crate::pubsub::PubSub;
+use crate::rdb::{self, Record};
+use crate::replication::Replication;
+use crate::value::{Value, WrongType};
+use crate::{Command, Config, Frame};
+
+use bytes::{Bytes, BytesMut};
+use indexmap::IndexMap;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+// 之前的 Db 只是一个 `Arc<Mutex<HashMap<String, Bytes>>>`，数据永远不会过期。
+// 现在每个键可以带上一个过期时间，过期的键通过两种方式删除：
+// - 惰性删除：访问一个键时发现它已经过期了，就当它不存在，顺便删掉
+// - 定期删除：一个后台任务按照过期时间的顺序删除已经过期的键，
+//   否则那些过期之后再也没有被访问过的键会一直占用内存
+//
+// 后台任务不需要每隔一段时间扫描一遍所有的键：所有带过期时间的键按照过期时间排序放在
+// `expirations` 中，任务只需要睡到最早的过期时间，醒来删掉到期的键，再睡到下一个过期时间。
+// 设置了一个更早的过期时间时，通过 `Notify` 提前叫醒它。
+//
+// 只有一把锁时，所有连接的所有命令都要排队，即使它们访问的是完全不相关的键。
+// 因此数据库被分成了若干个分片(见 tokio_hello 中的 `_split_lock`)，每个分片有自己的锁，
+// 键通过哈希函数映射到某一个分片上，访问不同分片的命令可以同时执行。
+// 每个分片有自己的 `expirations`，后台任务依次清理每个分片。
+//
+// 数据只保存在内存中，服务器重启之后就没有了。SAVE 和 BGSAVE 把整个数据库写成一个
+// RDB 快照文件(格式见 rdb.rs)，服务器启动时再读回来。快照需要是某一时刻的完整状态，
+// 因此生成快照时会按顺序锁住所有分片，把数据复制一份之后就释放锁，
+// 编码和写文件这些慢的操作在锁外面完成(Redis 使用 fork 达到同样的效果)。
+// 开启 AOF 之后，每个写命令还会在持有锁的时候追加到 AOF 中(见 aof.rs)，
+// 启动时优先从 AOF 恢复数据，它比快照更新。同样的命令还会转发给从服务器(见 replication.rs)。
+//
+// WATCH 的实现和 Redis 一样：每个分片记录哪些连接在 WATCH 哪些键，
+// 修改一个键(包括删除和过期)时把 WATCH 了它的连接标记为 dirty，EXEC 时发现 dirty 就放弃执行。
+//
+// 每个键还记录了自己大约占用多少内存，以及最后一次访问的时间和频率，
+// 配置了 maxmemory 时用来决定淘汰哪些键(见 eviction.rs)。
+
+/// 默认的分片数，可以通过 `--shards` 修改。
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// 共享的数据库句柄，克隆的开销很小(内部只是一个 `Arc`)。
+#[derive(Debug, Clone)]
+pub struct Db {
+    shared: Arc<Shared>,
+}
+
+/// 持有它的一方(服务器)drop 它时，后台的过期删除任务也会随之退出。
+///
+/// 不能简单地在 `Db` 的 drop 中退出后台任务：每个连接都持有一个 `Db` 的克隆，
+/// 后台任务自己也持有一个，很难判断哪一个才是最后一个“真正的”使用者。
+#[derive(Debug)]
+pub struct DbDropGuard {
+    db: Db,
+    /// 定期保存快照(`--save`)和每秒 fsync AOF(`--appendfsync everysec`)的任务
+    tasks: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    shards: Box<[Mutex<State>]>,
+    /// 把键映射到分片的哈希函数(SipHash)。每个进程使用随机的密钥，
+    /// 客户端没办法故意构造出一批都落在同一个分片上的键
+    hasher: RandomState,
+    /// 叫醒后台的过期删除任务
+    background_task: Notify,
+    shutdown: AtomicBool,
+    /// 发布/订阅和数据库的内容互不相关，使用单独的锁，发布消息时不会和其它命令竞争
+    pub_sub: Mutex<PubSub>,
+    /// 快照和 AOF 的文件路径等配置
+    config: Config,
+    /// 开启了 AOF 时，`Db::load` 读完原来的数据之后打开 AOF
+    aof: OnceLock<Aof>,
+    /// 上一次保存快照之后执行过的写命令个数，没有修改时定期保存会跳过
+    dirty: AtomicU64,
+    /// 上一次成功保存快照的时间(Unix 秒)，LASTSAVE 命令返回这个值
+    last_save: AtomicU64,
+    /// 同一时间只能有一个 SAVE 或者 BGSAVE 在执行
+    saving: AtomicBool,
+    /// 主从复制的状态
+    replication: Replication,
+    /// 所有分片中的键大约占用的内存(字节)，每个命令释放锁时把分片的变化加进来
+    used_memory: AtomicUsize,
+    /// 因为 maxmemory 被淘汰的键的个数
+    evicted_keys: AtomicU64,
+    /// 开启了集群模式时，每个哈希槽属于哪个节点
+    cluster: Option<Cluster>,
+}
+
+/// 一个分片的内容。
+#[derive(Debug, Default)]
+struct State {
+    /// 使用 `IndexMap` 而不是 `HashMap`：淘汰时需要 O(1) 地随机抽取一个键
+    entries: IndexMap<String, Entry>,
+    /// 按过期时间排序的键，后台任务从最前面开始删除
+    expirations: BTreeSet<(Instant, String)>,
+    /// 被 WATCH 的键，以及 WATCH 了它的每个连接的 dirty 标记
+    watchers: HashMap<String, Vec<Arc<AtomicBool>>>,
+    /// 这个分片中的键大约占用的内存，等于每个 `Entry::size` 之和
+    used: usize,
+    /// 通过 `get_mut` 等方法拿到了 `&mut Value` 的键，它们的大小在释放锁时重新估算
+    resized: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    // 在上一节中，我们使用 Vec<u8> 来保存目标数据，
+    // 但是它有一个问题，对它进行克隆时会将底层数据也整个复制一份，效率很低
+    // Bytes 是一个引用计数类型，跟 Arc 非常类似，或者准确的说，
+    // Bytes 就是基于 Arc 实现的，但相比后者Bytes 提供了一些额外的能力。
+    // 列表、哈希表等类型中的元素同样使用 Bytes 保存
+    data: Value,
+    expires_at: Option<Instant>,
+    /// 大约占用的内存，见 `entry_size`
+    size: usize,
+    access: Access,
+}
+
+impl DbDropGuard {
+    /// 创建数据库并启动后台的过期删除任务，必须在 tokio 运行时中调用。
+    pub fn new() -> DbDropGuard {
+        DbDropGuard::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// 创建一个有 `shards` 个分片的数据库，分片数必须大于 0。
+    pub fn with_shards(shards: usize) -> DbDropGuard {
+        DbDropGuard::with_config(&Config {
+            shards,
+            ..Config::default()
+        })
+    }
+
+    /// 按照配置创建数据库。快照和 AOF 不会被读取，需要时调用 `Db::load`。
+    ///
+    /// 配置了 `save` 时会启动一个定期保存快照的任务，
+    /// AOF 使用 `Fsync::EverySec` 时会启动一个每秒 fsync 的任务。
+    pub fn with_config(config: &Config) -> DbDropGuard {
+        let db = Db::new(config.clone());
+        let mut tasks = vec![];
+        if let Some(period) = config.save {
+            tasks.push(tokio::spawn(save_periodically(db.clone(), period)));
+        }
+        if config.appendonly && config.appendfsync == Fsync::EverySec {
+            tasks.push(tokio::spawn(sync_aof_every_second(db.clone())));
+        }
+        DbDropGuard { db, tasks }
+    }
+
+    pub fn db(&self) -> Db {
+        self.db.clone()
+    }
+}
+
+impl Default for DbDropGuard {
+    fn default() -> Self {
+        DbDropGuard::new()
+    }
+}
+
+impl Drop for DbDropGuard {
+    fn drop(&mut self) {
+        self.db.shutdown_purge_task();
+        self.db.shared.replication.shutdown();
+        for task in &self.tasks {
+            // 正在后台写的快照不受影响，它运行在 spawn_blocking 的线程中
+            task.abort();
+        }
+    }
+}
+
+impl Db {
+    fn new(config: Config) -> Db {
+        assert!(config.shards > 0, "the database needs at least one shard");
+        let replication = Replication::new(config.repl_backlog_size);
+        let cluster = Cluster::new(&config);
+        let shared = Arc::new(Shared {
+            shards: (0..config.shards).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            background_task: Notify::new(),
+            shutdown: AtomicBool::new(false),
+            pub_sub: Mutex::new(PubSub::default()),
+            config,
+            aof: OnceLock::new(),
+            dirty: AtomicU64::new(0),
+            last_save: AtomicU64::new(unix_time()),
+            saving: AtomicBool::new(false),
+            replication,
+            used_memory: AtomicUsize::new(0),
+            evicted_keys: AtomicU64::new(0),
+            cluster,
+        });
+        tokio::spawn(purge_expired_tasks(shared.clone()));
+        Db { shared }
+    }
+
+    /// 锁住 `keys` 所在的所有分片，返回的 `DbGuard` 只能访问这些键。
+    ///
+    /// 一个命令的所有操作都在同一次加锁中完成，其它连接不会看到执行到一半的状态。
+    /// 多个分片总是按照下标从小到大的顺序加锁，两个命令不会各自持有一把锁、
+    /// 又等待对方的锁而死锁。锁释放时，如果最早的过期时间提前了，后台任务会被叫醒。
+    pub fn lock<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> DbGuard<'_> {
+        let mut indexes: Vec<usize> = keys.into_iter().map(|key| self.shared.shard(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let shards = indexes
+            .into_iter()
+            .map(|index| {
+                let state = self.shared.shards[index].lock().unwrap();
+                let next_expiration = state.next_expiration();
+                let used = state.used;
+                LockedShard {
+                    index,
+                    state,
+                    next_expiration,
+                    used,
+                }
+            })
+            .collect();
+        DbGuard {
+            shared: &self.shared,
+            shards,
+            dirty: false,
+        }
+    }
+
+    /// WATCH 一个键：之后这个键被修改时 `dirty` 会被设为 true。
+    pub(crate) fn watch(&self, key: &str, dirty: &Arc<AtomicBool>) {
+        let mut state = self.shared.shards[self.shared.shard(key)].lock().unwrap();
+        state.watchers.entry(key.to_string()).or_default().push(dirty.clone());
+    }
+
+    /// 取消 `watch`，没有连接 WATCH 的键会被删除，不会一直占用内存。
+    pub(crate) fn unwatch(&self, key: &str, dirty: &Arc<AtomicBool>) {
+        let mut state = self.shared.shards[self.shared.shard(key)].lock().unwrap();
+        if let Some(watchers) = state.watchers.get_mut(key) {
+            if let Some(pos) = watchers.iter().position(|w| Arc::ptr_eq(w, dirty)) {
+                watchers.swap_remove(pos);
+            }
+            if watchers.is_empty() {
+                state.watchers.remove(key);
+            }
+        }
+    }
+
+    /// 分片数。
+    pub fn shards(&self) -> usize {
+        self.shared.shards.len()
+    }
+
+    /// 数据库中的键的个数，包括已经过期但还没有被删除的键。
+    ///
+    /// 各个分片是依次加锁统计的，其它连接同时在修改时，结果只是一个近似值。
+    pub fn len(&self) -> usize {
+        self.shared.shards.iter().map(|shard| shard.lock().unwrap().entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 向频道发布一条消息，返回收到消息的订阅者个数。
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        self.pub_sub().publish(channel, message)
+    }
+
+    pub(crate) fn pub_sub(&self) -> MutexGuard<'_, PubSub> {
+        self.shared.pub_sub.lock().unwrap()
+    }
+
+    /// 读取之前保存的数据，返回数据库中的键的个数。服务器启动时调用一次。
+    ///
+    /// 开启了 AOF 时重新执行 AOF 中的命令，然后打开 AOF 开始记录新的写命令。
+    /// AOF 还不存在时(第一次开启 AOF)从快照中读取数据，再用这些数据生成一个 AOF，
+    /// 否则刚开启 AOF 时会丢掉快照中的数据。没有开启 AOF 时只读取快照。
+    pub fn load(&self) -> crate::Result<usize> {
+        let config = &self.shared.config;
+        if !config.appendonly {
+            return self.load_rdb();
+        }
+        let commands = aof::read_file(&config.appendfilename)?;
+        let seed = commands.is_none();
+        match commands {
+            Some(commands) => {
+                // 和从服务器执行主服务器的命令一样直接执行，不经过 `Command::apply` 的只读和
+                // maxmemory 检查：重启时 maxmemory 变小了也要完整地读回 AOF 中的数据，
+                // 而不是回复 OOM 或者读到一半就开始淘汰键
+                for (i, frame) in commands.into_iter().enumerate() {
+                    let cmd = Command::from_frame(frame).map_err(|e| format!("aof: {}", e))?;
+                    let mut guard = self.lock(cmd.keys());
+                    // AOF 中只有执行成功的写命令，失败说明文件有问题
+                    if let Frame::Error(e) = cmd.apply_locked_into(&mut guard, &mut vec![]) {
+                        return Err(format!("aof: command #{} failed: {}", i + 1, e).into());
+                    }
+                }
+                self.shared.dirty.store(0, Ordering::Relaxed);
+            }
+            None => {
+                self.load_rdb()?;
+            }
+        }
+
+        let aof = Aof::open(config.appendfilename.clone(), config.appendfsync)?;
+        if self.shared.aof.set(aof).is_err() {
+            return Err("the database is already loaded".into());
+        }
+        if seed {
+            let (aof, records) = self.start_rewrite()?;
+            aof.finish_rewrite(&records)?;
+        }
+        Ok(self.len())
+    }
+
+    /// 读取快照文件，把其中的键加入数据库，返回读到的键的个数。
+    ///
+    /// 快照文件不存在时什么也不做，文件损坏时返回错误。已经过期的键会被跳过。
+    fn load_rdb(&self) -> crate::Result<usize> {
+        let Some(records) = rdb::read_file(&self.shared.config.dbfilename)? else {
+            return Ok(0);
+        };
+        let (now, instant) = (SystemTime::now(), Instant::now());
+        let mut loaded = 0;
+        for record in records {
+            let Some(expires_at) = to_instant(record.expires_at, now, instant) else {
+                continue;
+            };
+            self.lock([record.key.as_str()]).set(record.key, record.value, expires_at);
+            loaded += 1;
+        }
+        // 刚读出来的数据和快照文件是一样的
+        self.shared.dirty.store(0, Ordering::Relaxed);
+        Ok(loaded)
+    }
+
+    /// SAVE：在当前线程中生成快照并写入文件，写完之后才返回。
+    pub fn save(&self) -> crate::Result<()> {
+        let saving = Saving::start(&self.shared)?;
+        let (records, dirty) = self.snapshot();
+        saving.write(&records, dirty)
+    }
+
+    /// BGSAVE：复制一份数据之后立刻返回，编码和写文件在后台的线程中完成。
+    ///
+    /// 返回的 `JoinHandle` 在快照写完时完成，服务器并不等待它，主要用于测试。
+    pub fn bgsave(&self) -> crate::Result<JoinHandle<crate::Result<()>>> {
+        let saving = Saving::start(&self.shared)?;
+        let (records, dirty) = self.snapshot();
+        Ok(tokio::task::spawn_blocking(move || {
+            let result = saving.write(&records, dirty);
+            if let Err(e) = &result {
+                eprintln!("background saving failed: {}", e);
+            }
+            result
+        }))
+    }
+
+    /// BGREWRITEAOF：根据数据库当前的内容在后台重写 AOF，返回的 `JoinHandle` 同 `bgsave`。
+    pub fn bgrewriteaof(&self) -> crate::Result<JoinHandle<crate::Result<()>>> {
+        let (_, records) = self.start_rewrite()?;
+        let shared = self.shared.clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            let result = shared.aof.get().unwrap().finish_rewrite(&records);
+            if let Err(e) = &result {
+                eprintln!("background AOF rewriting failed: {}", e);
+            }
+            result
+        }))
+    }
+
+    /// 开始重写 AOF，返回此时数据库中所有的键。
+    ///
+    /// 开始重写和复制数据需要在同一次加锁中完成，见 `Aof::start_rewrite`。
+    fn start_rewrite(&self) -> crate::Result<(&Aof, Vec<Record>)> {
+        let aof = self.shared.aof.get().ok_or("append only file is disabled")?;
+        let states = self.lock_all();
+        aof.start_rewrite()?;
+        Ok((aof, records(&states)))
+    }
+
+    /// 把 AOF 中还没有 fsync 的内容刷到磁盘上，没有开启 AOF 时什么也不做。
+    pub fn sync_aof(&self) -> io::Result<()> {
+        match self.shared.aof.get() {
+            Some(aof) => aof.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// 上一次成功保存快照的时间(Unix 秒)。
+    pub fn last_save(&self) -> u64 {
+        self.shared.last_save.load(Ordering::Relaxed)
+    }
+
+    /// 复制出所有没有过期的键，同时返回此时的写命令计数。
+    ///
+    /// 所有分片按照下标顺序一起锁住，得到的是某一时刻的完整状态，
+    /// 不会出现一个命令的修改只有一部分出现在快照中的情况。
+    fn snapshot(&self) -> (Vec<Record>, u64) {
+        let states = self.lock_all();
+        (records(&states), self.shared.dirty.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.shared.config
+    }
+
+    pub(crate) fn replication(&self) -> &Replication {
+        &self.shared.replication
+    }
+
+    /// 没有开启集群模式时返回 `None`。
+    pub(crate) fn cluster(&self) -> Option<&Cluster> {
+        self.shared.cluster.as_ref()
+    }
+
+    /// 是否是从服务器。从服务器是只读的，写命令会被拒绝。
+    pub fn is_replica(&self) -> bool {
+        self.shared.replication.is_replica()
+    }
+
+    /// REPLICAOF：成为 `primary` 的从服务器，`None` 表示成为主服务器(REPLICAOF NO ONE)。
+    ///
+    /// 同步在后台进行，连接断开之后会自动重新连接。
+    pub fn replicaof(&self, primary: Option<(String, u16)>) {
+        self.shared.replication.replicaof(self, primary)
+    }
+
+    /// 主服务器上的全量同步：复制出所有的键，同时返回此时命令流的 replid、偏移量和接收端。
+    pub(crate) fn sync_replica(&self) -> (Vec<Record>, String, u64, broadcast::Receiver<Bytes>) {
+        let states = self.lock_all();
+        let (replid, offset, stream) = self.shared.replication.start_full_sync();
+        (records(&states), replid, offset, stream)
+    }
+
+    /// 从服务器上的全量同步：用主服务器的快照替换掉数据库原来的内容。
+    ///
+    /// 替换在同一次加锁中完成，其它连接看到的要么是原来的数据，要么是新的数据。
+    /// 开启了 AOF 时接着重写 AOF，否则 AOF 中还是原来的数据。
+    pub(crate) fn full_resync(
+        &self,
+        task_id: u64,
+        replid: String,
+        offset: u64,
+        records: Vec<Record>,
+    ) -> crate::Result<()> {
+        let mut states = self.lock_all();
+        self.shared.replication.reset(task_id, replid, offset)?;
+        for state in states.iter_mut() {
+            for key in state.entries.keys() {
+                state.touch(key);
+            }
+            self.shared.account(state.used, 0);
+            state.entries.clear();
+            state.expirations.clear();
+            state.used = 0;
+        }
+        let (now, instant) = (SystemTime::now(), Instant::now());
+        for record in records {
+            if let Some(expires_at) = to_instant(record.expires_at, now, instant) {
+                let state = &mut states[self.shared.shard(&record.key)];
+                state.set(record.key, record.value, expires_at);
+            }
+        }
+        for state in &states {
+            self.shared.account(0, state.used);
+        }
+        drop(states);
+        self.shared.dirty.fetch_add(1, Ordering::Relaxed);
+        self.shared.background_task.notify_one();
+        if self.shared.aof.get().is_some() {
+            let (aof, records) = self.start_rewrite()?;
+            aof.finish_rewrite(&records)?;
+        }
+        Ok(())
+    }
+
+    /// 数据库中的键大约占用了多少字节的内存。
+    pub fn used_memory(&self) -> usize {
+        self.shared.used_memory.load(Ordering::Relaxed)
+    }
+
+    /// 因为 maxmemory 被淘汰的键的个数。
+    pub fn evicted_keys(&self) -> u64 {
+        self.shared.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    /// 使用的内存超过了 maxmemory 时，按照淘汰策略删除键，直到低于 maxmemory。
+    ///
+    /// 返回 false 表示腾不出内存：策略是 noeviction，或者已经没有可以淘汰的键了。
+    /// 在执行会让内存增长的写命令之前调用，返回 false 时命令应该回复 OOM 错误。
+    pub fn free_memory(&self) -> bool {
+        let config = &self.shared.config;
+        if config.maxmemory == 0 {
+            return true;
+        }
+        while self.used_memory() > config.maxmemory {
+            if config.maxmemory_policy == Policy::NoEviction {
+                return false;
+            }
+            let Some(key) = self.eviction_candidate() else {
+                return false;
+            };
+            let mut db = self.lock([key.as_str()]);
+            // 抽样之后、加锁之前，这个键可能已经被其它连接删除了，再抽一次就是了
+            if db.remove(&key).is_some() {
+                self.shared.evicted_keys.fetch_add(1, Ordering::Relaxed);
+                // 淘汰和 DEL 一样需要写到 AOF 中、转发给从服务器，否则它们的数据会比这里多
+                let del = aof::command("DEL", [Bytes::from(key)]);
+                if let Err(e) = db.propagate(&[del]) {
+                    eprintln!("failed to write an eviction to the AOF: {}", e);
+                }
+            }
+        }
+        true
+    }
+
+    /// 按照淘汰策略抽样，返回抽到的键中最该被淘汰的一个，没有可以淘汰的键时返回 `None`。
+    ///
+    /// 从一个随机的分片开始，依次在每个分片中抽一个键，跳过没有候选的分片，
+    /// 一共抽 `maxmemory_samples` 个。每次只锁住一个分片。
+    fn eviction_candidate(&self) -> Option<String> {
+        let config = &self.shared.config;
+        let shards = &self.shared.shards;
+        let now = Instant::now();
+        // volatile-ttl 的候选是每个分片中最早过期的键，同一个分片不需要看第二遍
+        let visits = match config.maxmemory_policy {
+            Policy::VolatileTtl => shards.len(),
+            _ => usize::MAX,
+        };
+        let (mut best, mut sampled, mut misses) = (None::<(u64, String)>, 0, 0);
+        for index in (fastrand::usize(..shards.len())..).take(visits) {
+            if sampled == config.maxmemory_samples || misses == shards.len() {
+                break;
+            }
+            let state = shards[index % shards.len()].lock().unwrap();
+            let Some((score, key)) = state.eviction_candidate(config.maxmemory_policy, now) else {
+                misses += 1;
+                continue;
+            };
+            (sampled, misses) = (sampled + 1, 0);
+            if best.as_ref().is_none_or(|(best, _)| score > *best) {
+                best = Some((score, key.to_string()));
+            }
+        }
+        best.map(|(_, key)| key)
+    }
+
+    /// 满足 `filter` 的键，最多 `count` 个，不包括已经过期的键。
+    ///
+    /// 每次只锁住一个分片，但是需要遍历所有的键，只用于 CLUSTER GETKEYSINSLOT 这样的运维命令。
+    pub(crate) fn scan_keys(&self, count: usize, filter: impl Fn(&str) -> bool) -> Vec<String> {
+        let now = Instant::now();
+        let mut keys = Vec::new();
+        for shard in self.shared.shards.iter() {
+            let state = shard.lock().unwrap();
+            let live = state
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.expires_at.is_none_or(|when| when > now))
+                .filter(|(key, _)| filter(key))
+                .map(|(key, _)| key.clone());
+            keys.extend(live.take(count - keys.len()));
+            if keys.len() == count {
+                break;
+            }
+        }
+        keys
+    }
+
+    /// 按照下标顺序锁住所有的分片。
+    fn lock_all(&self) -> Vec<MutexGuard<'_, State>> {
+        self.shared.shards.iter().map(|shard| shard.lock().unwrap()).collect()
+    }
+
+    fn shutdown_purge_task(&self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        self.shared.background_task.notify_one();
+    }
+}
+
+/// `Db::lock` 返回的锁，持有若干个分片的锁。
+///
+/// 访问没有被锁住的分片上的键是一个 bug，会直接 panic。
+pub struct DbGuard<'a> {
+    shared: &'a Shared,
+    /// 按分片的下标排序
+    shards: Vec<LockedShard<'a>>,
+    /// 是否调用过修改数据的方法。`get_mut` 拿到的值不一定真的被修改了，
+    /// 这里宁可多算：最多只是多保存一次快照
+    dirty: bool,
+}
+
+struct LockedShard<'a> {
+    index: usize,
+    state: MutexGuard<'a, State>,
+    /// 加锁时这个分片最早的过期时间
+    next_expiration: Option<Instant>,
+    /// 加锁时这个分片使用的内存
+    used: usize,
+}
+
+impl DbGuard<'_> {
+    /// 键所在的分片。
+    fn shard(&mut self, key: &str) -> &mut State {
+        let index = self.shared.shard(key);
+        match self.shards.binary_search_by_key(&index, |shard| shard.index) {
+            Ok(pos) => &mut self.shards[pos].state,
+            Err(_) => panic!("the shard of key {:?} is not locked", key),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&Value> {
+        self.shard(key).get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.dirty = true;
+        self.shard(key).get_mut(key)
+    }
+
+    /// 读取一个字符串类型的键，键保存的是其它类型的值时返回 `WrongType`。
+    pub fn get_string(&mut self, key: &str) -> Result<Option<&Bytes>, WrongType> {
+        self.get(key).map(Value::as_string).transpose()
+    }
+
+    /// 键不存在时先插入 `default()` 返回的值，例如 LPUSH 会创建一个新的列表。
+    pub fn get_or_insert_with(&mut self, key: &str, default: impl FnOnce() -> Value) -> &mut Value {
+        self.dirty = true;
+        self.shard(key).get_or_insert_with(key, default)
+    }
+
+    pub fn contains_key(&mut self, key: &str) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    /// 设置一个键，同时设置(或者清除)它的过期时间，返回旧的值。
+    pub fn set(
+        &mut self,
+        key: String,
+        value: impl Into<Value>,
+        expires_at: Option<Instant>,
+    ) -> Option<Value> {
+        self.dirty = true;
+        self.shard(&key).set(key, value, expires_at)
+    }
+
+    /// 修改一个键的值，保留它原来的过期时间，例如 INCR 和 APPEND。
+    pub fn update(&mut self, key: String, value: impl Into<Value>) {
+        self.dirty = true;
+        self.shard(&key).update(key, value)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.dirty = true;
+        self.shard(key).remove(key)
+    }
+
+    /// 列表、哈希表等集合类型的值变成空的之后删除这个键，例如 LPOP 弹出了最后一个元素。
+    pub fn remove_if_empty(&mut self, key: &str) {
+        self.dirty = true;
+        self.shard(key).remove_if_empty(key)
+    }
+
+    /// 键的过期时间，键不存在时返回 `None`，键没有过期时间时返回 `Some(None)`。
+    pub fn expires_at(&mut self, key: &str) -> Option<Option<Instant>> {
+        self.shard(key).expires_at(key)
+    }
+
+    /// 修改键的过期时间，`None` 表示永不过期。键不存在时返回 false。
+    pub fn set_expires_at(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        self.dirty = true;
+        self.shard(key).set_expires_at(key, expires_at)
+    }
+
+    /// 键大约占用多少字节的内存，键不存在时返回 `None`。
+    pub fn memory_usage(&mut self, key: &str) -> Option<usize> {
+        self.shard(key).entry(key).map(|entry| entry.size)
+    }
+
+    /// 把执行成功的写命令追加到 AOF 中，并转发给从服务器。
+    ///
+    /// 在持有锁的时候调用，同一个键上的命令在 AOF 和命令流中的顺序才和执行的顺序一致。
+    /// EXEC 的多个写命令用 MULTI/EXEC 包起来一次写入：写到一半时崩溃了，
+    /// 重启时会丢弃没有 EXEC 的事务(见 `aof::read_file`)，从服务器也是收到 EXEC 才执行。
+    pub fn propagate(&self, commands: &[Frame]) -> io::Result<()> {
+        let aof = self.shared.aof.get();
+        if commands.is_empty() || (aof.is_none() && !self.shared.replication.is_feeding()) {
+            return Ok(());
+        }
+        let transaction = commands.len() > 1;
+        let mut data = BytesMut::new();
+        if transaction {
+            aof::command("MULTI", []).encode(Protocol::Resp2, &mut data);
+        }
+        for command in commands {
+            command.encode(Protocol::Resp2, &mut data);
+        }
+        if transaction {
+            aof::command("EXEC", []).encode(Protocol::Resp2, &mut data);
+        }
+        let data = data.freeze();
+        self.shared.replication.feed(data.clone());
+        match aof {
+            Some(aof) => aof.append(&data),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DbGuard<'_> {
+    fn drop(&mut self) {
+        if self.dirty {
+            self.shared.dirty.fetch_add(1, Ordering::Relaxed);
+        }
+        for shard in &mut self.shards {
+            shard.state.resize_changed();
+            self.shared.account(shard.used, shard.state.used);
+        }
+        // 后台任务正睡到加锁前最早的过期时间，只有出现了更早的过期时间才需要叫醒它。
+        // 过期时间被推迟或者删除时不需要：任务醒来后发现没有到期的键，会接着睡到新的时间
+        let earlier = self.shards.iter().any(|shard| {
+            match (shard.state.next_expiration(), shard.next_expiration) {
+                (Some(now), Some(before)) => now < before,
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
+        });
+        if earlier {
+            self.shared.background_task.notify_one();
+        }
+    }
+}
+
+impl State {
+    /// 返回一个没有过期的键，已经过期的键会被顺便删除(惰性删除)。
+    /// 同时记录一次访问，用于 LRU/LFU 淘汰。
+    fn entry(&mut self, key: &str) -> Option<&mut Entry> {
+        let now = Instant::now();
+        let expired = self
+            .entries
+            .get(key)?
+            .expires_at
+            .is_some_and(|when| when <= now);
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        let entry = self.entries.get_mut(key)?;
+        entry.access.touch(now);
+        Some(entry)
+    }
+
+    fn get(&mut self, key: &str) -> Option<&Value> {
+        self.entry(key).map(|entry| &entry.data)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        // 拿到 `&mut` 的调用者不一定真的修改了值，这里宁可多算，最多只是让一个事务多重试一次
+        self.touch(key);
+        self.resized.push(key.to_string());
+        self.entry(key).map(|entry| &mut entry.data)
+    }
+
+    /// 键不存在时先插入 `default()` 返回的值，例如 LPUSH 会创建一个新的列表。
+    fn get_or_insert_with(&mut self, key: &str, default: impl FnOnce() -> Value) -> &mut Value {
+        self.touch(key);
+        self.resized.push(key.to_string());
+        if self.entry(key).is_none() {
+            self.set(key.to_string(), default(), None);
+        }
+        &mut self.entries.get_mut(key).unwrap().data
+    }
+
+    fn contains_key(&mut self, key: &str) -> bool {
+        self.entry(key).is_some()
+    }
+
+    /// 设置一个键，同时设置(或者清除)它的过期时间，返回旧的值。
+    fn set(
+        &mut self,
+        key: String,
+        value: impl Into<Value>,
+        expires_at: Option<Instant>,
+    ) -> Option<Value> {
+        self.touch(&key);
+        let prev = self.remove(&key);
+        if let Some(when) = expires_at {
+            self.expirations.insert((when, key.clone()));
+        }
+        let mut entry = Entry {
+            data: value.into(),
+            expires_at,
+            size: 0,
+            access: Access::new(Instant::now()),
+        };
+        entry.size = entry_size(&key, &entry);
+        self.used += entry.size;
+        self.entries.insert(key, entry);
+        prev
+    }
+
+    /// 修改一个键的值，保留它原来的过期时间，例如 INCR 和 APPEND。
+    fn update(&mut self, key: String, value: impl Into<Value>) {
+        self.touch(&key);
+        match self.entry(&key) {
+            Some(entry) => {
+                entry.data = value.into();
+                self.resize(&key);
+            }
+            None => {
+                self.set(key, value, None);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        let entry = self.entries.swap_remove(key)?;
+        self.used -= entry.size;
+        self.touch(key);
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+        // 已经过期但还没来得及删除的键也当作不存在
+        match entry.expires_at {
+            Some(when) if when <= Instant::now() => None,
+            _ => Some(entry.data),
+        }
+    }
+
+    /// 列表、哈希表等集合类型的值变成空的之后删除这个键，例如 LPOP 弹出了最后一个元素。
+    fn remove_if_empty(&mut self, key: &str) {
+        let empty = self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.data.is_empty_collection());
+        if empty {
+            self.remove(key);
+        }
+    }
+
+    /// 键的过期时间，键不存在时返回 `None`，键没有过期时间时返回 `Some(None)`。
+    fn expires_at(&mut self, key: &str) -> Option<Option<Instant>> {
+        self.entry(key).map(|entry| entry.expires_at)
+    }
+
+    /// 修改键的过期时间，`None` 表示永不过期。键不存在时返回 false。
+    fn set_expires_at(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let Some(entry) = self.entry(key) else {
+            return false;
+        };
+        let prev = std::mem::replace(&mut entry.expires_at, expires_at);
+        self.touch(key);
+        if let Some(when) = prev {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+        if let Some(when) = expires_at {
+            self.expirations.insert((when, key.to_string()));
+        }
+        self.resize(key);
+        true
+    }
+
+    /// 删除所有已经过期的键，返回下一个键的过期时间。
+    fn purge_expired_keys(&mut self, now: Instant) -> Option<Instant> {
+        while let Some((when, key)) = self.expirations.first() {
+            if *when > now {
+                return Some(*when);
+            }
+            if let Some(entry) = self.entries.swap_remove(key) {
+                self.used -= entry.size;
+            }
+            self.touch(key);
+            self.expirations.pop_first();
+        }
+        None
+    }
+
+    /// 重新估算一个键的大小，值被修改之后调用。
+    fn resize(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            let size = entry_size(key, entry);
+            self.used = self.used - entry.size + size;
+            entry.size = size;
+        }
+    }
+
+    /// 重新估算所有拿到过 `&mut Value` 的键的大小，释放锁之前调用。
+    fn resize_changed(&mut self) {
+        for key in std::mem::take(&mut self.resized) {
+            self.resize(&key);
+        }
+    }
+
+    /// 按照淘汰策略随机抽取一个候选的键，返回它的分数(越大越应该被淘汰)。
+    ///
+    /// volatile-ttl 不需要抽样：`expirations` 本来就是按过期时间排好序的。
+    fn eviction_candidate(&self, policy: Policy, now: Instant) -> Option<(u64, &str)> {
+        let score = |entry: &Entry| match policy {
+            Policy::AllKeysLfu => (u8::MAX - entry.access.frequency(now)) as u64,
+            _ => entry.access.idle(now),
+        };
+        match policy {
+            Policy::NoEviction => None,
+            Policy::VolatileTtl => {
+                let (when, key) = self.expirations.first()?;
+                let ttl = when.saturating_duration_since(now).as_millis() as u64;
+                Some((u64::MAX - ttl, key))
+            }
+            Policy::AllKeysLru | Policy::AllKeysLfu => {
+                if self.entries.is_empty() {
+                    return None;
+                }
+                let (key, entry) = self.entries.get_index(fastrand::usize(..self.entries.len()))?;
+                Some((score(entry), key))
+            }
+        }
+    }
+
+    /// 键被修改了，把 WATCH 了它的连接标记为 dirty。
+    fn touch(&self, key: &str) {
+        if self.watchers.is_empty() {
+            return;
+        }
+        if let Some(watchers) = self.watchers.get(key) {
+            watchers.iter().for_each(|dirty| dirty.store(true, Ordering::Release));
+        }
+    }
+
+    fn next_expiration(&self) -> Option<Instant> {
+        self.expirations.first().map(|(when, _)| *when)
+    }
+}
+
+impl Shared {
+    /// 键所在分片的下标。
+    fn shard(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    /// 依次删除每个分片中已经过期的键，返回下一个键的过期时间。
+    ///
+    /// 每次只锁住一个分片，清理过期键的同时其它分片仍然可以正常访问。
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.shards
+            .iter()
+            .filter_map(|shard| {
+                let mut state = shard.lock().unwrap();
+                let used = state.used;
+                let next_expiration = state.purge_expired_keys(now);
+                self.account(used, state.used);
+                next_expiration
+            })
+            .min()
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    /// 一个分片使用的内存从 `before` 变成了 `after`。
+    fn account(&self, before: usize, after: usize) {
+        if after > before {
+            self.used_memory.fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            self.used_memory.fetch_sub(before - after, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 正在执行的 SAVE 或者 BGSAVE，drop 时允许下一次保存。
+struct Saving {
+    shared: Arc<Shared>,
+}
+
+impl Saving {
+    fn start(shared: &Arc<Shared>) -> crate::Result<Saving> {
+        if shared.saving.swap(true, Ordering::AcqRel) {
+            return Err("Background save already in progress".into());
+        }
+        Ok(Saving {
+            shared: shared.clone(),
+        })
+    }
+
+    /// 写入快照文件。`dirty` 是生成快照时的写命令计数，
+    /// 生成快照之后的修改不在文件中，仍然算作没有保存的修改。
+    fn write(self, records: &[Record], dirty: u64) -> crate::Result<()> {
+        rdb::write_file(&self.shared.config.dbfilename, records)?;
+        self.shared.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        self.shared.last_save.store(unix_time(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for Saving {
+    fn drop(&mut self) {
+        self.shared.saving.store(false, Ordering::Release);
+    }
+}
+
+/// 复制出所有没有过期的键，过期时间换算成绝对时间。
+fn records(states: &[MutexGuard<'_, State>]) -> Vec<Record> {
+    let (now, instant) = (SystemTime::now(), Instant::now());
+    let mut records = vec![];
+    for state in states {
+        for (key, entry) in &state.entries {
+            let expires_at = match entry.expires_at {
+                Some(when) if when <= instant => continue,
+                Some(when) => Some(now + (when - instant)),
+                None => None,
+            };
+            records.push(Record {
+                key: key.clone(),
+                value: entry.data.clone(),
+                expires_at,
+            });
+        }
+    }
+    records
+}
+
+/// 把快照中的绝对过期时间换算成 `Instant`，已经过期的键返回 `None`。
+fn to_instant(
+    expires_at: Option<SystemTime>,
+    now: SystemTime,
+    instant: Instant,
+) -> Option<Option<Instant>> {
+    match expires_at {
+        Some(when) => match when.duration_since(now) {
+            Ok(ttl) if !ttl.is_zero() => Some(Some(instant + ttl)),
+            _ => None,
+        },
+        None => Some(None),
+    }
+}
+
+/// 一个键大约占用多少字节的内存：键、值和 `Entry` 本身，
+/// 设置了过期时间时还有 `expirations` 中的一项。
+fn entry_size(key: &str, entry: &Entry) -> usize {
+    let expiration = match entry.expires_at {
+        Some(_) => size_of::<(Instant, String)>() + key.len(),
+        None => 0,
+    };
+    size_of::<(String, Entry)>() + key.len() + entry.data.memory_usage() + expiration
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// 每隔 `period` 检查一次，有没有保存的修改时在后台保存一次快照。
+async fn save_periodically(db: Db, period: Duration) {
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        // 上一次 BGSAVE 还没写完时跳过这一次，等下一个周期
+        if db.shared.dirty.load(Ordering::Relaxed) > 0
+            && let Err(e) = db.bgsave()
+        {
+            eprintln!("periodic save skipped: {}", e);
+        }
+    }
+}
+
+/// `Fsync::EverySec`：每秒 fsync 一次 AOF。
+async fn sync_aof_every_second(db: Db) {
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        if let Ok(Err(e)) = tokio::task::spawn_blocking(move || db.sync_aof()).await {
+            eprintln!("failed to fsync the AOF: {}", e);
+        }
+    }
+}
+
+/// 后台的过期删除任务：删除到期的键，然后一直睡到下一个过期时间或者被叫醒。
+async fn purge_expired_tasks(shared: Arc<Shared>) {
+    while !shared.is_shutdown() {
+        if let Some(when) = shared.purge_expired_keys() {
+            tokio::select! {
+                _ = time::sleep_until(when) => {}
+                _ = shared.background_task.notified() => {}
+            }
+        } else {
+            // 没有会过期的键，等到有新的过期时间被设置时再醒来
+            shared.background_task.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// 所有分片中等待过期的键的个数。
+    fn pending_expirations(db: &Db) -> usize {
+        let shards = db.shared.shards.iter();
+        shards.map(|shard| shard.lock().unwrap().expirations.len()).sum()
+    }
+
+    /// 一个和 `key` 不在同一个分片上的键。
+    fn key_in_another_shard(db: &Db, key: &str) -> String {
+        (0..)
+            .map(|i| format!("key:{}", i))
+            .find(|other| db.shared.shard(other) != db.shared.shard(key))
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn background_task_purges_keys_nobody_reads() {
+        let guard = DbDropGuard::new();
+        let db = guard.db();
+        let now = Instant::now();
+        {
+            let mut state = db.lock(["late", "forever"]);
+            state.set("late".into(), Bytes::from("1"), Some(now + Duration::from_secs(10)));
+            state.set("forever".into(), Bytes::from("2"), None);
+        }
+        // 后台任务正在睡到 10 秒之后，更早的过期时间会把它叫醒
+        let early = Some(now + Duration::from_secs(1));
+        db.lock(["early"]).set("early".into(), Bytes::from("3"), early);
+
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(db.len(), 2);
+        time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(db.len(), 1);
+        assert_eq!(pending_expirations(&db), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_keys_are_invisible_before_they_are_purged() {
+        let guard = DbDropGuard::new();
+        let db = guard.db();
+        let when = Instant::now() + Duration::from_millis(100);
+        db.lock(["k"]).set("k".into(), Bytes::from("v"), Some(when));
+
+        // 先让后台任务退出，只剩下惰性删除
+        drop(guard);
+        time::advance(Duration::from_millis(100)).await;
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.lock(["k"]).get("k"), None);
+        assert_eq!(db.len(), 0);
+        assert_eq!(pending_expirations(&db), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overwriting_a_key_clears_its_old_expiration() {
+        let guard = DbDropGuard::new();
+        let db = guard.db();
+        let soon = Instant::now() + Duration::from_secs(1);
+        db.lock(["k"]).set("k".into(), Bytes::from("v1"), Some(soon));
+        let prev = db.lock(["k"]).set("k".into(), Bytes::from("v2"), None);
+        assert_eq!(prev, Some(Value::String(Bytes::from("v1"))));
+
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(db.lock(["k"]).get_string("k"), Ok(Some(&Bytes::from("v2"))));
+        assert_eq!(db.lock(["k"]).expires_at("k"), Some(None));
+    }
+
+    #[tokio::test]
+    async fn keys_are_spread_over_shards() {
+        let guard = DbDropGuard::with_shards(8);
+        let db = guard.db();
+        let keys: Vec<String> = (0..1000).map(|i| format!("key:{}", i)).collect();
+        let mut counts = [0; 8];
+        for key in &keys {
+            counts[db.shared.shard(key)] += 1;
+        }
+        // 每个分片平均 125 个键，哈希函数足够均匀时不会偏离太多
+        assert!(counts.iter().all(|&n| (75..175).contains(&n)), "{:?}", counts);
+
+        // 一次锁住所有键所在的分片
+        let mut state = db.lock(keys.iter().map(String::as_str));
+        for key in &keys {
+            state.set(key.clone(), Bytes::from("v"), None);
+        }
+        drop(state);
+        assert_eq!(db.len(), keys.len());
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "is not locked")]
+    async fn accessing_an_unlocked_shard_panics() {
+        let guard = DbDropGuard::with_shards(64);
+        let db = guard.db();
+        let other = key_in_another_shard(&db, "k");
+        db.lock(["k"]).get(&other);
+    }
+
+    #[test]
+    fn locking_shards_in_any_key_order_does_not_deadlock() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _enter = runtime.enter();
+        let guard = DbDropGuard::with_shards(64);
+        let db = guard.db();
+        let a = "a".to_string();
+        let b = key_in_another_shard(&db, &a);
+
+        // 两个线程以相反的顺序给出同样的两个键，加锁的顺序是一样的
+        let threads: Vec<_> = [[a.clone(), b.clone()], [b, a]]
+            .into_iter()
+            .map(|keys| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..10_000 {
+                        let mut state = db.lock(keys.iter().map(String::as_str));
+                        for key in &keys {
+                            state.set(key.clone(), Bytes::from(i.to_string()), None);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(db.len(), 2);
+    }
+
+    fn temp_rdb(name: &str) -> Config {
+        let name = format!("my-redis2-{}-{}.rdb", std::process::id(), name);
+        Config {
+            dbfilename: std::env::temp_dir().join(name),
+            ..Config::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn only_one_save_at_a_time() {
+        let config = temp_rdb("bgsave");
+        let guard = DbDropGuard::with_config(&config);
+        let db = guard.db();
+        db.lock(["k"]).set("k".into(), Bytes::from("v"), None);
+        assert_eq!(db.shared.dirty.load(Ordering::Relaxed), 1);
+
+        let saving = Saving::start(&db.shared).unwrap();
+        let err = db.bgsave().unwrap_err().to_string();
+        assert_eq!(err, "Background save already in progress");
+        assert!(db.save().is_err());
+        drop(saving);
+
+        db.bgsave().unwrap().await.unwrap().unwrap();
+        assert_eq!(db.shared.dirty.load(Ordering::Relaxed), 0);
+        let restarted = DbDropGuard::with_config(&config);
+        assert_eq!(restarted.db().load().unwrap(), 1);
+        std::fs::remove_file(config.dbfilename).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_snapshots_are_not_loaded() {
+        let config = temp_rdb("corrupted");
+        std::fs::write(&config.dbfilename, b"MYRDB\0\x01garbage-garbage").unwrap();
+        let guard = DbDropGuard::with_config(&config);
+        assert!(guard.db().load().is_err());
+        assert!(guard.db().is_empty());
+        std::fs::remove_file(&config.dbfilename).unwrap();
+
+        // 文件不存在时是一个空的数据库
+        assert_eq!(guard.db().load().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_saved_periodically_when_there_are_changes() {
+        let config = Config {
+            save: Some(Duration::from_millis(20)),
+            ..temp_rdb("periodic")
+        };
+        let guard = DbDropGuard::with_config(&config);
+        let db = guard.db();
+        time::sleep(Duration::from_millis(100)).await;
+        // 没有修改时不会保存
+        assert!(!config.dbfilename.exists());
+
+        db.lock(["k"]).set("k".into(), Bytes::from("v"), None);
+        for _ in 0..100 {
+            if db.shared.dirty.load(Ordering::Relaxed) == 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        let restarted = DbDropGuard::with_config(&temp_rdb("periodic"));
+        assert_eq!(restarted.db().load().unwrap(), 1);
+        std::fs::remove_file(config.dbfilename).unwrap();
+    }
+
+    fn with_maxmemory(maxmemory: usize, policy: Policy) -> DbDropGuard {
+        DbDropGuard::with_config(&Config {
+            maxmemory,
+            maxmemory_policy: policy,
+            // 测试中的键不多，抽样数大一些，结果才是确定的
+            maxmemory_samples: 10,
+            ..Config::default()
+        })
+    }
+
+    fn value(size: usize) -> Bytes {
+        Bytes::from(vec![b'x'; size])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn used_memory_follows_writes_and_deletes() {
+        let guard = DbDropGuard::new();
+        let db = guard.db();
+        db.lock(["s"]).set("s".into(), value(1000), None);
+        let string = db.used_memory();
+        assert!(string > 1000, "{}", string);
+        assert_eq!(db.lock(["s"]).memory_usage("s"), Some(string));
+
+        // 通过 `&mut Value` 修改的值在释放锁时重新估算
+        {
+            let mut db = db.lock(["l"]);
+            let list = db.get_or_insert_with("l", || Value::List(Default::default()));
+            list.as_list_mut().unwrap().extend((0..100).map(|_| value(100)));
+        }
+        assert!(db.used_memory() > string + 100 * 100, "{}", db.used_memory());
+
+        let soon = Some(Instant::now() + Duration::from_secs(1));
+        db.lock(["t"]).set("t".into(), value(10), soon);
+        db.lock(["l"]).remove("l");
+        db.lock(["s"]).remove("s");
+        assert!(db.used_memory() > 0);
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[tokio::test]
+    async fn noeviction_refuses_to_free_memory() {
+        let guard = with_maxmemory(10_000, Policy::NoEviction);
+        let db = guard.db();
+        for i in 0..9 {
+            assert!(db.free_memory());
+            db.lock([format!("k{}", i).as_str()]).set(format!("k{}", i), value(1000), None);
+        }
+        assert!(!db.free_memory());
+        assert_eq!((db.len(), db.evicted_keys()), (9, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allkeys_lru_keeps_recently_used_keys() {
+        let guard = with_maxmemory(50_000, Policy::AllKeysLru);
+        let db = guard.db();
+        let hot: Vec<String> = (0..10).map(|i| format!("hot:{}", i)).collect();
+        for key in &hot {
+            db.lock([key.as_str()]).set(key.clone(), value(1000), None);
+        }
+        for i in 0..100 {
+            time::advance(Duration::from_millis(1)).await;
+            for key in &hot {
+                db.lock([key.as_str()]).get(key);
+            }
+            assert!(db.free_memory());
+            let key = format!("cold:{}", i);
+            db.lock([key.as_str()]).set(key, value(1000), None);
+        }
+        assert!(db.used_memory() <= 50_000 + 2000);
+        assert!(db.evicted_keys() > 50, "{}", db.evicted_keys());
+        for key in &hot {
+            assert!(db.lock([key.as_str()]).contains_key(key), "{} was evicted", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn allkeys_lfu_keeps_frequently_used_keys() {
+        let guard = with_maxmemory(50_000, Policy::AllKeysLfu);
+        let db = guard.db();
+        let hot: Vec<String> = (0..10).map(|i| format!("hot:{}", i)).collect();
+        for key in &hot {
+            let mut db = db.lock([key.as_str()]);
+            db.set(key.clone(), value(1000), None);
+            for _ in 0..20 {
+                db.get(key);
+            }
+        }
+        for i in 0..100 {
+            assert!(db.free_memory());
+            let key = format!("cold:{}", i);
+            db.lock([key.as_str()]).set(key, value(1000), None);
+        }
+        assert!(db.evicted_keys() > 50, "{}", db.evicted_keys());
+        for key in &hot {
+            assert!(db.lock([key.as_str()]).contains_key(key), "{} was evicted", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn volatile_ttl_evicts_keys_that_expire_soonest() {
+        let guard = with_maxmemory(10_000, Policy::VolatileTtl);
+        let db = guard.db();
+        let now = Instant::now();
+        for i in 0..5 {
+            let key = format!("persistent:{}", i);
+            db.lock([key.as_str()]).set(key, value(1000), None);
+        }
+        for i in 0..4 {
+            let key = format!("volatile:{}", i);
+            let when = now + Duration::from_secs(100 * (i + 1));
+            db.lock([key.as_str()]).set(key, value(1000), Some(when));
+        }
+        db.lock(["new"]).set("new".into(), value(2000), None);
+        assert!(db.free_memory());
+        assert!(!db.lock(["volatile:0"]).contains_key("volatile:0"));
+        assert!(db.lock(["volatile:3"]).contains_key("volatile:3"));
+
+        // 没有设置过期时间的键不会被淘汰
+        db.lock(["big"]).set("big".into(), value(10_000), None);
+        assert!(!db.free_memory());
+        assert_eq!(pending_expirations(&db), 0);
+        for i in 0..5 {
+            let key = format!("persistent:{}", i);
+            assert!(db.lock([key.as_str()]).contains_key(&key));
+        }
+    }
+}
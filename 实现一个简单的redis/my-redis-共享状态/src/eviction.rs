@@ -0,0 +1,140 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// 内存淘汰：配置了 maxmemory 之后，数据库使用的内存超过这个值时，执行会让内存增长的写命令
+// (SET、LPUSH 等)之前先按照淘汰策略删除一些键，腾不出内存时回复 OOM 错误。
+// 把服务器当作缓存使用时，它不会一直增长到被操作系统杀掉。
+//
+// 使用的内存是估算出来的：每个键记录自己大约占用多少字节(见 `Value::memory_usage`)，
+// 数据库把它们加起来。这里只统计数据本身，不包括连接的缓冲区、AOF 重写的缓冲区等。
+//
+// 和 Redis 一样，淘汰不是精确的 LRU/LFU：精确的 LRU 需要一个按访问时间排序的全局链表，
+// 每次访问都要修改它，和分片锁的初衷相违背。每个键只记录最后一次访问的时间和
+// 访问频率的计数器，淘汰时随机抽取 `maxmemory-samples` 个键，删除其中最该被删除的一个。
+// 抽样数越大越接近精确的 LRU/LFU，但是每次淘汰的开销也越大。
+
+/// maxmemory 默认的抽样数，和 Redis 一样是 5。
+pub const DEFAULT_SAMPLES: usize = 5;
+
+/// 使用的内存超过 maxmemory 时删除哪些键。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// 不删除键，让写命令回复 OOM 错误
+    #[default]
+    NoEviction,
+    /// 在所有的键中删除最久没有被访问的
+    AllKeysLru,
+    /// 在所有的键中删除访问频率最低的
+    AllKeysLfu,
+    /// 在设置了过期时间的键中删除最快要过期的
+    VolatileTtl,
+}
+
+impl FromStr for Policy {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> crate::Result<Policy> {
+        match s {
+            "noeviction" => Ok(Policy::NoEviction),
+            "allkeys-lru" => Ok(Policy::AllKeysLru),
+            "allkeys-lfu" => Ok(Policy::AllKeysLfu),
+            "volatile-ttl" => Ok(Policy::VolatileTtl),
+            _ => Err(format!("invalid maxmemory policy '{}'", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+            Policy::AllKeysLfu => "allkeys-lfu",
+            Policy::VolatileTtl => "volatile-ttl",
+        })
+    }
+}
+
+/// 新的键的访问计数器从这里开始，否则刚写入的键马上就会因为计数器最小而被淘汰。
+const LFU_INIT: u8 = 5;
+
+/// 计数器增长的速度，越大增长得越慢，和 Redis 的 `lfu-log-factor` 一样是 10。
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// 每隔这么长时间没有被访问，计数器减一，和 Redis 的 `lfu-decay-time` 一样是一分钟。
+const LFU_DECAY_PERIOD: Duration = Duration::from_secs(60);
+
+/// 一个键最后一次被访问的时间和访问频率，淘汰时用来判断先删除哪个键。
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Access {
+    accessed: Instant,
+    /// 对数计数器：8 位就可以区分从几次到上百万次的访问频率。
+    /// 计数器越大，访问一次让它加一的概率越小
+    counter: u8,
+}
+
+impl Access {
+    pub(crate) fn new(now: Instant) -> Access {
+        Access {
+            accessed: now,
+            counter: LFU_INIT,
+        }
+    }
+
+    /// 记录一次访问。
+    pub(crate) fn touch(&mut self, now: Instant) {
+        let counter = self.frequency(now);
+        let base = counter.saturating_sub(LFU_INIT) as f64;
+        let increment = counter < u8::MAX && fastrand::f64() < 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        self.counter = counter + increment as u8;
+        self.accessed = now;
+    }
+
+    /// 多久没有被访问了(毫秒)。
+    pub(crate) fn idle(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.accessed).as_millis() as u64
+    }
+
+    /// 衰减之后的计数器：很久以前访问得很频繁、最近不再访问的键也会逐渐被淘汰。
+    pub(crate) fn frequency(&self, now: Instant) -> u8 {
+        let periods = now.saturating_duration_since(self.accessed).as_secs()
+            / LFU_DECAY_PERIOD.as_secs();
+        self.counter.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_policies() {
+        for policy in ["noeviction", "allkeys-lru", "allkeys-lfu", "volatile-ttl"] {
+            assert_eq!(policy.parse::<Policy>().unwrap().to_string(), policy);
+        }
+        assert!("allkeys-random".parse::<Policy>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn frequency_grows_logarithmically_and_decays() {
+        let now = Instant::now();
+        let mut access = Access::new(now);
+        for _ in 0..100 {
+            access.touch(now);
+        }
+        let hundred = access.frequency(now);
+        for _ in 0..10_000 {
+            access.touch(now);
+        }
+        let ten_thousand = access.frequency(now);
+        assert!(LFU_INIT < hundred && hundred < ten_thousand, "{} {}", hundred, ten_thousand);
+        // 和 Redis 的 lfu-log-factor 10 一样，一万次访问大约是 50
+        assert!((40..60).contains(&ten_thousand), "{}", ten_thousand);
+
+        let later = now + LFU_DECAY_PERIOD * 3;
+        assert_eq!(access.frequency(later), ten_thousand - 3);
+        assert_eq!(access.idle(later), LFU_DECAY_PERIOD.as_millis() as u64 * 3);
+    }
+}
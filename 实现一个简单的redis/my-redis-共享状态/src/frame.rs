@@ -0,0 +1,652 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt::{self, Write};
+
+// Redis 客户端和服务器之间使用 RESP 协议通信，数据由一个个帧(frame)组成，
+// 第一个字节决定了帧的类型，每一行以 \r\n 结尾：
+//
+//   +OK\r\n                       简单字符串
+//   -ERR unknown command\r\n      错误
+//   :-3\r\n                       整数
+//   $5\r\nhello\r\n               二进制安全的字符串(bulk)，$-1\r\n 表示空值
+//   *2\r\n$3\r\nGET\r\n$1\r\nk\r\n   数组，客户端发送的命令就是一个 bulk 数组
+//
+// RESP3 在此基础上增加了几种类型，客户端通过 HELLO 3 切换到 RESP3 之后才会用到：
+//
+//   _\r\n                         空值
+//   ,1.5\r\n                      浮点数，还可以是 inf、-inf 和 nan
+//   #t\r\n                        布尔值
+//   %1\r\n+k\r\n:1\r\n            map，后面跟着 key、value 交替的 2n 个帧
+//   ~2\r\n:1\r\n:2\r\n            集合
+//   >3\r\n...                     服务器主动推送的数据，例如订阅的消息
+//
+// 为了方便用 telnet 调试，第一个字节不是上面这些类型时，这一行当作内联命令(inline command)：
+// `SET k v\r\n` 按空白字符分割成参数，和 `*3\r\n$3\r\nSET\r\n...` 一样。不支持引号。
+//
+// 解析时先检查缓冲区中是否已经有一个完整的帧，然后把它从缓冲区中切下来(`split_to`)，
+// bulk 字符串直接引用这块内存，不会复制数据。
+
+/// RESP 协议的版本，新连接使用 RESP2，客户端可以通过 HELLO 切换。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// RESP 协议中的一个帧。
+///
+/// 整数是有符号的：DECR 的结果、TTL 返回的 -1/-2 都可能是负数，mini-redis 的 `Integer(u64)`
+/// 表示不了。`Double` 之后的类型是 RESP3 新增的，回复 RESP2 的客户端时会转换成 RESP2 的类型。
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    Push(Vec<Frame>),
+}
+
+/// 一个帧(包括其中嵌套的所有帧)默认的最大字节数，和 Redis 的 `proto-max-bulk-len` 一样是 512MB。
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 512 * 1024 * 1024;
+
+/// 数组、map 等最多嵌套多少层。解析是递归的，不加限制的话，
+/// 几 KB 的 `*1\r\n*1\r\n...` 就能让服务器栈溢出。
+const MAX_DEPTH: usize = 64;
+
+impl Frame {
+    /// `OK` 是最常见的回复，单独提供一个构造函数。
+    pub fn ok() -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+
+    /// 从 `src` 的开头解析一个帧，数据还不完整时返回 `None`。
+    ///
+    /// 解析出的帧占用的字节会从 `src` 中移走。超过 `max_size` 字节的帧是协议错误：
+    /// 否则客户端只要声明一个很长的 bulk 字符串，服务器就会一直缓存它发送的数据。
+    pub fn parse(src: &mut BytesMut, max_size: usize) -> crate::Result<Option<Frame>> {
+        loop {
+            let Some(&first) = src.first() else {
+                return Ok(None);
+            };
+            if !is_type_byte(first) {
+                match parse_inline(src, max_size)? {
+                    // 空行直接跳过，和 Redis 一样
+                    Some(args) if args.is_empty() => continue,
+                    args => return Ok(args.map(Frame::Array)),
+                }
+            }
+
+            let mut scanner = Scanner::new(&src[..], max_size);
+            match scanner.check(0) {
+                Ok(()) if scanner.pos > max_size => return Err(too_large(max_size)),
+                Ok(()) => {}
+                Err(Error::Incomplete) if src.len() > max_size => return Err(too_large(max_size)),
+                Err(Error::Incomplete) => return Ok(None),
+                Err(Error::Invalid(msg)) => return Err(msg.into()),
+            }
+            let data = src.split_to(scanner.pos).freeze();
+            return match Scanner::new(&data, max_size).parse(&data, 0) {
+                Ok(frame) => Ok(Some(frame)),
+                Err(Error::Invalid(msg)) => Err(msg.into()),
+                Err(Error::Incomplete) => unreachable!("the frame was checked to be complete"),
+            };
+        }
+    }
+
+    /// 按照 `protocol` 序列化为可以直接写入连接的字节。
+    ///
+    /// RESP2 中没有的类型和 Redis 一样转换：map 展开成 key、value 交替的数组，集合和推送的数据
+    /// 写成数组，浮点数写成 bulk 字符串，布尔值写成整数 1 和 0。
+    pub fn encode(&self, protocol: Protocol, dst: &mut BytesMut) {
+        let resp3 = protocol == Protocol::Resp3;
+        match self {
+            Frame::Simple(val) => put_line(dst, '+', val),
+            Frame::Error(val) => put_line(dst, '-', val),
+            Frame::Integer(val) => put_line(dst, ':', val),
+            Frame::Bulk(val) => {
+                put_line(dst, '$', val.len());
+                dst.put_slice(val);
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Null if resp3 => dst.put_slice(b"_\r\n"),
+            Frame::Null => dst.put_slice(b"$-1\r\n"),
+            Frame::Array(items) => put_aggregate(dst, '*', items, protocol),
+            Frame::Double(val) if resp3 => put_line(dst, ',', format_double(*val)),
+            Frame::Double(val) => Frame::Bulk(format_double(*val).into()).encode(protocol, dst),
+            Frame::Boolean(val) if resp3 => put_line(dst, '#', if *val { 't' } else { 'f' }),
+            Frame::Boolean(val) => put_line(dst, ':', *val as i64),
+            Frame::Map(pairs) => {
+                match resp3 {
+                    true => put_line(dst, '%', pairs.len()),
+                    false => put_line(dst, '*', pairs.len() * 2),
+                }
+                for (key, value) in pairs {
+                    key.encode(protocol, dst);
+                    value.encode(protocol, dst);
+                }
+            }
+            Frame::Set(items) if resp3 => put_aggregate(dst, '~', items, protocol),
+            Frame::Push(items) if resp3 => put_aggregate(dst, '>', items, protocol),
+            Frame::Set(items) | Frame::Push(items) => put_aggregate(dst, '*', items, protocol),
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Simple(response) => response.fmt(fmt),
+            Frame::Error(msg) => write!(fmt, "error: {}", msg),
+            Frame::Integer(num) => num.fmt(fmt),
+            Frame::Bulk(msg) => match std::str::from_utf8(msg) {
+                Ok(string) => string.fmt(fmt),
+                Err(_) => write!(fmt, "{:?}", msg),
+            },
+            Frame::Null => "(nil)".fmt(fmt),
+            Frame::Double(num) => format_double(*num).fmt(fmt),
+            Frame::Boolean(b) => b.fmt(fmt),
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    part.fmt(fmt)?;
+                }
+                Ok(())
+            }
+            Frame::Map(pairs) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{} {}", key, value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// 解析帧时的错误，只在这个模块内部使用。
+#[derive(Debug)]
+enum Error {
+    /// 数据还不完整，需要从连接中读取更多的数据
+    Incomplete,
+    /// 数据格式不对
+    Invalid(String),
+}
+
+fn invalid<T>(msg: &str) -> Result<T, Error> {
+    Err(Error::Invalid(format!("Protocol error: {}", msg)))
+}
+
+fn too_large(max_size: usize) -> crate::Error {
+    format!("Protocol error: frame is larger than {} bytes", max_size).into()
+}
+
+fn is_type_byte(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'+' | b'-' | b':' | b'$' | b'*' | b'_' | b',' | b'#' | b'%' | b'~' | b'>'
+    )
+}
+
+/// 解析一行内联命令，返回按空白字符分割的参数，数据还不完整时返回 `None`。
+fn parse_inline(src: &mut BytesMut, max_size: usize) -> crate::Result<Option<Vec<Frame>>> {
+    let Some(end) = src.iter().position(|&b| b == b'\n') else {
+        return match src.len() > max_size {
+            true => Err(too_large(max_size)),
+            false => Ok(None),
+        };
+    };
+    if end >= max_size {
+        return Err(too_large(max_size));
+    }
+    let line = src.split_to(end + 1).freeze();
+    let args = line
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| Frame::Bulk(line.slice_ref(arg)))
+        .collect();
+    Ok(Some(args))
+}
+
+/// 在缓冲区中从前往后读取一个帧。
+///
+/// `check` 只确认帧是完整的、格式是对的，不分配内存；`parse` 在帧被切下来之后真正构造出 `Frame`。
+struct Scanner<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    max_size: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(buf: &'a [u8], max_size: usize) -> Scanner<'a> {
+        Scanner {
+            buf,
+            pos: 0,
+            max_size,
+        }
+    }
+
+    fn check(&mut self, depth: usize) -> Result<(), Error> {
+        if depth > MAX_DEPTH {
+            return invalid("frames are nested too deeply");
+        }
+        match self.u8()? {
+            b'+' | b'-' => self.string().map(drop),
+            b':' => self.decimal().map(drop),
+            b',' => self.double().map(drop),
+            b'#' => self.boolean().map(drop),
+            b'_' => self.null(),
+            b'$' => match self.length()? {
+                Some(len) => self.bulk(len).map(drop),
+                None => Ok(()),
+            },
+            b'*' => (0..self.length()?.unwrap_or(0)).try_for_each(|_| self.check(depth + 1)),
+            b'~' | b'>' => (0..self.aggregate_length()?).try_for_each(|_| self.check(depth + 1)),
+            b'%' => (0..self.aggregate_length()?).try_for_each(|_| {
+                self.check(depth + 1)?;
+                self.check(depth + 1)
+            }),
+            actual => invalid(&format!("invalid frame type byte `{}`", actual)),
+        }
+    }
+
+    /// `data` 就是 `self.buf`，bulk 字符串通过 `data.slice` 引用其中的一段。
+    fn parse(&mut self, data: &Bytes, depth: usize) -> Result<Frame, Error> {
+        let frame = match self.u8()? {
+            b'+' => Frame::Simple(self.string()?),
+            b'-' => Frame::Error(self.string()?),
+            b':' => Frame::Integer(self.decimal()?),
+            b',' => Frame::Double(self.double()?),
+            b'#' => Frame::Boolean(self.boolean()?),
+            b'_' => {
+                self.null()?;
+                Frame::Null
+            }
+            b'$' => match self.length()? {
+                Some(len) => {
+                    let start = self.bulk(len)?;
+                    Frame::Bulk(data.slice(start..start + len))
+                }
+                None => Frame::Null,
+            },
+            b'*' => match self.length()? {
+                Some(len) => Frame::Array(self.parse_items(data, depth, len)?),
+                None => Frame::Null,
+            },
+            b'~' => {
+                let len = self.aggregate_length()?;
+                Frame::Set(self.parse_items(data, depth, len)?)
+            }
+            b'>' => {
+                let len = self.aggregate_length()?;
+                Frame::Push(self.parse_items(data, depth, len)?)
+            }
+            b'%' => {
+                let len = self.aggregate_length()?;
+                let mut pairs = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    pairs.push((self.parse(data, depth + 1)?, self.parse(data, depth + 1)?));
+                }
+                Frame::Map(pairs)
+            }
+            actual => return invalid(&format!("invalid frame type byte `{}`", actual)),
+        };
+        Ok(frame)
+    }
+
+    fn parse_items(&mut self, data: &Bytes, depth: usize, len: usize) -> Result<Vec<Frame>, Error> {
+        // 长度来自客户端，不能直接用来分配内存
+        let mut items = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            items.push(self.parse(data, depth + 1)?);
+        }
+        Ok(items)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        let byte = *self.buf.get(self.pos).ok_or(Error::Incomplete)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn line(&mut self) -> Result<&'a [u8], Error> {
+        let rest = &self.buf[self.pos..];
+        match rest.windows(2).position(|w| w == b"\r\n") {
+            Some(i) => {
+                self.pos += i + 2;
+                Ok(&rest[..i])
+            }
+            None => Err(Error::Incomplete),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        match std::str::from_utf8(self.line()?) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => invalid("invalid UTF-8 string"),
+        }
+    }
+
+    fn decimal(&mut self) -> Result<i64, Error> {
+        match std::str::from_utf8(self.line()?).ok().and_then(|s| s.parse().ok()) {
+            Some(n) => Ok(n),
+            None => invalid("invalid integer"),
+        }
+    }
+
+    fn double(&mut self) -> Result<f64, Error> {
+        // Rust 的 `f64::from_str` 接受 inf、-inf 和 nan，正好是 RESP3 使用的写法
+        match std::str::from_utf8(self.line()?).ok().and_then(|s| s.parse().ok()) {
+            Some(n) => Ok(n),
+            None => invalid("invalid double"),
+        }
+    }
+
+    fn boolean(&mut self) -> Result<bool, Error> {
+        match self.line()? {
+            b"t" => Ok(true),
+            b"f" => Ok(false),
+            _ => invalid("invalid boolean"),
+        }
+    }
+
+    fn null(&mut self) -> Result<(), Error> {
+        match self.line()? {
+            b"" => Ok(()),
+            _ => invalid("invalid null"),
+        }
+    }
+
+    /// bulk 字符串和数组的长度，`-1` 表示空值。
+    ///
+    /// 长度超过帧的最大字节数时直接报错，不用等到真的收到了这么多数据。
+    fn length(&mut self) -> Result<Option<usize>, Error> {
+        match self.decimal()? {
+            -1 => Ok(None),
+            len if len >= 0 && len as u64 <= self.max_size as u64 => Ok(Some(len as usize)),
+            _ => invalid("invalid length"),
+        }
+    }
+
+    /// map、集合和推送数据的长度，RESP3 中它们没有空值。
+    fn aggregate_length(&mut self) -> Result<usize, Error> {
+        match self.length()? {
+            Some(len) => Ok(len),
+            None => invalid("invalid length"),
+        }
+    }
+
+    /// 跳过 `len` 字节的 bulk 字符串和结尾的 \r\n，返回字符串的起始位置。
+    fn bulk(&mut self, len: usize) -> Result<usize, Error> {
+        let start = self.pos;
+        let Some(end) = self.buf.get(start + len..start + len + 2) else {
+            return Err(Error::Incomplete);
+        };
+        if end != b"\r\n" {
+            return invalid("invalid bulk string");
+        }
+        self.pos += len + 2;
+        Ok(start)
+    }
+}
+
+fn put_line(dst: &mut BytesMut, prefix: char, val: impl fmt::Display) {
+    // 写到 BytesMut 中不会失败，它会自动扩容
+    write!(dst, "{}{}\r\n", prefix, val).unwrap();
+}
+
+fn put_aggregate(dst: &mut BytesMut, prefix: char, items: &[Frame], protocol: Protocol) {
+    put_line(dst, prefix, items.len());
+    for item in items {
+        item.encode(protocol, dst);
+    }
+}
+
+/// RESP3 中浮点数的写法，无穷大写成 `inf`/`-inf`，`NaN` 写成 `nan`。
+///
+/// Rust 的 `Display` 输出的是能精确还原的最短的十进制数，不会丢失精度。
+fn format_double(val: f64) -> String {
+    match val {
+        f64::INFINITY => "inf".to_string(),
+        f64::NEG_INFINITY => "-inf".to_string(),
+        val if val.is_nan() => "nan".to_string(),
+        val => val.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MAX: usize = DEFAULT_MAX_FRAME_SIZE;
+
+    fn parse(src: &[u8]) -> crate::Result<Option<Frame>> {
+        Frame::parse(&mut BytesMut::from(src), MAX)
+    }
+
+    fn encode(frame: &Frame, protocol: Protocol) -> BytesMut {
+        let mut buf = BytesMut::new();
+        frame.encode(protocol, &mut buf);
+        buf
+    }
+
+    fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    #[test]
+    fn encode_and_parse_round_trip() {
+        let frame = Frame::Array(vec![
+            Frame::Simple("OK".into()),
+            Frame::Error("ERR oops".into()),
+            Frame::Integer(-2),
+            Frame::Bulk(Bytes::from_static(b"a\r\nb")),
+            Frame::Null,
+            Frame::Array(vec![Frame::Integer(1)]),
+        ]);
+        let buf = encode(&frame, Protocol::Resp2);
+        assert_eq!(
+            buf,
+            &b"*6\r\n+OK\r\n-ERR oops\r\n:-2\r\n$4\r\na\r\nb\r\n$-1\r\n*1\r\n:1\r\n"[..]
+        );
+        assert_eq!(parse(&buf).unwrap(), Some(frame));
+    }
+
+    #[test]
+    fn resp3_types_are_downgraded_for_resp2() {
+        let frame = Frame::Map(vec![
+            (bulk("d"), Frame::Double(1.5)),
+            (bulk("inf"), Frame::Double(f64::NEG_INFINITY)),
+            (bulk("b"), Frame::Boolean(true)),
+            (bulk("s"), Frame::Set(vec![Frame::Null])),
+        ]);
+        let resp3 = encode(&frame, Protocol::Resp3);
+        let expected = concat!(
+            "%4\r\n$1\r\nd\r\n,1.5\r\n$3\r\ninf\r\n,-inf\r\n",
+            "$1\r\nb\r\n#t\r\n$1\r\ns\r\n~1\r\n_\r\n",
+        );
+        assert_eq!(resp3, expected.as_bytes());
+        assert_eq!(parse(&resp3).unwrap(), Some(frame.clone()));
+
+        let resp2 = encode(&frame, Protocol::Resp2);
+        let expected = concat!(
+            "*8\r\n$1\r\nd\r\n$3\r\n1.5\r\n$3\r\ninf\r\n$4\r\n-inf\r\n",
+            "$1\r\nb\r\n:1\r\n$1\r\ns\r\n*1\r\n$-1\r\n",
+        );
+        assert_eq!(resp2, expected.as_bytes());
+
+        let push = Frame::Push(vec![bulk("message"), Frame::Double(f64::NAN)]);
+        assert_eq!(encode(&push, Protocol::Resp3), &b">2\r\n$7\r\nmessage\r\n,nan\r\n"[..]);
+    }
+
+    #[test]
+    fn bulk_strings_share_the_read_buffer() {
+        let mut buf = BytesMut::from(&b"$5\r\nhello\r\n:1\r\n"[..]);
+        let start = buf.as_ptr() as usize;
+        let Some(Frame::Bulk(data)) = Frame::parse(&mut buf, MAX).unwrap() else {
+            panic!("expected a bulk string");
+        };
+        assert_eq!(data, "hello");
+        // 指向的是原来缓冲区中的内存，而不是一份拷贝
+        assert_eq!(data.as_ptr() as usize, start + 4);
+        assert_eq!(buf, &b":1\r\n"[..]);
+    }
+
+    #[test]
+    fn inline_commands() {
+        let mut buf = BytesMut::from(&b"SET  k\tv\r\n\r\n\nping\n*1\r\n$4\r\nPING\r\nGET"[..]);
+        let mut next = || Frame::parse(&mut buf, MAX).unwrap();
+        assert_eq!(next(), Some(Frame::Array(vec![bulk("SET"), bulk("k"), bulk("v")])));
+        // 空行被跳过
+        assert_eq!(next(), Some(Frame::Array(vec![bulk("ping")])));
+        assert_eq!(next(), Some(Frame::Array(vec![bulk("PING")])));
+        assert_eq!(next(), None);
+        assert_eq!(buf, &b"GET"[..]);
+    }
+
+    #[test]
+    fn incomplete_and_invalid_frames() {
+        for partial in [&b"*2\r\n$3\r\nGET\r\n"[..], b"$5\r\nhel", b":12", b"%1\r\n:1\r\n", b""] {
+            assert_eq!(parse(partial).unwrap(), None);
+        }
+        for invalid in [
+            &b":x\r\n"[..],
+            b"$-2\r\n",
+            b"$3\r\nabcd\r\n",
+            b"#x\r\n",
+            b",1.5.1\r\n",
+            b"~-1\r\n",
+            b"*1\r\nGET\r\n",
+        ] {
+            assert!(parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn frames_larger_than_the_limit_are_rejected() {
+        let error = |src: &[u8]| {
+            let result = Frame::parse(&mut BytesMut::from(src), 16);
+            result.unwrap_err().to_string()
+        };
+        // 声明的长度就超过了限制，不需要等数据到齐
+        assert_eq!(error(b"$17\r\n"), "Protocol error: invalid length");
+        // 完整的帧、还没有收完的帧和内联命令都不能超过限制
+        for src in [
+            &b"*1\r\n$10\r\n0123456789\r\n"[..],
+            b"*4\r\n:1\r\n:2\r\n:3\r\n:4",
+            b"GET aaaaaaaaaaaaa",
+        ] {
+            assert_eq!(error(src), "Protocol error: frame is larger than 16 bytes");
+        }
+
+        let nested = b"*1\r\n".repeat(MAX_DEPTH + 2);
+        let err = parse(&nested).unwrap_err().to_string();
+        assert_eq!(err, "Protocol error: frames are nested too deeply");
+    }
+
+    /// 任意的帧。简单字符串和错误中不能有换行，浮点数不包括 NaN(NaN != NaN，无法比较)。
+    fn arb_frame() -> impl Strategy<Value = Frame> {
+        let leaf = prop_oneof![
+            "[^\r\n]*".prop_map(Frame::Simple),
+            "[^\r\n]*".prop_map(Frame::Error),
+            any::<i64>().prop_map(Frame::Integer),
+            any::<Vec<u8>>().prop_map(|data| Frame::Bulk(data.into())),
+            Just(Frame::Null),
+            any::<f64>()
+                .prop_filter("NaN", |n| !n.is_nan())
+                .prop_map(Frame::Double),
+            any::<bool>().prop_map(Frame::Boolean),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Frame::Array),
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Frame::Set),
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Frame::Push),
+                prop::collection::vec((inner.clone(), inner), 0..4).prop_map(Frame::Map),
+            ]
+        })
+    }
+
+    fn is_resp2(frame: &Frame) -> bool {
+        match frame {
+            Frame::Array(items) => items.iter().all(is_resp2),
+            Frame::Simple(_)
+            | Frame::Error(_)
+            | Frame::Integer(_)
+            | Frame::Bulk(_)
+            | Frame::Null => true,
+            _ => false,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn resp3_round_trip(frame in arb_frame()) {
+            let mut buf = encode(&frame, Protocol::Resp3);
+            prop_assert_eq!(Frame::parse(&mut buf, MAX).unwrap(), Some(frame));
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn resp2_output_only_uses_resp2_types(frame in arb_frame()) {
+            let mut buf = encode(&frame, Protocol::Resp2);
+            let parsed = Frame::parse(&mut buf, MAX).unwrap().unwrap();
+            prop_assert!(buf.is_empty());
+            prop_assert!(is_resp2(&parsed), "{:?}", parsed);
+            // 转换之后的帧再编码一次，结果不变
+            prop_assert_eq!(encode(&parsed, Protocol::Resp2), encode(&frame, Protocol::Resp2));
+        }
+
+        /// 数据被 TCP 分成任意的小段到达，解析的结果和一次收到所有数据一样。
+        #[test]
+        fn split_input_parses_the_same(
+            frames in prop::collection::vec(arb_frame(), 1..4),
+            chunk in 1..64usize,
+        ) {
+            let mut data = BytesMut::new();
+            for frame in &frames {
+                frame.encode(Protocol::Resp3, &mut data);
+            }
+            let mut buf = BytesMut::new();
+            let mut parsed = vec![];
+            for piece in data.chunks(chunk) {
+                buf.extend_from_slice(piece);
+                while let Some(frame) = Frame::parse(&mut buf, MAX).unwrap() {
+                    parsed.push(frame);
+                }
+            }
+            prop_assert_eq!(parsed, frames);
+            prop_assert!(buf.is_empty());
+        }
+
+        /// 任意的字节都不会让解析器 panic，解析成功时一定消耗了数据，不会死循环。
+        #[test]
+        fn arbitrary_bytes_never_panic(data in any::<Vec<u8>>(), max_size in 0..256usize) {
+            let mut buf = BytesMut::from(&data[..]);
+            let mut len = buf.len();
+            while let Ok(Some(_)) = Frame::parse(&mut buf, max_size) {
+                prop_assert!(buf.len() < len);
+                len = buf.len();
+            }
+        }
+
+        /// 在合法的帧中随机改掉一个字节，同样不会 panic。
+        #[test]
+        fn corrupted_frames_never_panic(frame in arb_frame(), index: usize, byte: u8) {
+            let mut data = encode(&frame, Protocol::Resp3);
+            let index = index % data.len();
+            data[index] = byte;
+            let _ = Frame::parse(&mut data, MAX);
+        }
+    }
+}
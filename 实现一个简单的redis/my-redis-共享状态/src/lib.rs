@@ -0,0 +1,56 @@
+// 共享状态章节的服务器最初只有 bin/server.rs 中的一个 `process` 函数，
+// 命令的解析和执行都交给 mini-redis。随着支持的命令越来越多，这些代码被移到了库中：
+// - frame: RESP2/RESP3 协议的帧，以及帧的解析和序列化
+// - connection: 在 TCP 连接上收发帧，记录连接使用的协议版本
+// - cluster: 集群模式，键按照哈希槽分散到多个服务器上，不在这里的键回复 MOVED/ASK 重定向
+// - cmd: 命令的解析和执行
+// - config: 服务器的命令行参数
+// - db: 数据库本身，分成多个分片，支持键的过期
+// - eviction: 配置了 maxmemory 时的内存淘汰策略
+// - pubsub: 发布/订阅，进入订阅状态的连接由 `Subscriber` 处理
+// - rdb: 快照文件的格式，SAVE/BGSAVE 把数据库保存到文件中，启动时再读回来
+// - aof: AOF 持久化，把写命令追加到文件中，启动时重新执行一遍
+// - replication: 主从复制，从服务器同步主服务器的快照，之后接收它的写命令
+// - transaction: 事务，MULTI/EXEC/DISCARD/WATCH，每个连接有一个 `Transaction`
+// - value: 数据库中保存的值，字符串、列表、哈希表、集合和有序集合
+// bin/server.rs 只负责接受连接，把每个连接交给 `process` 处理。
+
+pub mod aof;
+
+pub mod cluster;
+
+pub mod cmd;
+pub use cmd::Command;
+
+pub mod config;
+pub use config::Config;
+
+mod connection;
+pub use connection::Connection;
+
+pub mod db;
+pub use db::{Db, DbDropGuard, DbGuard};
+
+pub mod eviction;
+
+pub mod frame;
+pub use frame::Frame;
+
+mod parse;
+
+pub mod pubsub;
+pub use pubsub::Subscriber;
+
+pub mod rdb;
+
+pub mod replication;
+
+pub mod transaction;
+pub use transaction::Transaction;
+
+pub mod value;
+pub use value::{Value, WrongType};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T> = std::result::Result<T, Error>;
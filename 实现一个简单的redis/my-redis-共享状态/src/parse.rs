@@ -0,0 +1,106 @@
+use crate::Frame;
+
+use bytes::Bytes;
+use std::{fmt, str, vec};
+
+/// 依次取出命令数组中的各个参数，和 mini-redis 中的 `Parse` 一样。
+#[derive(Debug)]
+pub(crate) struct Parse {
+    parts: vec::IntoIter<Frame>,
+}
+
+#[derive(Debug)]
+pub(crate) enum ParseError {
+    /// 参数已经取完了，说明客户端发送的参数个数不对
+    EndOfStream,
+    /// 参数的格式不对，消息会原样回复给客户端
+    Other(String),
+}
+
+impl Parse {
+    /// 客户端发送的命令必须是一个数组。
+    pub(crate) fn new(frame: Frame) -> Result<Parse, ParseError> {
+        let array = match frame {
+            Frame::Array(array) => array,
+            frame => {
+                return Err(format!("ERR Protocol error: expected array, got {}", frame).into());
+            }
+        };
+        Ok(Parse {
+            parts: array.into_iter(),
+        })
+    }
+
+    fn next(&mut self) -> Result<Frame, ParseError> {
+        self.parts.next().ok_or(ParseError::EndOfStream)
+    }
+
+    pub(crate) fn next_string(&mut self) -> Result<String, ParseError> {
+        match self.next()? {
+            Frame::Simple(s) => Ok(s),
+            Frame::Bulk(data) => str::from_utf8(&data[..])
+                .map(|s| s.to_string())
+                .map_err(|_| "ERR Protocol error: invalid string".into()),
+            frame => Err(format!("ERR Protocol error: expected bulk string, got {}", frame).into()),
+        }
+    }
+
+    pub(crate) fn next_bytes(&mut self) -> Result<Bytes, ParseError> {
+        match self.next()? {
+            Frame::Simple(s) => Ok(Bytes::from(s.into_bytes())),
+            Frame::Bulk(data) => Ok(data),
+            frame => Err(format!("ERR Protocol error: expected bulk string, got {}", frame).into()),
+        }
+    }
+
+    pub(crate) fn next_int(&mut self) -> Result<i64, ParseError> {
+        const MSG: &str = "ERR value is not an integer or out of range";
+
+        match self.next()? {
+            Frame::Integer(v) => Ok(v),
+            Frame::Simple(data) => data.parse().map_err(|_| MSG.into()),
+            Frame::Bulk(data) => str::from_utf8(&data)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| MSG.into()),
+            _ => Err(MSG.into()),
+        }
+    }
+
+    /// 剩下的参数个数。
+    pub(crate) fn remaining(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// 确认所有的参数都已经取完了。
+    pub(crate) fn finish(&mut self) -> Result<(), ParseError> {
+        match self.parts.next() {
+            None => Ok(()),
+            // 多出来的参数和缺少参数一样，都是参数个数不对
+            Some(_) => Err(ParseError::EndOfStream),
+        }
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(src: String) -> ParseError {
+        ParseError::Other(src)
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(src: &str) -> ParseError {
+        src.to_string().into()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::EndOfStream => "ERR wrong number of arguments".fmt(f),
+            ParseError::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
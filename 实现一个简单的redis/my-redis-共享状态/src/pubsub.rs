@@ -0,0 +1,462 @@
+use crate::{Command, Connection, Db, Frame};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{StreamExt, StreamMap};
+
+// 发布/订阅：每个频道(以及每个模式)对应一个 broadcast 通道，
+// PUBLISH 把消息发送到频道的通道中，订阅了这个频道的每个连接都会收到一份。
+//
+// broadcast 通道的容量是固定的，发布者永远不会因为某个订阅者读得慢而被阻塞。
+// 订阅者落后太多时，通道中最旧的消息会被覆盖，它再去接收时会得到 `Lagged` 错误。
+// 这时候悄悄地跳过丢失的消息会让客户端以为自己收到了所有消息，因此和 Redis
+// (client-output-buffer-limit pubsub)一样，直接断开这个订阅者的连接，让它知道出了问题。
+
+/// 每个频道最多缓存多少条还没有被所有订阅者接收的消息。
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+/// 所有频道和模式的 broadcast 通道，保存在 `Db` 中。
+///
+/// 没有订阅者的通道会被删除，否则每个发布过的频道名都会一直占用内存。
+#[derive(Debug, Default)]
+pub(crate) struct PubSub {
+    channels: HashMap<String, broadcast::Sender<Bytes>>,
+    /// 通过模式订阅时，订阅者还需要知道消息来自哪个频道
+    patterns: HashMap<String, broadcast::Sender<(String, Bytes)>>,
+}
+
+impl PubSub {
+    pub(crate) fn subscribe(&mut self, channel: String) -> broadcast::Receiver<Bytes> {
+        self.channels
+            .entry(channel)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    pub(crate) fn psubscribe(&mut self, pattern: String) -> broadcast::Receiver<(String, Bytes)> {
+        self.patterns
+            .entry(pattern)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// 发布一条消息，返回收到消息的订阅者个数(直接订阅和模式订阅分别计算)。
+    pub(crate) fn publish(&mut self, channel: &str, message: Bytes) -> usize {
+        let mut receivers = 0;
+        if let Some(tx) = self.channels.get(channel) {
+            match tx.send(message.clone()) {
+                Ok(n) => receivers += n,
+                // 没有订阅者了
+                Err(_) => {
+                    self.channels.remove(channel);
+                }
+            }
+        }
+        self.patterns.retain(|pattern, tx| {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                return true;
+            }
+            match tx.send((channel.to_string(), message.clone())) {
+                Ok(n) => {
+                    receivers += n;
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+        receivers
+    }
+
+    /// 订阅者取消订阅(drop 掉接收端)之后调用，删除已经没有订阅者的通道。
+    pub(crate) fn remove_unused_channel(&mut self, channel: &str) {
+        if self.channels.get(channel).is_some_and(|tx| tx.receiver_count() == 0) {
+            self.channels.remove(channel);
+        }
+    }
+
+    pub(crate) fn remove_unused_pattern(&mut self, pattern: &str) {
+        if self.patterns.get(pattern).is_some_and(|tx| tx.receiver_count() == 0) {
+            self.patterns.remove(pattern);
+        }
+    }
+}
+
+/// 进入订阅状态的连接。
+///
+/// 客户端发送 SUBSCRIBE 或者 PSUBSCRIBE 之后，连接就只用来接收消息，
+/// 同时还能继续订阅、取消订阅或者 PING，其它命令都会回复错误。
+/// 取消了所有订阅之后，连接回到正常状态。
+#[derive(Debug)]
+pub struct Subscriber {
+    db: Db,
+    channels: StreamMap<String, BroadcastStream<Bytes>>,
+    patterns: StreamMap<String, BroadcastStream<(String, Bytes)>>,
+}
+
+impl Subscriber {
+    pub fn new(db: Db) -> Subscriber {
+        Subscriber {
+            db,
+            channels: StreamMap::new(),
+            patterns: StreamMap::new(),
+        }
+    }
+
+    /// 执行一个订阅相关的命令，然后转发消息，直到取消了所有订阅或者客户端关闭连接。
+    pub async fn run(mut self, connection: &mut Connection, cmd: Command) -> crate::Result<()> {
+        self.apply(connection, cmd).await?;
+
+        while self.count() > 0 {
+            tokio::select! {
+                Some((channel, message)) = self.channels.next() => {
+                    let message = check_lagged(connection, message).await?;
+                    let frame = push(vec![bulk("message"), bulk(channel), Frame::Bulk(message)]);
+                    connection.write_frame(&frame).await?;
+                }
+                Some((pattern, message)) = self.patterns.next() => {
+                    let (channel, message) = check_lagged(connection, message).await?;
+                    let frame = push(vec![
+                        bulk("pmessage"),
+                        bulk(pattern),
+                        bulk(channel),
+                        Frame::Bulk(message),
+                    ]);
+                    connection.write_frame(&frame).await?;
+                }
+                frame = connection.read_frame() => {
+                    let Some(frame) = frame? else {
+                        return Ok(());
+                    };
+                    match Command::from_frame(frame) {
+                        Ok(cmd) => self.apply(connection, cmd).await?,
+                        Err(e) => connection.write_frame(&Frame::Error(e.to_string())).await?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply(&mut self, connection: &mut Connection, cmd: Command) -> crate::Result<()> {
+        match cmd {
+            Command::Subscribe { channels } => {
+                for channel in channels {
+                    if !self.channels.contains_key(&channel) {
+                        let rx = self.db.pub_sub().subscribe(channel.clone());
+                        self.channels.insert(channel.clone(), BroadcastStream::new(rx));
+                    }
+                    let reply = self.reply("subscribe", bulk(channel));
+                    connection.write_frame(&reply).await?;
+                }
+            }
+            Command::PSubscribe { patterns } => {
+                for pattern in patterns {
+                    if !self.patterns.contains_key(&pattern) {
+                        let rx = self.db.pub_sub().psubscribe(pattern.clone());
+                        self.patterns.insert(pattern.clone(), BroadcastStream::new(rx));
+                    }
+                    let reply = self.reply("psubscribe", bulk(pattern));
+                    connection.write_frame(&reply).await?;
+                }
+            }
+            Command::Unsubscribe { channels } => {
+                // 没有指定频道时取消所有的订阅
+                let channels = if channels.is_empty() {
+                    self.channels.keys().cloned().collect()
+                } else {
+                    channels
+                };
+                if channels.is_empty() {
+                    connection.write_frame(&self.reply("unsubscribe", Frame::Null)).await?;
+                }
+                for channel in channels {
+                    self.channels.remove(&channel);
+                    self.db.pub_sub().remove_unused_channel(&channel);
+                    let reply = self.reply("unsubscribe", bulk(channel));
+                    connection.write_frame(&reply).await?;
+                }
+            }
+            Command::PUnsubscribe { patterns } => {
+                let patterns = if patterns.is_empty() {
+                    self.patterns.keys().cloned().collect()
+                } else {
+                    patterns
+                };
+                if patterns.is_empty() {
+                    connection.write_frame(&self.reply("punsubscribe", Frame::Null)).await?;
+                }
+                for pattern in patterns {
+                    self.patterns.remove(&pattern);
+                    self.db.pub_sub().remove_unused_pattern(&pattern);
+                    let reply = self.reply("punsubscribe", bulk(pattern));
+                    connection.write_frame(&reply).await?;
+                }
+            }
+            // 和 Redis 一样，订阅状态下的 PING 回复一个数组
+            Command::Ping { message } => {
+                let message = message.map_or_else(|| bulk(""), Frame::Bulk);
+                connection.write_frame(&Frame::Array(vec![bulk("pong"), message])).await?;
+            }
+            _ => {
+                let msg =
+                    "ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context";
+                connection.write_frame(&Frame::Error(msg.to_string())).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 订阅的频道和模式的总数。
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// 订阅和取消订阅的回复：`[kind, 频道或模式, 剩余的订阅数]`。
+    fn reply(&self, kind: &str, name: Frame) -> Frame {
+        push(vec![bulk(kind), name, Frame::Integer(self.count() as i64)])
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        // 连接断开时也要删除没有订阅者的通道，先 drop 掉自己持有的接收端
+        let channels: Vec<_> = self.channels.keys().cloned().collect();
+        let patterns: Vec<_> = self.patterns.keys().cloned().collect();
+        self.channels.clear();
+        self.patterns.clear();
+
+        let mut pub_sub = self.db.pub_sub();
+        for channel in &channels {
+            pub_sub.remove_unused_channel(channel);
+        }
+        for pattern in &patterns {
+            pub_sub.remove_unused_pattern(pattern);
+        }
+    }
+}
+
+/// 订阅者落后太多、丢失了消息时，告诉客户端之后断开连接。
+async fn check_lagged<T>(
+    connection: &mut Connection,
+    message: Result<T, BroadcastStreamRecvError>,
+) -> crate::Result<T> {
+    match message {
+        Ok(message) => Ok(message),
+        Err(BroadcastStreamRecvError::Lagged(n)) => {
+            let msg = format!("ERR subscriber is too slow, {} messages were dropped", n);
+            connection.write_frame(&Frame::Error(msg.clone())).await?;
+            Err(msg.into())
+        }
+    }
+}
+
+fn bulk(s: impl Into<String>) -> Frame {
+    Frame::Bulk(Bytes::from(s.into()))
+}
+
+/// 订阅的消息和订阅、取消订阅的回复，RESP3 中是服务器推送的数据，RESP2 中是普通的数组。
+fn push(frames: Vec<Frame>) -> Frame {
+    Frame::Push(frames)
+}
+
+/// Redis 风格的 glob 匹配，用于 PSUBSCRIBE：
+/// `*` 匹配任意个字符，`?` 匹配一个字符，`[abc]`、`[^a]`、`[a-z]` 匹配字符集合，`\` 转义。
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // 最近一个 `*` 之后的位置，以及它开始匹配的字符串位置。
+    // 后面匹配失败时回到这里，让 `*` 多匹配一个字符再试
+    let mut backtrack = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, s));
+            continue;
+        }
+        if p < pattern.len() {
+            let (len, matched) = match_one(&pattern[p..], string[s]);
+            if matched {
+                p += len;
+                s += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// 用模式开头的一个元素匹配字符 `c`，返回这个元素在模式中的长度以及是否匹配。
+fn match_one(pattern: &[u8], c: u8) -> (usize, bool) {
+    match pattern[0] {
+        b'?' => (1, true),
+        b'\\' if pattern.len() > 1 => (2, pattern[1] == c),
+        b'[' => {
+            let mut i = 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            // 和 Redis 一样，没有 `]` 时一直到模式的末尾都算作字符集合
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    i += 1;
+                    matched |= pattern[i] == c;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (lo, hi) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+                    matched |= (lo..=hi).contains(&c);
+                    i += 2;
+                } else {
+                    matched |= pattern[i] == c;
+                }
+                i += 1;
+            }
+            // 跳过 `]`
+            (pattern.len().min(i + 1), matched != negate)
+        }
+        literal => (1, literal == c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DbDropGuard;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn glob_patterns() {
+        let cases = [
+            ("news.*", "news.sports", true),
+            ("news.*", "news", false),
+            ("*", "", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*o*d", "hello world", true),
+            ("h*o*d", "hello worlds", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), string.as_bytes()),
+                expected,
+                "{:?} {:?}",
+                pattern,
+                string
+            );
+        }
+    }
+
+    fn command(args: &[&str]) -> Frame {
+        Frame::Array(args.iter().map(|arg| bulk(*arg)).collect())
+    }
+
+    /// 启动一个只处理订阅命令的服务端，返回客户端的连接。
+    async fn subscriber(db: Db) -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut connection = Connection::new(socket);
+            while let Some(frame) = connection.read_frame().await.unwrap() {
+                let cmd = Command::from_frame(frame).unwrap();
+                if Subscriber::new(db.clone()).run(&mut connection, cmd).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Connection::new(TcpStream::connect(addr).await.unwrap())
+    }
+
+    async fn send(client: &mut Connection, args: &[&str]) {
+        client.write_frame(&command(args)).await.unwrap();
+    }
+
+    async fn recv(client: &mut Connection) -> Frame {
+        client.read_frame().await.unwrap().unwrap()
+    }
+
+    fn subscribed(kind: &str, name: &str, count: i64) -> Frame {
+        Frame::Array(vec![bulk(kind), bulk(name), Frame::Integer(count)])
+    }
+
+    #[tokio::test]
+    async fn subscribe_publish_and_unsubscribe() {
+        let guard = DbDropGuard::new();
+        let db = guard.db();
+        let mut client = subscriber(db.clone()).await;
+
+        send(&mut client, &["SUBSCRIBE", "news", "weather"]).await;
+        assert_eq!(recv(&mut client).await, subscribed("subscribe", "news", 1));
+        assert_eq!(recv(&mut client).await, subscribed("subscribe", "weather", 2));
+        send(&mut client, &["PSUBSCRIBE", "n*"]).await;
+        assert_eq!(recv(&mut client).await, subscribed("psubscribe", "n*", 3));
+
+        assert_eq!(db.publish("news", "hi".into()), 2);
+        assert_eq!(db.publish("nobody", "x".into()), 1);
+        assert_eq!(db.publish("other", "x".into()), 0);
+        // 直接订阅和模式订阅的消息来自不同的通道，它们之间的先后顺序是不确定的
+        let mut frames = vec![];
+        for _ in 0..3 {
+            frames.push(recv(&mut client).await);
+        }
+        frames.sort_by_key(|frame| frame.to_string());
+        let mut expected = [
+            command(&["message", "news", "hi"]),
+            command(&["pmessage", "n*", "news", "hi"]),
+            command(&["pmessage", "n*", "nobody", "x"]),
+        ];
+        expected.sort_by_key(|frame| frame.to_string());
+        assert_eq!(frames, expected);
+
+        send(&mut client, &["GET", "k"]).await;
+        assert!(matches!(recv(&mut client).await, Frame::Error(_)));
+        send(&mut client, &["PING"]).await;
+        assert_eq!(recv(&mut client).await, command(&["pong", ""]));
+
+        send(&mut client, &["UNSUBSCRIBE", "weather"]).await;
+        assert_eq!(recv(&mut client).await, subscribed("unsubscribe", "weather", 2));
+        send(&mut client, &["PUNSUBSCRIBE"]).await;
+        assert_eq!(recv(&mut client).await, subscribed("punsubscribe", "n*", 1));
+        assert_eq!(db.publish("weather", "sunny".into()), 0);
+        send(&mut client, &["UNSUBSCRIBE"]).await;
+        assert_eq!(recv(&mut client).await, subscribed("unsubscribe", "news", 0));
+
+        // 取消了所有订阅之后，没有订阅者的通道都被删除了
+        let pub_sub = db.pub_sub();
+        assert!(pub_sub.channels.is_empty() && pub_sub.patterns.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_are_disconnected() {
+        let guard = DbDropGuard::new();
+        let db = guard.db();
+        let mut client = subscriber(db.clone()).await;
+        send(&mut client, &["SUBSCRIBE", "firehose"]).await;
+        assert_eq!(recv(&mut client).await, subscribed("subscribe", "firehose", 1));
+
+        // 发布的过程中订阅者没有机会运行，通道被塞满之后最旧的消息被覆盖
+        for i in 0..CHANNEL_CAPACITY + 10 {
+            assert_eq!(db.publish("firehose", i.to_string().into()), 1);
+        }
+        let msg = "ERR subscriber is too slow, 10 messages were dropped";
+        assert_eq!(recv(&mut client).await, Frame::Error(msg.into()));
+        assert!(client.read_frame().await.unwrap().is_none());
+    }
+}
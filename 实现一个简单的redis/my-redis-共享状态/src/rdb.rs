@@ -0,0 +1,350 @@
+use crate::value::{SortedSet, Value};
+
+use bytes::Bytes;
+use crc::{CRC_64_REDIS, Crc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// RDB 快照：把整个数据库写成一个紧凑的二进制文件，服务器重启时再读回来。
+// 格式参考了 Redis 的 RDB，但是简单得多：
+//
+//   "MYRDB" 版本号(u16 大端)
+//   每个键：[0xFC 过期时间(u64 小端，Unix 毫秒)] 类型(u8) 键 值
+//   0xFF
+//   校验和(u64 小端，之前所有字节的 CRC-64，和 Redis 使用的算法一样)
+//
+// 字符串写成 长度 + 内容，长度和元素个数使用变长编码(LEB128)，小的数字只占一个字节。
+// 过期时间保存的是绝对时间：tokio 的 `Instant` 只在当前进程中有意义，重启之后需要重新换算。
+
+const MAGIC: &[u8] = b"MYRDB";
+/// 格式有不兼容的修改时加 1，旧版本的服务器会拒绝读取新版本的文件
+pub const VERSION: u16 = 1;
+
+const OP_EXPIRE_MS: u8 = 0xFC;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+
+const CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+/// 快照中的一个键。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub key: String,
+    pub value: Value,
+    pub expires_at: Option<SystemTime>,
+}
+
+/// 把所有的键编码成快照文件的内容。
+pub fn encode(records: &[Record]) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&VERSION.to_be_bytes());
+    for record in records {
+        if let Some(when) = record.expires_at {
+            buf.push(OP_EXPIRE_MS);
+            buf.extend_from_slice(&unix_millis(when).to_le_bytes());
+        }
+        match &record.value {
+            Value::String(s) => {
+                buf.push(TYPE_STRING);
+                put_bytes(&mut buf, record.key.as_bytes());
+                put_bytes(&mut buf, s);
+            }
+            Value::List(list) => {
+                buf.push(TYPE_LIST);
+                put_bytes(&mut buf, record.key.as_bytes());
+                put_len(&mut buf, list.len());
+                list.iter().for_each(|item| put_bytes(&mut buf, item));
+            }
+            Value::Hash(hash) => {
+                buf.push(TYPE_HASH);
+                put_bytes(&mut buf, record.key.as_bytes());
+                put_len(&mut buf, hash.len());
+                for (field, value) in hash {
+                    put_bytes(&mut buf, field);
+                    put_bytes(&mut buf, value);
+                }
+            }
+            Value::Set(set) => {
+                buf.push(TYPE_SET);
+                put_bytes(&mut buf, record.key.as_bytes());
+                put_len(&mut buf, set.len());
+                set.iter().for_each(|member| put_bytes(&mut buf, member));
+            }
+            Value::ZSet(zset) => {
+                buf.push(TYPE_ZSET);
+                put_bytes(&mut buf, record.key.as_bytes());
+                put_len(&mut buf, zset.len());
+                for (member, score) in zset.iter() {
+                    put_bytes(&mut buf, member);
+                    buf.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+        }
+    }
+    buf.push(OP_EOF);
+    let checksum = CRC64.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+/// 解析快照文件的内容，文件损坏时返回错误。
+pub fn decode(data: Bytes) -> crate::Result<Vec<Record>> {
+    if data.len() < MAGIC.len() + 2 + 1 + 8 || !data.starts_with(MAGIC) {
+        return Err("rdb: not a snapshot file".into());
+    }
+    let version = u16::from_be_bytes([data[MAGIC.len()], data[MAGIC.len() + 1]]);
+    if version != VERSION {
+        return Err(format!("rdb: unsupported version {}", version).into());
+    }
+    // 先检查校验和，后面的解析就不需要担心数据被篡改成了奇怪的样子
+    let (body, checksum) = data.split_at(data.len() - 8);
+    if CRC64.checksum(body) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err("rdb: checksum mismatch".into());
+    }
+
+    let mut reader = Reader {
+        data: data.slice(MAGIC.len() + 2..data.len() - 8),
+        pos: 0,
+    };
+    let mut records = vec![];
+    loop {
+        let mut expires_at = None;
+        let mut op = reader.u8()?;
+        if op == OP_EXPIRE_MS {
+            let millis = u64::from_le_bytes(reader.array()?);
+            expires_at = Some(UNIX_EPOCH + Duration::from_millis(millis));
+            op = reader.u8()?;
+        }
+        if op == OP_EOF {
+            break;
+        }
+        let key = String::from_utf8(reader.bytes()?.to_vec())
+            .map_err(|_| "rdb: key is not valid UTF-8")?;
+        let value = match op {
+            TYPE_STRING => Value::String(reader.bytes()?),
+            TYPE_LIST => {
+                let len = reader.len()?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    list.push_back(reader.bytes()?);
+                }
+                Value::List(list)
+            }
+            TYPE_HASH => {
+                let len = reader.len()?;
+                let mut hash = HashMap::new();
+                for _ in 0..len {
+                    hash.insert(reader.bytes()?, reader.bytes()?);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_SET => {
+                let len = reader.len()?;
+                let mut set = HashSet::new();
+                for _ in 0..len {
+                    set.insert(reader.bytes()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_ZSET => {
+                let len = reader.len()?;
+                let mut zset = SortedSet::new();
+                for _ in 0..len {
+                    let member = reader.bytes()?;
+                    let score = f64::from_le_bytes(reader.array()?);
+                    if score.is_nan() {
+                        return Err("rdb: invalid sorted set score".into());
+                    }
+                    zset.insert(member, score);
+                }
+                Value::ZSet(zset)
+            }
+            op => return Err(format!("rdb: unknown record type {:#x}", op).into()),
+        };
+        records.push(Record {
+            key,
+            value,
+            expires_at,
+        });
+    }
+    if reader.pos != reader.data.len() {
+        return Err("rdb: unexpected data after the end of the snapshot".into());
+    }
+    Ok(records)
+}
+
+/// 读取快照文件，文件不存在时返回 `None`。
+pub fn read_file(path: &Path) -> crate::Result<Option<Vec<Record>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(decode(Bytes::from(data))?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 写入快照文件。
+///
+/// 先写到一个临时文件中，fsync 之后再重命名成目标文件。重命名是原子的，
+/// 即使写到一半时进程崩溃了，原来的快照文件也是完整的。
+pub fn write_file(path: &Path, records: &[Record]) -> io::Result<()> {
+    let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+    let result = (|| {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&encode(records))?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// DUMP 命令的内容：只有一个值的快照，同样带有版本号和校验和。
+/// 键名和过期时间不在其中，由 RESTORE 的参数指定。
+pub fn dump(value: &Value) -> Vec<u8> {
+    encode(&[Record {
+        key: String::new(),
+        value: value.clone(),
+        expires_at: None,
+    }])
+}
+
+/// 解析 DUMP 的内容。
+pub fn restore(payload: Bytes) -> crate::Result<Value> {
+    match <[Record; 1]>::try_from(decode(payload)?) {
+        Ok([record]) => Ok(record.value),
+        Err(_) => Err("rdb: a dump should contain exactly one value".into()),
+    }
+}
+
+fn unix_millis(when: SystemTime) -> u64 {
+    // 早于 1970 年的过期时间当作 0，读回来时这个键已经过期了
+    when.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+fn put_len(buf: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        buf.push((len as u8 & 0x7F) | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    put_len(buf, data.len());
+    buf.extend_from_slice(data);
+}
+
+struct Reader {
+    data: Bytes,
+    pos: usize,
+}
+
+impl Reader {
+    fn take(&mut self, n: usize) -> crate::Result<Bytes> {
+        if self.data.len() - self.pos < n {
+            return Err("rdb: unexpected end of file".into());
+        }
+        self.pos += n;
+        // 只是增加引用计数，不会复制数据
+        Ok(self.data.slice(self.pos - n..self.pos))
+    }
+
+    fn u8(&mut self) -> crate::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> crate::Result<[u8; N]> {
+        Ok(self.take(N)?[..].try_into().unwrap())
+    }
+
+    fn len(&mut self) -> crate::Result<usize> {
+        let mut len = 0usize;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            len |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(len);
+            }
+        }
+        Err("rdb: invalid length".into())
+    }
+
+    fn bytes(&mut self) -> crate::Result<Bytes> {
+        let len = self.len()?;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<Record> {
+        let b = |s: &str| Bytes::copy_from_slice(s.as_bytes());
+        let mut zset = SortedSet::new();
+        zset.insert(b("a"), 1.5);
+        zset.insert(b("b"), f64::NEG_INFINITY);
+        vec![
+            Record {
+                key: "string".into(),
+                // 长度超过 127，变长编码需要两个字节
+                value: Value::String(Bytes::from(vec![b'x'; 300])),
+                expires_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+            },
+            Record {
+                key: "list".into(),
+                value: Value::List([b("a"), b(""), b("c")].into()),
+                expires_at: None,
+            },
+            Record {
+                key: "hash".into(),
+                value: Value::Hash([(b("f"), b("v"))].into()),
+                expires_at: None,
+            },
+            Record {
+                key: "set".into(),
+                value: Value::Set([b("m"), b("n")].into()),
+                expires_at: None,
+            },
+            Record {
+                key: "zset".into(),
+                value: Value::ZSet(zset),
+                expires_at: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = encode(&records());
+        assert!(data.starts_with(b"MYRDB\0\x01"));
+        assert_eq!(decode(Bytes::from(data)).unwrap(), records());
+        assert_eq!(decode(Bytes::from(encode(&[]))).unwrap(), []);
+    }
+
+    #[test]
+    fn corrupted_files_are_rejected() {
+        let data = encode(&records());
+        let error = |data: Vec<u8>| decode(Bytes::from(data)).unwrap_err().to_string();
+
+        let mut flipped = data.clone();
+        flipped[20] ^= 1;
+        assert_eq!(error(flipped), "rdb: checksum mismatch");
+        assert_eq!(error(data[..data.len() - 1].to_vec()), "rdb: checksum mismatch");
+        assert_eq!(error(b"REDIS0009".to_vec()), "rdb: not a snapshot file");
+
+        let mut newer = encode(&[]);
+        newer[6] = 2;
+        assert_eq!(error(newer), "rdb: unsupported version 2");
+    }
+}
@@ -0,0 +1,799 @@
+use crate::aof::command;
+use crate::frame::{Frame, Protocol};
+use crate::{Command, Connection, Db, rdb};
+
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+// 主从复制：从服务器(replica)连接到主服务器(primary)，先全量同步一份快照，
+// 之后主服务器把每个写命令(和写到 AOF 中的一样，见 `Command::propagate`)原样转发给它。
+// 从服务器是只读的，主服务器出问题时执行 REPLICAOF NO ONE 就可以顶上去(warm standby)。
+//
+// 写命令组成一个字节流，每个字节都有一个偏移量(offset)。主服务器在积压缓冲区(backlog)中
+// 保留最近的一段，从服务器记录自己已经收到了多少字节。连接断开之后从服务器带着
+// `replid` 和偏移量重新连接(PSYNC)，只要缺少的部分还在积压缓冲区中，主服务器回复 +CONTINUE
+// 并补发这一段(部分同步)，否则回复 +FULLRESYNC，重新发送一份完整的快照：
+//
+//   replica -> PING
+//   replica -> REPLCONF listening-port 6380
+//   replica -> PSYNC <replid> <offset>       自己的 replid 和偏移量
+//   primary -> +FULLRESYNC <replid> <offset>
+//   primary -> $<len>\r\n<RDB 快照>\r\n
+//   primary -> 写命令...                     从服务器每秒回复一次 REPLCONF ACK <offset>
+//
+// 和 Redis 的区别：PSYNC 的偏移量是已经收到的字节数，而不是下一个字节的偏移量；
+// 快照是一个普通的 bulk 字符串(结尾有 \r\n)。EXEC 中的写命令和 Redis 一样用 MULTI/EXEC
+// 包起来转发，从服务器收到 EXEC 之后才在同一次加锁中执行它们，不会只执行了半个事务。
+//
+// 从服务器收到的命令流会原样放进它自己的积压缓冲区，replid 和偏移量也和主服务器一样，
+// 因此它被提升为主服务器之后，其它从服务器可以直接从它这里部分同步。
+
+/// 积压缓冲区默认的大小，和 Redis 的 `repl-backlog-size` 一样是 1MB。
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// 每个从服务器最多缓存多少条还没有发送出去的写命令，超过之后断开它，让它重新同步。
+const STREAM_CAPACITY: usize = 4096;
+
+/// 和主服务器的连接断开之后，等多久再重新连接。
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// 从服务器每隔多久向主服务器报告一次自己的偏移量。
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// 复制的状态，保存在 `Db` 中。
+#[derive(Debug)]
+pub(crate) struct Replication {
+    /// 有从服务器连接过、或者自己是从服务器之后才开始记录命令流。
+    /// 没有复制时写命令只需要检查这个标记，不需要竞争下面这把全局的锁
+    enabled: AtomicBool,
+    /// 是否是从服务器，每个写命令都要检查，单独放在锁外面
+    replica: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// 命令流的 id，只有 replid 相同时偏移量才有意义
+    replid: String,
+    /// 命令流一共有多少字节
+    offset: u64,
+    /// 提升为主服务器之前的 replid 和当时的偏移量。之前和自己连着同一个主服务器的
+    /// 从服务器带着这个 replid 来同步时，在这个偏移量之前的部分是一样的，可以部分同步
+    previous: Option<(String, u64)>,
+    /// 命令流最后的 `backlog_size` 个字节
+    backlog: VecDeque<u8>,
+    backlog_size: usize,
+    /// 转发给每个从服务器的命令流。替换成一个新的通道会让所有的从服务器断开
+    stream: broadcast::Sender<Bytes>,
+    role: Role,
+    /// 连接着的从服务器，以及它们最后一次确认的偏移量
+    replicas: HashMap<u64, ReplicaInfo>,
+    next_id: u64,
+    /// 全量同步和部分同步的次数
+    full_syncs: u64,
+    partial_syncs: u64,
+}
+
+#[derive(Debug)]
+enum Role {
+    Primary,
+    Replica {
+        host: String,
+        port: u16,
+        link: Link,
+        /// 同一个 `Replication` 上可能先后启动过好几个复制任务(REPLICAOF 到不同的主服务器)，
+        /// 已经被取消的任务通过这个 id 发现自己过时了，不会再修改状态
+        task_id: u64,
+        task: JoinHandle<()>,
+    },
+}
+
+/// 和主服务器之间连接的状态，ROLE 命令中的写法和 Redis 一样。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link {
+    /// 等待重新连接
+    Connect,
+    Connecting,
+    /// 正在接收快照
+    Sync,
+    Connected,
+}
+
+#[derive(Debug)]
+struct ReplicaInfo {
+    addr: SocketAddr,
+    offset: u64,
+}
+
+impl Replication {
+    pub(crate) fn new(backlog_size: usize) -> Replication {
+        Replication {
+            enabled: AtomicBool::new(false),
+            replica: AtomicBool::new(false),
+            state: Mutex::new(State {
+                replid: new_replid(),
+                offset: 0,
+                previous: None,
+                backlog: VecDeque::new(),
+                backlog_size,
+                stream: broadcast::channel(STREAM_CAPACITY).0,
+                role: Role::Primary,
+                replicas: HashMap::new(),
+                next_id: 0,
+                full_syncs: 0,
+                partial_syncs: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn is_replica(&self) -> bool {
+        self.replica.load(Ordering::Acquire)
+    }
+
+    /// 写命令是否需要加入命令流。不需要时调用者可以省掉编码命令的开销。
+    ///
+    /// 从服务器上的写命令都来自主服务器，由 `applied` 原样加入命令流，不需要 `feed`。
+    pub(crate) fn is_feeding(&self) -> bool {
+        self.enabled.load(Ordering::Acquire) && !self.is_replica()
+    }
+
+    /// 把执行成功的写命令(编码之后的)加入命令流，在持有命令访问的键所在分片的锁时调用。
+    pub(crate) fn feed(&self, data: Bytes) {
+        if self.is_feeding() {
+            self.state.lock().unwrap().append(data);
+        }
+    }
+
+    /// 全量同步：从现在开始记录命令流，返回当前的 replid、偏移量和命令流的接收端。
+    ///
+    /// 调用者需要锁住所有的分片并在同一次加锁中复制出所有的键，
+    /// 快照和偏移量才是一致的，之后的命令一条不多一条不少。
+    pub(crate) fn start_full_sync(&self) -> (String, u64, broadcast::Receiver<Bytes>) {
+        let mut state = self.state.lock().unwrap();
+        self.enabled.store(true, Ordering::Release);
+        state.full_syncs += 1;
+        (state.replid.clone(), state.offset, state.stream.subscribe())
+    }
+
+    /// 部分同步：从服务器缺少的部分还在积压缓冲区中时，
+    /// 返回当前的 replid、缺少的这一部分和命令流的接收端。
+    fn partial_sync(
+        &self,
+        replid: &str,
+        offset: i64,
+    ) -> Option<(String, Bytes, broadcast::Receiver<Bytes>)> {
+        let mut state = self.state.lock().unwrap();
+        let offset = u64::try_from(offset).ok()?;
+        let same_history = match &state.previous {
+            _ if replid == state.replid => true,
+            Some((previous, end)) => replid == previous && offset <= *end,
+            None => false,
+        };
+        let start = state.offset - state.backlog.len() as u64;
+        let available = start <= offset && offset <= state.offset;
+        if !self.enabled.load(Ordering::Acquire) || !same_history || !available {
+            return None;
+        }
+        let missing: Vec<u8> = state.backlog.range((offset - start) as usize..).copied().collect();
+        state.partial_syncs += 1;
+        Some((state.replid.clone(), missing.into(), state.stream.subscribe()))
+    }
+
+    fn add_replica(&self, addr: SocketAddr, offset: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.replicas.insert(id, ReplicaInfo { addr, offset });
+        id
+    }
+
+    fn ack(&self, id: u64, offset: u64) {
+        if let Some(replica) = self.state.lock().unwrap().replicas.get_mut(&id) {
+            replica.offset = offset;
+        }
+    }
+
+    fn remove_replica(&self, id: u64) {
+        self.state.lock().unwrap().replicas.remove(&id);
+    }
+
+    /// REPLICAOF：`Some` 时成为从服务器，`None` 时成为主服务器。
+    ///
+    /// 角色或者主服务器变了时，连着自己的从服务器都会断开，让它们重新同步。
+    pub(crate) fn replicaof(&self, db: &Db, primary: Option<(String, u16)>) {
+        let mut state = self.state.lock().unwrap();
+        if let Role::Replica { host, port, task, .. } = &state.role {
+            // 已经在复制同一个主服务器了，什么也不做
+            if primary.as_ref().is_some_and(|(h, p)| h == host && p == port) {
+                return;
+            }
+            task.abort();
+        }
+        match primary {
+            Some((host, port)) => {
+                state.stream = broadcast::channel(STREAM_CAPACITY).0;
+                self.enabled.store(true, Ordering::Release);
+                self.replica.store(true, Ordering::Release);
+                state.next_id += 1;
+                let task_id = state.next_id;
+                let task = tokio::spawn(replicate(db.clone(), task_id, host.clone(), port));
+                state.role = Role::Replica {
+                    host,
+                    port,
+                    link: Link::Connect,
+                    task_id,
+                    task,
+                };
+            }
+            None if matches!(state.role, Role::Primary) => {}
+            None => {
+                // 新的命令流从当前的偏移量接着往下记，之前的部分和原来的主服务器是一样的
+                state.stream = broadcast::channel(STREAM_CAPACITY).0;
+                let previous = std::mem::replace(&mut state.replid, new_replid());
+                state.previous = Some((previous, state.offset));
+                state.role = Role::Primary;
+                self.replica.store(false, Ordering::Release);
+            }
+        }
+    }
+
+    /// 停止复制任务，服务器退出时调用。
+    pub(crate) fn shutdown(&self) {
+        if let Role::Replica { task, .. } = &self.state.lock().unwrap().role {
+            task.abort();
+        }
+    }
+
+    /// 复制任务 `task_id` 是否还是当前的任务，过时的任务返回错误并退出。
+    fn check_task(state: &State, task_id: u64) -> crate::Result<()> {
+        match state.role {
+            Role::Replica { task_id: id, .. } if id == task_id => Ok(()),
+            _ => Err("replication was reconfigured".into()),
+        }
+    }
+
+    fn set_link(&self, task_id: u64, new_link: Link) -> crate::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Replication::check_task(&state, task_id)?;
+        if let Role::Replica { link, .. } = &mut state.role {
+            *link = new_link;
+        }
+        Ok(())
+    }
+
+    /// 下一次 PSYNC 使用的 replid 和偏移量。
+    fn resume_point(&self) -> (String, u64) {
+        let state = self.state.lock().unwrap();
+        (state.replid.clone(), state.offset)
+    }
+
+    /// 全量同步完成，命令流换成主服务器的。在持有所有分片的锁时调用。
+    pub(crate) fn reset(&self, task_id: u64, replid: String, offset: u64) -> crate::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Replication::check_task(&state, task_id)?;
+        state.replid = replid;
+        state.offset = offset;
+        state.previous = None;
+        state.backlog.clear();
+        state.stream = broadcast::channel(STREAM_CAPACITY).0;
+        Ok(())
+    }
+
+    /// 部分同步成功。主服务器可能在这期间被提升过(REPLICAOF NO ONE)，换了一个新的 replid，
+    /// 这时换成它的 replid，偏移量和积压缓冲区不变：在这之前的命令流和它是一样的。
+    /// 否则下一次重新连接时带着过时的 replid，只能全量同步。
+    fn continue_with(&self, task_id: u64, replid: &str) -> crate::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Replication::check_task(&state, task_id)?;
+        if state.replid != replid {
+            let previous = std::mem::replace(&mut state.replid, replid.to_string());
+            state.previous = Some((previous, state.offset));
+            // 让连着自己的从服务器重新同步，拿到新的 replid
+            state.stream = broadcast::channel(STREAM_CAPACITY).0;
+        }
+        Ok(())
+    }
+
+    /// 从服务器执行了主服务器发来的一个命令，把它原样加入自己的命令流。
+    fn applied(&self, task_id: u64, data: Bytes) -> crate::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Replication::check_task(&state, task_id)?;
+        state.append(data);
+        Ok(())
+    }
+
+    /// 自己已经处理到的偏移量。
+    pub(crate) fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    /// 全量同步和部分同步的次数。
+    #[cfg(test)]
+    pub(crate) fn syncs(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.full_syncs, state.partial_syncs)
+    }
+
+    /// ROLE 命令的回复。
+    pub(crate) fn role(&self) -> Frame {
+        let state = self.state.lock().unwrap();
+        match &state.role {
+            Role::Primary => {
+                let replicas = state.replicas.values().map(|replica| {
+                    Frame::Array(vec![
+                        bulk(replica.addr.ip().to_string()),
+                        bulk(replica.addr.port().to_string()),
+                        bulk(replica.offset.to_string()),
+                    ])
+                });
+                Frame::Array(vec![
+                    bulk("master".to_string()),
+                    Frame::Integer(state.offset as i64),
+                    Frame::Array(replicas.collect()),
+                ])
+            }
+            Role::Replica {
+                host, port, link, ..
+            } => {
+                let link = match link {
+                    Link::Connect => "connect",
+                    Link::Connecting => "connecting",
+                    Link::Sync => "sync",
+                    Link::Connected => "connected",
+                };
+                Frame::Array(vec![
+                    bulk("slave".to_string()),
+                    bulk(host.clone()),
+                    Frame::Integer(*port as i64),
+                    bulk(link.to_string()),
+                    Frame::Integer(state.offset as i64),
+                ])
+            }
+        }
+    }
+}
+
+impl State {
+    fn append(&mut self, data: Bytes) {
+        self.offset += data.len() as u64;
+        self.backlog.extend(&data[..]);
+        let excess = self.backlog.len().saturating_sub(self.backlog_size);
+        self.backlog.drain(..excess);
+        // 没有从服务器时没有接收端，发送失败是正常的
+        let _ = self.stream.send(data);
+    }
+}
+
+/// 处理一个从服务器的 PSYNC：先同步，然后一直转发命令流，直到连接断开。
+///
+/// `addr` 是从服务器监听的地址，只用于 ROLE 命令的显示。
+pub async fn serve_replica(
+    connection: &mut Connection,
+    db: &Db,
+    addr: SocketAddr,
+    replid: &str,
+    offset: i64,
+) -> crate::Result<()> {
+    let replication = db.replication();
+    let (offset, mut stream) = match replication.partial_sync(replid, offset) {
+        Some((replid, missing, stream)) => {
+            let reply = format!("CONTINUE {}", replid);
+            connection.write_frame(&Frame::Simple(reply)).await?;
+            connection.write_bytes(&missing).await?;
+            (offset as u64, stream)
+        }
+        None => {
+            let (records, replid, offset, stream) = db.sync_replica();
+            let reply = format!("FULLRESYNC {} {}", replid, offset);
+            connection.write_frame(&Frame::Simple(reply)).await?;
+            // 编码快照比较慢，放到单独的线程中，不阻塞其它连接
+            let snapshot = tokio::task::spawn_blocking(move || rdb::encode(&records)).await?;
+            connection.write_frame(&Frame::Bulk(snapshot.into())).await?;
+            (offset, stream)
+        }
+    };
+
+    let id = replication.add_replica(addr, offset);
+    let result = forward(connection, replication, id, &mut stream).await;
+    replication.remove_replica(id);
+    result
+}
+
+async fn forward(
+    connection: &mut Connection,
+    replication: &Replication,
+    id: u64,
+    stream: &mut broadcast::Receiver<Bytes>,
+) -> crate::Result<()> {
+    loop {
+        tokio::select! {
+            data = stream.recv() => match data {
+                Ok(data) => connection.write_bytes(&data).await?,
+                // 从服务器跟不上时断开它，它重新连接之后会尽量部分同步
+                Err(RecvError::Lagged(n)) => {
+                    return Err(format!("replica is too slow, {} commands were dropped", n).into());
+                }
+                // 命令流被替换了(例如 REPLICAOF)，让从服务器重新同步
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            frame = connection.read_frame() => match frame? {
+                Some(frame) => {
+                    if let Ok(Command::ReplConf {
+                        ack: Some(offset), ..
+                    }) = Command::from_frame(frame)
+                    {
+                        replication.ack(id, offset);
+                    }
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// 从服务器的复制任务：连接主服务器、同步、执行命令流，断开之后重新连接。
+async fn replicate(db: Db, task_id: u64, host: String, port: u16) {
+    loop {
+        if db.replication().set_link(task_id, Link::Connecting).is_err() {
+            return;
+        }
+        match sync_with_primary(&db, task_id, &host, port).await {
+            Ok(()) => eprintln!("replication: primary {}:{} closed the connection", host, port),
+            Err(e) => eprintln!("replication: lost the connection to {}:{}: {}", host, port, e),
+        }
+        if db.replication().set_link(task_id, Link::Connect).is_err() {
+            return;
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_primary(db: &Db, task_id: u64, host: &str, port: u16) -> crate::Result<()> {
+    let replication = db.replication();
+    let socket = TcpStream::connect((host, port)).await?;
+    // 快照是一个很大的 bulk 字符串，不受帧大小的限制
+    let mut connection = Connection::with_max_frame_size(socket, usize::MAX);
+
+    request(&mut connection, "PING", &[]).await?;
+    let listening_port = db.config().port.to_string();
+    request(&mut connection, "REPLCONF", &["listening-port", &listening_port]).await?;
+    let (replid, offset) = replication.resume_point();
+    let psync = command("PSYNC", [replid.into(), offset.to_string().into()]);
+    connection.write_frame(&psync).await?;
+    let reply = match connection.read_frame().await? {
+        Some(Frame::Simple(reply)) => reply,
+        Some(Frame::Error(e)) => return Err(e.into()),
+        _ => return Err("unexpected reply to PSYNC".into()),
+    };
+    let mut words = reply.split(' ');
+    match (words.next(), words.next(), words.next()) {
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+            let offset = offset.parse().map_err(|_| "invalid offset in FULLRESYNC")?;
+            replication.set_link(task_id, Link::Sync)?;
+            let Some(Frame::Bulk(snapshot)) = connection.read_frame().await? else {
+                return Err("expected a snapshot after FULLRESYNC".into());
+            };
+            let records = rdb::decode(snapshot)?;
+            db.full_resync(task_id, replid.to_string(), offset, records)?;
+        }
+        (Some("CONTINUE"), Some(replid), _) => replication.continue_with(task_id, replid)?,
+        (Some("CONTINUE"), None, _) => {}
+        _ => return Err(format!("unexpected reply to PSYNC: {}", reply).into()),
+    }
+    replication.set_link(task_id, Link::Connected)?;
+
+    let mut ack = time::interval(ACK_PERIOD);
+    // 正在接收的事务，连接在 EXEC 之前断开时丢弃，重新连接之后从 MULTI 开始重新接收
+    let mut multi = None;
+    loop {
+        tokio::select! {
+            frame = connection.read_frame() => {
+                let Some(frame) = frame? else {
+                    return Ok(());
+                };
+                apply(db, task_id, frame, &mut multi)?;
+            }
+            _ = ack.tick() => {
+                let offset = replication.offset().to_string();
+                connection.write_frame(&command("REPLCONF", ["ACK".into(), offset.into()])).await?;
+            }
+        }
+    }
+}
+
+/// 命令流中 MULTI 之后、EXEC 之前的命令，以及它们在命令流中的字节。
+#[derive(Debug)]
+struct Multi {
+    commands: Vec<Command>,
+    data: BytesMut,
+}
+
+/// 执行主服务器发来的一个命令。MULTI 之后的命令先攒在 `multi` 中，收到 EXEC 时一起执行。
+///
+/// 主服务器发送的就是 `Frame::encode` 的结果，重新编码得到的字节和收到的一样，
+/// 偏移量和主服务器保持一致。加入命令流和执行命令在同一次加锁中完成，
+/// 从服务器自己的从服务器做全量同步时，快照和偏移量才是一致的。
+fn apply(db: &Db, task_id: u64, frame: Frame, multi: &mut Option<Multi>) -> crate::Result<()> {
+    let mut data = BytesMut::new();
+    frame.encode(Protocol::Resp2, &mut data);
+    match (Command::from_frame(frame), multi.as_mut()) {
+        (Ok(Command::Multi), None) => {
+            *multi = Some(Multi {
+                commands: vec![],
+                data,
+            });
+            Ok(())
+        }
+        (Ok(Command::Exec), Some(_)) => {
+            let Multi {
+                commands,
+                data: mut buf,
+            } = multi.take().unwrap();
+            buf.extend_from_slice(&data);
+            let mut guard = db.lock(commands.iter().flat_map(Command::keys));
+            let mut propagated = vec![];
+            for cmd in commands {
+                let _ = cmd.apply_locked_into(&mut guard, &mut propagated);
+            }
+            // 写到自己的 AOF 中同样是一个完整的事务
+            let _ = guard.propagate(&propagated);
+            db.replication().applied(task_id, buf.freeze())
+        }
+        (cmd, Some(pending)) => {
+            pending.commands.extend(cmd.ok());
+            pending.data.extend_from_slice(&data);
+            Ok(())
+        }
+        (Ok(cmd), None) => {
+            let mut guard = db.lock(cmd.keys());
+            // 执行失败(例如 WRONGTYPE)的命令在主服务器上同样失败了，不需要处理
+            let _ = cmd.apply_locked(&mut guard);
+            db.replication().applied(task_id, data.freeze())
+        }
+        (Err(_), None) => db.replication().applied(task_id, data.freeze()),
+    }
+}
+
+/// 握手阶段发送一个命令，主服务器回复错误时返回错误。
+async fn request(
+    connection: &mut Connection,
+    name: &'static str,
+    args: &[&str],
+) -> crate::Result<()> {
+    let args = args.iter().map(|arg| Bytes::copy_from_slice(arg.as_bytes()));
+    connection.write_frame(&command(name, args)).await?;
+    match connection.read_frame().await? {
+        Some(Frame::Error(e)) => Err(e.into()),
+        Some(_) => Ok(()),
+        None => Err("the primary closed the connection".into()),
+    }
+}
+
+/// 40 个十六进制字符的随机 id。
+fn new_replid() -> String {
+    // 每个 `RandomState` 都有不同的随机密钥，不需要为此引入 rand
+    let now = SystemTime::now();
+    let id: String = (0..3)
+        .map(|_| format!("{:016x}", RandomState::new().hash_one(now)))
+        .collect();
+    id[..40].to_string()
+}
+
+fn bulk(s: String) -> Frame {
+    Frame::Bulk(Bytes::from(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DbDropGuard;
+
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    fn run(db: &Db, args: &[&str]) -> Frame {
+        let frame = Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        match Command::from_frame(frame) {
+            Ok(cmd) => cmd.apply(db),
+            Err(e) => Frame::Error(e.to_string()),
+        }
+    }
+
+    fn get(db: &Db, key: &str) -> Frame {
+        run(db, &["GET", key])
+    }
+
+    async fn wait_until(mut condition: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    /// 在一个随机的端口上运行主服务器，和 bin/server.rs 一样处理 PSYNC，返回端口。
+    async fn serve(db: Db) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (socket, addr) = listener.accept().await.unwrap();
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let mut connection = Connection::new(socket);
+                    while let Ok(Some(frame)) = connection.read_frame().await {
+                        let response = match Command::from_frame(frame) {
+                            Ok(Command::PSync { replid, offset }) => {
+                                let _ = serve_replica(&mut connection, &db, addr, &replid, offset)
+                                    .await;
+                                return;
+                            }
+                            Ok(cmd) => cmd.apply(&db),
+                            Err(e) => Frame::Error(e.to_string()),
+                        };
+                        if connection.write_frame(&response).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    /// 在主服务器前面放一个可以随时切断的代理，模拟网络断开。
+    async fn proxy(port: u16) -> (u16, Arc<Mutex<Vec<JoinHandle<()>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let links = Arc::new(Mutex::new(vec![]));
+        let tasks = links.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let mut server = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                tasks.lock().unwrap().push(tokio::spawn(async move {
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+                }));
+            }
+        });
+        (proxy_port, links)
+    }
+
+    #[tokio::test]
+    async fn replicas_sync_a_snapshot_and_then_stream_writes() {
+        let primary_guard = DbDropGuard::new();
+        let primary = primary_guard.db();
+        run(&primary, &["SET", "before", "1"]);
+        run(&primary, &["RPUSH", "list", "a", "b"]);
+        let port = serve(primary.clone()).await;
+
+        let replica_guard = DbDropGuard::new();
+        let replica = replica_guard.db();
+        run(&replica, &["SET", "stale", "x"]);
+        replica.replicaof(Some(("127.0.0.1".to_string(), port)));
+        wait_until(|| get(&replica, "before") == Frame::Bulk("1".into())).await;
+        // 全量同步会替换掉从服务器原来的数据
+        assert_eq!(get(&replica, "stale"), Frame::Null);
+
+        run(&primary, &["LPOP", "list"]);
+        run(&primary, &["SET", "after", "2", "EX", "100"]);
+        wait_until(|| get(&replica, "after") == Frame::Bulk("2".into())).await;
+        let list = run(&replica, &["LRANGE", "list", "0", "-1"]);
+        assert_eq!(list, Frame::Array(vec![Frame::Bulk("b".into())]));
+        assert!(matches!(run(&replica, &["TTL", "after"]), Frame::Integer(99..=100)));
+        wait_until(|| replica.replication().offset() == primary.replication().offset()).await;
+
+        let readonly = Frame::Error("READONLY You can't write against a read only replica.".into());
+        assert_eq!(run(&replica, &["SET", "k", "v"]), readonly);
+        let Frame::Array(role) = run(&replica, &["ROLE"]) else {
+            panic!("ROLE should reply an array");
+        };
+        assert_eq!(role[0], Frame::Bulk("slave".into()));
+        assert_eq!(role[3], Frame::Bulk("connected".into()));
+        let Frame::Array(role) = run(&primary, &["ROLE"]) else {
+            panic!("ROLE should reply an array");
+        };
+        assert_eq!(role[0], Frame::Bulk("master".into()));
+        assert!(matches!(&role[2], Frame::Array(replicas) if replicas.len() == 1));
+
+        // 提升为主服务器之后可以写入
+        assert_eq!(run(&replica, &["REPLICAOF", "NO", "ONE"]), Frame::ok());
+        assert_eq!(run(&replica, &["SET", "k", "v"]), Frame::ok());
+        run(&primary, &["SET", "after", "3"]);
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(get(&replica, "after"), Frame::Bulk("2".into()));
+    }
+
+    #[tokio::test]
+    async fn replicas_resume_with_a_partial_resync() {
+        let primary_guard = DbDropGuard::new();
+        let primary = primary_guard.db();
+        let (port, links) = proxy(serve(primary.clone()).await).await;
+
+        let replica_guard = DbDropGuard::new();
+        let replica = replica_guard.db();
+        replica.replicaof(Some(("127.0.0.1".to_string(), port)));
+        run(&primary, &["SET", "k", "1"]);
+        wait_until(|| get(&replica, "k") == Frame::Bulk("1".into())).await;
+
+        // 断开期间的写命令还在积压缓冲区中，重新连接之后只需要补上这一部分
+        links.lock().unwrap().drain(..).for_each(|link| link.abort());
+        run(&primary, &["SET", "k", "2"]);
+        run(&primary, &["INCR", "n"]);
+        wait_until(|| get(&replica, "n") == Frame::Bulk("1".into())).await;
+        assert_eq!(get(&replica, "k"), Frame::Bulk("2".into()));
+        assert_eq!(primary.replication().syncs(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn replicas_fall_back_to_a_full_resync() {
+        let primary_guard = DbDropGuard::with_config(&crate::Config {
+            repl_backlog_size: 16,
+            ..crate::Config::default()
+        });
+        let primary = primary_guard.db();
+        let (port, links) = proxy(serve(primary.clone()).await).await;
+
+        let replica_guard = DbDropGuard::new();
+        let replica = replica_guard.db();
+        replica.replicaof(Some(("127.0.0.1".to_string(), port)));
+        run(&primary, &["SET", "k", "1"]);
+        wait_until(|| get(&replica, "k") == Frame::Bulk("1".into())).await;
+
+        // 断开期间的写命令比积压缓冲区大，只能重新同步整个数据库
+        links.lock().unwrap().drain(..).for_each(|link| link.abort());
+        run(&primary, &["SET", "k", "a value that does not fit in the backlog"]);
+        run(&primary, &["DEL", "k"]);
+        run(&primary, &["SET", "n", "1"]);
+        wait_until(|| get(&replica, "n") == Frame::Bulk("1".into())).await;
+        assert_eq!(get(&replica, "k"), Frame::Null);
+        assert_eq!(primary.replication().syncs(), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn replicas_keep_resuming_from_a_promoted_replica() {
+        let primary_guard = DbDropGuard::new();
+        let primary = primary_guard.db();
+        let port = serve(primary.clone()).await;
+        let promoted_guard = DbDropGuard::new();
+        let promoted = promoted_guard.db();
+        let replica_guard = DbDropGuard::new();
+        let replica = replica_guard.db();
+        promoted.replicaof(Some(("127.0.0.1".to_string(), port)));
+        replica.replicaof(Some(("127.0.0.1".to_string(), port)));
+        run(&primary, &["SET", "k", "1"]);
+        wait_until(|| get(&promoted, "k") == Frame::Bulk("1".into())).await;
+        wait_until(|| get(&replica, "k") == Frame::Bulk("1".into())).await;
+
+        // 原来的主服务器出了问题，提升其中一个从服务器，另一个从服务器改为复制它
+        run(&promoted, &["REPLICAOF", "NO", "ONE"]);
+        let (port, links) = proxy(serve(promoted.clone()).await).await;
+        replica.replicaof(Some(("127.0.0.1".to_string(), port)));
+        wait_until(|| replica.replication().resume_point() == promoted.replication().resume_point())
+            .await;
+
+        // 换成了新的 replid，之后每次重新连接都可以部分同步
+        for n in ["1", "2"] {
+            links.lock().unwrap().drain(..).for_each(|link| link.abort());
+            run(&promoted, &["SET", "n", n]);
+            wait_until(|| get(&replica, "n") == Frame::Bulk(Bytes::copy_from_slice(n.as_bytes())))
+                .await;
+        }
+        assert_eq!(promoted.replication().syncs(), (0, 3));
+    }
+}
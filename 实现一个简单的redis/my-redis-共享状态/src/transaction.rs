@@ -0,0 +1,367 @@
+use crate::{Command, Db, Frame};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// 事务：MULTI 之后的命令不会立刻执行，而是放进队列，回复 QUEUED。
+// EXEC 一次锁住所有命令访问的键所在的分片，在同一次加锁中依次执行它们，
+// 其它连接不会看到执行到一半的状态，也不能在中间插入自己的命令。
+//
+// 和 Redis 一样，事务不会回滚：执行时出错的命令(例如 WRONGTYPE)只影响它自己，
+// 后面的命令照常执行。但是排队时就能发现的错误(命令不存在、参数个数不对)
+// 会让 EXEC 拒绝执行整个事务。
+//
+// WATCH 提供乐观锁：先 WATCH 几个键、读出它们的值，根据读到的值决定要执行的命令，
+// 如果从 WATCH 到 EXEC 之间有其它连接修改了这些键，EXEC 什么也不做、回复 nil，
+// 客户端重新读取再试一次。例如把库存减一：
+//
+//   WATCH stock
+//   GET stock          => 10
+//   MULTI
+//   SET stock 9
+//   EXEC               => 其它客户端同时修改了 stock 时回复 nil
+
+/// 一个连接的事务状态，每个连接都有一个。
+///
+/// drop 时取消所有的 WATCH。
+#[derive(Debug)]
+pub struct Transaction {
+    db: Db,
+    /// MULTI 之后排队的命令，`None` 表示不在事务中
+    queued: Option<Vec<Command>>,
+    /// 排队时有命令出错，EXEC 会拒绝执行
+    aborted: bool,
+    watched: Vec<String>,
+    /// WATCH 的键被修改过，由 `Db` 在修改键时设置
+    dirty: Arc<AtomicBool>,
+}
+
+impl Transaction {
+    pub fn new(db: Db) -> Transaction {
+        Transaction {
+            db,
+            queued: None,
+            aborted: false,
+            watched: vec![],
+            dirty: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// 是否在 MULTI 之后、EXEC 或者 DISCARD 之前。
+    pub fn is_queuing(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// 执行一个命令：事务相关的命令由这里处理，事务中的其它命令放进队列，
+    /// 不在事务中时直接执行。
+    pub fn apply(&mut self, cmd: Command) -> Frame {
+        match cmd {
+            Command::Multi => {
+                if self.queued.is_some() {
+                    return error("ERR MULTI calls can not be nested");
+                }
+                self.queued = Some(vec![]);
+                Frame::ok()
+            }
+            Command::Exec => self.exec(),
+            Command::Discard => {
+                if self.queued.take().is_none() {
+                    return error("ERR DISCARD without MULTI");
+                }
+                self.aborted = false;
+                self.unwatch();
+                Frame::ok()
+            }
+            Command::Watch { keys } => {
+                if self.queued.is_some() {
+                    return error("ERR WATCH inside MULTI is not allowed");
+                }
+                for key in keys {
+                    if !self.watched.contains(&key) {
+                        self.db.watch(&key, &self.dirty);
+                        self.watched.push(key);
+                    }
+                }
+                Frame::ok()
+            }
+            Command::Unwatch if self.queued.is_none() => {
+                self.unwatch();
+                Frame::ok()
+            }
+            cmd if self.queued.is_some() => self.queue(cmd),
+            cmd => cmd.apply(&self.db),
+        }
+    }
+
+    /// 命令解析失败(参数个数不对等)，在事务中时会让 EXEC 拒绝执行。
+    pub fn reject(&mut self, msg: String) -> Frame {
+        if self.queued.is_some() {
+            self.aborted = true;
+        }
+        Frame::Error(msg)
+    }
+
+    fn queue(&mut self, cmd: Command) -> Frame {
+        let allowed = match &cmd {
+            Command::Unknown { .. } => {
+                self.aborted = true;
+                return cmd.apply(&self.db);
+            }
+            // 这些命令要锁住所有的分片，在 EXEC 持有锁的时候执行会死锁；
+            // 订阅会让连接进入订阅状态，HELLO 会修改连接的协议版本，
+            // 复制相关的命令会修改服务器的角色或者把连接变成复制连接，也不能放在事务中。
+            // CLUSTER GETKEYSINSLOT 等命令同样要遍历所有的分片，ASKING 修改的是连接的状态
+            Command::Save { .. }
+            | Command::BgRewriteAof
+            | Command::Hello { .. }
+            | Command::ReplicaOf { .. }
+            | Command::PSync { .. }
+            | Command::ReplConf { .. }
+            | Command::Cluster { .. }
+            | Command::Asking => false,
+            cmd => !cmd.is_subscription(),
+        };
+        // 和 Redis 一样在排队时检查内存，EXEC 持有锁的时候没办法再去淘汰其它分片中的键
+        if let Some(frame) = cmd.readonly_error(&self.db).or_else(|| cmd.oom_error(&self.db)) {
+            self.aborted = true;
+            return frame;
+        }
+        if !allowed {
+            self.aborted = true;
+            return error("ERR Command not allowed inside a transaction");
+        }
+        self.queued.as_mut().unwrap().push(cmd);
+        Frame::Simple("QUEUED".to_string())
+    }
+
+    fn exec(&mut self) -> Frame {
+        let Some(commands) = self.queued.take() else {
+            return error("ERR EXEC without MULTI");
+        };
+        if std::mem::take(&mut self.aborted) {
+            self.unwatch();
+            return error("EXECABORT Transaction discarded because of previous errors.");
+        }
+
+        // WATCH 的键也要锁住：检查 dirty 之后、执行完之前，其它连接不能再修改它们
+        let keys = commands.iter().flat_map(Command::keys);
+        let mut db = self.db.lock(keys.chain(self.watched.iter().map(String::as_str)));
+        let frame = if self.dirty.load(Ordering::Acquire) {
+            Frame::Null
+        } else {
+            let mut propagated = vec![];
+            let replies = commands.into_iter().map(|cmd| match cmd {
+                // 不访问键的命令，不需要持有的锁
+                Command::Publish { .. } | Command::LastSave | Command::Role => cmd.apply(&self.db),
+                Command::Unwatch => Frame::ok(),
+                cmd => cmd.apply_locked_into(&mut db, &mut propagated),
+            });
+            let replies = Frame::Array(replies.collect());
+            // 所有的写命令作为一个事务一次写到 AOF 中、转发给从服务器
+            match db.propagate(&propagated) {
+                Ok(()) => replies,
+                Err(e) => error(&format!("ERR error writing to the AOF: {}", e)),
+            }
+        };
+        drop(db);
+        self.unwatch();
+        frame
+    }
+
+    fn unwatch(&mut self) {
+        for key in self.watched.drain(..) {
+            self.db.unwatch(&key, &self.dirty);
+        }
+        self.dirty.store(false, Ordering::Release);
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
+
+fn error(msg: &str) -> Frame {
+    Frame::Error(msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DbDropGuard;
+
+    use bytes::Bytes;
+
+    fn run(tx: &mut Transaction, args: &[&str]) -> Frame {
+        let frame = Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        match Command::from_frame(frame) {
+            Ok(cmd) => tx.apply(cmd),
+            Err(e) => tx.reject(e.to_string()),
+        }
+    }
+
+    fn queued() -> Frame {
+        Frame::Simple("QUEUED".into())
+    }
+
+    #[tokio::test]
+    async fn commands_are_queued_until_exec() {
+        let guard = DbDropGuard::new();
+        let mut tx = Transaction::new(guard.db());
+        let mut other = Transaction::new(guard.db());
+        assert_eq!(run(&mut tx, &["EXEC"]), error("ERR EXEC without MULTI"));
+        assert_eq!(run(&mut tx, &["DISCARD"]), error("ERR DISCARD without MULTI"));
+
+        assert_eq!(run(&mut tx, &["MULTI"]), Frame::ok());
+        assert_eq!(run(&mut tx, &["MULTI"]), error("ERR MULTI calls can not be nested"));
+        assert_eq!(run(&mut tx, &["SET", "k", "v"]), queued());
+        assert_eq!(run(&mut tx, &["INCR", "n"]), queued());
+        assert_eq!(run(&mut other, &["GET", "k"]), Frame::Null);
+        assert_eq!(
+            run(&mut tx, &["EXEC"]),
+            Frame::Array(vec![Frame::ok(), Frame::Integer(1)])
+        );
+        assert_eq!(run(&mut other, &["GET", "k"]), Frame::Bulk("v".into()));
+
+        run(&mut tx, &["MULTI"]);
+        run(&mut tx, &["DEL", "k"]);
+        assert_eq!(run(&mut tx, &["DISCARD"]), Frame::ok());
+        assert_eq!(run(&mut tx, &["EXISTS", "k"]), Frame::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn errors_while_queuing_abort_the_transaction() {
+        let guard = DbDropGuard::new();
+        let mut tx = Transaction::new(guard.db());
+        let aborted = error("EXECABORT Transaction discarded because of previous errors.");
+        for bad in [&["NOPE"][..], &["GET"], &["SUBSCRIBE", "c"], &["SAVE"]] {
+            run(&mut tx, &["MULTI"]);
+            assert_eq!(run(&mut tx, &["SET", "k", "v"]), queued());
+            assert!(matches!(run(&mut tx, bad), Frame::Error(_)), "{:?}", bad);
+            assert_eq!(run(&mut tx, &["EXEC"]), aborted);
+            assert_eq!(run(&mut tx, &["EXISTS", "k"]), Frame::Integer(0));
+        }
+
+        // 执行时的错误只影响出错的命令
+        run(&mut tx, &["MULTI"]);
+        run(&mut tx, &["SET", "k", "v"]);
+        run(&mut tx, &["LPUSH", "k", "x"]);
+        run(&mut tx, &["APPEND", "k", "w"]);
+        let Frame::Array(replies) = run(&mut tx, &["EXEC"]) else {
+            panic!("EXEC should reply an array");
+        };
+        assert!(matches!(&replies[1], Frame::Error(e) if e.starts_with("WRONGTYPE")));
+        assert_eq!(replies[2], Frame::Integer(2));
+    }
+
+    #[tokio::test]
+    async fn exec_fails_if_a_watched_key_changed() {
+        let guard = DbDropGuard::new();
+        let mut tx = Transaction::new(guard.db());
+        let mut other = Transaction::new(guard.db());
+        run(&mut other, &["SET", "stock", "10"]);
+
+        run(&mut tx, &["WATCH", "stock", "unrelated"]);
+        run(&mut other, &["SET", "stock", "5"]);
+        run(&mut tx, &["MULTI"]);
+        assert_eq!(run(&mut tx, &["WATCH", "x"]), error("ERR WATCH inside MULTI is not allowed"));
+        run(&mut tx, &["SET", "stock", "9"]);
+        assert_eq!(run(&mut tx, &["EXEC"]), Frame::Null);
+        assert_eq!(run(&mut tx, &["GET", "stock"]), Frame::Bulk("5".into()));
+
+        // EXEC 之后不再 WATCH，没有被修改的键不影响事务
+        run(&mut tx, &["WATCH", "stock"]);
+        run(&mut other, &["SET", "unrelated", "1"]);
+        run(&mut tx, &["MULTI"]);
+        run(&mut tx, &["DECR", "stock"]);
+        assert_eq!(run(&mut tx, &["EXEC"]), Frame::Array(vec![Frame::Integer(4)]));
+
+        // UNWATCH 之后的修改也不影响
+        run(&mut tx, &["WATCH", "stock"]);
+        run(&mut tx, &["UNWATCH"]);
+        run(&mut other, &["DEL", "stock"]);
+        run(&mut tx, &["MULTI"]);
+        run(&mut tx, &["INCR", "stock"]);
+        assert_eq!(run(&mut tx, &["EXEC"]), Frame::Array(vec![Frame::Integer(1)]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expiring_a_watched_key_counts_as_a_change() {
+        let guard = DbDropGuard::new();
+        let mut tx = Transaction::new(guard.db());
+        run(&mut tx, &["SET", "session", "v", "PX", "100"]);
+        run(&mut tx, &["WATCH", "session"]);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        run(&mut tx, &["MULTI"]);
+        run(&mut tx, &["SET", "session", "w"]);
+        assert_eq!(run(&mut tx, &["EXEC"]), Frame::Null);
+    }
+
+    #[test]
+    fn optimistic_increments_from_many_threads_are_not_lost() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _enter = runtime.enter();
+        let guard = DbDropGuard::new();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mut tx = Transaction::new(guard.db());
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        // 读出当前的值，加一之后写回去，被其它线程抢先修改时重试
+                        loop {
+                            run(&mut tx, &["WATCH", "counter"]);
+                            let n = match run(&mut tx, &["GET", "counter"]) {
+                                Frame::Bulk(n) => std::str::from_utf8(&n).unwrap().parse().unwrap(),
+                                _ => 0,
+                            };
+                            run(&mut tx, &["MULTI"]);
+                            run(&mut tx, &["SET", "counter", &(n + 1).to_string()]);
+                            if run(&mut tx, &["EXEC"]) != Frame::Null {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let mut tx = Transaction::new(guard.db());
+        assert_eq!(run(&mut tx, &["GET", "counter"]), Frame::Bulk("800".into()));
+    }
+
+    #[tokio::test]
+    async fn a_transaction_cut_off_in_the_aof_is_not_replayed() {
+        use crate::cmd::tests::{restart, temp_config};
+
+        let config = crate::Config {
+            appendonly: true,
+            ..temp_config("tx")
+        };
+        let guard = restart(&config);
+        for value in ["1", "2"] {
+            let mut tx = Transaction::new(guard.db());
+            run(&mut tx, &["MULTI"]);
+            run(&mut tx, &["SET", "k", value]);
+            run(&mut tx, &["INCR", "n"]);
+            run(&mut tx, &["EXEC"]);
+        }
+        drop(guard);
+
+        // 事务在 AOF 中用 MULTI/EXEC 包起来，模拟写到 EXEC 之前就崩溃了
+        let data = std::fs::read(&config.appendfilename).unwrap();
+        assert_eq!(data.windows(5).filter(|w| w == b"MULTI").count(), 2);
+        std::fs::write(&config.appendfilename, &data[..data.len() - 8]).unwrap();
+        let mut tx = Transaction::new(restart(&config).db());
+        assert_eq!(run(&mut tx, &["GET", "k"]), Frame::Bulk("1".into()));
+        assert_eq!(run(&mut tx, &["GET", "n"]), Frame::Bulk("1".into()));
+        std::fs::remove_file(config.appendfilename).unwrap();
+    }
+}
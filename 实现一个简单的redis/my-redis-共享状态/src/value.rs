@@ -0,0 +1,316 @@
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::mem::size_of;
+
+// Redis 的值不只是字符串，一个键还可以保存列表、哈希表、集合和有序集合。
+// 每个命令只能作用于特定类型的值，例如对一个列表执行 GET 会得到 WRONGTYPE 错误。
+
+/// 数据库中保存的值。
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    /// 两端都可以高效地插入和弹出
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    ZSet(SortedSet),
+}
+
+/// 对一个键执行了类型不对的操作。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongType;
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WRONGTYPE Operation against a key holding the wrong kind of value")
+    }
+}
+
+impl std::error::Error for WrongType {}
+
+impl Value {
+    /// TYPE 命令返回的类型名。
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+        }
+    }
+
+    /// 集合类型的值变成空的之后，键就应该被删除，Redis 中不存在空的列表、哈希表或集合。
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+        }
+    }
+
+    /// 大约占用多少字节的内存，用于 maxmemory(见 eviction.rs)。
+    ///
+    /// 集合类型和 Redis 的 MEMORY USAGE 一样只看前几个元素，按它们的平均大小估算整个集合，
+    /// 每次修改之后重新估算的开销不会随着集合变大而变大。
+    pub fn memory_usage(&self) -> usize {
+        // 哈希表的每个元素除了自己之外，还有控制字节和空闲的槽位，粗略算作 16 字节
+        const SLOT: usize = 16;
+        let bytes = |b: &Bytes| size_of::<Bytes>() + b.len();
+        size_of::<Value>()
+            + match self {
+                Value::String(s) => s.len(),
+                Value::List(list) => estimate(list.len(), list.iter().map(bytes)),
+                Value::Hash(hash) => {
+                    let pairs = hash.iter().map(|(field, value)| bytes(field) + bytes(value));
+                    estimate(hash.len(), pairs.map(|size| size + SLOT))
+                }
+                Value::Set(set) => estimate(set.len(), set.iter().map(|m| bytes(m) + SLOT)),
+                // 成员同时保存在哈希表和 BTreeSet 中
+                Value::ZSet(zset) => {
+                    let member = |m: &Bytes| 2 * (bytes(m) + size_of::<f64>()) + SLOT;
+                    estimate(zset.len(), zset.iter().map(|(m, _)| member(m)))
+                }
+            }
+    }
+
+    pub fn as_string(&self) -> Result<&Bytes, WrongType> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&VecDeque<Bytes>, WrongType> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&HashMap<Bytes, Bytes>, WrongType> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_set(&self) -> Result<&HashSet<Bytes>, WrongType> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_zset(&self) -> Result<&SortedSet, WrongType> {
+        match self {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut VecDeque<Bytes>, WrongType> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut HashMap<Bytes, Bytes>, WrongType> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Result<&mut HashSet<Bytes>, WrongType> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_zset_mut(&mut self) -> Result<&mut SortedSet, WrongType> {
+        match self {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(WrongType),
+        }
+    }
+}
+
+/// 根据前几个元素的平均大小估算 `len` 个元素一共有多大。
+fn estimate(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    const SAMPLES: usize = 5;
+    let (count, total) = sizes.take(SAMPLES).fold((0, 0), |(n, sum), size| (n + 1, sum + size));
+    (total * len).checked_div(count).unwrap_or(0)
+}
+
+impl From<Bytes> for Value {
+    fn from(s: Bytes) -> Value {
+        Value::String(s)
+    }
+}
+
+/// 有序集合：每个成员带有一个分数，成员按分数排序，分数相同时按成员的字节序排序。
+///
+/// 同时维护两个索引：按成员查找分数的哈希表，以及按(分数, 成员)排序的 BTreeSet，
+/// 这样 ZADD/ZREM 和按分数范围查找都只需要 O(log n)。
+/// Redis 用的是跳表，它还能 O(log n) 地按排名查找，BTreeSet 按排名查找需要 O(n)。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+/// f64 没有实现 `Ord`(NaN 无法比较)，有序集合中不允许出现 NaN，可以用 `total_cmp` 排序。
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// 添加成员或者更新它的分数，新添加的成员返回 true。分数不能是 NaN。
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        assert!(!score.is_nan(), "sorted set score must not be NaN");
+        // -0.0 和 0.0 用 total_cmp 比较是不相等的，统一成 0.0
+        let score = if score == 0.0 { 0.0 } else { score };
+        let prev = self.scores.insert(member.clone(), score);
+        if let Some(prev) = prev {
+            self.ordered.remove(&(Score(prev), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        prev.is_none()
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove_entry(member) {
+            Some((member, score)) => {
+                self.ordered.remove(&(Score(score), member));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 按分数从小到大遍历所有成员。
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+
+    /// 分数在 `min` 和 `max` 之间的成员。
+    pub fn range_by_score(
+        &self,
+        min: ScoreBound,
+        max: ScoreBound,
+    ) -> impl Iterator<Item = (&Bytes, f64)> {
+        // 从第一个分数不小于 min 的成员开始，不需要从头遍历
+        let start = (Score(min.value), Bytes::new());
+        self.ordered
+            .range(start..)
+            .map(|(score, member)| (member, score.0))
+            .skip_while(move |(_, score)| !min.below(*score))
+            .take_while(move |(_, score)| max.above(*score))
+    }
+}
+
+/// ZRANGEBYSCORE 的分数范围的一端，`(1.5` 表示不包括 1.5。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    /// 作为下界时，`score` 是否在范围内
+    fn below(&self, score: f64) -> bool {
+        if self.exclusive { self.value < score } else { self.value <= score }
+    }
+
+    /// 作为上界时，`score` 是否在范围内
+    fn above(&self, score: f64) -> bool {
+        if self.exclusive { score < self.value } else { score <= self.value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members<'a>(iter: impl Iterator<Item = (&'a Bytes, f64)>) -> Vec<(&'a str, f64)> {
+        iter.map(|(m, s)| (std::str::from_utf8(m).unwrap(), s)).collect()
+    }
+
+    #[test]
+    fn sorted_set_orders_by_score_then_member() {
+        let mut zset = SortedSet::new();
+        assert!(zset.insert(Bytes::from("b"), 2.0));
+        assert!(zset.insert(Bytes::from("a"), 2.0));
+        assert!(zset.insert(Bytes::from("c"), -1.5));
+        // 更新分数不算新成员
+        assert!(!zset.insert(Bytes::from("c"), 3.0));
+        assert_eq!(members(zset.iter()), [("a", 2.0), ("b", 2.0), ("c", 3.0)]);
+        assert_eq!(zset.score(b"c"), Some(3.0));
+
+        assert!(zset.remove(b"a"));
+        assert!(!zset.remove(b"a"));
+        assert_eq!(zset.len(), 2);
+        assert_eq!(members(zset.iter()), [("b", 2.0), ("c", 3.0)]);
+    }
+
+    #[test]
+    fn memory_usage_grows_with_the_value() {
+        let small = Value::String(Bytes::from("v"));
+        let large = Value::String(Bytes::from(vec![0; 1000]));
+        assert_eq!(large.memory_usage() - small.memory_usage(), 999);
+
+        let list = |n| Value::List((0..n).map(|_| Bytes::from("item")).collect());
+        let (ten, thousand) = (list(10).memory_usage(), list(1000).memory_usage());
+        assert_eq!(thousand - size_of::<Value>(), (ten - size_of::<Value>()) * 100);
+    }
+
+    #[test]
+    fn range_by_score_respects_exclusive_bounds() {
+        let mut zset = SortedSet::new();
+        for (i, member) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            zset.insert(Bytes::from(member), i as f64);
+        }
+        let bound = |value, exclusive| ScoreBound { value, exclusive };
+        let range = |min, max| members(zset.range_by_score(min, max));
+        assert_eq!(range(bound(1.0, false), bound(2.0, false)), [("b", 1.0), ("c", 2.0)]);
+        assert_eq!(range(bound(1.0, true), bound(3.0, true)), [("c", 2.0)]);
+        assert_eq!(range(bound(f64::NEG_INFINITY, false), bound(0.0, false)), [("a", 0.0)]);
+        assert!(range(bound(2.0, true), bound(2.0, false)).is_empty());
+    }
+}
@@ -322,9 +322,15 @@ fn study_tokio_split_lock() {
         // 首先，使用 key 通过特定的算法寻找到对应的分片，然后再使用该 key 从分片中查询到值:
         let db = new_sharded_db(3);
         let key = "hello";
-        fn hash(_key: &str) -> usize {
-            // ...
-            0
+        fn hash(key: &str) -> usize {
+            // 任何分布均匀的哈希函数都可以，这里使用标准库 HashMap 默认的 SipHash。
+            // 注意同一个键每次都必须得到同样的结果：DefaultHasher::new() 的密钥是固定的，
+            // 而每个 RandomState::new() 的密钥都不一样。
+            // my-redis-共享状态 中的 db.rs 是一个完整的分片数据库
+            use std::hash::{DefaultHasher, Hash, Hasher};
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish() as usize
         }
         let mut shard = db[hash(key) % db.len()].lock().unwrap();
         let value = Vec::from("world");
@@ -333,5 +339,7 @@ fn study_tokio_split_lock() {
         // 这里我们使用 hash 算法来进行分片，但是该算法有个缺陷：分片的数量不能变，
         // 一旦变了后，那之前落入分片 1 的key很可能将落入到其它分片中，最终全部乱掉。
         // 此时你可以考虑 dashmap，它提供了更复杂、更精妙的支持分片的hash map。
+        // Redis Cluster 用的是另一种办法：键先映射到固定的 16384 个哈希槽，槽再分配给各个服务器，
+        // 增减服务器时只需要迁移一部分槽(见 my-redis-共享状态 中的 cluster.rs)。
     }
 }